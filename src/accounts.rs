@@ -0,0 +1,663 @@
+//! Creating the initial user account directly in the target.
+//!
+//! The entry is appended to the target's `/etc/passwd`, `/etc/shadow`,
+//! `/etc/group` (and `/etc/gshadow` if it has one), the user is added to
+//! its supplementary groups, and the home directory is populated from the
+//! target's `/etc/skel` (or `--skel`) and handed to the new user. Nothing runs inside
+//! the target, so no chroot (and no script left behind in `/root`) is
+//! needed. UIDs and GIDs come from the target's `/etc/login.defs`.
+//!
+//! With `--sudo`, wheel's sudo rights come from a drop-in of our own,
+//! `/etc/sudoers.d/10-recstrap`, rather than whatever the image's
+//! `/etc/sudoers` happens to say. It is checked with `visudo -c` before
+//! it is put in place: a broken sudoers file locks everyone out of sudo.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{fchown, lchown, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use clap::ValueEnum;
+
+use crate::helpers::{resolve_in_root, tool_available};
+use crate::runner;
+
+/// `UID_MIN`/`UID_MAX` (and the GID ones) when login.defs doesn't say.
+const ID_MIN: u32 = 1000;
+const ID_MAX: u32 = 60000;
+
+/// The drop-in `--sudo` writes. sudo skips names with a `.`, so the
+/// temporary file next to it is never read half-written.
+pub const SUDOERS_DROP_IN: &str = "/etc/sudoers.d/10-recstrap";
+
+/// What wheel members may do with sudo (`--sudo`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SudoPolicy {
+    /// Any command, after typing their own password
+    Password,
+    /// Any command, without a password (appliances, test VMs)
+    Nopasswd,
+}
+
+impl SudoPolicy {
+    /// The drop-in's content.
+    fn sudoers(self) -> String {
+        let tag = match self {
+            SudoPolicy::Password => "",
+            SudoPolicy::Nopasswd => "NOPASSWD: ",
+        };
+        format!(
+            "# Written by recstrap for the initial user (--sudo)\n%wheel ALL=(ALL:ALL) {}ALL\n",
+            tag
+        )
+    }
+}
+
+/// An account to create in the target.
+pub struct NewUser<'a> {
+    pub name: &'a str,
+    /// crypt(3) hash for `/etc/shadow`
+    pub password_hash: &'a str,
+    /// Supplementary groups, which must exist in the target
+    pub groups: &'a [&'a str],
+    pub shell: &'a str,
+    /// Home directory template instead of the target's `/etc/skel`
+    pub skel: Option<&'a Path>,
+}
+
+/// What the command line says about the initial user, asked for
+/// interactively after extraction.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InitialUser<'a> {
+    /// `--password-hash-fd`: use this hash, don't ask for a password
+    pub password_hash: Option<&'a str>,
+    /// `--sudo`
+    pub sudo: Option<SudoPolicy>,
+    /// `--skel`
+    pub skel: Option<&'a Path>,
+}
+
+/// Whether `name` is a portable user or group name (what useradd accepts
+/// by default).
+pub fn valid_account_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= 32
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/// Parse a user name argument, e.g. `--autologin`.
+pub fn account_name(s: &str) -> std::result::Result<String, String> {
+    if valid_account_name(s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "invalid user name '{}' (lowercase letters, digits, '_' and '-')",
+            s
+        ))
+    }
+}
+
+/// Whether `target`'s `/etc/passwd` has `name`.
+pub fn user_exists(target: &Path, name: &str) -> io::Result<bool> {
+    let passwd = fs::read_to_string(resolve_in_root(target, Path::new("/etc/passwd"))?)?;
+    Ok(has_entry(&passwd, name))
+}
+
+/// Add `user` to `group` in `target` (group and gshadow). Returns false
+/// if there is no such group.
+pub fn add_to_group(target: &Path, group: &str, user: &str) -> io::Result<bool> {
+    let group_path = resolve_in_root(target, Path::new("/etc/group"))?;
+    let content = fs::read_to_string(&group_path)?;
+    if !has_entry(&content, group) {
+        return Ok(false);
+    }
+    replace_file(&group_path, &add_member(&content, group, user))?;
+    let gshadow_path = resolve_in_root(target, Path::new("/etc/gshadow"))?;
+    if let Ok(content) = fs::read_to_string(&gshadow_path) {
+        replace_file(&gshadow_path, &add_member(&content, group, user))?;
+    }
+    Ok(true)
+}
+
+/// Hash `password` for `/etc/shadow` (SHA-512 crypt, via openssl).
+pub fn hash_password(password: &str) -> io::Result<String> {
+    let output = runner::output_with_input(
+        Command::new("openssl")
+            .args(["passwd", "-6", "-stdin"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+        format!("{}\n", password).as_bytes(),
+    )?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "openssl passwd failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let hash = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !hash.starts_with("$6$") {
+        return Err(io::Error::other("openssl passwd printed no SHA-512 hash"));
+    }
+    Ok(hash)
+}
+
+/// Create `user` in `target`, with a user private group of the same name
+/// and a home directory under `/home`.
+pub fn create_user(target: &Path, user: &NewUser) -> io::Result<()> {
+    let etc = |name: &str| resolve_in_root(target, &Path::new("/etc").join(name));
+    let passwd_path = etc("passwd")?;
+    let shadow_path = etc("shadow")?;
+    let group_path = etc("group")?;
+    let gshadow_path = etc("gshadow")?;
+    let passwd = fs::read_to_string(&passwd_path)?;
+    let shadow = fs::read_to_string(&shadow_path)?;
+    let group = fs::read_to_string(&group_path)?;
+    let gshadow = fs::read_to_string(&gshadow_path).ok();
+    let login_defs = fs::read_to_string(etc("login.defs")?).unwrap_or_default();
+
+    // Everything is checked before anything is written
+    if has_entry(&passwd, user.name) {
+        return Err(io::Error::other(format!(
+            "user '{}' already exists",
+            user.name
+        )));
+    }
+    if has_entry(&group, user.name) {
+        return Err(io::Error::other(format!(
+            "group '{}' already exists",
+            user.name
+        )));
+    }
+    if let Some(missing) = user.groups.iter().find(|g| !has_entry(&group, g)) {
+        return Err(io::Error::other(format!(
+            "group '{}' does not exist",
+            missing
+        )));
+    }
+    let home = Path::new("/home").join(user.name);
+    let home_path = resolve_in_root(target, &home)?;
+    if fs::symlink_metadata(&home_path).is_ok() {
+        return Err(io::Error::other(format!(
+            "{} already exists",
+            home.display()
+        )));
+    }
+
+    let id_range = |key: &str| {
+        (
+            login_defs_value(&login_defs, &format!("{}_MIN", key)).unwrap_or(ID_MIN),
+            login_defs_value(&login_defs, &format!("{}_MAX", key)).unwrap_or(ID_MAX),
+        )
+    };
+    let (uid_min, uid_max) = id_range("UID");
+    let uid = next_free_id(&passwd, uid_min, uid_max)
+        .ok_or_else(|| io::Error::other("no free UID left"))?;
+    // Same GID as UID where possible, like useradd
+    let (gid_min, gid_max) = id_range("GID");
+    let gid = if ids_in_use(&group).contains(&uid) {
+        next_free_id(&group, gid_min, gid_max)
+            .ok_or_else(|| io::Error::other("no free GID left"))?
+    } else {
+        uid
+    };
+
+    replace_file(
+        &passwd_path,
+        &append_line(
+            &passwd,
+            &format!(
+                "{}:x:{}:{}::{}:{}",
+                user.name,
+                uid,
+                gid,
+                home.display(),
+                user.shell
+            ),
+        ),
+    )?;
+    replace_file(
+        &shadow_path,
+        &append_line(
+            &shadow,
+            &format!(
+                "{}:{}:{}:0:99999:7:::",
+                user.name,
+                user.password_hash,
+                days_since_epoch()
+            ),
+        ),
+    )?;
+    let mut group = append_line(&group, &format!("{}:x:{}:", user.name, gid));
+    for name in user.groups {
+        group = add_member(&group, name, user.name);
+    }
+    replace_file(&group_path, &group)?;
+    if let Some(gshadow) = gshadow {
+        let mut gshadow = append_line(&gshadow, &format!("{}:!::", user.name));
+        for name in user.groups {
+            gshadow = add_member(&gshadow, name, user.name);
+        }
+        replace_file(&gshadow_path, &gshadow)?;
+    }
+
+    let skel = match user.skel {
+        Some(skel) => skel.to_path_buf(),
+        None => resolve_in_root(target, Path::new("/etc/skel"))?,
+    };
+    if skel.is_dir() {
+        copy_tree(&skel, &home_path)?;
+    } else {
+        fs::create_dir_all(&home_path)?;
+    }
+    fs::set_permissions(&home_path, fs::Permissions::from_mode(0o700))?;
+    chown_tree(&home_path, uid, gid)
+}
+
+/// Write the `--sudo` drop-in into `target` with mode 0440, after
+/// `visudo -c` accepted it. Returns a warning if the target's sudoers
+/// won't read it.
+pub fn write_sudoers(target: &Path, policy: SudoPolicy) -> io::Result<Option<String>> {
+    let drop_in = Path::new(SUDOERS_DROP_IN);
+    let sudoers = resolve_in_root(target, Path::new("/etc/sudoers"))?;
+    let main = fs::read_to_string(&sudoers).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("/etc/sudoers: {} (is sudo installed in the image?)", e),
+        )
+    })?;
+    let dir = resolve_in_root(target, drop_in.parent().unwrap_or(drop_in))?;
+    if !dir.exists() {
+        fs::create_dir(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o750))?;
+    }
+    let path = dir.join(drop_in.file_name().unwrap_or_default());
+
+    let temp = path.with_extension("recstrap");
+    fs::write(&temp, policy.sudoers())?;
+    fs::set_permissions(&temp, fs::Permissions::from_mode(0o440))?;
+    if let Err(e) = check_sudoers(&temp) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    fs::rename(&temp, &path)?;
+
+    Ok((!includes_drop_ins(&main)).then(|| {
+        format!(
+            "/etc/sudoers has no @includedir /etc/sudoers.d, so {} is ignored",
+            SUDOERS_DROP_IN
+        )
+    }))
+}
+
+/// `visudo -c` on `path`, if the live system has visudo.
+fn check_sudoers(path: &Path) -> io::Result<()> {
+    if !tool_available("visudo") {
+        return Ok(());
+    }
+    let output = runner::output(
+        Command::new("visudo")
+            .args(["-c", "-q", "-f"])
+            .arg(path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+    if !output.status.success() {
+        let mut message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if message.is_empty() {
+            message = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        }
+        return Err(io::Error::other(format!(
+            "visudo rejected the sudoers drop-in: {}",
+            message
+        )));
+    }
+    Ok(())
+}
+
+/// Whether sudoers `content` reads `/etc/sudoers.d` (`#includedir` is the
+/// old spelling, not a comment).
+fn includes_drop_ins(content: &str) -> bool {
+    content.lines().any(|line| {
+        let mut words = line.split_whitespace();
+        matches!(words.next(), Some("@includedir" | "#includedir"))
+            && words.next() == Some("/etc/sudoers.d")
+    })
+}
+
+/// Whether the colon-separated `content` has an entry named `name`.
+fn has_entry(content: &str, name: &str) -> bool {
+    content
+        .lines()
+        .any(|line| line.split(':').next() == Some(name))
+}
+
+/// The numeric IDs (third field) used in passwd or group `content`.
+fn ids_in_use(content: &str) -> Vec<u32> {
+    content
+        .lines()
+        .filter_map(|line| line.split(':').nth(2)?.parse().ok())
+        .collect()
+}
+
+/// The lowest ID from `min` to `max` not used in `content`.
+fn next_free_id(content: &str, min: u32, max: u32) -> Option<u32> {
+    let used = ids_in_use(content);
+    (min..=max).find(|id| !used.contains(id))
+}
+
+/// A numeric setting from login.defs `content`.
+fn login_defs_value(content: &str, key: &str) -> Option<u32> {
+    content.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        (words.next()? == key).then(|| words.next()?.parse().ok())?
+    })
+}
+
+/// `content` with `line` added at the end.
+fn append_line(content: &str, line: &str) -> String {
+    let mut out = content.to_string();
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(line);
+    out.push('\n');
+    out
+}
+
+/// Group or gshadow `content` with `user` added to the members (the
+/// fourth field in both) of `group`.
+fn add_member(content: &str, group: &str, user: &str) -> String {
+    content
+        .lines()
+        .map(|line| {
+            let mut fields: Vec<&str> = line.split(':').collect();
+            if fields.len() != 4 || fields[0] != group {
+                return format!("{}\n", line);
+            }
+            let members = match fields[3] {
+                "" => user.to_string(),
+                members if members.split(',').any(|m| m == user) => members.to_string(),
+                members => format!("{},{}", members, user),
+            };
+            fields[3] = &members;
+            format!("{}\n", fields.join(":"))
+        })
+        .collect()
+}
+
+fn days_since_epoch() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / 86_400)
+}
+
+/// Replace `path` with `content` atomically, keeping its mode and owner
+/// (shadow files are often 0640 root:shadow). The new file is created
+/// with that mode before anything is written to it, and never through a
+/// link left at its name.
+fn replace_file(path: &Path, content: &str) -> io::Result<()> {
+    let meta = fs::metadata(path)?;
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".recstrap");
+    let temp = path.with_file_name(name);
+    // Left over from an interrupted run; unlinking a symlink leaves its
+    // target alone
+    match fs::remove_file(&temp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(meta.mode() & 0o7777)
+        .open(&temp)?;
+    file.write_all(content.as_bytes())?;
+    fchown(&file, Some(meta.uid()), Some(meta.gid()))?;
+    // After the chown, which clears setuid bits; and past the umask
+    file.set_permissions(meta.permissions())?;
+    drop(file);
+    fs::rename(&temp, path)
+}
+
+/// Copy the tree at `from` to `to` (which must not exist), keeping modes.
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let dest: PathBuf = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &dest)?;
+            fs::set_permissions(&dest, entry.metadata()?.permissions())?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &dest)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), &dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Give everything under `path` to `uid`:`gid`, without following links.
+fn chown_tree(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    lchown(path, Some(uid), Some(gid))?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_tree(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::FakeRunner;
+
+    #[test]
+    fn test_next_free_id() {
+        let passwd = "root:x:0:0::/root:/bin/sh\nalice:x:1000:1000::/home/alice:/bin/sh\n\
+                      nobody:x:65534:65534::/:/sbin/nologin\n";
+        assert_eq!(next_free_id(passwd, 1000, 60000), Some(1001));
+        assert_eq!(next_free_id(passwd, 1000, 1000), None);
+        assert_eq!(next_free_id("", 1000, 60000), Some(1000));
+    }
+
+    #[test]
+    fn test_login_defs_value() {
+        let defs = "# UID_MIN 1\nUID_MIN\t\t 2000\nUID_MAX 60000\nGID_MIN oops\n";
+        assert_eq!(login_defs_value(defs, "UID_MIN"), Some(2000));
+        assert_eq!(login_defs_value(defs, "UID_MAX"), Some(60000));
+        assert_eq!(login_defs_value(defs, "GID_MIN"), None);
+        assert_eq!(login_defs_value(defs, "GID_MAX"), None);
+    }
+
+    #[test]
+    fn test_add_member() {
+        let group = "root:x:0:\nwheel:x:10:\naudio:x:63:bob\n";
+        assert_eq!(
+            add_member(group, "wheel", "alice"),
+            "root:x:0:\nwheel:x:10:alice\naudio:x:63:bob\n"
+        );
+        assert_eq!(
+            add_member(group, "audio", "alice"),
+            "root:x:0:\nwheel:x:10:\naudio:x:63:bob,alice\n"
+        );
+        assert_eq!(add_member(group, "audio", "bob"), group);
+        assert_eq!(append_line("root:x:0:", "a:x:1:"), "root:x:0:\na:x:1:\n");
+    }
+
+    #[test]
+    fn test_valid_account_name() {
+        assert!(valid_account_name("alice"));
+        assert!(valid_account_name("_svc-1"));
+        assert!(!valid_account_name("Alice"));
+        assert!(!valid_account_name("1alice"));
+        assert!(!valid_account_name("al:ce"));
+        assert!(!valid_account_name(""));
+        assert!(!valid_account_name(&"a".repeat(33)));
+    }
+
+    #[test]
+    fn test_sudoers() {
+        assert_eq!(
+            SudoPolicy::Nopasswd.sudoers().lines().last(),
+            Some("%wheel ALL=(ALL:ALL) NOPASSWD: ALL")
+        );
+        assert_eq!(
+            SudoPolicy::Password.sudoers().lines().last(),
+            Some("%wheel ALL=(ALL:ALL) ALL")
+        );
+        assert!(includes_drop_ins(
+            "root ALL=(ALL) ALL\n@includedir /etc/sudoers.d\n"
+        ));
+        assert!(includes_drop_ins("#includedir /etc/sudoers.d\n"));
+        assert!(!includes_drop_ins("# includedir /etc/sudoers.d\n"));
+    }
+
+    #[test]
+    fn test_write_sudoers() {
+        let root = std::env::temp_dir().join("recstrap_test_write_sudoers");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("etc")).unwrap();
+        assert!(write_sudoers(&root, SudoPolicy::Password).is_err());
+
+        fs::write(root.join("etc/sudoers"), "root ALL=(ALL:ALL) ALL\n").unwrap();
+        let (fake, _runner) = FakeRunner::install();
+        let warning = write_sudoers(&root, SudoPolicy::Nopasswd).unwrap();
+        assert!(warning.unwrap().contains("no @includedir"));
+        let drop_in = root.join("etc/sudoers.d/10-recstrap");
+        assert!(fs::read_to_string(&drop_in).unwrap().contains("NOPASSWD"));
+        assert_eq!(fs::metadata(&drop_in).unwrap().mode() & 0o777, 0o440);
+        assert_eq!(
+            fake.calls()[1],
+            format!(
+                "visudo -c -q -f {}",
+                root.join("etc/sudoers.d/10-recstrap.recstrap").display()
+            )
+        );
+
+        // Nothing is left behind when visudo says no
+        fs::remove_file(&drop_in).unwrap();
+        fake.reply("visudo", 1, "syntax error near line 2");
+        let error = write_sudoers(&root, SudoPolicy::Password).unwrap_err();
+        assert!(error.to_string().contains("syntax error"));
+        assert_eq!(fs::read_dir(root.join("etc/sudoers.d")).unwrap().count(), 0);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_replace_file() {
+        let root = std::env::temp_dir().join("recstrap_test_replace_file");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let shadow = root.join("shadow");
+        fs::write(&shadow, "root:!:19000::::::\n").unwrap();
+        fs::set_permissions(&shadow, fs::Permissions::from_mode(0o640)).unwrap();
+        // A link planted at the temporary name is replaced, not followed
+        let outside = root.join("outside");
+        fs::write(&outside, "untouched").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("shadow.recstrap")).unwrap();
+
+        replace_file(&shadow, "root:*:19000::::::\n").unwrap();
+        assert_eq!(fs::read_to_string(&shadow).unwrap(), "root:*:19000::::::\n");
+        assert_eq!(fs::metadata(&shadow).unwrap().mode() & 0o7777, 0o640);
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "untouched");
+        assert!(!root.join("shadow.recstrap").exists());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_hash_password() {
+        let (fake, _runner) = FakeRunner::install();
+        // The fake prints nothing
+        assert!(hash_password("s3cret").is_err());
+        assert_eq!(fake.calls(), ["openssl passwd -6 -stdin"]);
+    }
+
+    #[test]
+    fn test_create_user() {
+        // Handing the home directory over needs root
+        if !crate::helpers::is_root() {
+            return;
+        }
+        let root = std::env::temp_dir().join("recstrap_test_create_user");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("etc/skel/.config")).unwrap();
+        fs::create_dir_all(root.join("home")).unwrap();
+        fs::write(root.join("etc/skel/.bashrc"), "# bashrc\n").unwrap();
+        fs::write(
+            root.join("etc/passwd"),
+            "root:x:0:0::/root:/bin/sh\nbob:x:1000:1000::/home/bob:/bin/sh\n",
+        )
+        .unwrap();
+        fs::write(root.join("etc/shadow"), "root:!:19000::::::\n").unwrap();
+        fs::set_permissions(root.join("etc/shadow"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::write(
+            root.join("etc/group"),
+            "root:x:0:\nwheel:x:10:\nbob:x:1000:\n",
+        )
+        .unwrap();
+
+        let user = NewUser {
+            name: "alice",
+            password_hash: "$6$salt$hash",
+            groups: &["wheel"],
+            shell: "/bin/bash",
+            skel: None,
+        };
+        create_user(&root, &user).unwrap();
+
+        let read = |p: &str| fs::read_to_string(root.join(p)).unwrap();
+        assert!(read("etc/passwd").ends_with("\nalice:x:1001:1001::/home/alice:/bin/bash\n"));
+        let shadow = read("etc/shadow");
+        assert!(shadow.contains("\nalice:$6$salt$hash:"), "{}", shadow);
+        assert_eq!(
+            fs::metadata(root.join("etc/shadow")).unwrap().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            read("etc/group"),
+            "root:x:0:\nwheel:x:10:alice\nbob:x:1000:\nalice:x:1001:\n"
+        );
+        assert_eq!(read("home/alice/.bashrc"), "# bashrc\n");
+        assert!(root.join("home/alice/.config").is_dir());
+        let home = fs::metadata(root.join("home/alice")).unwrap();
+        assert_eq!(home.mode() & 0o777, 0o700);
+        assert_eq!((home.uid(), home.gid()), (1001, 1001));
+
+        // --skel replaces the target's /etc/skel
+        let skel = root.join("org-skel");
+        fs::create_dir_all(skel.join(".ssh")).unwrap();
+        fs::write(skel.join(".ssh/config"), "Host *\n").unwrap();
+        let dave = NewUser {
+            name: "dave",
+            groups: &[],
+            skel: Some(&skel),
+            ..user
+        };
+        create_user(&root, &dave).unwrap();
+        assert_eq!(read("home/dave/.ssh/config"), "Host *\n");
+        assert!(!root.join("home/dave/.bashrc").exists());
+        let ssh = fs::metadata(root.join("home/dave/.ssh")).unwrap();
+        assert_eq!((ssh.uid(), ssh.gid()), (1002, 1002));
+
+        // Nothing is written when the user exists or a group is missing
+        assert!(create_user(&root, &user).is_err());
+        let user = NewUser {
+            name: "carol",
+            groups: &["sudo"],
+            ..user
+        };
+        assert!(create_user(&root, &user).is_err());
+        assert!(!read("etc/passwd").contains("carol"));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,525 @@
+//! Unattended installs from an answers file (`recstrap --answers FILE`).
+//!
+//! A kickstart-lite: one TOML file names the target, the image, and the
+//! configuration recstrap already knows how to apply (network, console,
+//! units, bootloader), plus what only makes sense in a full install -
+//! hostname, users, and hook commands. The extraction itself is an
+//! ordinary recstrap run; the file just spells out its arguments.
+//!
+//! ```toml
+//! target = "/mnt"
+//! hostname = "build01"
+//!
+//! [network]
+//! config = "dhcp"
+//!
+//! [bootloader]
+//! type = "grub"
+//! device = "/dev/sda"
+//!
+//! [[users]]
+//! name = "alice"
+//! groups = ["wheel"]
+//! password_hash = "$6$..."
+//!
+//! [hooks]
+//! post = ["systemctl enable sshd"]
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command as Process;
+
+use clap::Parser;
+
+use crate::accounts::valid_account_name;
+use crate::chroot::{run_in_chroot, warn_kernel_mismatch, ChrootMounts};
+use crate::cli::{Args, BootloaderArgs, Command};
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::{sync_filesystem, valid_password_hash};
+use crate::runner;
+use crate::toml::{self, Table, Value};
+
+/// Keys allowed in each section, so typos fail instead of being ignored.
+const ROOT_KEYS: &[&str] = &[
+    "target",
+    "rootfs",
+    "flavor",
+    "hostname",
+    "verify",
+    "force",
+    "reinstall",
+    "strict",
+    "umount_after",
+];
+const SYSTEM_KEYS: &[&str] = &[
+    "keymap",
+    "console_font",
+    "serial_console",
+    "enable",
+    "disable",
+    "regen_initramfs",
+    "selinux_relabel",
+    "copy_into",
+];
+const NETWORK_KEYS: &[&str] = &["config", "resolv_conf"];
+const BOOTLOADER_KEYS: &[&str] = &["type", "device", "removable"];
+const USER_KEYS: &[&str] = &["name", "groups", "password_hash", "shell"];
+const HOOK_KEYS: &[&str] = &["pre", "post"];
+const SECTIONS: &[&str] = &["system", "network", "bootloader", "users", "hooks"];
+
+/// A user to create in the installed system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub groups: Vec<String>,
+    /// crypt(3) hash for /etc/shadow; the account stays locked without one
+    pub password_hash: Option<String>,
+    pub shell: Option<String>,
+}
+
+/// GRUB installation after extraction (`recstrap bootloader --grub`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grub {
+    pub disk: String,
+    pub removable: bool,
+}
+
+/// A parsed answers file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answers {
+    pub target: String,
+    /// Arguments for the extraction run (without the program name)
+    pub install_args: Vec<String>,
+    pub hostname: Option<String>,
+    pub users: Vec<User>,
+    pub grub: Option<Grub>,
+    /// Run on the host before extraction, with `RECSTRAP_TARGET` set
+    pub pre_hooks: Vec<String>,
+    /// Run inside the installed system after everything else
+    pub post_hooks: Vec<String>,
+    pub umount_after: bool,
+}
+
+impl Answers {
+    pub fn parse(content: &str) -> std::result::Result<Self, String> {
+        let doc = toml::parse(content)?;
+        for (name, _, _) in &doc.tables {
+            if !SECTIONS.contains(&name.as_str()) {
+                return Err(format!("unknown section [{}]", name));
+            }
+        }
+        let empty = Table::default();
+        let root = &doc.root;
+        let system = doc.table("system").unwrap_or(&empty);
+        let network = doc.table("network").unwrap_or(&empty);
+        let bootloader = doc.table("bootloader").unwrap_or(&empty);
+        let hooks = doc.table("hooks").unwrap_or(&empty);
+        check_keys(root, ROOT_KEYS, "top level")?;
+        check_keys(system, SYSTEM_KEYS, "[system]")?;
+        check_keys(network, NETWORK_KEYS, "[network]")?;
+        check_keys(bootloader, BOOTLOADER_KEYS, "[bootloader]")?;
+        check_keys(hooks, HOOK_KEYS, "[hooks]")?;
+
+        let target = string(root, "target")?.ok_or("missing 'target'")?;
+        let mut install_args = vec![target.clone(), "--unattended".to_string()];
+        let mut push = |flag: &str, value: Option<String>| {
+            if let Some(value) = value {
+                install_args.push(format!("--{}={}", flag, value));
+            }
+        };
+        push("rootfs", string(root, "rootfs")?);
+        push("flavor", string(root, "flavor")?);
+        push("verify", string(root, "verify")?);
+        push("keymap", string(system, "keymap")?);
+        push("console-font", string(system, "console_font")?);
+        push("serial-console", string(system, "serial_console")?);
+        push("copy-into", string(system, "copy_into")?);
+        push("network", string(network, "config")?);
+        push("resolv-conf", string(network, "resolv_conf")?);
+        for unit in list(system, "enable")? {
+            push("enable", Some(unit));
+        }
+        for unit in list(system, "disable")? {
+            push("disable", Some(unit));
+        }
+
+        let mut grub = None;
+        let device = string(bootloader, "device")?;
+        match string(bootloader, "type")?.as_deref().unwrap_or("none") {
+            "none" => {}
+            "grub" => {
+                grub = Some(Grub {
+                    disk: device.ok_or("[bootloader] type 'grub' needs 'device' (the disk)")?,
+                    removable: flag(bootloader, "removable")?,
+                })
+            }
+            "uki" => push(
+                "uki",
+                Some(device.ok_or("[bootloader] type 'uki' needs 'device' (the ESP)")?),
+            ),
+            other => {
+                return Err(format!(
+                    "[bootloader] unknown type '{}' (expected grub, uki, or none)",
+                    other
+                ))
+            }
+        }
+
+        for (table, key, arg) in [
+            (root, "force", "--force"),
+            (root, "reinstall", "--reinstall"),
+            (root, "strict", "--strict"),
+            (system, "regen_initramfs", "--regen-initramfs"),
+            (system, "selinux_relabel", "--selinux-relabel"),
+        ] {
+            if flag(table, key)? {
+                install_args.push(arg.to_string());
+            }
+        }
+
+        let hostname = string(root, "hostname")?;
+        if let Some(name) = &hostname {
+            if !valid_hostname(name) {
+                return Err(format!("invalid hostname '{}'", name));
+            }
+        }
+
+        let users = doc
+            .array("users")
+            .into_iter()
+            .map(parse_user)
+            .collect::<std::result::Result<_, _>>()?;
+
+        Ok(Self {
+            target,
+            install_args,
+            hostname,
+            users,
+            grub,
+            pre_hooks: list(hooks, "pre")?,
+            post_hooks: list(hooks, "post")?,
+            umount_after: flag(root, "umount_after")?,
+        })
+    }
+}
+
+fn parse_user(table: &Table) -> std::result::Result<User, String> {
+    check_keys(table, USER_KEYS, "[[users]]")?;
+    let name = string(table, "name")?.ok_or("[[users]] entry without 'name'")?;
+    let groups = list(table, "groups")?;
+    for n in std::iter::once(&name).chain(&groups) {
+        if !valid_account_name(n) {
+            return Err(format!("invalid user or group name '{}'", n));
+        }
+    }
+    let password_hash = string(table, "password_hash")?;
+    if password_hash
+        .as_deref()
+        .is_some_and(|h| !valid_password_hash(h))
+    {
+        return Err(format!("invalid password_hash for user '{}'", name));
+    }
+    Ok(User {
+        name,
+        groups,
+        password_hash,
+        shell: string(table, "shell")?,
+    })
+}
+
+fn check_keys(table: &Table, allowed: &[&str], section: &str) -> std::result::Result<(), String> {
+    match table
+        .entries
+        .iter()
+        .find(|(k, _)| !allowed.contains(&k.as_str()))
+    {
+        Some((key, _)) => Err(format!("unknown key '{}' in {}", key, section)),
+        None => Ok(()),
+    }
+}
+
+fn string(table: &Table, key: &str) -> std::result::Result<Option<String>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(format!("'{}' must be a string", key)),
+    }
+}
+
+fn flag(table: &Table, key: &str) -> std::result::Result<bool, String> {
+    match table.get(key) {
+        None => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(_) => Err(format!("'{}' must be true or false", key)),
+    }
+}
+
+fn list(table: &Table, key: &str) -> std::result::Result<Vec<String>, String> {
+    match table.get(key) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|v| match v {
+                Value::String(s) => Ok(s.clone()),
+                _ => Err(format!("'{}' must be a list of strings", key)),
+            })
+            .collect(),
+        Some(_) => Err(format!("'{}' must be a list of strings", key)),
+    }
+}
+
+/// RFC 1123 host name: dot-separated labels of letters, digits, and
+/// inner hyphens.
+pub fn valid_hostname(name: &str) -> bool {
+    name.len() <= 253
+        && name.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Run a whole install from the answers file at `path`.
+pub fn install(path: &str, quiet: bool) -> Result<()> {
+    let fail = |detail: String| RecError::configuration_failed("answers file", &detail);
+    let content = fs::read_to_string(path).map_err(|e| fail(format!("{}: {}", path, e)))?;
+    let answers = Answers::parse(&content).map_err(|e| fail(format!("{}: {}", path, e)))?;
+
+    let mut argv = vec!["recstrap".to_string()];
+    argv.extend(answers.install_args.iter().cloned());
+    if quiet {
+        argv.push("--quiet".to_string());
+    }
+    let mut args = Args::try_parse_from(&argv).map_err(|e| {
+        fail(format!(
+            "{}: {}",
+            path,
+            e.to_string()
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches("error: ")
+        ))
+    })?;
+    args.argv = argv[1..].to_vec();
+
+    let target = Path::new(&answers.target);
+    for hook in &answers.pre_hooks {
+        run_hook(hook, "pre", quiet, || {
+            let status = runner::status(
+                Process::new("sh")
+                    .args(["-c", hook])
+                    .env("RECSTRAP_TARGET", target),
+            )?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "sh exited with {}",
+                    status.code().unwrap_or(-1)
+                )));
+            }
+            Ok(())
+        })?;
+    }
+
+    crate::run(&args)?;
+
+    if let Some(hostname) = &answers.hostname {
+        fs::write(target.join("etc/hostname"), format!("{}\n", hostname))
+            .map_err(|e| RecError::configuration_failed("hostname", &e.to_string()))?;
+    }
+    if !answers.users.is_empty() {
+        let _mounts = ChrootMounts::setup(target)
+            .map_err(|e| RecError::configuration_failed("users", &e.to_string()))?;
+        for user in &answers.users {
+            create_user(target, user, quiet)?;
+        }
+    }
+    if let Some(grub) = &answers.grub {
+        crate::commands::run(&Command::Bootloader(BootloaderArgs {
+            target: answers.target.clone(),
+            grub: grub.disk.clone(),
+            removable: grub.removable,
+            quiet,
+        }))?;
+    }
+    if !answers.post_hooks.is_empty() {
+        warn_kernel_mismatch(target, quiet);
+        let _mounts = ChrootMounts::setup(target)
+            .map_err(|e| RecError::configuration_failed("post hooks", &e.to_string()))?;
+        for hook in &answers.post_hooks {
+            run_hook(hook, "post", quiet, || {
+                run_in_chroot(target, "/bin/sh", &["-c", hook])
+            })?;
+        }
+    }
+
+    // The extraction run already flushed; this covers what we wrote since
+    let _ = sync_filesystem(target);
+    if answers.umount_after {
+        crate::unmount_target(target, quiet)?;
+    }
+    if !quiet {
+        eprintln!();
+        eprintln!("Done! Installed {} from {}.", answers.target, path);
+    }
+    Ok(())
+}
+
+/// Run one hook command; any failure aborts the install.
+fn run_hook(
+    hook: &str,
+    stage: &str,
+    quiet: bool,
+    run: impl FnOnce() -> io::Result<()>,
+) -> Result<()> {
+    if !quiet {
+        eprintln!("Running {} hook: {}", stage, hook);
+    }
+    run().map_err(|e| {
+        RecError::new(
+            ErrorCode::ConfigurationFailed,
+            format!("{} hook '{}' failed: {}", stage, hook, e),
+        )
+    })
+}
+
+fn create_user(target: &Path, user: &User, quiet: bool) -> Result<()> {
+    if !quiet {
+        eprintln!("Creating user {}...", user.name);
+    }
+    let groups = user.groups.join(",");
+    let mut args = vec!["-m"];
+    if !groups.is_empty() {
+        args.extend(["-G", &groups]);
+    }
+    if let Some(shell) = &user.shell {
+        args.extend(["-s", shell]);
+    }
+    if let Some(hash) = &user.password_hash {
+        args.extend(["-p", hash]);
+    }
+    args.push(&user.name);
+    run_in_chroot(target, "useradd", &args)
+        .map_err(|e| RecError::configuration_failed(&format!("user {}", user.name), &e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: &str = r#"
+target = "/mnt"
+rootfs = "/run/live/filesystem.erofs"
+hostname = "build01"
+verify = "full"
+umount_after = true
+
+[system]
+keymap = "de-latin1"
+serial_console = "ttyS1,9600"
+enable = ["sshd.service"]
+regen_initramfs = true
+
+[network]
+config = "dhcp"
+
+[bootloader]
+type = "grub"
+device = "/dev/sda"
+
+[[users]]
+name = "alice"
+groups = ["wheel", "audio"]
+password_hash = "$6$salt$hash"
+
+[[users]]
+name = "bob"
+
+[hooks]
+pre = ["wipefs -n /dev/sda"]
+post = ["systemctl enable chronyd"]
+"#;
+
+    #[test]
+    fn test_parse_full() {
+        let answers = Answers::parse(FULL).unwrap();
+        assert_eq!(answers.target, "/mnt");
+        assert_eq!(answers.hostname.as_deref(), Some("build01"));
+        assert_eq!(
+            answers.install_args,
+            [
+                "/mnt",
+                "--unattended",
+                "--rootfs=/run/live/filesystem.erofs",
+                "--verify=full",
+                "--keymap=de-latin1",
+                "--serial-console=ttyS1,9600",
+                "--network=dhcp",
+                "--enable=sshd.service",
+                "--regen-initramfs",
+            ]
+        );
+        assert_eq!(
+            answers.grub,
+            Some(Grub {
+                disk: "/dev/sda".into(),
+                removable: false
+            })
+        );
+        assert_eq!(answers.users.len(), 2);
+        assert_eq!(answers.users[0].groups, ["wheel", "audio"]);
+        assert_eq!(answers.users[1].password_hash, None);
+        assert_eq!(answers.pre_hooks, ["wipefs -n /dev/sda"]);
+        assert_eq!(answers.post_hooks, ["systemctl enable chronyd"]);
+        assert!(answers.umount_after);
+    }
+
+    #[test]
+    fn test_install_args_parse() {
+        let answers = Answers::parse(FULL).unwrap();
+        let argv = std::iter::once("recstrap".to_string()).chain(answers.install_args);
+        let args = Args::try_parse_from(argv).unwrap();
+        assert_eq!(args.target.as_deref(), Some("/mnt"));
+        assert!(args.unattended);
+        assert!(args.regen_initramfs);
+        assert_eq!(args.enable, ["sshd.service"]);
+    }
+
+    #[test]
+    fn test_uki_bootloader() {
+        let answers = Answers::parse(
+            "target = \"/mnt\"\n[bootloader]\ntype = \"uki\"\ndevice = \"/dev/sda1\"\n",
+        )
+        .unwrap();
+        assert!(answers
+            .install_args
+            .contains(&"--uki=/dev/sda1".to_string()));
+        assert_eq!(answers.grub, None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |content: &str| Answers::parse(content).unwrap_err();
+        assert_eq!(err("hostname = \"x\"\n"), "missing 'target'");
+        assert!(err("target = \"/mnt\"\ntaget = 1\n").contains("unknown key 'taget'"));
+        assert!(err("target = \"/mnt\"\n[sytem]\n").contains("unknown section"));
+        assert!(err("target = \"/mnt\"\n[bootloader]\ntype = \"grub\"\n").contains("device"));
+        assert!(err("target = \"/mnt\"\n[bootloader]\ntype = \"lilo\"\n").contains("lilo"));
+        assert!(err("target = \"/mnt\"\nhostname = \"-bad\"\n").contains("hostname"));
+        assert!(err("target = \"/mnt\"\n[[users]]\nname = \"Root:x\"\n").contains("Root:x"));
+        assert!(err("target = 5\n").contains("string"));
+    }
+
+    #[test]
+    fn test_valid_hostname() {
+        assert!(valid_hostname("build01"));
+        assert!(valid_hostname("web-1.example.org"));
+        assert!(!valid_hostname(""));
+        assert!(!valid_hostname("a..b"));
+        assert!(!valid_hostname("under_score"));
+        assert!(!valid_hostname(&"a".repeat(64)));
+    }
+}
@@ -0,0 +1,245 @@
+//! Audit trail of installs (`--audit`).
+//!
+//! Some sites must be able to show who imaged which machine with what.
+//! With `--audit`, the start of every extraction and its outcome (with
+//! the image's SHA-256, or the error code) are recorded twice: to syslog
+//! under the authpriv facility, and to the kernel audit log as
+//! `AUDIT_TRUSTED_APP` records, which auditd keeps and ties to the login
+//! session. The records are `key="value"` lists in the audit style:
+//!
+//! ```text
+//! op="install" res="success" target="/mnt" image="/run/.../filesystem.erofs" sha256="..." uid="0" auid="1000"
+//! ```
+//!
+//! A record that can't be written anywhere is a warning, so `--strict`
+//! turns a missing audit trail into a failed install.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixDatagram;
+
+use crate::error::{RecError, Result};
+use crate::warnings::warn;
+use distro_spec::shared::error::ToolErrorCode;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// `LOG_AUTHPRIV`: security records, kept from ordinary users.
+const FACILITY_AUTHPRIV: u8 = 10;
+const SEVERITY_ERR: u8 = 3;
+const SEVERITY_INFO: u8 = 6;
+
+/// Free-form record from a trusted application (linux/audit.h).
+const AUDIT_TRUSTED_APP: u16 = 1121;
+
+const NLMSG_HEADER_LEN: usize = 16;
+
+/// An extraction whose outcome is still to be recorded.
+struct Pending {
+    target: String,
+    image: String,
+    quiet: bool,
+}
+
+thread_local! {
+    static PENDING: RefCell<Option<Pending>> = const { RefCell::new(None) };
+}
+
+/// Record that an extraction of `image` into `target` starts; [`complete`]
+/// or [`finish`] records how it ended.
+pub fn begin(target: &str, image: &str, quiet: bool) {
+    let pending = Pending {
+        target: target.to_string(),
+        image: image.to_string(),
+        quiet,
+    };
+    emit(&pending, "start", &[]);
+    PENDING.with(|p| *p.borrow_mut() = Some(pending));
+}
+
+/// Record that the pending extraction succeeded.
+pub fn complete(sha256: Option<&str>) {
+    if let Some(pending) = PENDING.with(|p| p.borrow_mut().take()) {
+        emit(
+            &pending,
+            "success",
+            &[("sha256", sha256.unwrap_or("unknown"))],
+        );
+    }
+}
+
+/// Record the outcome of an extraction still pending; called with the
+/// result of the whole run.
+pub fn finish(result: &Result<()>) {
+    let Some(pending) = PENDING.with(|p| p.borrow_mut().take()) else {
+        return;
+    };
+    match result {
+        Err(RecError { code, .. }) => emit(&pending, "failed", &[("error", code.code())]),
+        Ok(()) => emit(&pending, "success", &[("sha256", "unknown")]),
+    }
+}
+
+fn emit(pending: &Pending, res: &str, extra: &[(&str, &str)]) {
+    let uid = unsafe { libc::getuid() }.to_string();
+    let auid = login_uid();
+    let mut fields = vec![
+        ("op", "install"),
+        ("res", res),
+        ("target", pending.target.as_str()),
+        ("image", pending.image.as_str()),
+    ];
+    fields.extend_from_slice(extra);
+    fields.push(("uid", &uid));
+    fields.push(("auid", &auid));
+    let record = format_record(&fields);
+
+    let severity = if res == "failed" {
+        SEVERITY_ERR
+    } else {
+        SEVERITY_INFO
+    };
+    let to_syslog = send_syslog(severity, &record);
+    let to_audit = send_audit(&record);
+    if let (Err(syslog), Err(audit)) = (to_syslog, to_audit) {
+        warn(
+            pending.quiet,
+            &format!("cannot record the audit event ({})", record),
+            &[&format!("syslog: {}", syslog), &format!("audit: {}", audit)],
+        );
+    }
+}
+
+/// The login uid the kernel tracks across su/sudo, or `unset`.
+fn login_uid() -> String {
+    match fs::read_to_string("/proc/self/loginuid") {
+        Ok(uid) if uid.trim() != u32::MAX.to_string() => uid.trim().to_string(),
+        _ => "unset".to_string(),
+    }
+}
+
+/// `key="value"` pairs; values with spaces, quotes or control characters
+/// are hex-encoded without quotes, as auditd's own tools expect.
+fn format_record(fields: &[(&str, &str)]) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| {
+            if value
+                .bytes()
+                .any(|b| b == b'"' || !(0x21..=0x7e).contains(&b))
+            {
+                let hex: String = value.bytes().map(|b| format!("{:02X}", b)).collect();
+                format!("{}={}", key, hex)
+            } else {
+                format!("{}=\"{}\"", key, value)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn send_syslog(severity: u8, record: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    let message = format!(
+        "<{}>recstrap[{}]: {}",
+        FACILITY_AUTHPRIV * 8 + severity,
+        std::process::id(),
+        record
+    );
+    socket.send_to(message.as_bytes(), SYSLOG_SOCKET)?;
+    Ok(())
+}
+
+/// Hand `record` to the kernel audit subsystem over netlink and wait for
+/// its acknowledgement. Needs CAP_AUDIT_WRITE; without auditd the kernel
+/// logs it to dmesg.
+fn send_audit(record: &str) -> io::Result<()> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_AUDIT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // struct nlmsghdr: length, type, flags, sequence, port; then the text
+    let len = NLMSG_HEADER_LEN + record.len() + 1;
+    let mut message = Vec::with_capacity(len.next_multiple_of(4));
+    message.extend_from_slice(&(len as u32).to_ne_bytes());
+    message.extend_from_slice(&AUDIT_TRUSTED_APP.to_ne_bytes());
+    message.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16).to_ne_bytes());
+    message.extend_from_slice(&1u32.to_ne_bytes());
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    message.extend_from_slice(record.as_bytes());
+    message.resize(len.next_multiple_of(4), 0);
+
+    let mut kernel: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    kernel.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            message.as_ptr() as *const libc::c_void,
+            message.len(),
+            0,
+            &kernel as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The acknowledgement: an NLMSG_ERROR whose error is 0 on success
+    let timeout = libc::timeval {
+        tv_sec: 1,
+        tv_usec: 0,
+    };
+    unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    let mut reply = [0u8; 256];
+    let received = unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            reply.as_mut_ptr() as *mut libc::c_void,
+            reply.len(),
+            0,
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let kind = u16::from_ne_bytes([reply[4], reply[5]]);
+    if received as usize >= NLMSG_HEADER_LEN + 4 && kind == libc::NLMSG_ERROR as u16 {
+        let error = i32::from_ne_bytes(reply[16..20].try_into().unwrap());
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(-error));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_record() {
+        assert_eq!(
+            format_record(&[("op", "install"), ("target", "/mnt"), ("image", "/a b")]),
+            "op=\"install\" target=\"/mnt\" image=2F612062"
+        );
+    }
+}
@@ -0,0 +1,426 @@
+//! Boot mode detection and partition layout sanity checks.
+//!
+//! recstrap doesn't install a bootloader, but it can tell the user which one
+//! fits how the live system booted, and warn when the target disk lacks the
+//! partition that bootloader will need. With `--uki` it also builds a
+//! Unified Kernel Image into the ESP, which firmware or systemd-boot can
+//! start directly.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::chroot::{find_tool, run_in_chroot, ChrootMounts};
+use crate::error::{RecError, Result};
+use crate::mountinfo;
+use crate::runner;
+
+/// EFI system partition type GUID (C12A7328-F81F-11D2-BA4B-00A0C93EC93B),
+/// as stored on disk (first three fields little-endian).
+const GPT_ESP: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+
+/// BIOS boot partition type GUID (21686148-6449-6E6F-744E-656564454649),
+/// which GRUB needs to embed itself on GPT disks.
+const GPT_BIOS_BOOT: [u8; 16] = *b"Hah!IdontNeedEFI";
+
+/// MBR partition type of an EFI system partition.
+const MBR_ESP: u8 = 0xef;
+
+/// How the running (live) system was booted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    Uefi,
+    Bios,
+}
+
+impl BootMode {
+    /// The kernel exposes `/sys/firmware/efi` only when booted via UEFI.
+    pub fn detect() -> Self {
+        if Path::new("/sys/firmware/efi").exists() {
+            BootMode::Uefi
+        } else {
+            BootMode::Bios
+        }
+    }
+
+    /// Bootloader installation commands to suggest, run inside the chroot.
+    pub fn bootloader_commands(self) -> &'static [&'static str] {
+        match self {
+            BootMode::Uefi => &["bootctl install"],
+            BootMode::Bios => &[
+                "grub-install --target=i386-pc /dev/sdX",
+                "grub-mkconfig -o /boot/grub/grub.cfg",
+            ],
+        }
+    }
+}
+
+impl fmt::Display for BootMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootMode::Uefi => write!(f, "UEFI"),
+            BootMode::Bios => write!(f, "BIOS (legacy)"),
+        }
+    }
+}
+
+/// Partition table of a disk, reduced to the partition types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionTable {
+    Gpt(Vec<[u8; 16]>),
+    Mbr(Vec<u8>),
+}
+
+impl PartitionTable {
+    /// Parse the start of a disk (at least 34 sectors for GPT).
+    pub fn parse(disk: &[u8], sector_size: usize) -> Option<Self> {
+        let header = disk.get(sector_size..sector_size + 92)?;
+        if &header[..8] == b"EFI PART" {
+            let le_u32 = |off: usize| u32::from_le_bytes(header[off..off + 4].try_into().unwrap());
+            let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap()) as usize;
+            let count = le_u32(80) as usize;
+            let entry_size = le_u32(84) as usize;
+            if entry_size < 16 {
+                return None;
+            }
+            let start = entries_lba.checked_mul(sector_size)?;
+            let types = (0..count)
+                .filter_map(|i| disk.get(start + i * entry_size..start + i * entry_size + 16))
+                .map(|t| <[u8; 16]>::try_from(t).unwrap())
+                .filter(|t| t != &[0; 16])
+                .collect();
+            return Some(PartitionTable::Gpt(types));
+        }
+
+        let mbr = disk.get(..512)?;
+        if mbr[510..512] != [0x55, 0xaa] {
+            return None;
+        }
+        let types = (0..4)
+            .map(|i| mbr[446 + i * 16 + 4])
+            .filter(|&t| t != 0)
+            .collect();
+        Some(PartitionTable::Mbr(types))
+    }
+
+    fn has_esp(&self) -> bool {
+        match self {
+            PartitionTable::Gpt(types) => types.contains(&GPT_ESP),
+            PartitionTable::Mbr(types) => types.contains(&MBR_ESP),
+        }
+    }
+
+    /// Why this layout won't suit a bootloader for `mode`, if it won't.
+    pub fn mismatch(&self, mode: BootMode) -> Option<&'static str> {
+        match (mode, self) {
+            (BootMode::Uefi, _) if !self.has_esp() => Some("has no EFI system partition"),
+            (BootMode::Bios, PartitionTable::Gpt(types)) if !types.contains(&GPT_BIOS_BOOT) => {
+                Some("is GPT without a BIOS boot partition (needed by GRUB)")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The whole disk holding the filesystem `target` is on, e.g. `sda` for a
+/// target on `/dev/sda2`. `None` for non-partition sources (tmpfs, LVM, ...).
+pub fn target_disk(target: &Path) -> Option<String> {
+    let entries = mountinfo::read().ok()?;
+    let source = mountinfo::containing(&entries, target)?.source;
+    let partition = Path::new(&source).canonicalize().ok()?;
+    let name = partition.file_name()?.to_str()?;
+    let sys = PathBuf::from("/sys/class/block").join(name);
+    if !sys.join("partition").exists() {
+        return None;
+    }
+    let disk = sys
+        .canonicalize()
+        .ok()?
+        .parent()?
+        .file_name()?
+        .to_str()?
+        .to_string();
+    Some(disk)
+}
+
+/// Read the partition table of `/dev/<disk>`.
+pub fn read_partition_table(disk: &str) -> Option<PartitionTable> {
+    let sector_size = fs::read_to_string(format!("/sys/block/{}/queue/logical_block_size", disk))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(512usize);
+    let mut file = File::open(Path::new("/dev").join(disk)).ok()?;
+    // Header plus the standard 128 entries of 128 bytes
+    let mut buf = vec![0u8; sector_size * 2 + 128 * 128];
+    file.read_exact(&mut buf).ok()?;
+    PartitionTable::parse(&buf, sector_size)
+}
+
+/// A warning when the target's disk doesn't fit how the system booted.
+pub fn layout_warning(mode: BootMode, target: &Path) -> Option<String> {
+    let disk = target_disk(target)?;
+    let reason = read_partition_table(&disk)?.mismatch(mode)?;
+    Some(format!("booted {} but /dev/{} {}", mode, disk, reason))
+}
+
+/// Where an ESP may be mounted in the target, in order of preference
+/// (the same places `bootctl` and grub-install look).
+const ESP_MOUNT_POINTS: &[&str] = &["efi", "boot/efi", "boot"];
+
+/// The ESP mounted in the target, relative to the target root.
+pub fn find_esp(target: &Path) -> Option<&'static str> {
+    let entries = mountinfo::read().ok()?;
+    ESP_MOUNT_POINTS.iter().copied().find(|rel| {
+        let path = target.join(rel);
+        entries
+            .iter()
+            .any(|e| e.mount_point == path && e.fs_type == "vfat")
+    })
+}
+
+/// Kernel command line used for UKIs, relative to the target root.
+pub const KERNEL_CMDLINE_FILE: &str = "etc/kernel/cmdline";
+
+/// Where the ESP is mounted in the target while UKIs are built (the
+/// `bootctl`/systemd default).
+pub const ESP_MOUNT_POINT: &str = "efi";
+
+/// An ESP mounted at `<target>/efi`, unmounted on drop.
+pub struct EspMount {
+    path: PathBuf,
+}
+
+impl EspMount {
+    pub fn mount(device: &Path, target: &Path) -> io::Result<Self> {
+        let path = target.join(ESP_MOUNT_POINT);
+        fs::create_dir_all(&path)?;
+        let status = runner::status(
+            Command::new("mount")
+                .args(["-t", "vfat"])
+                .arg(device)
+                .arg(&path),
+        )?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "mounting {} on {} failed",
+                device.display(),
+                path.display()
+            )));
+        }
+        Ok(Self { path })
+    }
+}
+
+impl Drop for EspMount {
+    fn drop(&mut self) {
+        let _ = runner::status(Command::new("umount").arg(&self.path));
+    }
+}
+
+/// Filesystem UUID of the device mounted at `target`, via /dev/disk/by-uuid.
+pub fn root_uuid(target: &Path) -> Option<String> {
+    let entries = mountinfo::read().ok()?;
+    let source = mountinfo::containing(&entries, target)?.source;
+    let device = Path::new(&source).canonicalize().ok()?;
+    fs::read_dir("/dev/disk/by-uuid")
+        .ok()?
+        .filter_map(|e| e.ok())
+        .find(|e| e.path().canonicalize().is_ok_and(|p| p == device))
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+}
+
+/// Kernel versions installed in the target (`usr/lib/modules/<ver>/vmlinuz`).
+pub fn installed_kernels(target: &Path) -> Vec<String> {
+    let mut kernels: Vec<String> = fs::read_dir(target.join("usr/lib/modules"))
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().join("vmlinuz").is_file())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    kernels.sort();
+    kernels
+}
+
+/// Build a UKI for every installed kernel into `EFI/Linux` on the ESP
+/// `device`, using dracut, or ukify with an existing initramfs.
+/// `extra_cmdline` is appended to a generated `/etc/kernel/cmdline` (an
+/// existing one is used as is).
+pub fn build_ukis(target: &Path, device: &Path, extra_cmdline: Option<&str>) -> Result<()> {
+    let fail = |detail: &str| RecError::configuration_failed("UKI", detail);
+
+    let cmdline_path = target.join(KERNEL_CMDLINE_FILE);
+    if !cmdline_path.exists() {
+        let uuid = root_uuid(target).ok_or_else(|| {
+            fail("cannot find the root filesystem UUID; write /etc/kernel/cmdline first")
+        })?;
+        let mut cmdline = format!("root=UUID={} rw", uuid);
+        if let Some(extra) = extra_cmdline {
+            cmdline.push(' ');
+            cmdline.push_str(extra);
+        }
+        fs::create_dir_all(cmdline_path.parent().unwrap_or(target))
+            .and_then(|()| fs::write(&cmdline_path, format!("{}\n", cmdline)))
+            .map_err(|e| fail(&e.to_string()))?;
+    }
+    let cmdline = fs::read_to_string(&cmdline_path).map_err(|e| fail(&e.to_string()))?;
+    let cmdline = cmdline.trim();
+
+    let kernels = installed_kernels(target);
+    if kernels.is_empty() {
+        return Err(fail("no kernels in /usr/lib/modules"));
+    }
+    let tool = find_tool(
+        target,
+        &[
+            "/usr/bin/dracut",
+            "/usr/bin/ukify",
+            "/usr/lib/systemd/ukify",
+        ],
+    )
+    .ok_or_else(|| fail("neither dracut nor ukify is installed in the target"))?;
+
+    let _esp = EspMount::mount(device, target).map_err(|e| fail(&e.to_string()))?;
+    fs::create_dir_all(target.join(ESP_MOUNT_POINT).join("EFI/Linux"))
+        .map_err(|e| fail(&e.to_string()))?;
+    let _mounts = ChrootMounts::setup(target).map_err(|e| fail(&e.to_string()))?;
+    for kver in &kernels {
+        let output = format!("/{}/EFI/Linux/levitateos-{}.efi", ESP_MOUNT_POINT, kver);
+        let result = if tool.ends_with("dracut") {
+            run_in_chroot(
+                target,
+                tool,
+                &[
+                    "--force",
+                    "--uefi",
+                    "--kernel-cmdline",
+                    cmdline,
+                    "--kver",
+                    kver,
+                    &output,
+                ],
+            )
+        } else {
+            let linux = format!("/usr/lib/modules/{}/vmlinuz", kver);
+            let initrd = [
+                format!("/boot/initramfs-{}.img", kver),
+                format!("/usr/lib/modules/{}/initrd", kver),
+            ]
+            .into_iter()
+            .find(|p| target.join(&p[1..]).is_file())
+            .ok_or_else(|| {
+                fail(&format!(
+                    "no initramfs for {} (try --regen-initramfs)",
+                    kver
+                ))
+            })?;
+            run_in_chroot(
+                target,
+                tool,
+                &[
+                    "build",
+                    &format!("--linux={}", linux),
+                    &format!("--initrd={}", initrd),
+                    &format!("--cmdline={}", cmdline),
+                    &format!("--output={}", output),
+                ],
+            )
+        };
+        result.map_err(|e| fail(&e.to_string()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpt_disk(types: &[[u8; 16]]) -> Vec<u8> {
+        let mut disk = vec![0u8; 512 * 34];
+        let header = &mut disk[512..];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        for (i, t) in types.iter().enumerate() {
+            let at = 1024 + i * 128;
+            disk[at..at + 16].copy_from_slice(t);
+        }
+        disk
+    }
+
+    fn mbr_disk(types: &[u8]) -> Vec<u8> {
+        let mut disk = vec![0u8; 512 * 34];
+        for (i, t) in types.iter().enumerate() {
+            disk[446 + i * 16 + 4] = *t;
+        }
+        disk[510] = 0x55;
+        disk[511] = 0xaa;
+        disk
+    }
+
+    const LINUX: [u8; 16] = [0xaf; 16];
+
+    #[test]
+    fn test_parse_gpt() {
+        let table = PartitionTable::parse(&gpt_disk(&[GPT_ESP, LINUX]), 512).unwrap();
+        assert_eq!(table, PartitionTable::Gpt(vec![GPT_ESP, LINUX]));
+        assert_eq!(table.mismatch(BootMode::Uefi), None);
+        assert!(table.mismatch(BootMode::Bios).is_some());
+    }
+
+    #[test]
+    fn test_gpt_bios_boot() {
+        let table = PartitionTable::parse(&gpt_disk(&[GPT_BIOS_BOOT, LINUX]), 512).unwrap();
+        assert_eq!(table.mismatch(BootMode::Bios), None);
+        assert_eq!(
+            table.mismatch(BootMode::Uefi),
+            Some("has no EFI system partition")
+        );
+    }
+
+    #[test]
+    fn test_parse_mbr() {
+        let table = PartitionTable::parse(&mbr_disk(&[0x83]), 512).unwrap();
+        assert_eq!(table, PartitionTable::Mbr(vec![0x83]));
+        assert_eq!(table.mismatch(BootMode::Bios), None);
+        assert!(table.mismatch(BootMode::Uefi).is_some());
+
+        let table = PartitionTable::parse(&mbr_disk(&[MBR_ESP, 0x83]), 512).unwrap();
+        assert_eq!(table.mismatch(BootMode::Uefi), None);
+    }
+
+    #[test]
+    fn test_parse_blank_disk() {
+        assert_eq!(PartitionTable::parse(&[0u8; 512 * 34], 512), None);
+        assert_eq!(PartitionTable::parse(&[0u8; 100], 512), None);
+    }
+
+    #[test]
+    fn test_bootloader_commands() {
+        assert_eq!(BootMode::Uefi.bootloader_commands(), ["bootctl install"]);
+        assert!(BootMode::Bios.bootloader_commands()[0].starts_with("grub-install"));
+    }
+
+    #[test]
+    fn test_installed_kernels() {
+        let temp = std::env::temp_dir().join("recstrap_test_installed_kernels");
+        let _ = fs::remove_dir_all(&temp);
+        for kver in ["6.12.1", "6.6.30"] {
+            let dir = temp.join("usr/lib/modules").join(kver);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("vmlinuz"), "").unwrap();
+        }
+        // Modules without a kernel (leftovers) are ignored
+        fs::create_dir_all(temp.join("usr/lib/modules/6.1.0")).unwrap();
+
+        assert_eq!(installed_kernels(&temp), vec!["6.12.1", "6.6.30"]);
+        assert!(installed_kernels(&temp.join("missing")).is_empty());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+}
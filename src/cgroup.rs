@@ -0,0 +1,152 @@
+//! `--memory-limit`: a transient cgroup for helper processes.
+//!
+//! Everything the runner starts (erofsfuse, veritysetup, tar, ...) is
+//! placed in `/sys/fs/cgroup/recstrap-<pid>` with `memory.max` and
+//! `pids.max` set, so a helper decompressing a huge image on a low-RAM
+//! live system is reclaimed or fails on its own instead of waking the OOM
+//! killer, which may pick the copy (or the desktop) instead. recstrap
+//! itself stays where it is. Needs cgroup v2 and real root.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+/// Where cgroup v2 is mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Processes helpers may have at once (erofsfuse runs a thread per CPU).
+pub const HELPER_PIDS_MAX: u64 = 512;
+
+/// `cgroup.procs` of the active cgroup, while a [`HelperCgroup`] lives.
+static PROCS: Mutex<Option<CString>> = Mutex::new(None);
+
+/// The cgroup helpers run in; removed on drop.
+pub struct HelperCgroup {
+    path: PathBuf,
+}
+
+impl HelperCgroup {
+    /// Create the cgroup with `memory_max` bytes and start placing
+    /// helpers in it.
+    pub fn create(memory_max: u64) -> io::Result<Self> {
+        let cgroup = Self::create_in(Path::new(CGROUP_ROOT), memory_max)?;
+        let procs = CString::new(cgroup.path.join("cgroup.procs").as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        *PROCS.lock().unwrap_or_else(|e| e.into_inner()) = Some(procs);
+        Ok(cgroup)
+    }
+
+    fn create_in(root: &Path, memory_max: u64) -> io::Result<Self> {
+        if !root.join("cgroup.controllers").is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("no cgroup v2 hierarchy at {}", root.display()),
+            ));
+        }
+        enable_controllers(root)?;
+
+        let path = root.join(format!("recstrap-{}", std::process::id()));
+        fs::create_dir(&path)?;
+        // From here on drop cleans up
+        let cgroup = Self { path };
+        fs::write(cgroup.path.join("memory.max"), memory_max.to_string())?;
+        // Swapping a helper out would only trade the OOM kill for thrashing
+        let _ = fs::write(cgroup.path.join("memory.swap.max"), "0");
+        fs::write(cgroup.path.join("pids.max"), HELPER_PIDS_MAX.to_string())?;
+        Ok(cgroup)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for HelperCgroup {
+    fn drop(&mut self) {
+        *PROCS.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        // Fails while a helper (an erofsfuse finishing its unmount) is
+        // still in it; an empty leftover cgroup costs nothing
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+/// Make the memory and pids controllers available to children of `root`.
+fn enable_controllers(root: &Path) -> io::Result<()> {
+    let enabled = fs::read_to_string(root.join("cgroup.subtree_control"))?;
+    let missing: Vec<String> = ["memory", "pids"]
+        .iter()
+        .filter(|c| !enabled.split_whitespace().any(|e| e == **c))
+        .map(|c| format!("+{}", c))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    fs::write(root.join("cgroup.subtree_control"), missing.join(" "))
+}
+
+/// Have `cmd` join the helper cgroup, if there is one, before it execs.
+pub fn confine(cmd: &mut Command) {
+    let Some(procs) = PROCS.lock().unwrap_or_else(|e| e.into_inner()).clone() else {
+        return;
+    };
+    // Only async-signal-safe calls between fork and exec: writing "0"
+    // moves the writing process itself
+    unsafe {
+        cmd.pre_exec(move || {
+            // Already joined before giving up root (privsep)
+            if libc::geteuid() != 0 {
+                return Ok(());
+            }
+            let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+            libc::close(fd);
+            if written != 1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_in() {
+        let root = std::env::temp_dir().join("recstrap_test_cgroup");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        // Not a cgroup v2 hierarchy
+        let err = HelperCgroup::create_in(&root, 1 << 30).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        fs::write(root.join("cgroup.controllers"), "cpu memory pids\n").unwrap();
+        fs::write(root.join("cgroup.subtree_control"), "memory\n").unwrap();
+        let cgroup = HelperCgroup::create_in(&root, 1 << 30).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("cgroup.subtree_control")).unwrap(),
+            "+pids"
+        );
+        assert_eq!(
+            fs::read_to_string(cgroup.path().join("memory.max")).unwrap(),
+            "1073741824"
+        );
+        assert_eq!(
+            fs::read_to_string(cgroup.path().join("pids.max")).unwrap(),
+            HELPER_PIDS_MAX.to_string()
+        );
+        drop(cgroup);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,583 @@
+//! The registry of cheat-guarded validation checks.
+//!
+//! Every check recstrap runs through [`guarded_ensure!`](crate::guarded_ensure)
+//! is declared here once: a stable id, when it runs, what it protects,
+//! how severe a failure is, and the cheat vectors that would weaken it.
+//! `recstrap checks --list` prints the registry, so documentation, tests
+//! and frontends can't drift from the checks that actually run.
+
+use crate::json::Value;
+
+/// When a check runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Before anything is written (`--check` runs these only)
+    Preflight,
+    /// On the extracted system, before the install counts as done
+    Verification,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Preflight => "preflight",
+            Stage::Verification => "verification",
+        }
+    }
+}
+
+/// One validation check and its cheat documentation.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Check {
+    /// Stable kebab-case name, for scripts and frontends
+    pub id: &'static str,
+    pub stage: Stage,
+    /// Later checks rely on this one passing, so `--check --all` stops
+    /// at its failure too
+    pub prerequisite: bool,
+    pub protects: &'static str,
+    pub severity: &'static str,
+    /// Ways the check could be weakened
+    pub cheats: &'static [&'static str],
+    /// What the user suffers if it is
+    pub consequence: &'static str,
+}
+
+impl Check {
+    /// The banner printed on stderr when the check fails.
+    pub fn banner(&self) -> String {
+        let rule = "=".repeat(70);
+        let cheats = self
+            .cheats
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("  {}. {}\n", i + 1, c))
+            .collect::<String>();
+        format!(
+            "\n{rule}\n=== CHEAT-GUARDED VALIDATION FAILED ===\n{rule}\n\n\
+             CHECK:    {}\nPROTECTS: {}\nSEVERITY: {}\n\n\
+             CHEAT VECTORS (ways this check could be weakened):\n{}\n\
+             USER CONSEQUENCE IF CHEATED:\n  {}\n\n{rule}\n\n",
+            self.id, self.protects, self.severity, cheats, self.consequence
+        )
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("id", Value::from(self.id)),
+            ("stage", Value::from(self.stage.name())),
+            ("prerequisite", Value::from(self.prerequisite)),
+            ("protects", Value::from(self.protects)),
+            ("severity", Value::from(self.severity)),
+            (
+                "cheats",
+                Value::from(
+                    self.cheats
+                        .iter()
+                        .map(|c| c.to_string())
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+            ("consequence", Value::from(self.consequence)),
+        ])
+    }
+}
+
+pub const RUNS_AS_ROOT: Check = Check {
+    id: "runs-as-root",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "Installation runs with sufficient privileges",
+    severity: "CRITICAL",
+    cheats: &[
+        "Skip root check entirely",
+        "Use capabilities instead of full root",
+        "Assume sudo will handle it",
+    ],
+    consequence: "Extraction fails with permission denied on first file",
+};
+
+pub const TARGET_EXISTS: Check = Check {
+    id: "target-exists",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Target directory exists before we try to use it",
+    severity: "CRITICAL",
+    cheats: &[
+        "Create the directory automatically",
+        "Skip existence check",
+        "Accept parent directory instead",
+    ],
+    consequence: "Confusing 'No such file or directory' errors during extraction",
+};
+
+pub const TARGET_IS_DIRECTORY: Check = Check {
+    id: "target-is-directory",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Target is a directory, not a file or device",
+    severity: "CRITICAL",
+    cheats: &[
+        "Accept any path type",
+        "Truncate file and use as directory",
+        "Skip the check",
+    ],
+    consequence: "Catastrophic data loss if target is a file, or extraction to device node",
+};
+
+pub const TARGET_NOT_PROTECTED: Check = Check {
+    id: "target-not-protected",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Critical system directories are never overwritten",
+    severity: "CRITICAL",
+    cheats: &[
+        "Remove paths from protected list",
+        "Add --force override for protected paths",
+        "Skip check when running as root",
+        "Check before canonicalization (symlink bypass)",
+    ],
+    consequence: "Complete system destruction - / or /usr overwritten, unbootable system",
+};
+
+pub const TARGET_READ_WRITE: Check = Check {
+    id: "target-read-write",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "Target filesystem is mounted read-write",
+    severity: "CRITICAL",
+    cheats: &[
+        "Rely on the write test alone",
+        "Skip the check with --force",
+    ],
+    consequence: "Extraction fails on the first file with a confusing EROFS error",
+};
+
+pub const TARGET_WRITABLE: Check = Check {
+    id: "target-writable",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "We can actually write to the target before starting extraction",
+    severity: "CRITICAL",
+    cheats: &[
+        "Skip write test",
+        "Assume root can write anywhere",
+        "Check parent directory instead",
+    ],
+    consequence: "Extraction starts, partially completes, then fails - corrupted state",
+};
+
+pub const WORKDIR_OUTSIDE_TARGET: Check = Check {
+    id: "workdir-outside-target",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "The temporary mount point is not inside the extraction target",
+    severity: "CRITICAL",
+    cheats: &["Skip this check", "Compare paths before canonicalization"],
+    consequence: "Copier walks into its own output - endless recursion, disk fills up",
+};
+
+pub const OVERLAY_OUTSIDE_TARGET: Check = Check {
+    id: "overlay-outside-target",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "The overlay copy terminates",
+    severity: "CRITICAL",
+    cheats: &["Skip this check", "Compare paths before canonicalization"],
+    consequence: "Copier walks into its own output - endless recursion, disk fills up",
+};
+
+pub const TARGET_MOUNTED: Check = Check {
+    id: "target-mounted",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "User has actually mounted a filesystem for installation",
+    severity: "HIGH",
+    cheats: &[
+        "Always allow with --force",
+        "Skip check entirely",
+        "Accept any directory",
+    ],
+    consequence: "User installs to wrong filesystem, fills up wrong disk, loses work",
+};
+
+pub const PRIOR_INSTALL: Check = Check {
+    id: "prior-install",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "--reinstall only ever overwrites a previous LevitateOS install",
+    severity: "HIGH",
+    cheats: &[
+        "Treat any non-empty target as a previous install",
+        "Look for /etc/os-release instead of the install record",
+    ],
+    consequence: "User's data disk is overwritten by a flag that promised to replace an OS",
+};
+
+pub const TARGET_EMPTY: Check = Check {
+    id: "target-empty",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "User doesn't accidentally overwrite existing data",
+    severity: "HIGH",
+    cheats: &[
+        "Always allow with --force",
+        "Ignore hidden files",
+        "Only check for specific files",
+    ],
+    consequence: "User's existing data silently overwritten, possibly unrecoverable",
+};
+
+pub const MIN_FREE_SPACE: Check = Check {
+    id: "min-free-space",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "Sufficient disk space exists for the full extraction",
+    severity: "HIGH",
+    cheats: &[
+        "Reduce MIN_REQUIRED_BYTES",
+        "Skip space check",
+        "Only warn instead of fail",
+    ],
+    consequence: "Extraction runs out of space mid-way, leaving corrupted partial system",
+};
+
+pub const ROOTFS_EXISTS: Check = Check {
+    id: "rootfs-exists",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Specified rootfs file actually exists",
+    severity: "CRITICAL",
+    cheats: &[
+        "Create empty file",
+        "Use default path instead",
+        "Skip existence check",
+    ],
+    consequence: "Extraction fails with 'file not found'",
+};
+
+pub const ROOTFS_IS_FILE: Check = Check {
+    id: "rootfs-is-file",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Rootfs path points to a file, not directory",
+    severity: "CRITICAL",
+    cheats: &["Accept directories", "Skip type check"],
+    consequence: "Extraction fails with confusing error about invalid format",
+};
+
+pub const ROOTFS_FOUND: Check = Check {
+    id: "rootfs-found",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Live ISO rootfs is found automatically",
+    severity: "CRITICAL",
+    cheats: &[
+        "Return first path without checking existence",
+        "Hardcode a path",
+        "Create empty file at expected location",
+    ],
+    consequence: "User must manually specify --rootfs, poor UX",
+};
+
+pub const FOUND_ROOTFS_IS_FILE: Check = Check {
+    id: "found-rootfs-is-file",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Auto-detected rootfs is actually a file",
+    severity: "CRITICAL",
+    cheats: &["Skip type verification", "Accept any path type"],
+    consequence: "Extraction fails with confusing error",
+};
+
+pub const ROOTFS_READABLE: Check = Check {
+    id: "rootfs-readable",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Rootfs file is readable before starting extraction",
+    severity: "CRITICAL",
+    cheats: &[
+        "Skip readability check",
+        "Only check file permissions metadata",
+        "Assume root can read anything",
+    ],
+    consequence: "Extraction fails immediately with permission denied",
+};
+
+pub const ROOTFS_OUTSIDE_TARGET: Check = Check {
+    id: "rootfs-outside-target",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "Rootfs is not inside the extraction target",
+    severity: "CRITICAL",
+    cheats: &[
+        "Skip this check",
+        "Only check exact path match",
+        "Check before canonicalization",
+    ],
+    consequence: "Recursive extraction disaster - extracting overwrites source mid-extraction",
+};
+
+pub const KERNEL_EROFS: Check = Check {
+    id: "kernel-erofs",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Kernel can mount EROFS filesystems",
+    severity: "CRITICAL",
+    cheats: &[
+        "Skip kernel check",
+        "Assume module is loaded",
+        "Silently fall back to unsupported formats",
+    ],
+    consequence: "Mount fails with cryptic 'unknown filesystem type' error",
+};
+
+pub const KERNEL_EROFS_FEATURES: Check = Check {
+    id: "kernel-erofs-features",
+    stage: Stage::Preflight,
+    prerequisite: true,
+    protects: "Kernel understands every on-disk feature the image uses",
+    severity: "CRITICAL",
+    cheats: &[
+        "Only check the magic bytes",
+        "Ignore compression algorithms",
+        "Let mount fail and report that instead",
+    ],
+    consequence: "Mount fails with 'wrong fs type' and no hint that the kernel is too old",
+};
+
+pub const CPU_LEVEL: Check = Check {
+    id: "cpu-level",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "Installed binaries can run on this CPU",
+    severity: "CRITICAL",
+    cheats: &[
+        "Only check the first flag of each level",
+        "Skip the check when cpuinfo looks unfamiliar",
+        "Warn instead of fail",
+    ],
+    consequence: "Installed system dies with 'Illegal instruction' on first boot",
+};
+
+pub const FREE_INODES: Check = Check {
+    id: "free-inodes",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "Target filesystem has an inode for every file in the image",
+    severity: "HIGH",
+    cheats: &[
+        "Only check free bytes",
+        "Assume every filesystem allocates inodes dynamically",
+        "Only warn instead of fail",
+    ],
+    consequence: "Extraction fails with 'No space left on device' on a half-empty disk",
+};
+
+pub const IMAGE_FITS: Check = Check {
+    id: "image-fits",
+    stage: Stage::Preflight,
+    prerequisite: false,
+    protects: "Target has room for everything the image unpacks to",
+    severity: "HIGH",
+    cheats: &[
+        "Compare against the compressed image size",
+        "Only check the fixed minimum",
+        "Only warn instead of fail",
+    ],
+    consequence: "Extraction runs out of space mid-way, leaving corrupted partial system",
+};
+
+pub const ESSENTIAL_DIRS: Check = Check {
+    id: "essential-dirs",
+    stage: Stage::Verification,
+    prerequisite: false,
+    protects: "Extracted system has all essential directories",
+    severity: "CRITICAL",
+    cheats: &[
+        "Reduce ESSENTIAL_DIRS list",
+        "Move missing dirs to 'optional' list",
+        "Check exists() instead of is_dir()",
+        "Skip verification entirely",
+        "Only check one directory",
+    ],
+    consequence:
+        "System extracts 'successfully' but is incomplete - /bin, /usr, or /etc missing, unbootable",
+};
+
+pub const SYSTEM_SANITY: Check = Check {
+    id: "system-sanity",
+    stage: Stage::Verification,
+    prerequisite: false,
+    protects: "Extracted system has a loader, shell, init, and parseable accounts",
+    severity: "CRITICAL",
+    cheats: &[
+        "Only check that the files exist",
+        "Accept any file as an ELF binary",
+        "Skip the passwd/shadow checks",
+        "Skip verification entirely",
+    ],
+    consequence: "Kernel panics with 'No working init found', or nobody can log in",
+};
+
+pub const PACKAGE_DATABASE: Check = Check {
+    id: "package-database",
+    stage: Stage::Verification,
+    prerequisite: false,
+    protects: "The installed system's package manager knows what is installed",
+    severity: "HIGH",
+    cheats: &[
+        "Only check that the database directory exists",
+        "Skip unrecognized package managers",
+        "Skip verification entirely",
+    ],
+    consequence: "First update conflicts with or reinstalls every package",
+};
+
+pub const USRMERGE: Check = Check {
+    id: "usrmerge",
+    stage: Stage::Verification,
+    prerequisite: false,
+    protects: "usrmerge symlinks (/bin, /sbin, /lib, /lib64) stay symlinks into /usr",
+    severity: "HIGH",
+    cheats: &[
+        "Accept a directory with the same contents",
+        "Only check that the path exists",
+        "Skip verification entirely",
+    ],
+    consequence: "/bin and /usr/bin diverge - upgrades conflict, scripts run stale binaries",
+};
+
+pub const HARDLINKS: Check = Check {
+    id: "hardlinks",
+    stage: Stage::Verification,
+    prerequisite: false,
+    protects: "Hardlinked files in the image stay hardlinked in the target",
+    severity: "HIGH",
+    cheats: &[
+        "Compare contents instead of inode numbers",
+        "Sample zero groups",
+        "Only check that each path exists",
+        "Check the groups the copier recorded instead of counting them on the image",
+        "Skip verification entirely",
+    ],
+    consequence: "Hardlinks become copies - doubled disk usage, broken package layouts",
+};
+
+pub const CAPABILITIES: Check = Check {
+    id: "capabilities",
+    stage: Stage::Verification,
+    prerequisite: false,
+    protects: "Binaries keep the file capabilities they ship with",
+    severity: "HIGH",
+    cheats: &[
+        "Only check that the xattr exists, not its value",
+        "Always run in relaxed mode",
+        "Check a hardcoded list of binaries",
+        "Skip verification entirely",
+    ],
+    consequence: "ping and similar tools fail with 'Operation not permitted' for users",
+};
+
+pub const MATCHES_IMAGE: Check = Check {
+    id: "matches-image",
+    stage: Stage::Verification,
+    prerequisite: false,
+    protects: "Installed files match the image byte-for-byte",
+    severity: "HIGH",
+    cheats: &[
+        "Compare sizes instead of contents",
+        "Stop at the first mismatch and call it a pass",
+        "Skip files that fail to read",
+        "Only report mismatches as warnings",
+    ],
+    consequence: "Silent corruption (bad RAM, failing disk) ships in the installed system",
+};
+
+/// Every check, in the order an install runs them.
+pub const ALL: &[&Check] = &[
+    &RUNS_AS_ROOT,
+    &TARGET_EXISTS,
+    &TARGET_IS_DIRECTORY,
+    &TARGET_NOT_PROTECTED,
+    &TARGET_READ_WRITE,
+    &TARGET_WRITABLE,
+    &WORKDIR_OUTSIDE_TARGET,
+    &OVERLAY_OUTSIDE_TARGET,
+    &TARGET_MOUNTED,
+    &PRIOR_INSTALL,
+    &TARGET_EMPTY,
+    &MIN_FREE_SPACE,
+    &ROOTFS_EXISTS,
+    &ROOTFS_IS_FILE,
+    &ROOTFS_FOUND,
+    &FOUND_ROOTFS_IS_FILE,
+    &ROOTFS_READABLE,
+    &ROOTFS_OUTSIDE_TARGET,
+    &KERNEL_EROFS,
+    &KERNEL_EROFS_FEATURES,
+    &CPU_LEVEL,
+    &FREE_INODES,
+    &IMAGE_FITS,
+    &ESSENTIAL_DIRS,
+    &SYSTEM_SANITY,
+    &PACKAGE_DATABASE,
+    &USRMERGE,
+    &HARDLINKS,
+    &CAPABILITIES,
+    &MATCHES_IMAGE,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_ids() {
+        let mut ids: Vec<&str> = ALL.iter().map(|c| c.id).collect();
+        assert!(ids
+            .iter()
+            .all(|id| id.bytes().all(|b| b.is_ascii_lowercase() || b == b'-')));
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), ALL.len(), "check ids must be unique");
+    }
+
+    #[test]
+    fn test_banner() {
+        let rule = "=".repeat(70);
+        assert_eq!(
+            ROOTFS_IS_FILE.banner(),
+            format!(
+                "\n{rule}\n=== CHEAT-GUARDED VALIDATION FAILED ===\n{rule}\n\n\
+                 CHECK:    rootfs-is-file\n\
+                 PROTECTS: Rootfs path points to a file, not directory\n\
+                 SEVERITY: CRITICAL\n\n\
+                 CHEAT VECTORS (ways this check could be weakened):\n\
+                 \x20 1. Accept directories\n\x20 2. Skip type check\n\n\
+                 USER CONSEQUENCE IF CHEATED:\n\
+                 \x20 Extraction fails with confusing error about invalid format\n\n{rule}\n\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_prerequisites_are_preflight() {
+        assert!(ALL
+            .iter()
+            .filter(|c| c.prerequisite)
+            .all(|c| c.stage == Stage::Preflight));
+    }
+
+    #[test]
+    fn test_registry_order() {
+        // Pre-flight checks all come before the verification ones
+        let first_verification = ALL
+            .iter()
+            .position(|c| c.stage == Stage::Verification)
+            .unwrap();
+        assert!(ALL[first_verification..]
+            .iter()
+            .all(|c| c.stage == Stage::Verification));
+    }
+}
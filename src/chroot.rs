@@ -0,0 +1,172 @@
+//! Running tools inside the target, like `recchroot` does.
+//!
+//! Some post-install steps (initramfs, bootloaders) can only be done by the
+//! installed system's own tools. They need the API filesystems mounted in
+//! the target; [`ChrootMounts`] sets those up and tears them down on drop.
+//! They also ask `uname -r` which kernel to work on, which is the live
+//! one; [`warn_kernel_mismatch`] says so when the target doesn't have it.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::boot::installed_kernels;
+use crate::helpers::{kernel_release, resolve_in_root};
+use crate::runner;
+use crate::sandbox;
+use crate::warnings::warn;
+
+/// API filesystems mounted into the target, unmounted on drop.
+pub struct ChrootMounts {
+    /// Mounted paths, in mount order
+    mounts: Vec<PathBuf>,
+}
+
+impl ChrootMounts {
+    /// Mount `/proc`, `/sys`, `/dev`, and a fresh `/run` inside `target`.
+    pub fn setup(target: &Path) -> io::Result<Self> {
+        let mut guard = Self { mounts: Vec::new() };
+        guard.mount(target, "proc", &["-t", "proc", "proc"])?;
+        // Recursive so efivars and devpts come along; rslave so unmounting
+        // here never propagates back to the live system
+        guard.mount(target, "sys", &["--rbind", "--make-rslave", "/sys"])?;
+        guard.mount(target, "dev", &["--rbind", "--make-rslave", "/dev"])?;
+        guard.mount(target, "run", &["-t", "tmpfs", "tmpfs"])?;
+        Ok(guard)
+    }
+
+    fn mount(&mut self, target: &Path, dir: &str, args: &[&str]) -> io::Result<()> {
+        let path = target.join(dir);
+        std::fs::create_dir_all(&path)?;
+        let status = runner::status(Command::new("mount").args(args).arg(&path))?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "mounting {} failed",
+                path.display()
+            )));
+        }
+        self.mounts.push(path);
+        Ok(())
+    }
+}
+
+impl Drop for ChrootMounts {
+    fn drop(&mut self) {
+        for path in self.mounts.iter().rev() {
+            let _ = runner::status(Command::new("umount").arg("-R").arg(path));
+        }
+    }
+}
+
+/// Run `program` with `args` chrooted into `target`. Output goes to the
+/// terminal; a non-zero exit is an error.
+pub fn run_in_chroot(target: &Path, program: &str, args: &[&str]) -> io::Result<()> {
+    let mut cmd = Command::new("chroot");
+    cmd.arg(target).arg(program).args(args).env(
+        "PATH",
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    );
+    sandbox::confine(&mut cmd);
+    let status = runner::status(&mut cmd)?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} exited with {}",
+            program,
+            status.code().unwrap_or(-1)
+        )));
+    }
+    Ok(())
+}
+
+/// Why chrooted tools may misbehave in `target` under the kernel
+/// `release`: it has no module tree for it.
+pub fn kernel_mismatch(target: &Path, release: &str) -> Option<String> {
+    let present = ["usr/lib/modules", "lib/modules"].iter().any(|dir| {
+        resolve_in_root(target, &Path::new(dir).join(release)).is_ok_and(|p| p.is_dir())
+    });
+    (!present).then(|| {
+        format!(
+            "the target has no modules for the running kernel {} (/usr/lib/modules/{})",
+            release, release
+        )
+    })
+}
+
+/// Warn before a chrooted post-install step if the target doesn't have the
+/// running kernel's modules.
+pub fn warn_kernel_mismatch(target: &Path, quiet: bool) {
+    let Some(release) = kernel_release() else {
+        return;
+    };
+    if let Some(message) = kernel_mismatch(target, &release) {
+        let installed = installed_kernels(target);
+        let installed = format!(
+            "Installed kernels: {}",
+            if installed.is_empty() {
+                "none".to_string()
+            } else {
+                installed.join(", ")
+            }
+        );
+        warn(
+            quiet,
+            &message,
+            &[
+                "Chrooted tools (initramfs, bootloader) may pick the wrong kernel or fail",
+                &installed,
+                "If they do, boot the installed system and run them there",
+            ],
+        );
+    }
+}
+
+/// Interactive shells, in order of preference.
+pub const SHELLS: &[&str] = &["/bin/bash", "/bin/sh"];
+
+/// The first of `candidates` (absolute paths) that exists in the target.
+pub fn find_tool<'a>(target: &Path, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .copied()
+        .find(|c| target.join(c.trim_start_matches('/')).exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_tool() {
+        let temp = std::env::temp_dir().join("recstrap_test_find_tool");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(temp.join("usr/bin")).unwrap();
+        std::fs::write(temp.join("usr/bin/mkinitcpio"), "").unwrap();
+
+        assert_eq!(
+            find_tool(&temp, &["/usr/bin/dracut", "/usr/bin/mkinitcpio"]),
+            Some("/usr/bin/mkinitcpio")
+        );
+        assert_eq!(find_tool(&temp, &["/usr/bin/dracut"]), None);
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_kernel_mismatch() {
+        let temp = std::env::temp_dir().join("recstrap_test_kernel_mismatch");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(temp.join("usr/lib/modules/6.12.9-levitate")).unwrap();
+        std::os::unix::fs::symlink("usr/lib", temp.join("lib")).unwrap();
+
+        assert_eq!(kernel_mismatch(&temp, "6.12.9-levitate"), None);
+        let message = kernel_mismatch(&temp, "6.8.0-live").unwrap();
+        assert!(message.contains("running kernel 6.8.0-live"), "{}", message);
+
+        // Found through /lib on images that aren't usrmerged
+        std::fs::remove_file(temp.join("lib")).unwrap();
+        std::fs::create_dir_all(temp.join("lib/modules/6.8.0-live")).unwrap();
+        assert_eq!(kernel_mismatch(&temp, "6.8.0-live"), None);
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+}
@@ -0,0 +1,763 @@
+//! Command-line interface definitions.
+//!
+//! `recstrap <TARGET>` is the main extraction flow. Helpers that work on
+//! images or maintain installs (inspect, find, verify, clean, ...) are
+//! subcommands.
+//!
+//! Most install options can also come from `RECSTRAP_*` environment
+//! variables (listed in `--help`), so provisioning systems can configure a
+//! run without building an argument list; arguments take precedence.
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use clap::builder::BoolishValueParser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+
+use crate::accounts::{account_name, SudoPolicy};
+use crate::commands::{ExportFormat, Scheme};
+use crate::configure::{console_setting, unit_name, NetworkConfig, ResolvConf, SerialConsole};
+use crate::constants::{DEFAULT_SPACE_MARGIN, VERIFY_SAMPLE_FILES};
+use crate::disk::{parse_size, RootFs};
+use crate::extractor::Backend;
+use crate::helpers::{DEFAULT_COMMAND_TIMEOUT_SECS, DEFAULT_IO_RETRIES};
+use crate::verity::root_hash;
+
+#[derive(Parser)]
+#[command(name = "recstrap")]
+#[command(version)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(about = "Extract LevitateOS rootfs to target directory (like pacstrap)")]
+#[command(
+    long_about = "Extracts the LevitateOS EROFS rootfs image to a target directory. \
+    This is the pacstrap equivalent for LevitateOS - it only extracts files. \
+    You must do everything else manually: partitioning, formatting, mounting, \
+    fstab generation, bootloader installation, and system configuration."
+)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Target directory (must be mounted, e.g., /mnt)
+    #[arg(
+        required_unless_present_any = ["image", "answers", "targets", "serve"],
+        conflicts_with_all = ["image", "answers", "targets", "serve"]
+    )]
+    pub target: Option<String>,
+
+    /// Install the same image into several targets in one run, mounting
+    /// it only once (lab/classroom provisioning); stops at the first
+    /// target that fails
+    #[arg(
+        long,
+        value_name = "DIR,...",
+        value_delimiter = ',',
+        conflicts_with_all = ["image", "answers", "rootless"]
+    )]
+    pub targets: Option<Vec<String>>,
+
+    /// Run a whole unattended install from a TOML answers file (target,
+    /// rootfs, hostname, users, network, bootloader, hooks); other options
+    /// except --quiet come from the file
+    #[arg(long, value_name = "FILE", conflicts_with = "image")]
+    pub answers: Option<String>,
+
+    /// Run as a daemon taking JSON-RPC requests (preflight, start,
+    /// progress, cancel) on this Unix socket, for unprivileged installer
+    /// frontends; `-` serves one client on stdin/stdout (for pkexec)
+    #[arg(
+        long,
+        value_name = "SOCKET",
+        conflicts_with_all = ["image", "answers", "targets", "check"]
+    )]
+    pub serve: Option<String>,
+
+    /// Never prompt and skip the manual next steps (set by --answers)
+    #[arg(long, hide = true)]
+    pub unattended: bool,
+
+    /// Rootfs location (auto-detected from common paths if not specified)
+    /// Must be an EROFS image ending in `.erofs`, or `-` to read from stdin.
+    /// An image split into `.000`, `.001`, ... parts is joined first.
+    #[arg(long, env = "RECSTRAP_ROOTFS")]
+    pub rootfs: Option<String>,
+
+    /// Root hash of the image's dm-verity hash tree (default: read from a
+    /// `.roothash` file next to the image)
+    #[arg(
+        long,
+        value_name = "HEX",
+        env = "RECSTRAP_VERITY_ROOT_HASH",
+        value_parser = root_hash
+    )]
+    pub verity_root_hash: Option<String>,
+
+    /// Install this variant when the live medium ships several images
+    /// (listed in flavors.toml, or filesystem-NAME.erofs)
+    #[arg(
+        long,
+        value_name = "NAME",
+        env = "RECSTRAP_FLAVOR",
+        conflicts_with = "rootfs"
+    )]
+    pub flavor: Option<String>,
+
+    /// Force extraction even if target is not empty or not a mount point
+    #[arg(short, long)]
+    pub force: bool,
+
+    /// Don't ask before --force overwrites a non-empty target (only asked
+    /// when stdin is a terminal)
+    #[arg(long)]
+    pub assume_yes: bool,
+
+    /// Replace a previous LevitateOS install (identified by its
+    /// /etc/recstrap-release); unlike --force, refuses any other data
+    #[arg(long, conflicts_with_all = ["force", "image"])]
+    pub reinstall: bool,
+
+    /// Keep PATH from the existing target across the install, replacing
+    /// the image's version (repeatable). --reinstall always keeps
+    /// /etc/fstab, /etc/machine-id and /home
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "image",
+        value_parser = crate::preserve::parse_path
+    )]
+    pub preserve: Vec<String>,
+
+    /// Treat warnings as failures: a non-Linux target partition (ESP,
+    /// Windows, swap, ...) fails with E021, any other warning with E023
+    #[arg(long, env = "RECSTRAP_STRICT", value_parser = BoolishValueParser::new())]
+    pub strict: bool,
+
+    /// Record the start and outcome of the extraction (target, image,
+    /// SHA-256, user) to syslog and the kernel audit log
+    #[arg(long, env = "RECSTRAP_AUDIT", value_parser = BoolishValueParser::new())]
+    pub audit: bool,
+
+    /// Send a desktop notification to the live session when the install
+    /// ends (success or failure, elapsed time)
+    #[arg(long, conflicts_with = "check")]
+    pub notify: bool,
+
+    /// Quiet mode - minimal output for scripting
+    #[arg(short, long, env = "RECSTRAP_QUIET", value_parser = BoolishValueParser::new())]
+    pub quiet: bool,
+
+    /// Also show every command run (-v) and every entry extracted (-vv);
+    /// ignored with --quiet
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Don't color the output (also NO_COLOR=1 or TERM=dumb; off anyway
+    /// when stderr isn't a terminal)
+    #[arg(long, global = true)]
+    pub no_color: bool,
+
+    /// Remount the target without noexec/nodev/nosuid instead of warning
+    #[arg(long, conflicts_with = "rootless")]
+    pub remount: bool,
+
+    /// Flush the target to disk (syncfs) before reporting success (default)
+    #[arg(long, overrides_with = "no_sync")]
+    pub sync: bool,
+
+    /// Skip the final flush; faster, but pulling the power right after can
+    /// lose files that verification already checked
+    #[arg(long, overrides_with = "sync")]
+    pub no_sync: bool,
+
+    /// Unmount the target and its nested mounts (e.g. the ESP) after a
+    /// successful install, for scripts that reboot right away
+    #[arg(long, conflicts_with_all = ["check", "image", "rootless"])]
+    pub umount_after: bool,
+
+    /// Don't snapshot a non-empty btrfs target before --force overwrites it
+    #[arg(long)]
+    pub no_snapshot: bool,
+
+    /// Check mode - run pre-flight validation only, don't extract
+    #[arg(short, long)]
+    pub check: bool,
+
+    /// With --check, go on past failed checks and report every failure
+    /// (E024 lists them if there are several); stops only where later
+    /// checks need an earlier one to pass (target or image missing)
+    #[arg(long, requires = "check")]
+    pub all: bool,
+
+    /// Retries for transient I/O errors (busy device, loop devices
+    /// exhausted, read errors on flaky media), with exponential backoff
+    #[arg(long, value_name = "N", env = "RECSTRAP_RETRIES", default_value_t = DEFAULT_IO_RETRIES)]
+    pub retries: u32,
+
+    /// Threads writing file data during extraction (0: one per CPU, at
+    /// most 8); 1 suits rotating disks, which parallel writes make seek
+    #[arg(
+        long,
+        value_name = "N",
+        env = "RECSTRAP_COPY_JOBS",
+        default_value_t = 0
+    )]
+    pub copy_jobs: usize,
+
+    /// Copy file data through a buffer instead of reflinks,
+    /// copy_file_range or sendfile, to rule those out when debugging
+    #[arg(long)]
+    pub no_copy_offload: bool,
+
+    /// Seconds mount, modprobe and umount may run before they are killed
+    /// and the install fails with E022 (0 waits forever)
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "RECSTRAP_COMMAND_TIMEOUT",
+        default_value_t = DEFAULT_COMMAND_TIMEOUT_SECS
+    )]
+    pub command_timeout: u64,
+
+    /// Time a short write to the target and estimate the extraction time
+    /// (also warns about slow USB sticks)
+    #[arg(long)]
+    pub probe_speed: bool,
+
+    /// Print a one-line progress snapshot every SECS seconds, even with
+    /// --quiet (keeps CI inactivity timeouts from killing long runs)
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub heartbeat: Option<u64>,
+
+    /// Print the final statistics (sizes, counts, timing) as JSON on stdout
+    #[arg(long, conflicts_with = "check")]
+    pub json: bool,
+
+    /// Write the final statistics, verification result, and warnings as
+    /// JSON to FILE (also on failure, with the error); ignored by --check
+    /// and --targets
+    #[arg(long, value_name = "FILE", env = "RECSTRAP_STATS_FILE")]
+    pub stats_file: Option<String>,
+
+    /// Append a copy of everything printed to stderr (messages, warnings,
+    /// helper output, the error) to FILE
+    #[arg(long, value_name = "FILE", env = "RECSTRAP_LOG")]
+    pub log: Option<String>,
+
+    /// Report format for --check (tap: one TAP test point per check, on stdout)
+    #[arg(long, value_enum, default_value_t = CheckOutput::Human, requires = "check")]
+    pub output: CheckOutput,
+
+    /// Relaxed verification - report stripped file capabilities as warnings
+    /// instead of failing (e.g. when the target filesystem lacks xattrs)
+    #[arg(long)]
+    pub relaxed: bool,
+
+    /// Post-extraction verification level
+    #[arg(
+        long,
+        value_enum,
+        value_name = "LEVEL",
+        env = "RECSTRAP_VERIFY",
+        default_value_t = VerifyLevel::Basic
+    )]
+    pub verify: VerifyLevel,
+
+    /// Number of files compared by `--verify sample`
+    #[arg(long, value_name = "N", default_value_t = VERIFY_SAMPLE_FILES)]
+    pub verify_samples: usize,
+
+    /// Copy this directory tree over the target after extraction
+    /// (site configs, units, branding); files end up owned by root
+    #[arg(long, value_name = "DIR")]
+    pub copy_into: Option<String>,
+
+    /// Enable a login prompt on a serial console (default ttyS0,115200)
+    #[arg(
+        long,
+        value_name = "TTY[,BAUD]",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "ttyS0,115200",
+        value_parser = SerialConsole::parse
+    )]
+    pub serial_console: Option<SerialConsole>,
+
+    /// Enable a systemd unit in the target, like `systemctl enable`
+    /// (repeatable; `sshd` means `sshd.service`)
+    #[arg(long, value_name = "UNIT", value_parser = unit_name)]
+    pub enable: Vec<String>,
+
+    /// Disable a systemd unit in the target (repeatable; applied after --enable)
+    #[arg(long, value_name = "UNIT", value_parser = unit_name)]
+    pub disable: Vec<String>,
+
+    /// Network setup for first boot via systemd-networkd: `dhcp` or
+    /// `static:<ip/cidr>[,gw=<ip>][,dns=<ip>]...`
+    #[arg(long, value_name = "CONFIG", value_parser = NetworkConfig::parse)]
+    pub network: Option<NetworkConfig>,
+
+    /// /etc/resolv.conf in the target: copy the live system's resolvers,
+    /// link systemd-resolved's stub (and enable it), or keep the image's
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = ResolvConf::None)]
+    pub resolv_conf: ResolvConf,
+
+    /// Copy the live session's /etc/locale.conf, /etc/vconsole.conf and
+    /// /etc/localtime into the target; --keymap/--console-font still win
+    #[arg(long)]
+    pub inherit_live_config: bool,
+
+    /// Console keymap for /etc/vconsole.conf (default: the live system's)
+    #[arg(long, value_name = "KEYMAP", value_parser = console_setting)]
+    pub keymap: Option<String>,
+
+    /// Console font for /etc/vconsole.conf (default: the live system's)
+    #[arg(long, value_name = "FONT", value_parser = console_setting)]
+    pub console_font: Option<String>,
+
+    /// Read the initial user's password as a crypt(3) hash from file
+    /// descriptor FD (e.g. `3<hash.txt`) instead of prompting for it
+    #[arg(
+        long,
+        value_name = "FD",
+        value_parser = clap::value_parser!(i32).range(3..),
+        conflicts_with_all = ["answers", "targets"]
+    )]
+    pub password_hash_fd: Option<i32>,
+
+    /// Give wheel (which the initial user joins) sudo rights from a
+    /// checked /etc/sudoers.d/10-recstrap instead of the image's sudoers
+    #[arg(long, value_enum, value_name = "POLICY", conflicts_with_all = ["answers", "targets"])]
+    pub sudo: Option<SudoPolicy>,
+
+    /// Populate the initial user's home from DIR (dotfiles, desktop
+    /// settings, SSH config) instead of the image's /etc/skel
+    #[arg(long, value_name = "DIR", conflicts_with = "answers")]
+    pub skel: Option<String>,
+
+    /// Log USER in automatically on tty1, the --serial-console and the
+    /// image's display manager (gdm, sddm, lightdm), for kiosks and
+    /// appliances; USER must exist by the end of the install
+    #[arg(long, value_name = "USER", value_parser = account_name, conflicts_with = "answers")]
+    pub autologin: Option<String>,
+
+    /// Rebuild the initramfs inside the target (dracut, mkinitcpio, or
+    /// update-initramfs) so it has this machine's drivers and hooks
+    #[arg(long, conflicts_with = "rootless")]
+    pub regen_initramfs: bool,
+
+    /// Touch /.autorelabel so SELinux relabels the target on first boot
+    #[arg(long)]
+    pub selinux_relabel: bool,
+
+    /// With --selinux-relabel: copy the live system's /etc/selinux (config
+    /// and policy) into the target first
+    #[arg(long, requires = "selinux_relabel")]
+    pub selinux_copy_policy: bool,
+
+    /// Build a Unified Kernel Image per installed kernel (dracut or ukify)
+    /// onto this EFI system partition, mounted at <TARGET>/efi meanwhile
+    #[arg(long, value_name = "ESP_DEVICE", conflicts_with_all = ["rootless", "image"])]
+    pub uki: Option<String>,
+
+    /// Directory for temporary mount points and stdin spooling
+    /// (default: $TMPDIR). Use this when /tmp is a small tmpfs.
+    #[arg(long, value_name = "DIR", env = "RECSTRAP_TARGET_WORKDIR")]
+    pub workdir: Option<String>,
+
+    /// Free space the target needs at least, whatever the image's size
+    /// (e.g. 512M for embedded targets; default 2G)
+    #[arg(long, value_name = "SIZE", env = "RECSTRAP_MIN_FREE", value_parser = parse_size_arg)]
+    pub min_free: Option<u64>,
+
+    /// Run helper processes (erofsfuse, veritysetup, ...) in a transient
+    /// cgroup with this much memory (e.g. 512M) and a process limit, so
+    /// low-RAM live systems don't hit the OOM killer mid-copy
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "RECSTRAP_MEMORY_LIMIT",
+        value_parser = parse_size_arg,
+        conflicts_with = "rootless"
+    )]
+    pub memory_limit: Option<u64>,
+
+    /// Free space the target needs on top of the image's unpacked size,
+    /// in percent
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        env = "RECSTRAP_SPACE_MARGIN",
+        default_value_t = DEFAULT_SPACE_MARGIN,
+        value_parser = clap::value_parser!(u64).range(0..=1000)
+    )]
+    pub space_margin: u64,
+
+    /// Install into a new raw disk image file instead of a directory
+    /// (GPT with an empty ESP and a root partition, for VM images)
+    #[arg(long, value_name = "FILE", requires = "size", conflicts_with_all = ["check", "rootless"])]
+    pub image: Option<String>,
+
+    /// Size of the disk image created by --image (e.g. 20G)
+    #[arg(long, value_name = "SIZE", requires = "image", value_parser = parse_size_arg)]
+    pub size: Option<u64>,
+
+    /// Root filesystem of the disk image created by --image
+    #[arg(long, value_enum, value_name = "FS", requires = "image", default_value_t = RootFs::Ext4)]
+    pub fs: RootFs,
+
+    /// Rootless mode - extract as a normal user via erofsfuse inside a user
+    /// namespace (development and container rootfs only, not real installs)
+    #[arg(long)]
+    pub rootless: bool,
+
+    /// How to read the image: auto, erofs-mount (kernel driver) or
+    /// erofs-fuse (erofsfuse, for kernels without EROFS support)
+    #[arg(
+        long,
+        value_enum,
+        value_name = "BACKEND",
+        env = "RECSTRAP_BACKEND",
+        default_value_t = Backend::Auto
+    )]
+    pub backend: Backend,
+
+    /// The arguments these options came from, without the program name;
+    /// kept in the install record
+    #[arg(skip)]
+    pub argv: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Show rootfs image metadata (format, compression, UUID, features)
+    /// without extracting anything
+    Inspect(InspectArgs),
+    /// List usable rootfs images in the standard search paths and on
+    /// mounted removable media
+    Find(FindArgs),
+    /// Copy a single file or directory out of a rootfs image (for repairs)
+    #[command(name = "extract-path")]
+    ExtractPath(ExtractPathArgs),
+    /// Audit an installed system against its image: modified, missing,
+    /// and extra files
+    Verify(VerifyArgs),
+    /// Remove a partial or failed extraction from a target, plus stale
+    /// recstrap mounts and leftover .recstrap_* files
+    Clean(CleanArgs),
+    /// Install GRUB into an extracted system (chroot, grub-install,
+    /// grub-mkconfig) and verify the result
+    Bootloader(BootloaderArgs),
+    /// Partition, format, and mount a disk (GPT, ESP + root), then extract
+    /// into it
+    Prepare(PrepareArgs),
+    /// Mount an image read-only and open a shell inside it, to look
+    /// around (or, with --overlay, try things) before installing
+    Preview(PreviewArgs),
+    /// Convert the image into an archive for another platform (WSL,
+    /// container engines)
+    Export(ExportArgs),
+    /// Install a tiny generated image into a temp directory and verify it,
+    /// to check that recstrap works on this system
+    #[command(name = "self-test")]
+    SelfTest(SelfTestArgs),
+    /// List the validation checks recstrap runs (id, stage, severity,
+    /// what each protects, and the cheats that would weaken it)
+    Checks(ChecksArgs),
+}
+
+#[derive(clap::Args)]
+pub struct InspectArgs {
+    /// Rootfs image to inspect (.erofs)
+    pub image: String,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
+}
+
+#[derive(clap::Args)]
+pub struct FindArgs {
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
+}
+
+#[derive(clap::Args)]
+pub struct ExtractPathArgs {
+    /// Rootfs image to read from (.erofs)
+    pub image: String,
+
+    /// Path inside the image, e.g. /etc/os-release
+    pub path: String,
+
+    /// Destination; an existing directory receives the entry under its own name
+    pub dest: String,
+
+    /// Directory for the temporary mount point (default: $TMPDIR)
+    #[arg(long, value_name = "DIR", env = "RECSTRAP_TARGET_WORKDIR")]
+    pub workdir: Option<String>,
+
+    /// Quiet mode - minimal output for scripting
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+#[derive(clap::Args)]
+pub struct VerifyArgs {
+    /// Root of the installed system (e.g. /mnt, or / for the running system)
+    pub target: String,
+
+    /// Rootfs image to compare against (default: the image named in
+    /// /etc/recstrap-release, else auto-detected)
+    #[arg(long, env = "RECSTRAP_ROOTFS")]
+    pub rootfs: Option<String>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
+
+    /// Directory for the temporary mount point (default: $TMPDIR)
+    #[arg(long, value_name = "DIR", env = "RECSTRAP_TARGET_WORKDIR")]
+    pub workdir: Option<String>,
+
+    /// Quiet mode - minimal output for scripting
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+#[derive(clap::Args)]
+pub struct CleanArgs {
+    /// Target directory of the failed extraction (e.g. /mnt)
+    pub target: String,
+
+    /// Only list what would be removed
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Directory to scan for stale mount points (default: $TMPDIR)
+    #[arg(long, value_name = "DIR", env = "RECSTRAP_TARGET_WORKDIR")]
+    pub workdir: Option<String>,
+
+    /// Quiet mode - minimal output for scripting
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+#[derive(clap::Args)]
+pub struct BootloaderArgs {
+    /// Root of the extracted system (e.g. /mnt); on UEFI the ESP must be
+    /// mounted at <TARGET>/efi, /boot/efi, or /boot
+    pub target: String,
+
+    /// Install GRUB for this disk (e.g. /dev/sda); BIOS or UEFI follows
+    /// how the live system booted
+    #[arg(long, value_name = "DISK", required = true)]
+    pub grub: String,
+
+    /// UEFI: install to the fallback path EFI/BOOT/BOOTX64.EFI instead of
+    /// registering an NVRAM entry (for removable media and picky firmware)
+    #[arg(long)]
+    pub removable: bool,
+
+    /// Quiet mode - minimal output for scripting
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+#[derive(clap::Args)]
+pub struct PrepareArgs {
+    /// Whole disk to partition (e.g. /dev/sda, /dev/nvme0n1)
+    pub disk: String,
+
+    /// Partition layout
+    #[arg(long, value_enum)]
+    pub scheme: Scheme,
+
+    /// Filesystem for the root partition
+    #[arg(long, value_enum, default_value_t = RootFs::Ext4)]
+    pub fs: RootFs,
+
+    /// Where to mount the new root (the ESP goes to <MOUNT_POINT>/efi)
+    #[arg(long, value_name = "DIR", default_value = "/mnt")]
+    pub mount_point: String,
+
+    /// Rootfs image for the extraction (auto-detected if not specified)
+    #[arg(long, env = "RECSTRAP_ROOTFS")]
+    pub rootfs: Option<String>,
+
+    /// Stop after mounting; don't run the extraction
+    #[arg(long)]
+    pub no_extract: bool,
+
+    /// Don't ask for confirmation before changing the partition table
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Quiet mode - minimal output for scripting
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+#[derive(clap::Args)]
+pub struct PreviewArgs {
+    /// Rootfs image to preview (.erofs)
+    pub image: String,
+
+    /// Put a tmpfs overlay on top so the image is writable; changes are
+    /// discarded when the shell exits
+    #[arg(long)]
+    pub overlay: bool,
+
+    /// Shell to run inside the image (default: /bin/bash, else /bin/sh)
+    #[arg(long, value_name = "PROGRAM")]
+    pub shell: Option<String>,
+
+    /// Directory for the temporary mount points (default: $TMPDIR)
+    #[arg(long, value_name = "DIR", env = "RECSTRAP_TARGET_WORKDIR")]
+    pub workdir: Option<String>,
+
+    /// Quiet mode - minimal output for scripting
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+#[derive(clap::Args)]
+pub struct ExportArgs {
+    /// What to produce
+    #[arg(long, value_enum)]
+    pub format: ExportFormat,
+
+    /// Rootfs image to export (.erofs)
+    pub image: String,
+
+    /// Archive to write, compressed by suffix (.tar.gz, .tar.xz); for
+    /// `oci`, a new or empty directory
+    pub output: String,
+
+    /// Directory for the temporary mount points (default: $TMPDIR)
+    #[arg(long, value_name = "DIR", env = "RECSTRAP_TARGET_WORKDIR")]
+    pub workdir: Option<String>,
+
+    /// Quiet mode - minimal output for scripting
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+#[derive(clap::Args)]
+pub struct SelfTestArgs {
+    /// Directory for the test image, target, and mount points
+    /// (default: $TMPDIR)
+    #[arg(long, value_name = "DIR", env = "RECSTRAP_TARGET_WORKDIR")]
+    pub workdir: Option<String>,
+
+    /// Quiet mode - minimal output for scripting
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+#[derive(clap::Args)]
+pub struct ChecksArgs {
+    /// List every check, in the order an install runs them
+    #[arg(long, required = true)]
+    pub list: bool,
+
+    /// Print the list as JSON on stdout
+    #[arg(long)]
+    pub json: bool,
+}
+
+impl Args {
+    /// Parse `argv` alone, ignoring the `RECSTRAP_*` environment, for
+    /// callers that spell out every option themselves.
+    pub fn try_parse_without_env<I, T>(argv: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let mut command = Self::command().mut_args(|arg| arg.env(None));
+        for name in command
+            .get_subcommands()
+            .map(|sub| sub.get_name().to_string())
+            .collect::<Vec<_>>()
+        {
+            command = command.mut_subcommand(name, |sub| sub.mut_args(|arg| arg.env(None)));
+        }
+        Self::from_arg_matches(&command.try_get_matches_from(argv)?)
+    }
+
+    /// Parse the process's command line, keeping it in `argv`.
+    pub fn parse_command_line() -> Self {
+        let mut args = Self::parse();
+        args.argv = std::env::args_os()
+            .skip(1)
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        args
+    }
+
+    /// The options of a plain `recstrap TARGET`: the defaults, without the
+    /// `RECSTRAP_*` environment. Callers set the fields they need.
+    pub fn for_target(target: &Path) -> Result<Self, clap::Error> {
+        Self::try_parse_without_env([OsStr::new("recstrap"), OsStr::new("--"), target.as_os_str()])
+    }
+
+    /// Whether something parses the output: `--quiet`, `--json`,
+    /// `--output tap`, or a subcommand's `--quiet` or `--output json`.
+    pub fn machine_readable(&self) -> bool {
+        let json = |output: &OutputFormat| *output == OutputFormat::Json;
+        self.quiet
+            || self.json
+            || self.output == CheckOutput::Tap
+            || match &self.command {
+                None => false,
+                Some(Command::Inspect(a)) => json(&a.output),
+                Some(Command::Find(a)) => json(&a.output),
+                Some(Command::Verify(a)) => a.quiet || json(&a.output),
+                Some(Command::ExtractPath(a)) => a.quiet,
+                Some(Command::Clean(a)) => a.quiet,
+                Some(Command::Bootloader(a)) => a.quiet,
+                Some(Command::Prepare(a)) => a.quiet,
+                Some(Command::Preview(a)) => a.quiet,
+                Some(Command::Export(a)) => a.quiet,
+                Some(Command::SelfTest(a)) => a.quiet,
+                Some(Command::Checks(a)) => a.json,
+            }
+    }
+}
+
+fn parse_size_arg(s: &str) -> Result<u64, String> {
+    parse_size(s).ok_or_else(|| format!("invalid size '{}' (expected e.g. 20G or 512M)", s))
+}
+
+/// How thoroughly the target is checked against the image after extraction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VerifyLevel {
+    /// Essential directories, hardlinks, and file capabilities
+    Basic,
+    /// Basic checks, then compare a random sample of files with the image
+    Sample,
+    /// Basic checks, and checksum every file as it is read from the image,
+    /// then read it back from the target disk (not the page cache) right
+    /// away: no second pass over the image, but one fsync per file
+    Inline,
+    /// Basic checks, then re-read the image and compare every file byte-for-byte
+    Full,
+}
+
+/// Output format for commands that can emit machine-readable data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    Human,
+    /// JSON on stdout
+    Json,
+}
+
+/// Report format of `--check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CheckOutput {
+    /// Human-readable summary on stderr
+    Human,
+    /// Test Anything Protocol on stdout, for CI and test harnesses
+    Tap,
+}
@@ -0,0 +1,162 @@
+//! What an install over a non-empty target would overwrite.
+//!
+//! `--check` on a non-empty target (with `--force` or `--reinstall`)
+//! reports, for each top-level entry of the target, how many of the paths
+//! below it the image also has, and which entries the image leaves alone,
+//! so it is clear how destructive the real run will be.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::helpers::is_ignored_entry;
+
+/// A top-level entry of the target that the image also has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collision {
+    /// Name, with a trailing `/` for directories
+    pub entry: String,
+    /// Non-directory paths below it (or the entry itself) that the image
+    /// would replace
+    pub overwritten: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CollisionReport {
+    pub collisions: Vec<Collision>,
+    /// Top-level entries the image doesn't have
+    pub untouched: Vec<String>,
+}
+
+impl CollisionReport {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        if self.collisions.is_empty() {
+            out.push_str("The image overwrites nothing in the target.\n");
+        } else {
+            out.push_str("Existing paths the image would overwrite:\n");
+            for c in &self.collisions {
+                let noun = if c.overwritten == 1 { "path" } else { "paths" };
+                out.push_str(&format!("  {} ({} {})\n", c.entry, c.overwritten, noun));
+            }
+        }
+        if !self.untouched.is_empty() {
+            out.push_str(&format!("Left alone: {}\n", self.untouched.join(", ")));
+        }
+        out
+    }
+}
+
+/// Compare the target's top-level entries against the image tree mounted
+/// at `image`. Symlinks are never followed.
+pub fn find(image: &Path, target: &Path) -> io::Result<CollisionReport> {
+    let mut report = CollisionReport::default();
+    let mut names = Vec::new();
+    for entry in target.read_dir()? {
+        let name = entry?.file_name();
+        if !is_ignored_entry(&name) {
+            names.push(name);
+        }
+    }
+    names.sort();
+
+    for name in names {
+        let ours = target.join(&name);
+        let is_dir = fs::symlink_metadata(&ours)?.is_dir();
+        let entry = format!(
+            "{}{}",
+            name.to_string_lossy(),
+            if is_dir { "/" } else { "" }
+        );
+        let theirs = image.join(&name);
+        if fs::symlink_metadata(&theirs).is_ok() {
+            report.collisions.push(Collision {
+                entry,
+                overwritten: overlap(&theirs, &ours),
+            });
+        } else {
+            report.untouched.push(entry);
+        }
+    }
+    Ok(report)
+}
+
+/// Paths that exist in both trees and aren't directories on both sides.
+fn overlap(image: &Path, target: &Path) -> u64 {
+    let both_dirs = fs::symlink_metadata(image).is_ok_and(|m| m.is_dir())
+        && fs::symlink_metadata(target).is_ok_and(|m| m.is_dir());
+    if !both_dirs {
+        return 1;
+    }
+    let Ok(entries) = image.read_dir() else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| {
+            let ours = target.join(entry.file_name());
+            if fs::symlink_metadata(&ours).is_ok() {
+                overlap(&entry.path(), &ours)
+            } else {
+                0
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let root = std::env::temp_dir().join("recstrap_test_collisions");
+        let _ = fs::remove_dir_all(&root);
+        let (image, target) = (root.join("image"), root.join("target"));
+        for dir in [
+            "image/etc/ssh",
+            "image/usr/bin",
+            "target/etc/ssh",
+            "target/home",
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "image/etc/hostname",
+            "image/etc/ssh/sshd_config",
+            "image/usr/bin/sh",
+            "target/etc/hostname",
+            "target/etc/ssh/sshd_config",
+            "target/etc/fstab",
+            "target/home/notes",
+        ] {
+            fs::write(root.join(file), "x").unwrap();
+        }
+        // A file where the image has a directory is replaced as a whole
+        fs::write(target.join("usr"), "x").unwrap();
+        fs::create_dir(target.join("lost+found")).unwrap();
+
+        let report = find(&image, &target).unwrap();
+        assert_eq!(
+            report.collisions,
+            [
+                Collision {
+                    entry: "etc/".to_string(),
+                    overwritten: 2
+                },
+                Collision {
+                    entry: "usr".to_string(),
+                    overwritten: 1
+                },
+            ]
+        );
+        assert_eq!(report.untouched, ["home/"]);
+        assert_eq!(
+            report.render(),
+            "Existing paths the image would overwrite:\n  etc/ (2 paths)\n  usr (1 path)\n\
+             Left alone: home/\n"
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
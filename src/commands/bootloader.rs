@@ -0,0 +1,208 @@
+//! `recstrap bootloader --grub <disk> <target>` - install GRUB into an
+//! extracted system, for BIOS machines and setups not using systemd-boot.
+//!
+//! Runs the target's own `grub-install` and `grub-mkconfig` in a chroot with
+//! the API filesystems mounted, then checks that the result is bootable:
+//! a generated `grub.cfg` with menu entries, plus GRUB's boot code in the
+//! MBR (BIOS) or a GRUB EFI binary on the ESP (UEFI).
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use crate::boot::{find_esp, BootMode};
+use crate::chroot::{find_tool, run_in_chroot, warn_kernel_mismatch, ChrootMounts};
+use crate::cli::BootloaderArgs;
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::is_root;
+
+/// `grub-install` locations; Fedora-style systems call it `grub2-install`.
+const GRUB_INSTALL: &[&str] = &[
+    "/usr/bin/grub-install",
+    "/usr/sbin/grub-install",
+    "/usr/bin/grub2-install",
+    "/usr/sbin/grub2-install",
+];
+
+pub fn run(args: &BootloaderArgs) -> Result<()> {
+    if !is_root() {
+        return Err(RecError::not_root());
+    }
+
+    let target = Path::new(&args.target);
+    if !target.is_dir() {
+        return Err(RecError::not_a_directory(&args.target));
+    }
+    let target = target
+        .canonicalize()
+        .map_err(|e| RecError::new(ErrorCode::TargetNotFound, e.to_string()))?;
+
+    let disk = &args.grub;
+    let is_block = fs::metadata(disk).is_ok_and(|m| m.file_type().is_block_device());
+    if !is_block {
+        return Err(grub_error(&format!("{} is not a block device", disk)));
+    }
+
+    let install = find_tool(&target, GRUB_INSTALL)
+        .ok_or_else(|| grub_error("grub-install is not installed in the target"))?;
+    let (mkconfig, config) = grub_paths(install);
+
+    let mode = BootMode::detect();
+    let esp = match mode {
+        BootMode::Uefi => Some(find_esp(&target).ok_or_else(|| {
+            grub_error(
+                "booted UEFI but no ESP (vfat) is mounted at <target>/efi, /boot/efi, or /boot",
+            )
+        })?),
+        BootMode::Bios => None,
+    };
+
+    let mut install_args = match esp {
+        Some(esp) => vec![
+            "--target=x86_64-efi".to_string(),
+            format!("--efi-directory=/{}", esp),
+            "--bootloader-id=LevitateOS".to_string(),
+        ],
+        None => vec!["--target=i386-pc".to_string(), disk.clone()],
+    };
+    if args.removable && esp.is_some() {
+        install_args.push("--removable".to_string());
+    }
+    let install_args: Vec<&str> = install_args.iter().map(String::as_str).collect();
+
+    if !args.quiet {
+        eprintln!("Installing GRUB ({}) for {}...", mode, disk);
+    }
+    warn_kernel_mismatch(&target, args.quiet);
+    let mounts = ChrootMounts::setup(&target).map_err(|e| grub_error(&e.to_string()))?;
+    run_in_chroot(&target, install, &install_args).map_err(|e| grub_error(&e.to_string()))?;
+    run_in_chroot(&target, &mkconfig, &["-o", config]).map_err(|e| grub_error(&e.to_string()))?;
+    drop(mounts);
+
+    // Verify: both tools exiting 0 doesn't mean the machine will boot
+    let cfg = fs::read_to_string(target.join(&config[1..])).unwrap_or_default();
+    if !has_menu_entries(&cfg) {
+        return Err(grub_error(&format!(
+            "{} has no menu entries (no kernel found in /boot?)",
+            config
+        )));
+    }
+    match esp {
+        Some(esp) => {
+            let efi_dir = target.join(esp).join("EFI");
+            if !contains_grub_efi(&efi_dir) {
+                return Err(grub_error(&format!(
+                    "no GRUB EFI binary under {}",
+                    efi_dir.display()
+                )));
+            }
+        }
+        None => {
+            let mut mbr = [0u8; 440];
+            File::open(disk)
+                .and_then(|mut f| f.read_exact(&mut mbr))
+                .map_err(|e| grub_error(&format!("cannot read {}: {}", disk, e)))?;
+            if !has_grub_boot_code(&mbr) {
+                return Err(grub_error(&format!(
+                    "no GRUB boot code in the MBR of {}",
+                    disk
+                )));
+            }
+        }
+    }
+
+    if !args.quiet {
+        eprintln!("GRUB installed and verified ({}).", config);
+    }
+    Ok(())
+}
+
+fn grub_error(detail: &str) -> RecError {
+    RecError::configuration_failed("GRUB", detail)
+}
+
+/// `grub-mkconfig` and its output path matching a `grub-install` binary.
+fn grub_paths(install: &str) -> (String, &'static str) {
+    let mkconfig = install.replace("-install", "-mkconfig");
+    if install.contains("grub2-") {
+        (mkconfig, "/boot/grub2/grub.cfg")
+    } else {
+        (mkconfig, "/boot/grub/grub.cfg")
+    }
+}
+
+fn has_menu_entries(cfg: &str) -> bool {
+    cfg.lines()
+        .any(|l| l.trim_start().starts_with("menuentry "))
+}
+
+/// GRUB's `boot.img` carries the string "GRUB" in its error messages.
+fn has_grub_boot_code(mbr: &[u8]) -> bool {
+    mbr.windows(4).any(|w| w == b"GRUB")
+}
+
+/// Whether `EFI/*/grub*.efi` (or the removable `BOOTX64.EFI`) exists.
+fn contains_grub_efi(efi_dir: &Path) -> bool {
+    fs::read_dir(efi_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| fs::read_dir(e.path()).ok())
+        .flatten()
+        .filter_map(|e| e.ok())
+        .any(|e| {
+            let name = e.file_name().to_string_lossy().to_lowercase();
+            (name.starts_with("grub") || name == "bootx64.efi") && name.ends_with(".efi")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grub_paths() {
+        assert_eq!(
+            grub_paths("/usr/bin/grub-install"),
+            ("/usr/bin/grub-mkconfig".to_string(), "/boot/grub/grub.cfg")
+        );
+        assert_eq!(
+            grub_paths("/usr/sbin/grub2-install"),
+            (
+                "/usr/sbin/grub2-mkconfig".to_string(),
+                "/boot/grub2/grub.cfg"
+            )
+        );
+    }
+
+    #[test]
+    fn test_has_menu_entries() {
+        assert!(has_menu_entries(
+            "set timeout=5\n  menuentry 'LevitateOS' --class os {\n}\n"
+        ));
+        assert!(!has_menu_entries(
+            "set timeout=5\n### BEGIN /etc/grub.d/10_linux ###\n"
+        ));
+    }
+
+    #[test]
+    fn test_has_grub_boot_code() {
+        let mut mbr = [0u8; 440];
+        assert!(!has_grub_boot_code(&mbr));
+        mbr[0x180..0x185].copy_from_slice(b"GRUB ");
+        assert!(has_grub_boot_code(&mbr));
+    }
+
+    #[test]
+    fn test_contains_grub_efi() {
+        let temp = std::env::temp_dir().join("recstrap_test_grub_efi");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("EFI/LevitateOS")).unwrap();
+        assert!(!contains_grub_efi(&temp.join("EFI")));
+        fs::write(temp.join("EFI/LevitateOS/grubx64.efi"), "").unwrap();
+        assert!(contains_grub_efi(&temp.join("EFI")));
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+}
@@ -0,0 +1,53 @@
+//! `recstrap checks --list` - print the registry of validation checks.
+
+use crate::checks::ALL;
+use crate::cli::ChecksArgs;
+use crate::error::Result;
+use crate::json::Value;
+
+pub fn run(args: &ChecksArgs) -> Result<()> {
+    if args.json {
+        let checks = ALL.iter().map(|c| c.to_json()).collect();
+        print!("{}", Value::Array(checks).to_pretty_string());
+    } else {
+        print!("{}", render());
+    }
+    Ok(())
+}
+
+/// One line per check: id, stage, severity, what it protects.
+fn render() -> String {
+    let width = ALL.iter().map(|c| c.id.len()).max().unwrap_or(0);
+    let mut out = format!(
+        "{:width$}  {:12}  {:8}  PROTECTS\n",
+        "ID", "STAGE", "SEVERITY"
+    );
+    for check in ALL {
+        out.push_str(&format!(
+            "{:width$}  {:12}  {:8}  {}\n",
+            check.id,
+            check.stage.name(),
+            check.severity,
+            check.protects
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let out = render();
+        let mut lines = out.lines();
+        assert!(lines.next().unwrap().starts_with("ID "));
+        assert_eq!(
+            lines.next().unwrap(),
+            "runs-as-root            preflight     CRITICAL  \
+             Installation runs with sufficient privileges"
+        );
+        assert_eq!(out.lines().count(), ALL.len() + 1);
+    }
+}
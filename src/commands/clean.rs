@@ -0,0 +1,180 @@
+//! `recstrap clean <target>` - remove what an interrupted or failed
+//! extraction left behind, so nobody has to `rm -rf` a mount point by hand.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::cli::CleanArgs;
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::{is_protected_path, is_root};
+use crate::output;
+use crate::preserve::STASH_DIR;
+use crate::rootfs::{cleanup_stale_mounts, resolve_workdir};
+use crate::state::ExtractionState;
+
+/// Prefix of every file recstrap itself creates in a target.
+const ARTIFACT_PREFIX: &str = ".recstrap_";
+
+pub fn run(args: &CleanArgs) -> Result<()> {
+    if !is_root() {
+        return Err(RecError::not_root());
+    }
+
+    let target = Path::new(&args.target);
+    if !target.exists() {
+        return Err(RecError::target_not_found(&args.target));
+    }
+    if !target.is_dir() {
+        return Err(RecError::not_a_directory(&args.target));
+    }
+    let target = target
+        .canonicalize()
+        .map_err(|e| RecError::new(ErrorCode::TargetNotFound, e.to_string()))?;
+    let target_str = target.to_string_lossy();
+    if is_protected_path(&target) {
+        return Err(RecError::protected_path(&target_str));
+    }
+
+    let workdir = resolve_workdir(args.workdir.as_deref())?;
+    if !args.dry_run {
+        cleanup_stale_mounts(&workdir, args.quiet);
+    }
+
+    let state = ExtractionState::read(&target).map_err(|e| {
+        RecError::new(
+            ErrorCode::ExtractionFailed,
+            format!("cannot read extraction state in {}: {}", target_str, e),
+        )
+    })?;
+
+    match &state {
+        None => {
+            if !args.quiet {
+                eprintln!(
+                    "No interrupted extraction recorded in {}; only removing recstrap artifacts",
+                    target_str
+                );
+            }
+        }
+        Some(state) if state.owner_alive() => {
+            return Err(RecError::new(
+                ErrorCode::ExtractionFailed,
+                format!(
+                    "extraction into {} is still running (pid {})",
+                    target_str, state.pid
+                ),
+            ));
+        }
+        Some(state) => {
+            if !args.quiet {
+                eprintln!(
+                    "Removing partial extraction of {} (started {})",
+                    state.rootfs.display(),
+                    state.started
+                );
+            }
+        }
+    }
+
+    let root_dev = fs::metadata(&target)
+        .map_err(|e| clean_error(&target, e))?
+        .dev();
+    let mut entries: Vec<_> = fs::read_dir(&target)
+        .and_then(|entries| entries.collect::<io::Result<_>>())
+        .map_err(|e| clean_error(&target, e))?;
+    entries.sort_by_key(|e| e.file_name());
+
+    let mut removed = 0;
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == STASH_DIR {
+            if !args.quiet {
+                eprintln!(
+                    "Keeping {}: it holds the paths --preserve set aside; move them back by hand",
+                    entry.path().display()
+                );
+            }
+            continue;
+        }
+        let is_artifact = name.starts_with(ARTIFACT_PREFIX);
+        let added_by_failed_run = state
+            .as_ref()
+            .is_some_and(|s| !s.preexisting.contains(&name));
+        if !is_artifact && !added_by_failed_run {
+            continue;
+        }
+
+        let path = entry.path();
+        if args.dry_run {
+            println!("would remove {}", path.display());
+        } else {
+            remove_tree(&path, root_dev).map_err(|e| clean_error(&path, e))?;
+        }
+        removed += 1;
+    }
+
+    if !args.quiet {
+        let verb = if args.dry_run {
+            "Would remove"
+        } else {
+            "Removed"
+        };
+        eprintln!("{} {} entries from {}", verb, removed, target_str);
+    }
+    Ok(())
+}
+
+/// Remove `path` recursively without leaving the filesystem `dev`: anything
+/// mounted below the target (e.g. /mnt/boot) is left alone, and so is the
+/// directory it is mounted on.
+fn remove_tree(path: &Path, dev: u64) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return fs::remove_file(path);
+    }
+    if meta.dev() != dev {
+        output::warning(
+            &format!("not descending into mount point {}", path.display()),
+            &[],
+        );
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        remove_tree(&entry?.path(), dev)?;
+    }
+    match fs::remove_dir(path) {
+        // A mount point further down kept this directory non-empty
+        Err(e) if e.raw_os_error() == Some(libc::ENOTEMPTY) => Ok(()),
+        other => other,
+    }
+}
+
+fn clean_error(path: &Path, e: io::Error) -> RecError {
+    RecError::new(
+        ErrorCode::ExtractionFailed,
+        format!("cannot remove {}: {}", path.display(), e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_tree() {
+        let temp = std::env::temp_dir().join("recstrap_test_clean_remove");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("usr/bin")).unwrap();
+        fs::write(temp.join("usr/bin/sh"), b"").unwrap();
+        std::os::unix::fs::symlink("/", temp.join("usr/root-link")).unwrap();
+
+        let dev = fs::metadata(&temp).unwrap().dev();
+        remove_tree(&temp.join("usr"), dev).unwrap();
+        assert!(!temp.join("usr").exists());
+        assert!(Path::new("/").exists());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+}
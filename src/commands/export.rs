@@ -0,0 +1,377 @@
+//! `recstrap export --format <wsl|docker|oci> <image> <output>` - turn the
+//! rootfs image into something other systems can import.
+//!
+//! The image is mounted with a writable overlay on top, format-specific
+//! fixups are written into the overlay (the image itself is never
+//! touched), and the result is archived with tar(1).
+//!
+//! - `wsl`: a tarball for `wsl --import`, with `/etc/wsl.conf` enabling
+//!   systemd as PID 1 so the distro boots like an installed one.
+//! - `docker`: a plain rootfs tarball for `docker import`/`podman import`.
+//! - `oci`: an OCI image layout directory (one gzipped layer) that podman
+//!   and skopeo read directly as `oci:<dir>`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::ValueEnum;
+
+use crate::chroot::{find_tool, SHELLS};
+use crate::cli::ExportArgs;
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::{is_dir_empty, is_root, resolve_in_root, InterruptGuard};
+use crate::json::Value;
+use crate::record::{now_utc, sha256_file};
+use crate::rootfs::{mount_erofs, resolve_image, resolve_workdir, MountMethod, Overlay};
+use crate::runner;
+
+/// What `export` produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// Tarball for `wsl --import` (.tar, or compressed by suffix: .tar.gz)
+    Wsl,
+    /// Rootfs tarball for `docker import` / `podman import`
+    Docker,
+    /// OCI image layout directory, for `podman pull oci:<dir>` or skopeo
+    Oci,
+}
+
+/// `/etc/wsl.conf` for images that don't ship one.
+const WSL_CONF: &str = "\
+# Written by recstrap export --format wsl
+[boot]
+systemd=true
+";
+
+pub fn run(args: &ExportArgs) -> Result<()> {
+    if !is_root() {
+        return Err(RecError::not_root());
+    }
+
+    let (image, _rootfs_type) = resolve_image(&args.image)?;
+    let workdir = resolve_workdir(args.workdir.as_deref())?;
+    // Absolute, so the messages below say exactly where the archive went
+    let output = std::env::current_dir()
+        .map(|cwd| cwd.join(&args.output))
+        .unwrap_or_else(|_| PathBuf::from(&args.output));
+
+    let _interrupt = InterruptGuard::install();
+    let mount = mount_erofs(&image, MountMethod::Kernel, &workdir, args.quiet)?;
+    let overlay = Overlay::mount(mount.path(), &workdir)?;
+    let root = overlay.merged();
+
+    if args.format == ExportFormat::Wsl {
+        fix_wsl_conf(&root)?;
+    }
+
+    if !args.quiet {
+        eprintln!("Writing {}...", output.display());
+    }
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    match args.format {
+        ExportFormat::Wsl | ExportFormat::Docker => {
+            write_tar(&root, &output, true)?;
+        }
+        ExportFormat::Oci => write_oci_layout(&root, &output)?,
+    }
+
+    if !args.quiet {
+        let size = fs::metadata(&output).map_or(0, |m| m.len());
+        match args.format {
+            ExportFormat::Wsl => {
+                eprintln!(
+                    "Exported {} ({} MB). Import it on Windows with:",
+                    output.display(),
+                    size / (1024 * 1024)
+                );
+                eprintln!("  wsl --import LevitateOS <install dir> {}", name);
+            }
+            ExportFormat::Docker => {
+                eprintln!(
+                    "Exported {} ({} MB). Import it with:",
+                    output.display(),
+                    size / (1024 * 1024)
+                );
+                eprintln!("  docker import {} levitateos:latest", name);
+                eprintln!("  podman import {} levitateos:latest", name);
+            }
+            ExportFormat::Oci => {
+                eprintln!("Exported OCI layout {}. Use it with:", output.display());
+                eprintln!("  podman pull oci:{}", output.display());
+                eprintln!(
+                    "  skopeo copy oci:{} docker-daemon:levitateos:latest",
+                    output.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Archive `root` into `file`. With `compress`, tar picks the compressor
+/// from the file name (.tar.gz, .tar.xz, ...).
+fn write_tar(root: &Path, file: &Path, compress: bool) -> Result<()> {
+    let mut tar = Command::new("tar");
+    tar.args([
+        "--create",
+        "--numeric-owner",
+        "--xattrs",
+        "--xattrs-include=*",
+    ]);
+    if compress {
+        tar.arg("--auto-compress");
+    }
+    let status =
+        runner::status(tar.arg("--file").arg(file).arg("-C").arg(root).arg(".")).map_err(|e| {
+            match e.kind() {
+                std::io::ErrorKind::NotFound => RecError::tool_not_installed("tar", "tar"),
+                _ => export_error(format!("failed to run tar: {}", e)),
+            }
+        })?;
+    if !status.success() {
+        let _ = fs::remove_file(file);
+        return Err(export_error(format!(
+            "tar exited with {}",
+            status.code().unwrap_or(-1)
+        )));
+    }
+    Ok(())
+}
+
+/// Write an OCI image layout with `root` as its single layer.
+fn write_oci_layout(root: &Path, dir: &Path) -> Result<()> {
+    let fail = |e: std::io::Error| export_error(format!("{}: {}", dir.display(), e));
+    fs::create_dir_all(dir).map_err(fail)?;
+    if !is_dir_empty(dir).map_err(fail)? {
+        return Err(export_error(format!(
+            "{} is not empty; the OCI layout needs a new directory",
+            dir.display()
+        )));
+    }
+    let blobs = dir.join("blobs/sha256");
+    fs::create_dir_all(&blobs).map_err(fail)?;
+
+    // The config names the layer by its uncompressed digest, the manifest
+    // by the compressed one
+    let layer = blobs.join("layer.tar");
+    write_tar(root, &layer, false)?;
+    let diff_id = digest(&layer)?;
+    let status =
+        runner::status(Command::new("gzip").args(["-n", "-f"]).arg(&layer)).map_err(|e| match e
+            .kind()
+        {
+            std::io::ErrorKind::NotFound => RecError::tool_not_installed("gzip", "gzip"),
+            _ => export_error(format!("failed to run gzip: {}", e)),
+        })?;
+    if !status.success() {
+        return Err(export_error(format!(
+            "gzip exited with {}",
+            status.code().unwrap_or(-1)
+        )));
+    }
+    let layer = add_blob(&blobs, &blobs.join("layer.tar.gz"))?;
+
+    let shell = find_tool(root, SHELLS).unwrap_or("/bin/sh");
+    let config = oci_config(&diff_id, shell, &now_utc());
+    let config = write_blob(&blobs, &config.to_string())?;
+    let manifest = Value::object([
+        ("schemaVersion", Value::from(2u32)),
+        ("mediaType", Value::from(OCI_MANIFEST)),
+        (
+            "config",
+            config.descriptor("application/vnd.oci.image.config.v1+json"),
+        ),
+        (
+            "layers",
+            Value::Array(vec![
+                layer.descriptor("application/vnd.oci.image.layer.v1.tar+gzip")
+            ]),
+        ),
+    ]);
+    let manifest = write_blob(&blobs, &manifest.to_string())?;
+    let mut index_entry = manifest.descriptor(OCI_MANIFEST);
+    if let Value::Object(fields) = &mut index_entry {
+        fields.push((
+            "annotations".to_string(),
+            Value::object([("org.opencontainers.image.ref.name", Value::from("latest"))]),
+        ));
+    }
+    let index = Value::object([
+        ("schemaVersion", Value::from(2u32)),
+        ("manifests", Value::Array(vec![index_entry])),
+    ]);
+    fs::write(dir.join("index.json"), index.to_string()).map_err(fail)?;
+    fs::write(dir.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#).map_err(fail)?;
+    Ok(())
+}
+
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// A content-addressed file in `blobs/sha256`.
+struct Blob {
+    digest: String,
+    size: u64,
+}
+
+impl Blob {
+    fn descriptor(&self, media_type: &str) -> Value {
+        Value::object([
+            ("mediaType", Value::from(media_type)),
+            ("digest", Value::from(self.digest.as_str())),
+            ("size", Value::from(self.size)),
+        ])
+    }
+}
+
+/// Image config: one layer, `shell` as the default command.
+fn oci_config(diff_id: &str, shell: &str, created: &str) -> Value {
+    Value::object([
+        ("created", Value::from(created)),
+        (
+            "architecture",
+            Value::from(oci_arch(std::env::consts::ARCH)),
+        ),
+        ("os", Value::from("linux")),
+        ("config", Value::object([("Cmd", Value::from(vec![shell]))])),
+        (
+            "rootfs",
+            Value::object([
+                ("type", Value::from("layers")),
+                ("diff_ids", Value::from(vec![diff_id])),
+            ]),
+        ),
+    ])
+}
+
+/// Go's GOARCH names, which OCI uses.
+fn oci_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "riscv64" => "riscv64",
+        other => other,
+    }
+}
+
+fn digest(path: &Path) -> Result<String> {
+    sha256_file(path)
+        .map(|hash| format!("sha256:{}", hash))
+        .ok_or_else(|| {
+            export_error(format!(
+                "cannot hash {} (is sha256sum installed?)",
+                path.display()
+            ))
+        })
+}
+
+/// Move `file` into `blobs` under its digest.
+fn add_blob(blobs: &Path, file: &Path) -> Result<Blob> {
+    let digest = digest(file)?;
+    let dest = blobs.join(digest.trim_start_matches("sha256:"));
+    let fail = |e: std::io::Error| export_error(format!("{}: {}", dest.display(), e));
+    fs::rename(file, &dest).map_err(fail)?;
+    let size = fs::metadata(&dest).map_err(fail)?.len();
+    Ok(Blob { digest, size })
+}
+
+fn write_blob(blobs: &Path, content: &str) -> Result<Blob> {
+    let file = blobs.join("blob.tmp");
+    fs::write(&file, content).map_err(|e| export_error(format!("{}: {}", file.display(), e)))?;
+    add_blob(blobs, &file)
+}
+
+/// Write or amend `/etc/wsl.conf` in the overlay.
+fn fix_wsl_conf(root: &Path) -> Result<()> {
+    let fail = |e: std::io::Error| export_error(format!("cannot write /etc/wsl.conf: {}", e));
+    let resolved = resolve_in_root(root, Path::new("etc/wsl.conf")).map_err(fail)?;
+    let path = root.join(resolved);
+    let existing = fs::read_to_string(&path).ok();
+    if let Some(content) = wsl_conf(existing.as_deref()) {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(fail)?;
+        }
+        fs::write(&path, content).map_err(fail)?;
+    }
+    Ok(())
+}
+
+/// `wsl.conf` content with systemd enabled under `[boot]`; `None` when
+/// `existing` already sets `systemd` there (whatever the value: the image
+/// builder decided).
+fn wsl_conf(existing: Option<&str>) -> Option<String> {
+    let Some(existing) = existing else {
+        return Some(WSL_CONF.to_string());
+    };
+    let mut section = "";
+    let mut boot_header = None;
+    for (i, line) in existing.lines().enumerate() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim();
+            if section == "boot" && boot_header.is_none() {
+                boot_header = Some(i);
+            }
+        } else if section == "boot"
+            && line
+                .split_once('=')
+                .is_some_and(|(key, _)| key.trim() == "systemd")
+        {
+            return None;
+        }
+    }
+
+    let mut lines: Vec<&str> = existing.lines().collect();
+    match boot_header {
+        Some(i) => lines.insert(i + 1, "systemd=true"),
+        None => {
+            if lines.last().is_some_and(|l| !l.trim().is_empty()) {
+                lines.push("");
+            }
+            lines.extend(["[boot]", "systemd=true"]);
+        }
+    }
+    Some(lines.join("\n") + "\n")
+}
+
+fn export_error(message: String) -> RecError {
+    RecError::new(ErrorCode::ExtractionFailed, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wsl_conf() {
+        assert_eq!(wsl_conf(None).as_deref(), Some(WSL_CONF));
+        assert_eq!(wsl_conf(Some("[boot]\nsystemd = false\n")), None);
+        assert_eq!(
+            wsl_conf(Some("[boot]\ncommand = true\n")).as_deref(),
+            Some("[boot]\nsystemd=true\ncommand = true\n")
+        );
+        assert_eq!(
+            wsl_conf(Some("[user]\ndefault=levitate\n[network]\nsystemd=x")).as_deref(),
+            Some("[user]\ndefault=levitate\n[network]\nsystemd=x\n\n[boot]\nsystemd=true\n")
+        );
+    }
+
+    #[test]
+    fn test_oci_config() {
+        let config = oci_config("sha256:abc", "/bin/bash", "2026-01-01T00:00:00Z").to_string();
+        assert!(config.contains("\"os\":\"linux\""), "{}", config);
+        assert!(
+            config.contains("\"config\":{\"Cmd\":[\"/bin/bash\"]}"),
+            "{}",
+            config
+        );
+        assert!(
+            config.ends_with("\"rootfs\":{\"type\":\"layers\",\"diff_ids\":[\"sha256:abc\"]}}"),
+            "{}",
+            config
+        );
+        assert_eq!(oci_arch("x86_64"), "amd64");
+        assert_eq!(oci_arch("aarch64"), "arm64");
+    }
+}
@@ -0,0 +1,83 @@
+//! `recstrap extract-path <image> <path> <dest>` - pull one file or subtree
+//! out of a rootfs image without touching a full target.
+
+use std::path::{Path, PathBuf};
+
+use crate::cli::ExtractPathArgs;
+use crate::copy::{copy_path, CopyOptions};
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::{is_root, resolve_in_root, InterruptGuard};
+use crate::rootfs::{mount_erofs, resolve_image, resolve_workdir, MountMethod};
+
+pub fn run(args: &ExtractPathArgs) -> Result<()> {
+    // Mounting the image needs root, same as a full extraction.
+    if !is_root() {
+        return Err(RecError::not_root());
+    }
+
+    let (image, _rootfs_type) = resolve_image(&args.image)?;
+    let workdir = resolve_workdir(args.workdir.as_deref())?;
+    let interrupt = InterruptGuard::install();
+    let mount = mount_erofs(&image, MountMethod::Kernel, &workdir, args.quiet)?;
+
+    let src = resolve_in_root(mount.path(), Path::new(&args.path))
+        .and_then(|p| p.symlink_metadata().map(|_| p))
+        .map_err(|e| {
+            RecError::new(
+                ErrorCode::ExtractionFailed,
+                format!("{}: not found in image ({})", args.path, e),
+            )
+        })?;
+
+    if src == mount.path() {
+        return Err(RecError::new(
+            ErrorCode::ExtractionFailed,
+            "refusing to extract the whole image; use `recstrap <TARGET>` instead",
+        ));
+    }
+
+    let dest = destination(Path::new(&args.dest), &src);
+    let options = CopyOptions {
+        cancel: Some(&|| interrupt.interrupted()),
+        ..Default::default()
+    };
+    let stats = copy_path(&src, &dest, &options)?;
+
+    if !args.quiet {
+        eprintln!(
+            "Extracted {} -> {} ({} entries, {} MB)",
+            args.path,
+            dest.display(),
+            stats.entries(),
+            stats.bytes / (1024 * 1024)
+        );
+    }
+    Ok(())
+}
+
+/// An existing directory receives the entry under its own name (like `cp`);
+/// anything else is the destination path itself.
+fn destination(dest: &Path, src: &Path) -> PathBuf {
+    match src.file_name() {
+        Some(name) if dest.is_dir() => dest.join(name),
+        _ => dest.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destination() {
+        let src = Path::new("/mnt/image/etc/os-release");
+        assert_eq!(
+            destination(Path::new("/tmp"), src),
+            PathBuf::from("/tmp/os-release")
+        );
+        assert_eq!(
+            destination(Path::new("/tmp/recstrap-os-release"), src),
+            PathBuf::from("/tmp/recstrap-os-release")
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! `recstrap find` - list rootfs images that `--rootfs` could use.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{FindArgs, OutputFormat};
+use crate::constants::ROOTFS_SEARCH_PATHS;
+use crate::error::{RecError, Result};
+use crate::json::Value;
+use crate::mountinfo;
+use crate::output;
+use crate::rootfs::{validate_rootfs_magic, RootfsType};
+
+/// How deep to look below a removable mount point. Live media keep the
+/// image at `live/filesystem.erofs` or similar; deeper trees are not worth
+/// walking on a slow USB stick.
+const MEDIA_SEARCH_DEPTH: usize = 3;
+
+struct Candidate {
+    path: PathBuf,
+    size: u64,
+    rootfs_type: RootfsType,
+}
+
+pub fn run(args: &FindArgs) -> Result<()> {
+    let mut paths: BTreeSet<PathBuf> = ROOTFS_SEARCH_PATHS
+        .iter()
+        .map(Path::new)
+        .filter(|p| p.is_file())
+        .map(Path::to_path_buf)
+        .collect();
+
+    // A missing mountinfo only means we can't see removable media.
+    for entry in mountinfo::read().unwrap_or_default() {
+        if mountinfo::is_removable(&entry) {
+            scan_dir(&entry.mount_point, MEDIA_SEARCH_DEPTH, &mut paths);
+        }
+    }
+
+    let mut found = Vec::new();
+    let mut seen = BTreeSet::new();
+    for path in paths {
+        let path = path.canonicalize().unwrap_or(path);
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let Some(rootfs_type) = RootfsType::from_path(&path) else {
+            continue;
+        };
+        match validate_rootfs_magic(&path, rootfs_type) {
+            Ok(()) => {
+                let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                found.push(Candidate {
+                    path,
+                    size,
+                    rootfs_type,
+                });
+            }
+            Err(e) => output::warning(&format!("skipping {}: {}", path.display(), e), &[]),
+        }
+    }
+
+    match args.output {
+        OutputFormat::Human => {
+            for c in &found {
+                println!(
+                    "{}\t{} MB\t{}",
+                    c.path.display(),
+                    c.size / (1024 * 1024),
+                    format_name(c.rootfs_type)
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let images = found
+                .iter()
+                .map(|c| {
+                    Value::object([
+                        ("path", Value::from(c.path.display().to_string())),
+                        ("size", Value::from(c.size)),
+                        ("format", Value::from(format_name(c.rootfs_type))),
+                    ])
+                })
+                .collect::<Vec<_>>();
+            print!("{}", Value::from(images).to_pretty_string());
+        }
+    }
+
+    if found.is_empty() {
+        return Err(RecError::rootfs_not_found(ROOTFS_SEARCH_PATHS));
+    }
+    Ok(())
+}
+
+fn format_name(rootfs_type: RootfsType) -> &'static str {
+    match rootfs_type {
+        RootfsType::Erofs => "erofs",
+    }
+}
+
+/// Collect files with a rootfs extension below `dir`, descending at most
+/// `depth` directory levels and never following symlinks.
+fn scan_dir(dir: &Path, depth: usize, out: &mut BTreeSet<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if file_type.is_file() && RootfsType::from_path(&path).is_some() {
+            out.insert(path);
+        } else if file_type.is_dir() && depth > 0 {
+            scan_dir(&path, depth - 1, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_dir_respects_depth() {
+        let root = std::env::temp_dir().join("recstrap_test_find_scan");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("live/deep/deeper")).unwrap();
+        fs::write(root.join("live/filesystem.erofs"), b"").unwrap();
+        fs::write(root.join("live/readme.txt"), b"").unwrap();
+        fs::write(root.join("live/deep/deeper/other.erofs"), b"").unwrap();
+
+        let mut found = BTreeSet::new();
+        scan_dir(&root, 1, &mut found);
+        assert_eq!(
+            found.into_iter().collect::<Vec<_>>(),
+            vec![root.join("live/filesystem.erofs")]
+        );
+
+        let mut found = BTreeSet::new();
+        scan_dir(&root, 3, &mut found);
+        assert_eq!(found.len(), 2);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
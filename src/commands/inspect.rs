@@ -0,0 +1,100 @@
+//! `recstrap inspect <image>` - print image metadata without extracting.
+
+use std::fs;
+use std::path::Path;
+
+use crate::cli::{InspectArgs, OutputFormat};
+use crate::erofs::{Feature, Superblock};
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::format_utc;
+use crate::json::Value;
+use crate::rootfs::resolve_image;
+
+pub fn run(args: &InspectArgs) -> Result<()> {
+    let (path, _rootfs_type) = resolve_image(&args.image)?;
+
+    let sb = Superblock::read_from(&path)
+        .map_err(|e| RecError::invalid_rootfs_format(&args.image, &e.to_string()))?;
+    let size = fs::metadata(&path)
+        .map_err(|e| RecError::new(ErrorCode::RootfsNotReadable, e.to_string()))?
+        .len();
+
+    match args.output {
+        OutputFormat::Human => print_human(&path, size, &sb),
+        OutputFormat::Json => print!("{}", to_json(&path, size, &sb).to_pretty_string()),
+    }
+    Ok(())
+}
+
+fn names(features: &[&Feature]) -> Vec<&'static str> {
+    features.iter().map(|f| f.name).collect()
+}
+
+fn list_or_none(items: &[&str]) -> String {
+    if items.is_empty() {
+        "none".to_string()
+    } else {
+        items.join(", ")
+    }
+}
+
+fn print_human(path: &Path, size: u64, sb: &Superblock) {
+    println!("Image:          {}", path.display());
+    println!("Format:         EROFS");
+    println!(
+        "Image size:     {} bytes ({} MB)",
+        size,
+        size / (1024 * 1024)
+    );
+    println!(
+        "Filesystem:     {} blocks x {} bytes ({} MB)",
+        sb.blocks,
+        sb.block_size(),
+        sb.filesystem_size() / (1024 * 1024)
+    );
+    println!("Inodes:         {}", sb.inos);
+    println!("UUID:           {}", sb.uuid_string());
+    if let Some(name) = sb.volume_name() {
+        println!("Volume name:    {}", name);
+    }
+    println!("Created:        {}", format_utc(sb.build_time));
+    println!(
+        "Compression:    {}",
+        list_or_none(&names(&sb.compression_algorithms()))
+    );
+    println!(
+        "Compat:         {}",
+        list_or_none(&names(&sb.compat_features()))
+    );
+    println!(
+        "Incompat:       {}",
+        list_or_none(&names(&sb.incompat_features()))
+    );
+}
+
+fn to_json(path: &Path, size: u64, sb: &Superblock) -> Value {
+    Value::object([
+        ("path", Value::from(path.display().to_string())),
+        ("format", Value::from("erofs")),
+        ("image_size", Value::from(size)),
+        ("block_size", Value::from(sb.block_size())),
+        ("blocks", Value::from(sb.blocks)),
+        ("filesystem_size", Value::from(sb.filesystem_size())),
+        ("inodes", Value::from(sb.inos)),
+        ("uuid", Value::from(sb.uuid_string())),
+        ("volume_name", Value::from(sb.volume_name())),
+        ("build_time", Value::from(sb.build_time)),
+        ("build_time_utc", Value::from(format_utc(sb.build_time))),
+        (
+            "compression",
+            Value::from(names(&sb.compression_algorithms())),
+        ),
+        (
+            "features",
+            Value::object([
+                ("compat", Value::from(names(&sb.compat_features()))),
+                ("incompat", Value::from(names(&sb.incompat_features()))),
+            ]),
+        ),
+    ])
+}
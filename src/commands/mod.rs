@@ -0,0 +1,36 @@
+//! Subcommands: everything other than the main extraction flow.
+
+mod bootloader;
+mod checks;
+mod clean;
+mod export;
+mod extract_path;
+mod find;
+mod inspect;
+mod prepare;
+mod preview;
+mod self_test;
+mod verify;
+
+pub use export::ExportFormat;
+pub use prepare::Scheme;
+
+use crate::cli::Command;
+use crate::error::Result;
+
+/// Dispatch a parsed subcommand.
+pub fn run(command: &Command) -> Result<()> {
+    match command {
+        Command::Inspect(args) => inspect::run(args),
+        Command::Find(args) => find::run(args),
+        Command::ExtractPath(args) => extract_path::run(args),
+        Command::Verify(args) => verify::run(args),
+        Command::Clean(args) => clean::run(args),
+        Command::Bootloader(args) => bootloader::run(args),
+        Command::Prepare(args) => prepare::run(args),
+        Command::Preview(args) => preview::run(args),
+        Command::Export(args) => export::run(args),
+        Command::SelfTest(args) => self_test::run(args),
+        Command::Checks(args) => checks::run(args),
+    }
+}
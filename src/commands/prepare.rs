@@ -0,0 +1,397 @@
+//! `recstrap prepare <disk> --scheme ...` - partition, format, and mount a
+//! disk, then hand off to the normal extraction.
+//!
+//! An opt-in convenience for the common layouts; anything fancier (LUKS,
+//! LVM, RAID, subvolumes) is still done by hand. The root is mounted and
+//! extracted first, and the ESP is mounted at `<mount point>/efi` only
+//! afterwards, since the extraction wants an empty target.
+
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::process::Command;
+
+use clap::ValueEnum;
+
+use crate::boot::ESP_MOUNT_POINT;
+use crate::cli::PrepareArgs;
+use crate::disk::{run_tool, run_with_input, spawn_error, wait_for_device, ESP_SIZE_MIB};
+use crate::error::{RecError, Result};
+use crate::helpers::{is_dir_empty, is_mount_point, is_root};
+use crate::mountinfo;
+use crate::runner;
+
+/// GPT type of an EFI system partition, as `sfdisk --dump` prints it.
+const ESP_TYPE: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+
+/// Partition layouts `prepare` can create.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Scheme {
+    /// Wipe the disk: new GPT with a 512 MiB ESP and a root partition
+    SingleEfi,
+    /// Keep everything: add a root partition in free space and reuse the
+    /// existing ESP (next to Windows or another Linux)
+    Dualboot,
+}
+
+pub fn run(args: &PrepareArgs) -> Result<()> {
+    if !is_root() {
+        return Err(RecError::not_root());
+    }
+
+    let disk = Path::new(&args.disk)
+        .canonicalize()
+        .map_err(|e| prepare_error(format!("{}: {}", args.disk, e)))?;
+    let disk_str = disk.to_string_lossy().into_owned();
+    let is_block = fs::metadata(&disk).is_ok_and(|m| m.file_type().is_block_device());
+    let name = disk.file_name().unwrap_or_default().to_string_lossy();
+    if !is_block
+        || Path::new("/sys/class/block")
+            .join(&*name)
+            .join("partition")
+            .exists()
+    {
+        return Err(prepare_error(format!("{} is not a whole disk", disk_str)));
+    }
+    if let Some(usage) = disk_in_use(&disk_str, Path::new(SYS_BLOCK)) {
+        return Err(prepare_error(format!(
+            "{} is in use ({}); release it first",
+            disk_str, usage
+        )));
+    }
+
+    let mount_point = Path::new(&args.mount_point);
+    fs::create_dir_all(mount_point)
+        .map_err(|e| prepare_error(format!("{}: {}", mount_point.display(), e)))?;
+    if is_mount_point(mount_point).unwrap_or(false) || !is_dir_empty(mount_point).unwrap_or(false) {
+        return Err(prepare_error(format!(
+            "{} must be an empty, unmounted directory",
+            mount_point.display()
+        )));
+    }
+
+    let before = dump_partitions(&disk_str)?;
+    let existing_esp = before
+        .iter()
+        .find(|(_, t)| t.eq_ignore_ascii_case(ESP_TYPE))
+        .map(|(dev, _)| dev.clone());
+    let warning = match args.scheme {
+        Scheme::SingleEfi => format!("ALL DATA on {} will be erased.", disk_str),
+        Scheme::Dualboot => {
+            if existing_esp.is_none() {
+                return Err(prepare_error(format!(
+                    "{} has no EFI system partition to share (use --scheme single-efi)",
+                    disk_str
+                )));
+            }
+            format!(
+                "A root partition will be added to the free space on {}.",
+                disk_str
+            )
+        }
+    };
+    if !args.yes {
+        confirm(&warning)?;
+    }
+
+    if !args.quiet {
+        eprintln!("Partitioning {} ({:?})...", disk_str, args.scheme);
+    }
+    let (esp, root) = match args.scheme {
+        Scheme::SingleEfi => {
+            let layout = format!(
+                "label: gpt\nsize={}MiB, type=U, name=esp\ntype=L, name=root\n",
+                ESP_SIZE_MIB
+            );
+            run_with_input(
+                "sfdisk",
+                &["--quiet", "--wipe", "always"],
+                Some(&disk),
+                &layout,
+                "util-linux",
+            )?;
+            let after = dump_partitions(&disk_str)?;
+            match after.as_slice() {
+                [(esp, _), (root, _)] => (wait_for_device(esp)?, wait_for_device(root)?),
+                _ => return Err(prepare_error("unexpected partition layout".to_string())),
+            }
+        }
+        Scheme::Dualboot => {
+            run_with_input(
+                "sfdisk",
+                &["--quiet", "--append"],
+                Some(&disk),
+                "type=L, name=root\n",
+                "util-linux",
+            )?;
+            let after = dump_partitions(&disk_str)?;
+            let root = after
+                .into_iter()
+                .map(|(dev, _)| dev)
+                .find(|dev| !before.iter().any(|(old, _)| old == dev))
+                .ok_or_else(|| prepare_error("new partition not found".to_string()))?;
+            (existing_esp.unwrap_or_default(), wait_for_device(&root)?)
+        }
+    };
+
+    if !args.quiet {
+        eprintln!("Formatting {} ({:?})...", root, args.fs);
+    }
+    if args.scheme == Scheme::SingleEfi {
+        run_tool("mkfs.vfat", &["-F", "32", "-n", "ESP"], &esp, "dosfstools")?;
+    }
+    let (mkfs, mkfs_args, package) = args.fs.mkfs();
+    run_tool(mkfs, mkfs_args, &root, package)?;
+    mount(&root, mount_point)?;
+
+    let esp_dir = mount_point.join(ESP_MOUNT_POINT);
+    if args.no_extract {
+        if !args.quiet {
+            eprintln!();
+            eprintln!("{} mounted on {}. Next:", root, mount_point.display());
+            eprintln!("  recstrap {}", mount_point.display());
+            eprintln!(
+                "  mkdir {} && mount {} {}",
+                esp_dir.display(),
+                esp,
+                esp_dir.display()
+            );
+        }
+        return Ok(());
+    }
+
+    // Hand off to the normal extraction flow, with all its checks
+    let exe = std::env::current_exe().map_err(|e| prepare_error(e.to_string()))?;
+    let mut extract = Command::new(exe);
+    if let Some(rootfs) = &args.rootfs {
+        extract.args(["--rootfs", rootfs]);
+    }
+    if args.quiet {
+        extract.arg("--quiet");
+    }
+    let status = runner::status(extract.arg(mount_point))
+        .map_err(|e| prepare_error(format!("cannot run recstrap: {}", e)))?;
+    if !status.success() {
+        return Err(prepare_error(format!(
+            "extraction into {} failed; {} is left mounted there",
+            mount_point.display(),
+            root
+        )));
+    }
+
+    fs::create_dir_all(&esp_dir).map_err(|e| prepare_error(e.to_string()))?;
+    mount(&esp, &esp_dir)?;
+    if !args.quiet {
+        eprintln!("ESP {} mounted on {}", esp, esp_dir.display());
+    }
+    Ok(())
+}
+
+fn prepare_error(message: String) -> RecError {
+    RecError::prepare_failed(&message)
+}
+
+/// Where the kernel lists block devices, with their partitions and holders.
+const SYS_BLOCK: &str = "/sys/class/block";
+
+/// How `disk` or one of its partitions is in use, if it is: mounted,
+/// active swap, or held by another block device (device-mapper, so LVM
+/// and LUKS, or md RAID).
+fn disk_in_use(disk: &str, sys_block: &Path) -> Option<String> {
+    let mounts = mountinfo::read().unwrap_or_default();
+    if let Some(mount) = mounts.iter().find(|e| is_partition_of(&e.source, disk)) {
+        return Some(format!(
+            "{} is mounted on {}",
+            mount.source,
+            mount.mount_point.display()
+        ));
+    }
+    let swaps = fs::read_to_string("/proc/swaps").unwrap_or_default();
+    if let Some(swap) = swap_devices(&swaps)
+        .into_iter()
+        .find(|device| is_partition_of(device, disk))
+    {
+        return Some(format!("{} is active swap", swap));
+    }
+    let name = Path::new(disk).file_name()?.to_string_lossy().into_owned();
+    holders(sys_block, &name)
+        .into_iter()
+        .next()
+        .map(|(device, holder)| format!("/dev/{} is held by {}", device, holder))
+}
+
+/// Devices listed in `/proc/swaps` (swap files included).
+fn swap_devices(swaps: &str) -> Vec<String> {
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|device| device.to_string())
+        .collect()
+}
+
+/// `(device, holder)` for every block device stacked on the disk `name`
+/// or one of its partitions, per `holders/` in sysfs.
+fn holders(sys_block: &Path, name: &str) -> Vec<(String, String)> {
+    let disk = sys_block.join(name);
+    let partitions = fs::read_dir(&disk)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().join("partition").exists())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned());
+    std::iter::once(name.to_string())
+        .chain(partitions)
+        .flat_map(|device| {
+            let held = fs::read_dir(sys_block.join(&device).join("holders"));
+            held.into_iter()
+                .flatten()
+                .flatten()
+                .map(move |holder| (device.clone(), holder_name(sys_block, &holder.file_name())))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// `dm-0` as its device-mapper name (`vg-root`, `luks-...`) when it has
+/// one, so the message says what to stop.
+fn holder_name(sys_block: &Path, holder: &std::ffi::OsStr) -> String {
+    let holder = holder.to_string_lossy();
+    match fs::read_to_string(sys_block.join(&*holder).join("dm/name")) {
+        Ok(name) if !name.trim().is_empty() => format!("{} ({})", holder, name.trim()),
+        _ => holder.into_owned(),
+    }
+}
+
+/// `/dev/sda2` and `/dev/nvme0n1p2` belong to `/dev/sda` and `/dev/nvme0n1`;
+/// `/dev/sdaa1` doesn't belong to `/dev/sda`.
+fn is_partition_of(device: &str, disk: &str) -> bool {
+    match device.strip_prefix(disk) {
+        Some("") => true,
+        Some(rest) => {
+            let rest = rest.strip_prefix('p').unwrap_or(rest);
+            !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+/// `(device, type)` of each partition on `disk`, per `sfdisk --dump`.
+/// An unpartitioned disk has none.
+fn dump_partitions(disk: &str) -> Result<Vec<(String, String)>> {
+    let output = runner::output(Command::new("sfdisk").args(["--dump", disk]))
+        .map_err(|e| spawn_error("sfdisk", "util-linux", e))?;
+    Ok(parse_dump(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_dump(dump: &str) -> Vec<(String, String)> {
+    dump.lines()
+        .filter_map(|line| {
+            let (device, fields) = line.split_once(" : ")?;
+            let kind = fields
+                .split(',')
+                .find_map(|f| f.trim().strip_prefix("type="))?;
+            Some((device.trim().to_string(), kind.trim().to_string()))
+        })
+        .collect()
+}
+
+fn mount(device: &str, mount_point: &Path) -> Result<()> {
+    let status = runner::status(Command::new("mount").arg(device).arg(mount_point))
+        .map_err(|e| prepare_error(format!("failed to run mount: {}", e)))?;
+    if !status.success() {
+        return Err(prepare_error(format!(
+            "mounting {} on {} failed",
+            device,
+            mount_point.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Ask for an explicit "YES" on a terminal; refuse otherwise.
+fn confirm(warning: &str) -> Result<()> {
+    if !io::stdin().is_terminal() {
+        return Err(prepare_error(format!(
+            "{} Pass --yes to confirm non-interactively",
+            warning
+        )));
+    }
+    eprintln!("{}", warning);
+    eprint!("Type YES to continue: ");
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    let _ = io::stdin().lock().read_line(&mut answer);
+    if answer.trim() != "YES" {
+        return Err(prepare_error("aborted".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_partition_of() {
+        assert!(is_partition_of("/dev/sda2", "/dev/sda"));
+        assert!(is_partition_of("/dev/sda", "/dev/sda"));
+        assert!(is_partition_of("/dev/nvme0n1p3", "/dev/nvme0n1"));
+        assert!(!is_partition_of("/dev/sdaa1", "/dev/sda"));
+        assert!(!is_partition_of("tmpfs", "/dev/sda"));
+    }
+
+    #[test]
+    fn test_swap_devices() {
+        let swaps = "\
+Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/sda3                               partition\t8388604\t\t0\t\t-2
+/swapfile                               file\t\t1048572\t\t0\t\t-3
+";
+        assert_eq!(swap_devices(swaps), ["/dev/sda3", "/swapfile"]);
+        assert!(swap_devices("Filename Type Size Used Priority\n").is_empty());
+    }
+
+    #[test]
+    fn test_holders() {
+        let sys = std::env::temp_dir().join("recstrap_test_holders");
+        let _ = fs::remove_dir_all(&sys);
+        for dir in ["sda/sda1", "sda/sda2/holders/dm-0", "dm-0/dm", "sdb"] {
+            fs::create_dir_all(sys.join(dir)).unwrap();
+        }
+        // sysfs lists partitions below the disk and at the top level
+        fs::write(sys.join("sda/sda1/partition"), "1\n").unwrap();
+        fs::write(sys.join("sda/sda2/partition"), "2\n").unwrap();
+        std::os::unix::fs::symlink(sys.join("sda/sda2"), sys.join("sda2")).unwrap();
+        fs::write(sys.join("dm-0/dm/name"), "vg-root\n").unwrap();
+
+        assert_eq!(
+            holders(&sys, "sda"),
+            [("sda2".to_string(), "dm-0 (vg-root)".to_string())]
+        );
+        assert!(holders(&sys, "sdb").is_empty());
+        assert!(holders(&sys, "sdc").is_empty());
+
+        let _ = fs::remove_dir_all(&sys);
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        let dump = "\
+label: gpt
+label-id: 6D1B3A2E-0000-4000-8000-000000000000
+device: /dev/sda
+unit: sectors
+
+/dev/sda1 : start=        2048, size=     1048576, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, uuid=11111111-2222-3333-4444-555555555555, name=\"esp\"
+/dev/sda2 : start=     1050624, size=    40957919, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, uuid=66666666-7777-8888-9999-000000000000
+";
+        let parts = parse_dump(dump);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].0, "/dev/sda1");
+        assert_eq!(parts[0].1, ESP_TYPE);
+        assert_eq!(parts[1].0, "/dev/sda2");
+        assert!(parse_dump("").is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+//! `recstrap preview <image>` - look around inside an image before
+//! installing it.
+//!
+//! The image is mounted read-only and a shell runs chrooted into it, with
+//! the API filesystems in place. With `--overlay`, a tmpfs overlay on top
+//! makes it writable so packages or configs can be tried; everything
+//! written there is gone when the shell exits.
+
+use std::process::Command;
+
+use crate::chroot::{find_tool, ChrootMounts, SHELLS};
+use crate::cli::PreviewArgs;
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::{is_root, InterruptGuard};
+use crate::rootfs::{mount_erofs, resolve_image, resolve_workdir, MountMethod, Overlay};
+use crate::runner;
+
+pub fn run(args: &PreviewArgs) -> Result<()> {
+    if !is_root() {
+        return Err(RecError::not_root());
+    }
+
+    let (image, _rootfs_type) = resolve_image(&args.image)?;
+    let workdir = resolve_workdir(args.workdir.as_deref())?;
+    // Ctrl-C in the shell must not kill us with everything still mounted
+    let _interrupt = InterruptGuard::install();
+    let mount = mount_erofs(&image, MountMethod::Kernel, &workdir, args.quiet)?;
+
+    let overlay = if args.overlay {
+        Some(Overlay::mount(mount.path(), &workdir)?)
+    } else {
+        None
+    };
+    let root = overlay
+        .as_ref()
+        .map_or_else(|| mount.path().to_path_buf(), |o| o.merged());
+
+    let shell = match &args.shell {
+        Some(shell) => shell.as_str(),
+        None => find_tool(&root, SHELLS).ok_or_else(|| {
+            RecError::new(
+                ErrorCode::ExtractionFailed,
+                "image has neither /bin/bash nor /bin/sh; pass --shell",
+            )
+        })?,
+    };
+
+    let _api = ChrootMounts::setup(&root).map_err(|e| {
+        RecError::new(
+            ErrorCode::ExtractionFailed,
+            format!("cannot mount API filesystems in the image: {}", e),
+        )
+    })?;
+
+    if !args.quiet {
+        eprintln!(
+            "Previewing {} ({}). Exit the shell to unmount.",
+            image.display(),
+            if args.overlay {
+                "writable, changes are discarded"
+            } else {
+                "read-only"
+            }
+        );
+    }
+    runner::status(
+        Command::new("chroot")
+            .arg(&root)
+            .arg(shell)
+            .env(
+                "PATH",
+                "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+            )
+            .env("PS1", r"(recstrap preview) \w # "),
+    )
+    .map_err(|e| {
+        RecError::new(
+            ErrorCode::ExtractionFailed,
+            format!("cannot run {} in the image: {}", shell, e),
+        )
+    })?;
+    // Guards unmount in reverse: API filesystems, overlay, image
+    Ok(())
+}
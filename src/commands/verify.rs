@@ -0,0 +1,139 @@
+//! `recstrap verify <target>` - audit an installed system against the image
+//! it was extracted from, reporting modified, missing, and extra files.
+
+use std::path::Path;
+
+use crate::cli::{OutputFormat, VerifyArgs};
+use crate::constants::ROOTFS_SEARCH_PATHS;
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::{find_rootfs, is_root, InterruptGuard};
+use crate::json::Value;
+use crate::record::InstallRecord;
+use crate::rootfs::{mount_erofs, resolve_image, resolve_workdir, MountMethod};
+use crate::verify::{compare_trees, find_extra, DiffKind, Difference, VerifyOptions};
+
+pub fn run(args: &VerifyArgs) -> Result<()> {
+    // Mounting the image needs root, same as a full extraction.
+    if !is_root() {
+        return Err(RecError::not_root());
+    }
+
+    let target = Path::new(&args.target);
+    if !target.is_dir() {
+        return Err(RecError::not_a_directory(&args.target));
+    }
+    let target = target
+        .canonicalize()
+        .map_err(|e| RecError::new(ErrorCode::TargetNotFound, e.to_string()))?;
+
+    // Without --rootfs, prefer the image named in the install record
+    let recorded = InstallRecord::read(&target)
+        .ok()
+        .flatten()
+        .map(|r| r.image.to_string_lossy().into_owned())
+        .filter(|image| Path::new(image).is_file());
+    let image_arg = match (&args.rootfs, &recorded) {
+        (Some(path), _) | (None, Some(path)) => path.as_str(),
+        (None, None) => {
+            find_rootfs().ok_or_else(|| RecError::rootfs_not_found(ROOTFS_SEARCH_PATHS))?
+        }
+    };
+    let (image, _rootfs_type) = resolve_image(image_arg)?;
+    let workdir = resolve_workdir(args.workdir.as_deref())?;
+
+    let interrupt = InterruptGuard::install();
+    let mount = mount_erofs(&image, MountMethod::Kernel, &workdir, args.quiet)?;
+    if !args.quiet {
+        eprintln!(
+            "Comparing {} against {}...",
+            target.display(),
+            image.display()
+        );
+    }
+    let options = VerifyOptions {
+        cancel: Some(&|| interrupt.interrupted()),
+        check_ownership: true,
+    };
+    let report = compare_trees(mount.path(), &target, &options)?;
+    let extra = find_extra(mount.path(), &target)?;
+    drop(mount);
+
+    let (missing, modified): (Vec<&Difference>, Vec<&Difference>) = report
+        .differences
+        .iter()
+        .partition(|d| d.kind == DiffKind::Missing);
+
+    match args.output {
+        OutputFormat::Human => {
+            print_section("Modified", &modified, true);
+            print_section("Missing", &missing, false);
+            print_section("Extra", &extra.iter().collect::<Vec<_>>(), false);
+            println!(
+                "{} entries compared: {} modified, {} missing, {} extra",
+                report.entries,
+                modified.len(),
+                missing.len(),
+                extra.len()
+            );
+        }
+        OutputFormat::Json => {
+            let paths = |diffs: &[&Difference]| {
+                Value::from(
+                    diffs
+                        .iter()
+                        .map(|d| format!("/{}", d.path.display()))
+                        .collect::<Vec<_>>(),
+                )
+            };
+            let value = Value::object([
+                ("target", Value::from(target.display().to_string())),
+                ("image", Value::from(image.display().to_string())),
+                ("entries", Value::from(report.entries)),
+                (
+                    "modified",
+                    Value::Array(
+                        modified
+                            .iter()
+                            .map(|d| {
+                                Value::object([
+                                    ("path", Value::from(format!("/{}", d.path.display()))),
+                                    ("reason", Value::from(d.kind.to_string())),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+                ("missing", paths(&missing)),
+                ("extra", paths(&extra.iter().collect::<Vec<_>>())),
+            ]);
+            print!("{}", value.to_pretty_string());
+        }
+    }
+
+    let all: Vec<String> = report
+        .differences
+        .iter()
+        .chain(&extra)
+        .map(|d| d.to_string())
+        .collect();
+    if all.is_empty() {
+        Ok(())
+    } else {
+        Err(RecError::target_differs(&all))
+    }
+}
+
+fn print_section(title: &str, diffs: &[&Difference], with_reason: bool) {
+    if diffs.is_empty() {
+        return;
+    }
+    println!("{} ({}):", title, diffs.len());
+    for d in diffs {
+        if with_reason {
+            println!("  {}", d);
+        } else {
+            println!("  /{}", d.path.display());
+        }
+    }
+    println!();
+}
@@ -0,0 +1,1163 @@
+//! Post-extraction configuration of the target, done without a chroot.
+//!
+//! Only small, declarative changes belong here: the kind of thing a user
+//! would otherwise do with a single command in the chroot.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::accounts::{add_to_group, user_exists};
+use crate::chroot::{find_tool, run_in_chroot, ChrootMounts};
+use crate::copy::{copy_tree, CopyOptions};
+use crate::error::{RecError, Result};
+
+/// Where the image ships unit files.
+pub const SYSTEM_UNIT_DIR: &str = "usr/lib/systemd/system";
+
+/// Where enablement symlinks live.
+pub const ADMIN_UNIT_DIR: &str = "etc/systemd/system";
+
+/// A serial console to enable a login prompt on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialConsole {
+    pub device: String,
+    pub baud: u32,
+}
+
+impl SerialConsole {
+    /// Parse `ttyS0` or `ttyS0,115200`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let (device, baud) = match s.split_once(',') {
+            Some((device, baud)) => (
+                device,
+                baud.parse()
+                    .map_err(|_| format!("invalid baud rate '{}'", baud))?,
+            ),
+            None => (s, 115_200),
+        };
+        let valid = device.starts_with("tty")
+            && device.len() > 3
+            && device.chars().all(|c| c.is_ascii_alphanumeric());
+        if !valid {
+            return Err(format!(
+                "invalid serial device '{}' (expected e.g. ttyS0)",
+                device
+            ));
+        }
+        Ok(Self {
+            device: device.to_string(),
+            baud,
+        })
+    }
+
+    /// Kernel command line arguments that route the console to this port.
+    /// The baud rate set here is kept by serial-getty's `--keep-baud`.
+    pub fn kernel_args(&self) -> String {
+        format!("console=tty0 console={},{}", self.device, self.baud)
+    }
+}
+
+/// Enable `serial-getty@<device>.service` in the target.
+pub fn enable_serial_console(target: &Path, console: &SerialConsole) -> Result<()> {
+    let instance = format!("serial-getty@{}.service", console.device);
+    enable_unit_inner(target, &instance, &mut Vec::new())
+        .map_err(|e| RecError::configuration_failed("serial console", &e.to_string()))
+}
+
+/// The `[Install]` section of a unit file.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InstallSection {
+    pub wanted_by: Vec<String>,
+    pub required_by: Vec<String>,
+    pub alias: Vec<String>,
+    pub also: Vec<String>,
+    pub default_instance: Option<String>,
+}
+
+impl InstallSection {
+    pub fn parse(unit_file: &str) -> Self {
+        let mut install = Self::default();
+        let mut in_install = false;
+        for line in unit_file.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_install = line == "[Install]";
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=').filter(|_| in_install) else {
+                continue;
+            };
+            let values = value.split_whitespace().map(str::to_string);
+            match key.trim() {
+                "WantedBy" => install.wanted_by.extend(values),
+                "RequiredBy" => install.required_by.extend(values),
+                "Alias" => install.alias.extend(values),
+                "Also" => install.also.extend(values),
+                "DefaultInstance" => install.default_instance = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+        install
+    }
+
+    fn is_empty(&self) -> bool {
+        self.wanted_by.is_empty()
+            && self.required_by.is_empty()
+            && self.alias.is_empty()
+            && self.also.is_empty()
+    }
+}
+
+/// Normalize a unit name from the command line: `sshd` means
+/// `sshd.service`, like systemctl. Rejects anything path-like.
+pub fn unit_name(name: &str) -> std::result::Result<String, String> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(format!("invalid unit name '{}'", name));
+    }
+    if name.contains('.') {
+        Ok(name.to_string())
+    } else {
+        Ok(format!("{}.service", name))
+    }
+}
+
+/// File name of the template a unit instance comes from
+/// (`getty@tty2.service` -> `getty@.service`).
+fn template_of(name: &str) -> Option<String> {
+    let (prefix, rest) = name.split_once('@')?;
+    let (_, suffix) = rest.rsplit_once('.')?;
+    Some(format!("{}@.{}", prefix, suffix))
+}
+
+/// Find the unit file for `name` in the target: admin units first, then
+/// vendor units, then the template for an instance. Returns the path of
+/// the file relative to the target root.
+fn find_unit(target: &Path, name: &str) -> Option<PathBuf> {
+    let names = std::iter::once(name.to_string()).chain(template_of(name));
+    for file in names {
+        for dir in [ADMIN_UNIT_DIR, SYSTEM_UNIT_DIR] {
+            let rel = Path::new(dir).join(&file);
+            if fs::symlink_metadata(target.join(&rel)).is_ok_and(|m| m.is_file()) {
+                return Some(rel);
+            }
+        }
+    }
+    None
+}
+
+/// Enable `name` in the target the way `systemctl enable` does: create the
+/// `.wants`/`.requires` and alias symlinks its `[Install]` section asks
+/// for, and enable the units listed in `Also=`.
+pub fn enable_unit(target: &Path, name: &str) -> Result<()> {
+    enable_unit_inner(target, name, &mut Vec::new())
+        .map_err(|e| RecError::configuration_failed(&format!("unit {}", name), &e.to_string()))
+}
+
+fn enable_unit_inner(target: &Path, name: &str, seen: &mut Vec<String>) -> io::Result<()> {
+    if seen.iter().any(|s| s == name) {
+        return Ok(());
+    }
+    seen.push(name.to_string());
+
+    let rel = find_unit(target, name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no such unit in the installed system",
+        )
+    })?;
+    let install = InstallSection::parse(&fs::read_to_string(target.join(&rel))?);
+    if install.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unit has no [Install] section (static units can't be enabled)",
+        ));
+    }
+
+    // A bare template is enabled through its default instance
+    let instance = match (name.contains("@."), &install.default_instance) {
+        (false, _) => name.to_string(),
+        (true, Some(default)) => name.replacen("@.", &format!("@{}.", default), 1),
+        (true, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "template unit needs an instance name (e.g. getty@tty2.service)",
+            ))
+        }
+    };
+    let unit = Path::new("/").join(&rel);
+    let admin = target.join(ADMIN_UNIT_DIR);
+
+    for wanted_by in &install.wanted_by {
+        replace_symlink(
+            &unit,
+            &admin.join(format!("{}.wants", wanted_by)).join(&instance),
+        )?;
+    }
+    for required_by in &install.required_by {
+        replace_symlink(
+            &unit,
+            &admin
+                .join(format!("{}.requires", required_by))
+                .join(&instance),
+        )?;
+    }
+    for alias in &install.alias {
+        replace_symlink(&unit, &admin.join(alias))?;
+    }
+    for also in &install.also {
+        enable_unit_inner(target, also, seen)?;
+    }
+    Ok(())
+}
+
+/// Disable `name` in the target: remove its links from every `.wants` and
+/// `.requires` directory under `/etc/systemd/system`, its aliases, and the
+/// units listed in `Also=`. Units enabled by the image under
+/// `/usr/lib/systemd/system` can only be masked, not disabled.
+pub fn disable_unit(target: &Path, name: &str) -> Result<()> {
+    disable_unit_inner(target, name, &mut Vec::new())
+        .map_err(|e| RecError::configuration_failed(&format!("unit {}", name), &e.to_string()))
+}
+
+fn disable_unit_inner(target: &Path, name: &str, seen: &mut Vec<String>) -> io::Result<()> {
+    if seen.iter().any(|s| s == name) {
+        return Ok(());
+    }
+    seen.push(name.to_string());
+
+    let admin = target.join(ADMIN_UNIT_DIR);
+    let install = find_unit(target, name)
+        .and_then(|rel| fs::read_to_string(target.join(rel)).ok())
+        .map(|content| InstallSection::parse(&content))
+        .unwrap_or_default();
+
+    if let Ok(entries) = fs::read_dir(&admin) {
+        for entry in entries {
+            let dir = entry?.path();
+            let is_dep_dir = dir
+                .extension()
+                .is_some_and(|e| e == "wants" || e == "requires");
+            if is_dep_dir {
+                remove_symlink(&dir.join(name))?;
+            }
+        }
+    }
+    for alias in &install.alias {
+        remove_symlink(&admin.join(alias))?;
+    }
+    for also in &install.also {
+        disable_unit_inner(target, also, seen)?;
+    }
+    Ok(())
+}
+
+/// Point `link` at `dest`, creating parent directories and replacing
+/// whatever link was there.
+fn replace_symlink(dest: &Path, link: &Path) -> io::Result<()> {
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    remove_symlink(link)?;
+    symlink(dest, link)
+}
+
+/// Remove `link` if it is a symlink; anything else is left alone.
+fn remove_symlink(link: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(link) {
+        Ok(m) if m.file_type().is_symlink() => fs::remove_file(link),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a symlink", link.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// systemd-networkd configuration written by `--network`.
+pub const NETWORK_FILE: &str = "etc/systemd/network/20-recstrap.network";
+
+/// Network setup requested with `--network`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkConfig {
+    Dhcp,
+    Static {
+        address: IpAddr,
+        prefix: u8,
+        gateway: Option<IpAddr>,
+        dns: Vec<IpAddr>,
+    },
+}
+
+impl NetworkConfig {
+    /// Parse `dhcp` or `static:<ip/cidr>[,gw=<ip>][,dns=<ip>]...`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        if s == "dhcp" {
+            return Ok(NetworkConfig::Dhcp);
+        }
+        let spec = s
+            .strip_prefix("static:")
+            .ok_or_else(|| format!("expected 'dhcp' or 'static:<ip/cidr>,...', got '{}'", s))?;
+        let mut parts = spec.split(',');
+        let cidr = parts.next().unwrap_or_default();
+        let (ip, prefix) = cidr
+            .split_once('/')
+            .ok_or_else(|| format!("address '{}' needs a prefix length (e.g. /24)", cidr))?;
+        let address: IpAddr = parse_ip(ip)?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .parse()
+            .ok()
+            .filter(|p| *p <= max_prefix)
+            .ok_or_else(|| format!("invalid prefix length '{}'", prefix))?;
+
+        let mut gateway = None;
+        let mut dns = Vec::new();
+        for part in parts {
+            match part.split_once('=') {
+                Some(("gw", ip)) => gateway = Some(parse_ip(ip)?),
+                Some(("dns", ip)) => dns.push(parse_ip(ip)?),
+                _ => return Err(format!("unknown network option '{}'", part)),
+            }
+        }
+        Ok(NetworkConfig::Static {
+            address,
+            prefix,
+            gateway,
+            dns,
+        })
+    }
+
+    /// Contents of the `.network` file. Matches every wired interface, so
+    /// it works without knowing the installed machine's interface names.
+    pub fn to_network_file(&self) -> String {
+        let mut out =
+            String::from("# Written by recstrap --network\n[Match]\nType=ether\n\n[Network]\n");
+        match self {
+            NetworkConfig::Dhcp => out.push_str("DHCP=yes\n"),
+            NetworkConfig::Static {
+                address,
+                prefix,
+                gateway,
+                dns,
+            } => {
+                out.push_str(&format!("Address={}/{}\n", address, prefix));
+                if let Some(gateway) = gateway {
+                    out.push_str(&format!("Gateway={}\n", gateway));
+                }
+                for server in dns {
+                    out.push_str(&format!("DNS={}\n", server));
+                }
+            }
+        }
+        out
+    }
+}
+
+fn parse_ip(s: &str) -> std::result::Result<IpAddr, String> {
+    s.parse().map_err(|_| format!("invalid IP address '{}'", s))
+}
+
+/// Write the `.network` file and enable systemd-networkd in the target.
+pub fn write_network_config(target: &Path, config: &NetworkConfig) -> Result<()> {
+    let fail = |e: io::Error| RecError::configuration_failed("network", &e.to_string());
+    let path = target.join(NETWORK_FILE);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(fail)?;
+    }
+    fs::write(&path, config.to_network_file()).map_err(fail)?;
+    enable_unit_inner(target, "systemd-networkd.service", &mut Vec::new()).map_err(fail)
+}
+
+/// Console keymap and font file.
+pub const VCONSOLE_FILE: &str = "etc/vconsole.conf";
+
+/// Validate a keymap or console font name (`de-latin1`, `ter-v16n`).
+pub fn console_setting(s: &str) -> std::result::Result<String, String> {
+    let valid = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("invalid keymap/font name '{}'", s))
+    }
+}
+
+/// Set `KEY=value` lines in an env-style file, replacing existing
+/// assignments of those keys and keeping everything else.
+pub fn set_env_values(content: &str, values: &[(&str, &str)]) -> String {
+    let mut out: Vec<String> = content
+        .lines()
+        .filter(|line| {
+            let key = line.split_once('=').map(|(k, _)| k.trim());
+            !values.iter().any(|(k, _)| Some(*k) == key)
+        })
+        .map(str::to_string)
+        .collect();
+    out.extend(values.iter().map(|(k, v)| format!("{}={}", k, v)));
+    out.join("\n") + "\n"
+}
+
+/// Write `KEYMAP`/`FONT` into the target's vconsole.conf. `None` leaves a
+/// setting as it is.
+pub fn write_vconsole(target: &Path, keymap: Option<&str>, font: Option<&str>) -> Result<()> {
+    let values: Vec<(&str, &str)> = [("KEYMAP", keymap), ("FONT", font)]
+        .into_iter()
+        .filter_map(|(k, v)| Some((k, v?)))
+        .collect();
+    if values.is_empty() {
+        return Ok(());
+    }
+    let path = target.join(VCONSOLE_FILE);
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    fs::write(&path, set_env_values(&existing, &values))
+        .map_err(|e| RecError::configuration_failed("console", &e.to_string()))
+}
+
+/// Locale settings, relative to the target root.
+pub const LOCALE_FILE: &str = "etc/locale.conf";
+
+/// Time zone link, relative to the target root.
+pub const LOCALTIME: &str = "etc/localtime";
+
+/// `--inherit-live-config`: copy the locale, console settings and time
+/// zone of the live system at `live` into the target, so what the user
+/// picked in the live session carries over. Files the live system lacks
+/// are skipped. Returns the files written, relative to the root.
+pub fn inherit_live_config(target: &Path, live: &Path) -> Result<Vec<&'static str>> {
+    let fail = |e: io::Error| RecError::configuration_failed("live configuration", &e.to_string());
+    let mut copied = Vec::new();
+    for file in [LOCALE_FILE, VCONSOLE_FILE] {
+        if let Ok(content) = fs::read_to_string(live.join(file)) {
+            fs::write(target.join(file), content).map_err(fail)?;
+            copied.push(file);
+        }
+    }
+    if inherit_localtime(target, live).map_err(fail)? {
+        copied.push(LOCALTIME);
+    }
+    Ok(copied)
+}
+
+/// Replicate the live `/etc/localtime`: the same zoneinfo link if the
+/// target has that zone, else a copy of the zone data.
+fn inherit_localtime(target: &Path, live: &Path) -> io::Result<bool> {
+    let source = live.join(LOCALTIME);
+    let Ok(zone) = fs::read(&source) else {
+        return Ok(false);
+    };
+    let dest = target.join(LOCALTIME);
+    if let Ok(link) = fs::read_link(&source) {
+        let in_target = match link.strip_prefix("/") {
+            Ok(absolute) => target.join(absolute),
+            Err(_) => target.join("etc").join(&link),
+        };
+        if in_target.is_file() {
+            replace_symlink(&link, &dest)?;
+            return Ok(true);
+        }
+    }
+    remove_symlink(&dest)?;
+    fs::write(&dest, zone)?;
+    Ok(true)
+}
+
+/// Resolver configuration, relative to the target root.
+pub const RESOLV_CONF: &str = "etc/resolv.conf";
+
+/// systemd-resolved's stub file, as a link target from `etc/`.
+const RESOLVED_STUB: &str = "../run/systemd/resolve/stub-resolv.conf";
+
+/// The upstream servers systemd-resolved learned, on the live system.
+const RESOLVED_UPSTREAM: &str = "/run/systemd/resolve/resolv.conf";
+
+/// What to do with the target's `/etc/resolv.conf`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ResolvConf {
+    /// Copy the live system's resolvers into a static file
+    Copy,
+    /// Symlink to systemd-resolved's stub and enable systemd-resolved
+    Stub,
+    /// Keep whatever the image ships
+    None,
+}
+
+/// Whether resolv.conf content only points at a local stub resolver
+/// (systemd-resolved's 127.0.0.53 or similar), useless once copied.
+fn is_stub_resolver(content: &str) -> bool {
+    let mut servers = content.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        (fields.next() == Some("nameserver")).then(|| fields.next())?
+    });
+    let mut any = false;
+    let all_local = servers.all(|server| {
+        any = true;
+        server.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    });
+    any && all_local
+}
+
+/// Apply the `--resolv-conf` policy to the target.
+pub fn write_resolv_conf(target: &Path, policy: ResolvConf) -> Result<()> {
+    let fail = |e: io::Error| RecError::configuration_failed("resolv.conf", &e.to_string());
+    let path = target.join(RESOLV_CONF);
+    match policy {
+        ResolvConf::None => Ok(()),
+        ResolvConf::Copy => {
+            let mut content = fs::read_to_string("/etc/resolv.conf").map_err(fail)?;
+            // A stub copied into a system without the stub running resolves
+            // nothing; take the servers resolved itself uses
+            if is_stub_resolver(&content) {
+                if let Ok(upstream) = fs::read_to_string(RESOLVED_UPSTREAM) {
+                    content = upstream;
+                }
+            }
+            match fs::symlink_metadata(&path) {
+                Ok(m) if m.is_dir() => {
+                    return Err(RecError::configuration_failed(
+                        "resolv.conf",
+                        "/etc/resolv.conf is a directory",
+                    ))
+                }
+                Ok(_) => fs::remove_file(&path).map_err(fail)?,
+                Err(_) => {}
+            }
+            fs::write(&path, content).map_err(fail)
+        }
+        ResolvConf::Stub => {
+            if fs::symlink_metadata(&path).is_ok_and(|m| m.is_file()) {
+                fs::remove_file(&path).map_err(fail)?;
+            }
+            replace_symlink(Path::new(RESOLVED_STUB), &path).map_err(fail)?;
+            enable_unit_inner(target, "systemd-resolved.service", &mut Vec::new()).map_err(fail)
+        }
+    }
+}
+
+/// Flag file that makes systemd relabel the whole filesystem on next boot.
+pub const AUTORELABEL_FILE: &str = ".autorelabel";
+
+/// SELinux configuration and policy store.
+const SELINUX_DIR: &str = "etc/selinux";
+
+/// Request a full SELinux relabel on first boot. Files copied out of the
+/// image carry no (or the live system's) contexts; an enforcing system
+/// refuses to start services until they are labeled. With `copy_policy`,
+/// the live system's `/etc/selinux` (config and policy) is copied first.
+/// Returns a warning when the target has no SELinux config at all.
+pub fn selinux_relabel(target: &Path, copy_policy: bool) -> Result<Option<String>> {
+    let fail = |e: String| RecError::configuration_failed("SELinux", &e);
+    if copy_policy {
+        let live = Path::new("/").join(SELINUX_DIR);
+        if !live.is_dir() {
+            return Err(fail(format!("{} does not exist", live.display())));
+        }
+        let dest = target.join(SELINUX_DIR);
+        fs::create_dir_all(&dest).map_err(|e| fail(e.to_string()))?;
+        let options = CopyOptions {
+            overlay: true,
+            ..Default::default()
+        };
+        copy_tree(&live, &dest, &options).map_err(|e| fail(e.to_string()))?;
+    }
+    fs::write(target.join(AUTORELABEL_FILE), "").map_err(|e| fail(e.to_string()))?;
+
+    let configured = target.join(SELINUX_DIR).join("config").is_file();
+    Ok((!configured).then(|| {
+        format!(
+            "/{}/config is missing in the target; the relabel only runs once SELinux is enabled",
+            SELINUX_DIR
+        )
+    }))
+}
+
+/// Drop-in that turns a getty into an autologin one, under
+/// `<ADMIN_UNIT_DIR>/<instance>.d/`.
+const AUTOLOGIN_DROP_IN: &str = "autologin.conf";
+
+/// Display managers `--autologin` configures when the image ships their
+/// unit: unit, config file (relative to the target), its content.
+const DISPLAY_MANAGERS: &[(&str, &str)] = &[
+    ("gdm.service", "etc/gdm/custom.conf"),
+    ("sddm.service", "etc/sddm.conf.d/50-recstrap-autologin.conf"),
+    (
+        "lightdm.service",
+        "etc/lightdm/lightdm.conf.d/50-recstrap-autologin.conf",
+    ),
+];
+
+/// Session directories SDDM picks its autologin `Session=` from.
+const SESSION_DIRS: &[&str] = &["usr/share/wayland-sessions", "usr/share/xsessions"];
+
+/// Log `user` in automatically on tty1 (and the serial console, if any)
+/// and in the image's display manager, for kiosks and appliances. The
+/// user must exist in the target. Returns what was configured, and a
+/// warning when there is no desktop session for SDDM to start.
+pub fn write_autologin(
+    target: &Path,
+    user: &str,
+    serial: Option<&SerialConsole>,
+) -> Result<(Vec<String>, Option<String>)> {
+    let fail = |e: String| RecError::configuration_failed("autologin", &e);
+    if !user_exists(target, user).map_err(|e| fail(format!("/etc/passwd: {}", e)))? {
+        return Err(fail(format!(
+            "no user '{}' in the target's /etc/passwd",
+            user
+        )));
+    }
+
+    let mut configured = Vec::new();
+    let mut gettys = vec![(
+        "getty@tty1.service".to_string(),
+        format!(
+            "-/sbin/agetty -o '-p -f -- \\\\u' --noclear --autologin {} %I $TERM",
+            user
+        ),
+    )];
+    if let Some(console) = serial {
+        gettys.push((
+            format!("serial-getty@{}.service", console.device),
+            format!(
+                "-/sbin/agetty -o '-p -f -- \\\\u' --keep-baud --autologin {} \
+                 115200,57600,38400,9600 - $TERM",
+                user
+            ),
+        ));
+    }
+    for (instance, exec) in gettys {
+        let dir = target.join(ADMIN_UNIT_DIR).join(format!("{}.d", instance));
+        fs::create_dir_all(&dir).map_err(|e| fail(e.to_string()))?;
+        // The empty ExecStart= clears the unit's own before ours
+        fs::write(
+            dir.join(AUTOLOGIN_DROP_IN),
+            format!("[Service]\nExecStart=\nExecStart={}\n", exec),
+        )
+        .map_err(|e| fail(e.to_string()))?;
+        configured.push(instance);
+    }
+
+    let mut warning = None;
+    for (unit, config) in DISPLAY_MANAGERS {
+        if find_unit(target, unit).is_none() {
+            continue;
+        }
+        let path = target.join(config);
+        let content = match *unit {
+            "gdm.service" => set_ini_values(
+                &fs::read_to_string(&path).unwrap_or_default(),
+                "daemon",
+                &[("AutomaticLoginEnable", "True"), ("AutomaticLogin", user)],
+            ),
+            "sddm.service" => {
+                let session = default_session(target);
+                if session.is_none() {
+                    warning = Some(format!(
+                        "no desktop session in /{} or /{}; SDDM won't log {} in",
+                        SESSION_DIRS[0], SESSION_DIRS[1], user
+                    ));
+                }
+                let mut values = vec![("User", user)];
+                values.extend(session.as_deref().map(|s| ("Session", s)));
+                set_ini_values("", "Autologin", &values)
+            }
+            _ => {
+                // Arch and Fedora only let members of 'autologin' in
+                add_to_group(target, "autologin", user).map_err(|e| fail(e.to_string()))?;
+                set_ini_values("", "Seat:*", &[("autologin-user", user)])
+            }
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| fail(e.to_string()))?;
+        }
+        fs::write(&path, content).map_err(|e| fail(e.to_string()))?;
+        configured.push(unit.to_string());
+    }
+    Ok((configured, warning))
+}
+
+/// The first desktop session (`plasma.desktop`, ...) the image ships.
+fn default_session(target: &Path) -> Option<String> {
+    SESSION_DIRS.iter().find_map(|dir| {
+        let mut sessions: Vec<String> = fs::read_dir(target.join(dir))
+            .ok()?
+            .flatten()
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|name| name.ends_with(".desktop"))
+            .collect();
+        sessions.sort();
+        sessions.into_iter().next()
+    })
+}
+
+/// INI `content` with `values` set in `[section]`, which is added at the
+/// end if missing. Other sections and keys are kept.
+pub fn set_ini_values(content: &str, section: &str, values: &[(&str, &str)]) -> String {
+    let header = format!("[{}]", section);
+    let mut out: Vec<String> = Vec::new();
+    let mut in_section = false;
+    let mut found = false;
+    let flush = |out: &mut Vec<String>| {
+        // Keep a blank line before the next section
+        let at = out
+            .iter()
+            .rposition(|l| !l.trim().is_empty())
+            .map_or(0, |i| i + 1);
+        for (i, (k, v)) in values.iter().enumerate() {
+            out.insert(at + i, format!("{}={}", k, v));
+        }
+    };
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            if in_section {
+                flush(&mut out);
+            }
+            in_section = trimmed == header;
+            found |= in_section;
+        } else if in_section {
+            let key = trimmed.split_once('=').map(|(k, _)| k.trim());
+            if key.is_some_and(|k| values.iter().any(|(v, _)| *v == k)) {
+                continue;
+            }
+        }
+        out.push(line.to_string());
+    }
+    if in_section {
+        flush(&mut out);
+    }
+    if !found {
+        if out.last().is_some_and(|l| !l.trim().is_empty()) {
+            out.push(String::new());
+        }
+        out.push(header);
+        out.extend(values.iter().map(|(k, v)| format!("{}={}", k, v)));
+    }
+    out.join("\n") + "\n"
+}
+
+/// Initramfs generators, in order of preference, with the arguments that
+/// rebuild the images for every installed kernel.
+const INITRAMFS_TOOLS: &[(&str, &[&str])] = &[
+    ("/usr/bin/dracut", &["--regenerate-all", "--force"]),
+    ("/usr/sbin/dracut", &["--regenerate-all", "--force"]),
+    ("/usr/bin/mkinitcpio", &["-P"]),
+    ("/usr/sbin/update-initramfs", &["-u", "-k", "all"]),
+];
+
+/// Rebuild the target's initramfs images with its own generator, run in a
+/// chroot so it picks up this machine's storage, crypto, and driver needs.
+pub fn regenerate_initramfs(target: &Path) -> Result<()> {
+    let fail = |e: io::Error| RecError::configuration_failed("initramfs", &e.to_string());
+    let tools: Vec<&str> = INITRAMFS_TOOLS.iter().map(|(tool, _)| *tool).collect();
+    let tool = find_tool(target, &tools).ok_or_else(|| {
+        RecError::configuration_failed(
+            "initramfs",
+            "no dracut, mkinitcpio, or update-initramfs in the installed system",
+        )
+    })?;
+    let args = INITRAMFS_TOOLS
+        .iter()
+        .find(|(t, _)| *t == tool)
+        .map_or(&[][..], |(_, args)| *args);
+
+    let _mounts = ChrootMounts::setup(target).map_err(fail)?;
+    run_in_chroot(target, tool, args).map_err(fail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_stub_resolver() {
+        assert!(is_stub_resolver(
+            "# resolved\nnameserver 127.0.0.53\noptions edns0\n"
+        ));
+        assert!(!is_stub_resolver(
+            "nameserver 127.0.0.53\nnameserver 1.1.1.1\n"
+        ));
+        assert!(!is_stub_resolver("nameserver 192.168.1.1\n"));
+        assert!(!is_stub_resolver("search example.com\n"));
+    }
+
+    #[test]
+    fn test_write_resolv_conf_stub() {
+        let temp = std::env::temp_dir().join("recstrap_test_resolv_stub");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join(SYSTEM_UNIT_DIR)).unwrap();
+        fs::write(
+            temp.join(SYSTEM_UNIT_DIR).join("systemd-resolved.service"),
+            "[Install]\nWantedBy=multi-user.target\n",
+        )
+        .unwrap();
+        fs::create_dir_all(temp.join("etc")).unwrap();
+        fs::write(temp.join(RESOLV_CONF), "nameserver 10.0.0.1\n").unwrap();
+
+        write_resolv_conf(&temp, ResolvConf::Stub).unwrap();
+        assert_eq!(
+            fs::read_link(temp.join(RESOLV_CONF)).unwrap(),
+            Path::new(RESOLVED_STUB)
+        );
+        assert!(temp
+            .join(ADMIN_UNIT_DIR)
+            .join("multi-user.target.wants/systemd-resolved.service")
+            .symlink_metadata()
+            .is_ok());
+
+        // none leaves it alone
+        write_resolv_conf(&temp, ResolvConf::None).unwrap();
+        assert!(fs::read_link(temp.join(RESOLV_CONF)).is_ok());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_selinux_relabel() {
+        let temp = std::env::temp_dir().join("recstrap_test_selinux_relabel");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(&temp).unwrap();
+
+        let warning = selinux_relabel(&temp, false).unwrap();
+        assert!(temp.join(AUTORELABEL_FILE).is_file());
+        assert!(warning.unwrap().contains("config is missing"));
+
+        fs::create_dir_all(temp.join(SELINUX_DIR)).unwrap();
+        fs::write(temp.join(SELINUX_DIR).join("config"), "SELINUX=enforcing\n").unwrap();
+        assert_eq!(selinux_relabel(&temp, false).unwrap(), None);
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_parse_serial_console() {
+        assert_eq!(
+            SerialConsole::parse("ttyS1,9600").unwrap(),
+            SerialConsole {
+                device: "ttyS1".to_string(),
+                baud: 9600
+            }
+        );
+        assert_eq!(SerialConsole::parse("ttyAMA0").unwrap().baud, 115_200);
+        assert!(SerialConsole::parse("../../etc").is_err());
+        assert!(SerialConsole::parse("tty").is_err());
+        assert!(SerialConsole::parse("ttyS0,fast").is_err());
+    }
+
+    #[test]
+    fn test_kernel_args() {
+        let console = SerialConsole::parse("ttyS0").unwrap();
+        assert_eq!(console.kernel_args(), "console=tty0 console=ttyS0,115200");
+    }
+
+    #[test]
+    fn test_enable_serial_console() {
+        let temp = std::env::temp_dir().join("recstrap_test_serial_console");
+        let _ = fs::remove_dir_all(&temp);
+        let console = SerialConsole::parse("ttyS0").unwrap();
+
+        // Template missing from the image
+        fs::create_dir_all(&temp).unwrap();
+        assert!(enable_serial_console(&temp, &console).is_err());
+
+        fs::create_dir_all(temp.join(SYSTEM_UNIT_DIR)).unwrap();
+        fs::write(
+            temp.join(SYSTEM_UNIT_DIR).join("serial-getty@.service"),
+            "[Install]\nWantedBy=getty.target\n",
+        )
+        .unwrap();
+        enable_serial_console(&temp, &console).unwrap();
+        // Idempotent
+        enable_serial_console(&temp, &console).unwrap();
+
+        let link = temp.join("etc/systemd/system/getty.target.wants/serial-getty@ttyS0.service");
+        assert_eq!(
+            fs::read_link(link).unwrap(),
+            Path::new("/usr/lib/systemd/system/serial-getty@.service")
+        );
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_set_ini_values() {
+        let gdm = "# GDM configuration\n[daemon]\nWaylandEnable=false\nAutomaticLogin=old\n\n[security]\n";
+        assert_eq!(
+            set_ini_values(
+                gdm,
+                "daemon",
+                &[
+                    ("AutomaticLoginEnable", "True"),
+                    ("AutomaticLogin", "kiosk")
+                ]
+            ),
+            "# GDM configuration\n[daemon]\nWaylandEnable=false\nAutomaticLoginEnable=True\n\
+             AutomaticLogin=kiosk\n\n[security]\n"
+        );
+        assert_eq!(
+            set_ini_values("[security]\n", "daemon", &[("AutomaticLogin", "kiosk")]),
+            "[security]\n\n[daemon]\nAutomaticLogin=kiosk\n"
+        );
+        assert_eq!(
+            set_ini_values("", "Seat:*", &[("autologin-user", "kiosk")]),
+            "[Seat:*]\nautologin-user=kiosk\n"
+        );
+    }
+
+    #[test]
+    fn test_write_autologin() {
+        let temp = std::env::temp_dir().join("recstrap_test_autologin");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("etc")).unwrap();
+        fs::create_dir_all(temp.join(SYSTEM_UNIT_DIR)).unwrap();
+        fs::create_dir_all(temp.join("usr/share/xsessions")).unwrap();
+        fs::write(
+            temp.join("etc/passwd"),
+            "root:x:0:0::/root:/bin/sh\nkiosk:x:1000:1000::/home/kiosk:/bin/sh\n",
+        )
+        .unwrap();
+        fs::write(temp.join("etc/group"), "autologin:x:967:\n").unwrap();
+        fs::write(temp.join(SYSTEM_UNIT_DIR).join("sddm.service"), "").unwrap();
+        fs::write(temp.join(SYSTEM_UNIT_DIR).join("lightdm.service"), "").unwrap();
+
+        assert!(write_autologin(&temp, "nobody", None).is_err());
+
+        let serial = SerialConsole::parse("ttyS0").unwrap();
+        let (configured, warning) = write_autologin(&temp, "kiosk", Some(&serial)).unwrap();
+        assert_eq!(
+            configured,
+            [
+                "getty@tty1.service",
+                "serial-getty@ttyS0.service",
+                "sddm.service",
+                "lightdm.service"
+            ]
+        );
+        assert!(warning.unwrap().contains("SDDM won't log kiosk in"));
+        let getty =
+            fs::read_to_string(temp.join("etc/systemd/system/getty@tty1.service.d/autologin.conf"))
+                .unwrap();
+        assert!(getty.starts_with("[Service]\nExecStart=\nExecStart=-/sbin/agetty"));
+        assert!(getty.contains("--autologin kiosk %I $TERM"));
+        assert_eq!(
+            fs::read_to_string(temp.join("etc/lightdm/lightdm.conf.d/50-recstrap-autologin.conf"))
+                .unwrap(),
+            "[Seat:*]\nautologin-user=kiosk\n"
+        );
+        assert_eq!(
+            fs::read_to_string(temp.join("etc/group")).unwrap(),
+            "autologin:x:967:kiosk\n"
+        );
+
+        fs::write(temp.join("usr/share/xsessions/plasmax11.desktop"), "").unwrap();
+        let (_, warning) = write_autologin(&temp, "kiosk", None).unwrap();
+        assert_eq!(warning, None);
+        assert_eq!(
+            fs::read_to_string(temp.join("etc/sddm.conf.d/50-recstrap-autologin.conf")).unwrap(),
+            "[Autologin]\nUser=kiosk\nSession=plasmax11.desktop\n"
+        );
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_install_section_parse() {
+        let unit = "\
+[Unit]
+WantedBy=ignored.target
+
+[Install]
+WantedBy=multi-user.target graphical.target
+Alias=sshd.service
+Also=sshd.socket
+DefaultInstance=tty1
+";
+        let install = InstallSection::parse(unit);
+        assert_eq!(
+            install.wanted_by,
+            vec!["multi-user.target", "graphical.target"]
+        );
+        assert_eq!(install.alias, vec!["sshd.service"]);
+        assert_eq!(install.also, vec!["sshd.socket"]);
+        assert_eq!(install.default_instance.as_deref(), Some("tty1"));
+        assert!(InstallSection::parse("[Unit]\nDescription=x\n").is_empty());
+    }
+
+    #[test]
+    fn test_unit_name() {
+        assert_eq!(unit_name("sshd").unwrap(), "sshd.service");
+        assert_eq!(unit_name("fstrim.timer").unwrap(), "fstrim.timer");
+        assert!(unit_name("../evil.service").is_err());
+        assert!(unit_name("").is_err());
+    }
+
+    #[test]
+    fn test_enable_and_disable_unit() {
+        let temp = std::env::temp_dir().join("recstrap_test_enable_unit");
+        let _ = fs::remove_dir_all(&temp);
+        let vendor = temp.join(SYSTEM_UNIT_DIR);
+        fs::create_dir_all(&vendor).unwrap();
+        fs::write(
+            vendor.join("ssh.service"),
+            "[Install]\nWantedBy=multi-user.target\nAlias=sshd.service\nAlso=ssh.socket\n",
+        )
+        .unwrap();
+        fs::write(
+            vendor.join("ssh.socket"),
+            "[Install]\nWantedBy=sockets.target\n",
+        )
+        .unwrap();
+        fs::write(
+            vendor.join("getty@.service"),
+            "[Install]\nWantedBy=getty.target\n",
+        )
+        .unwrap();
+        fs::write(vendor.join("static.service"), "[Service]\nType=oneshot\n").unwrap();
+
+        let admin = temp.join(ADMIN_UNIT_DIR);
+        enable_unit(&temp, "ssh.service").unwrap();
+        assert_eq!(
+            fs::read_link(admin.join("multi-user.target.wants/ssh.service")).unwrap(),
+            Path::new("/usr/lib/systemd/system/ssh.service")
+        );
+        assert!(admin.join("sshd.service").is_symlink());
+        assert!(admin.join("sockets.target.wants/ssh.socket").is_symlink());
+
+        enable_unit(&temp, "getty@tty2.service").unwrap();
+        assert_eq!(
+            fs::read_link(admin.join("getty.target.wants/getty@tty2.service")).unwrap(),
+            Path::new("/usr/lib/systemd/system/getty@.service")
+        );
+
+        assert!(enable_unit(&temp, "static.service").is_err());
+        assert!(enable_unit(&temp, "missing.service").is_err());
+
+        disable_unit(&temp, "ssh.service").unwrap();
+        assert!(!admin.join("multi-user.target.wants/ssh.service").exists());
+        assert!(!admin.join("sshd.service").is_symlink());
+        assert!(!admin.join("sockets.target.wants/ssh.socket").is_symlink());
+        // Disabling something that isn't enabled is fine
+        disable_unit(&temp, "ssh.service").unwrap();
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_parse_network() {
+        assert_eq!(NetworkConfig::parse("dhcp").unwrap(), NetworkConfig::Dhcp);
+        let config =
+            NetworkConfig::parse("static:192.168.1.10/24,gw=192.168.1.1,dns=1.1.1.1,dns=9.9.9.9")
+                .unwrap();
+        assert_eq!(
+            config.to_network_file(),
+            "# Written by recstrap --network\n[Match]\nType=ether\n\n[Network]\n\
+             Address=192.168.1.10/24\nGateway=192.168.1.1\nDNS=1.1.1.1\nDNS=9.9.9.9\n"
+        );
+        assert!(NetworkConfig::parse("static:fd00::5/64").is_ok());
+        assert!(NetworkConfig::parse("static:192.168.1.10").is_err());
+        assert!(NetworkConfig::parse("static:192.168.1.10/33").is_err());
+        assert!(NetworkConfig::parse("static:10.0.0.2/8,gateway=10.0.0.1").is_err());
+        assert!(NetworkConfig::parse("wifi").is_err());
+    }
+
+    #[test]
+    fn test_write_network_config() {
+        let temp = std::env::temp_dir().join("recstrap_test_network");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join(SYSTEM_UNIT_DIR)).unwrap();
+        fs::write(
+            temp.join(SYSTEM_UNIT_DIR).join("systemd-networkd.service"),
+            "[Install]\nWantedBy=multi-user.target\n",
+        )
+        .unwrap();
+
+        write_network_config(&temp, &NetworkConfig::Dhcp).unwrap();
+        assert!(fs::read_to_string(temp.join(NETWORK_FILE))
+            .unwrap()
+            .contains("DHCP=yes"));
+        assert!(temp
+            .join(ADMIN_UNIT_DIR)
+            .join("multi-user.target.wants/systemd-networkd.service")
+            .is_symlink());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_set_env_values() {
+        let content = "KEYMAP=us\n# comment\nXKBLAYOUT=us\n";
+        assert_eq!(
+            set_env_values(content, &[("KEYMAP", "de"), ("FONT", "ter-v16n")]),
+            "# comment\nXKBLAYOUT=us\nKEYMAP=de\nFONT=ter-v16n\n"
+        );
+        assert_eq!(set_env_values("", &[("KEYMAP", "fr")]), "KEYMAP=fr\n");
+    }
+
+    #[test]
+    fn test_console_setting() {
+        assert!(console_setting("de-latin1").is_ok());
+        assert!(console_setting("ter-v16n").is_ok());
+        assert!(console_setting("us\nFONT=x").is_err());
+        assert!(console_setting("").is_err());
+    }
+
+    #[test]
+    fn test_inherit_live_config() {
+        let temp = std::env::temp_dir().join("recstrap_test_inherit_live");
+        let _ = fs::remove_dir_all(&temp);
+        let (live, target) = (temp.join("live"), temp.join("target"));
+        for root in [&live, &target] {
+            fs::create_dir_all(root.join("etc")).unwrap();
+            fs::create_dir_all(root.join("usr/share/zoneinfo/Europe")).unwrap();
+        }
+        fs::write(live.join(LOCALE_FILE), "LANG=de_DE.UTF-8\n").unwrap();
+        fs::write(live.join("usr/share/zoneinfo/Europe/Berlin"), "TZif-live").unwrap();
+        fs::write(target.join("usr/share/zoneinfo/Europe/Berlin"), "TZif").unwrap();
+        symlink("../usr/share/zoneinfo/Europe/Berlin", live.join(LOCALTIME)).unwrap();
+        symlink("../usr/share/zoneinfo/UTC", target.join(LOCALTIME)).unwrap();
+
+        // No vconsole.conf in the live system: left alone
+        let copied = inherit_live_config(&target, &live).unwrap();
+        assert_eq!(copied, [LOCALE_FILE, LOCALTIME]);
+        assert_eq!(
+            fs::read_to_string(target.join(LOCALE_FILE)).unwrap(),
+            "LANG=de_DE.UTF-8\n"
+        );
+        assert_eq!(
+            fs::read_link(target.join(LOCALTIME)).unwrap(),
+            Path::new("../usr/share/zoneinfo/Europe/Berlin")
+        );
+
+        // A zone the target doesn't ship is copied
+        fs::remove_file(target.join("usr/share/zoneinfo/Europe/Berlin")).unwrap();
+        inherit_live_config(&target, &live).unwrap();
+        assert!(!fs::symlink_metadata(target.join(LOCALTIME))
+            .unwrap()
+            .is_symlink());
+        assert_eq!(fs::read(target.join(LOCALTIME)).unwrap(), b"TZif-live");
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_write_vconsole() {
+        let temp = std::env::temp_dir().join("recstrap_test_vconsole");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("etc")).unwrap();
+
+        write_vconsole(&temp, None, None).unwrap();
+        assert!(!temp.join(VCONSOLE_FILE).exists());
+        write_vconsole(&temp, Some("de"), None).unwrap();
+        write_vconsole(&temp, None, Some("ter-v16n")).unwrap();
+        assert_eq!(
+            fs::read_to_string(temp.join(VCONSOLE_FILE)).unwrap(),
+            "KEYMAP=de\nFONT=ter-v16n\n"
+        );
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+}
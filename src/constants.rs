@@ -10,6 +10,29 @@ pub use distro_spec::shared::{
 
 // Note: EROFS_MAGIC_OFFSET is also available from distro_spec::shared if needed.
 
+/// Maximum number of hardlink groups checked after extraction.
+/// Groups are sampled evenly across the image so large images stay fast.
+pub const HARDLINK_SAMPLE_GROUPS: usize = 64;
+
+/// Default room required on top of the image's unpacked size, in percent
+/// (`--space-margin`): directories, metadata, and the page the last block
+/// of every file rounds up to.
+pub const DEFAULT_SPACE_MARGIN: u64 = 10;
+
+/// Default number of regular files compared by `--verify sample`.
+pub const VERIFY_SAMPLE_FILES: usize = 512;
+
+/// API mount points and temp dirs every target needs, with their modes.
+/// Created after extraction if the image lacks them.
+pub const API_DIRS: &[(&str, u32)] = &[
+    ("proc", 0o555),
+    ("sys", 0o555),
+    ("dev", 0o755),
+    ("run", 0o755),
+    ("tmp", 0o1777),
+    ("var/tmp", 0o1777),
+];
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -36,6 +59,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_min_required_bytes_is_reasonable() {
         // Should be at least 1GB, at most 10GB
         assert!(MIN_REQUIRED_BYTES >= 1024 * 1024 * 1024);
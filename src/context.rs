@@ -0,0 +1,171 @@
+//! Settings and state of one install.
+//!
+//! What an install is told besides what to install - how often to retry,
+//! how long helpers may run, how to copy, where helpers may write - and
+//! the token that cancels it. A [`Context`] is entered on the thread that
+//! runs the install and handed to the threads it starts (the copy
+//! workers), so installs embedded side by side in one process don't see
+//! each other's settings, and cancelling one leaves the others running.
+//! Code deep in the copier or the mount helpers reads it from there
+//! instead of taking it through every layer.
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::cli::Args;
+use crate::helpers::{DEFAULT_COMMAND_TIMEOUT_SECS, DEFAULT_IO_RETRIES};
+
+/// Stops a running install from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// How many times transient I/O failures are retried (`--retries`)
+    pub retries: u32,
+    /// Seconds a helper command (mount, modprobe, umount) may run before
+    /// it is killed (`--command-timeout`); 0 waits forever
+    pub command_timeout: u64,
+    /// Threads filling regular files (`--copy-jobs`); 0 picks by CPU count
+    pub copy_jobs: usize,
+    /// Whether file data may be cloned or copied kernel-side; cleared by
+    /// `--no-copy-offload`
+    pub copy_offload: bool,
+    /// `--verity-root-hash`
+    pub verity_root_hash: Option<String>,
+    /// Directories confined helpers may write beneath, while a
+    /// [`crate::sandbox::WriteRoots`] lives
+    pub write_roots: Vec<PathBuf>,
+    /// Whether SIGINT/SIGTERM/SIGHUP cancel the install. Only the command
+    /// line takes them over; an embedding program keeps its handlers.
+    pub signals: bool,
+    pub cancel: CancellationToken,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_IO_RETRIES,
+            command_timeout: DEFAULT_COMMAND_TIMEOUT_SECS,
+            copy_jobs: 0,
+            copy_offload: true,
+            verity_root_hash: None,
+            write_roots: Vec::new(),
+            signals: false,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+impl Context {
+    /// The context of a command-line run.
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            retries: args.retries,
+            command_timeout: args.command_timeout,
+            copy_jobs: args.copy_jobs,
+            copy_offload: !args.no_copy_offload,
+            verity_root_hash: args.verity_root_hash.clone(),
+            signals: true,
+            ..Self::default()
+        }
+    }
+
+    /// Make this the calling thread's context until the guard is dropped.
+    pub fn enter(self) -> Entered {
+        let previous = CURRENT.with(|c| c.replace(self));
+        Entered {
+            previous: Some(previous),
+            _thread: PhantomData,
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+/// Restores the thread's previous context on drop.
+pub struct Entered {
+    previous: Option<Context>,
+    /// Belongs to the thread it was entered on
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            CURRENT.with(|c| c.replace(previous));
+        }
+    }
+}
+
+/// Read the calling thread's context (the defaults outside an install).
+pub fn with<T>(f: impl FnOnce(&Context) -> T) -> T {
+    CURRENT.with(|c| f(&c.borrow()))
+}
+
+/// Change the calling thread's context.
+pub fn update(f: impl FnOnce(&mut Context)) {
+    CURRENT.with(|c| f(&mut c.borrow_mut()));
+}
+
+/// A copy of the calling thread's context, to enter on a thread it starts.
+pub fn current() -> Context {
+    with(Context::clone)
+}
+
+/// Whether the install on this thread has been cancelled.
+pub fn cancelled() -> bool {
+    with(|c| c.cancel.is_cancelled())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enter_restores_previous() {
+        assert_eq!(with(|c| c.retries), DEFAULT_IO_RETRIES);
+        {
+            let _outer = Context {
+                retries: 7,
+                ..Context::default()
+            }
+            .enter();
+            {
+                let inner = Context {
+                    retries: 1,
+                    ..Context::default()
+                };
+                inner.cancel.cancel();
+                let _inner = inner.enter();
+                assert_eq!(with(|c| c.retries), 1);
+                assert!(cancelled());
+            }
+            assert_eq!(with(|c| c.retries), 7);
+            assert!(!cancelled());
+            // Other threads keep their own
+            let other = std::thread::spawn(|| with(|c| c.retries)).join().unwrap();
+            assert_eq!(other, DEFAULT_IO_RETRIES);
+        }
+        assert_eq!(with(|c| c.retries), DEFAULT_IO_RETRIES);
+    }
+}
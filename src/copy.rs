@@ -0,0 +1,1327 @@
+//! Native recursive copier used for rootfs extraction.
+//!
+//! Replaces the old `cp -aT` invocation so that error handling, progress,
+//! filtering, and cancellation are under recstrap's control. Preserves
+//! ownership, permission bits, extended attributes, hardlinks, sparse regions,
+//! and timestamps - everything `cp -a` preserved.
+//!
+//! The walk itself is sequential: it creates directories, symlinks, links
+//! and empty regular files in order, and applies all metadata. Filling the
+//! regular files with data is handed to a pool of worker threads
+//! (`--copy-jobs`), which keeps a fast target busy; a file's metadata is
+//! applied by the walk once its worker reports back.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use crate::context;
+use crate::erofs::crc32c;
+use crate::error::{RecError, Result};
+use crate::heartbeat;
+use crate::helpers::{follow_in_root, is_transient_errno, path_to_cstring, retry_transient};
+use crate::output;
+
+/// Buffer size for the read/write fallback path.
+const COPY_BUF_SIZE: usize = 128 * 1024;
+
+/// How often (in entries) the live progress line is refreshed.
+const PROGRESS_INTERVAL: u64 = 512;
+
+/// Extended attribute holding file capabilities (e.g. cap_net_raw on ping).
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
+/// Copy workers with `--copy-jobs 0`: one per CPU, at most this many.
+const MAX_AUTO_COPY_JOBS: usize = 8;
+
+/// Files queued per worker; each holds its destination open until copied.
+const QUEUED_FILES_PER_JOB: usize = 16;
+
+/// Number of copy workers the install's context asks for.
+fn copy_jobs() -> usize {
+    match context::with(|c| c.copy_jobs) {
+        0 => thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(MAX_AUTO_COPY_JOBS),
+        jobs => jobs,
+    }
+}
+
+/// `FICLONE` ioctl (`_IOW(0x94, 9, int)`): share extents with another file.
+/// Not exported by the libc crate.
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+/// Options controlling a tree copy.
+#[derive(Default)]
+pub struct CopyOptions<'a> {
+    /// Called with each path relative to the source root.
+    /// Returning false skips the entry (and everything below it).
+    pub filter: Option<&'a dyn Fn(&Path) -> bool>,
+    /// Polled before every entry; once it returns true, the copy stops
+    /// with an error.
+    pub cancel: Option<&'a dyn Fn() -> bool>,
+    /// Print a live progress line to stderr (only if stderr is a terminal).
+    pub show_progress: bool,
+    /// Don't fail when ownership, privileged xattrs, or device nodes can't be
+    /// reproduced (rootless extraction); count them in `CopyStats::skipped`.
+    pub best_effort: bool,
+    /// Layer onto an existing tree: directories that already exist in the
+    /// destination keep their metadata, symlinks to directories there (like
+    /// a usrmerged `bin -> usr/bin`) are copied through instead of replaced,
+    /// and copied entries are owned by root instead of the (usually
+    /// unprivileged) source owner.
+    pub overlay: bool,
+    /// Checksum each regular file's data as it is read from the source,
+    /// then flush the written file, drop it from the page cache and read
+    /// it back from the disk to compare, failing on the first mismatch.
+    /// Files are never cloned then, so every one is checked.
+    pub checksum: bool,
+}
+
+/// Counters describing what a copy produced.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CopyStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub hardlinks: u64,
+    pub special: u64,
+    pub bytes: u64,
+    /// Regular files whose data was cloned (reflinked) instead of copied
+    pub reflinked: u64,
+    /// Ownership changes, xattrs, and device nodes dropped in best-effort mode
+    pub skipped: u64,
+    /// Regular files whose data on disk was checked against a checksum
+    /// taken while reading the source
+    pub checksummed: u64,
+    /// Groups of paths (relative to the destination) that are hardlinks of
+    /// the same source inode. An extraction replaces them with groups
+    /// counted on the image itself before verifying the links survived.
+    pub hardlink_groups: Vec<Vec<PathBuf>>,
+    /// Regular files (relative to the destination) that carry file
+    /// capabilities in the source, with the raw xattr value.
+    pub capabilities: Vec<(PathBuf, Vec<u8>)>,
+    /// Symlinks directly below the source root with their targets, such as
+    /// `bin -> usr/bin`. Used to verify the usrmerge layout survived.
+    pub root_symlinks: Vec<(PathBuf, PathBuf)>,
+}
+
+impl CopyStats {
+    /// Total number of entries created in the destination.
+    pub fn entries(&self) -> u64 {
+        self.files + self.dirs + self.symlinks + self.hardlinks + self.special
+    }
+}
+
+/// Recursively copy the contents of `src` into `dst` (like `cp -aT src dst`).
+///
+/// `dst` must already exist. The metadata of `src` itself (owner, mode, times)
+/// is applied to `dst` once all contents have been copied.
+pub fn copy_tree(src: &Path, dst: &Path, options: &CopyOptions) -> Result<CopyStats> {
+    let mut copier = Copier::new(options, dst);
+
+    copier.copy_dir_contents(src, dst, Path::new(""))?;
+    copier.collect(true)?;
+
+    if !options.overlay {
+        let meta = fs::symlink_metadata(src).map_err(|e| copy_error(src, e))?;
+        copier.apply_metadata(src, dst, &meta)?;
+    }
+
+    Ok(copier.finish())
+}
+
+/// Copy a single entry - file, symlink, special file, or whole directory -
+/// from `src` to `dst` (like `cp -a src dst` when `dst` does not exist).
+///
+/// An existing non-directory at `dst` is replaced; the parent of `dst` must
+/// exist.
+pub fn copy_path(src: &Path, dst: &Path, options: &CopyOptions) -> Result<CopyStats> {
+    let mut copier = Copier::new(options, dst.parent().unwrap_or(dst));
+    let rel = PathBuf::from(src.file_name().unwrap_or_default());
+    copier.copy_entry(src, dst, &rel)?;
+    copier.collect(true)?;
+    Ok(copier.finish())
+}
+
+struct Copier<'a> {
+    options: &'a CopyOptions<'a>,
+    /// Destination root; entry paths are relative to it
+    root: PathBuf,
+    stats: CopyStats,
+    /// (dev, ino) of already-copied multiply-linked sources ->
+    /// (first destination path, all relative paths linked to it)
+    links: HashMap<(u64, u64), (PathBuf, Vec<PathBuf>)>,
+    /// Workers filling regular files
+    pool: Pool,
+    progress_shown: bool,
+}
+
+impl<'a> Copier<'a> {
+    fn new(options: &'a CopyOptions<'a>, root: &Path) -> Self {
+        Self {
+            options,
+            root: root.to_path_buf(),
+            stats: CopyStats::default(),
+            links: HashMap::new(),
+            pool: Pool::new(copy_jobs(), options.checksum),
+            // The counter would garble the per-entry listing of -vv
+            progress_shown: options.show_progress
+                && unsafe { libc::isatty(2) } == 1
+                && output::verbosity() < 2,
+        }
+    }
+
+    fn finish(mut self) -> CopyStats {
+        self.finish_progress();
+        let mut stats = self.stats;
+        stats.hardlink_groups = self
+            .links
+            .into_values()
+            .map(|(_, group)| group)
+            .filter(|group| group.len() > 1)
+            .collect();
+        stats.hardlink_groups.sort();
+        stats
+    }
+
+    fn copy_dir_contents(&mut self, src_dir: &Path, dst_dir: &Path, rel: &Path) -> Result<()> {
+        let mut entries: Vec<_> = fs::read_dir(src_dir)
+            .map_err(|e| copy_error(src_dir, e))?
+            .collect::<io::Result<_>>()
+            .map_err(|e| copy_error(src_dir, e))?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            if let Some(cancel) = self.options.cancel {
+                if cancel() {
+                    self.finish_progress();
+                    return Err(RecError::extraction_failed("interrupted"));
+                }
+            }
+
+            let name = entry.file_name();
+            let rel_path = rel.join(&name);
+            if let Some(filter) = self.options.filter {
+                if !filter(&rel_path) {
+                    continue;
+                }
+            }
+
+            let src = src_dir.join(&name);
+            let dst = dst_dir.join(&name);
+            self.copy_entry(&src, &dst, &rel_path)?;
+            self.collect(false)?;
+            self.update_progress();
+        }
+        Ok(())
+    }
+
+    fn copy_entry(&mut self, src: &Path, dst: &Path, rel: &Path) -> Result<()> {
+        let meta = fs::symlink_metadata(src).map_err(|e| copy_error(src, e))?;
+        let ft = meta.file_type();
+        if self.options.show_progress {
+            output::file(rel);
+        }
+
+        if ft.is_dir() {
+            if self.options.overlay {
+                // Resolved inside the destination, so absolute links can't
+                // send the copy out to the host
+                let resolved = fs::symlink_metadata(dst)
+                    .is_ok_and(|m| m.file_type().is_symlink())
+                    .then(|| follow_in_root(&self.root, rel).ok())
+                    .flatten()
+                    .filter(|p| p.is_dir());
+                if let Some(resolved) = resolved {
+                    return self.copy_dir_contents(src, &resolved, rel);
+                }
+            }
+            let existed = fs::symlink_metadata(dst).is_ok_and(|m| m.is_dir());
+            prepare_dir(dst).map_err(|e| copy_error(dst, e))?;
+            self.copy_dir_contents(src, dst, rel)?;
+            if !(self.options.overlay && existed) {
+                self.apply_metadata(src, dst, &meta)?;
+                self.stats.dirs += 1;
+            }
+            return Ok(());
+        }
+
+        // Hardlinks: recreate the link instead of copying the data again
+        if meta.nlink() > 1 {
+            let key = (meta.dev(), meta.ino());
+            if let Some((first, group)) = self.links.get_mut(&key) {
+                remove_existing(dst).map_err(|e| copy_error(dst, e))?;
+                fs::hard_link(&*first, dst).map_err(|e| copy_error(dst, e))?;
+                group.push(rel.to_path_buf());
+                self.stats.hardlinks += 1;
+                return Ok(());
+            }
+            self.links
+                .insert(key, (dst.to_path_buf(), vec![rel.to_path_buf()]));
+        }
+
+        remove_existing(dst).map_err(|e| copy_error(dst, e))?;
+
+        if ft.is_file() {
+            if let Some(cap) = read_capability(src).map_err(|e| copy_error(src, e))? {
+                self.stats.capabilities.push((rel.to_path_buf(), cap));
+            }
+            // Created here so that hardlinks to it can be made right away;
+            // metadata follows once a worker has filled it
+            let output = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(dst)
+                .map_err(|e| copy_error(dst, e))?;
+            self.pool.submit(FileJob {
+                src: src.to_path_buf(),
+                dst: dst.to_path_buf(),
+                rel: rel.to_path_buf(),
+                output,
+                meta,
+            });
+            return Ok(());
+        } else if ft.is_symlink() {
+            let link = fs::read_link(src).map_err(|e| copy_error(src, e))?;
+            std::os::unix::fs::symlink(&link, dst).map_err(|e| copy_error(dst, e))?;
+            if rel.components().count() == 1 {
+                self.stats.root_symlinks.push((rel.to_path_buf(), link));
+            }
+            self.stats.symlinks += 1;
+        } else if ft.is_block_device() || ft.is_char_device() || ft.is_fifo() || ft.is_socket() {
+            match make_node(dst, &meta) {
+                Ok(()) => self.stats.special += 1,
+                // Unprivileged users can't create device nodes
+                Err(e) if self.options.best_effort && e.raw_os_error() == Some(libc::EPERM) => {
+                    self.stats.skipped += 1;
+                    return Ok(());
+                }
+                Err(e) => return Err(copy_error(dst, e)),
+            }
+        }
+
+        self.apply_metadata(src, dst, &meta)
+    }
+
+    /// Apply the metadata of files the workers are done with; with `wait`,
+    /// of all files still being copied.
+    fn collect(&mut self, wait: bool) -> Result<()> {
+        for (job, result) in self.pool.finished(wait) {
+            let copied = result?;
+            if copied.cloned {
+                self.stats.reflinked += 1;
+            }
+            if copied.checksum.is_some() {
+                self.stats.checksummed += 1;
+            }
+            self.stats.files += 1;
+            self.stats.bytes += job.meta.len();
+            self.apply_metadata(&job.src, &job.dst, &job.meta)?;
+        }
+        Ok(())
+    }
+
+    fn apply_metadata(&mut self, src: &Path, dst: &Path, meta: &Metadata) -> Result<()> {
+        let owner = if self.options.overlay {
+            (0, 0)
+        } else {
+            (meta.uid(), meta.gid())
+        };
+        let skipped = apply_metadata(src, dst, meta, owner, self.options.best_effort)
+            .map_err(|e| copy_error(dst, e))?;
+        self.stats.skipped += skipped;
+        Ok(())
+    }
+
+    fn update_progress(&self) {
+        heartbeat::report_copy(self.stats.entries(), self.stats.bytes);
+        if !self.progress_shown || !self.stats.entries().is_multiple_of(PROGRESS_INTERVAL) {
+            return;
+        }
+        eprint!(
+            "\r  {} entries, {} MB copied",
+            self.stats.entries(),
+            self.stats.bytes / (1024 * 1024)
+        );
+        let _ = io::stderr().flush();
+    }
+
+    fn finish_progress(&mut self) {
+        if self.progress_shown {
+            eprintln!(
+                "\r  {} entries, {} MB copied",
+                self.stats.entries(),
+                self.stats.bytes / (1024 * 1024)
+            );
+            self.progress_shown = false;
+        }
+    }
+}
+
+/// A regular file for a worker to fill.
+struct FileJob {
+    src: PathBuf,
+    dst: PathBuf,
+    rel: PathBuf,
+    /// The destination, already created (empty)
+    output: File,
+    meta: Metadata,
+}
+
+/// Worker threads filling regular files, stopped on drop.
+struct Pool {
+    jobs: Option<mpsc::SyncSender<FileJob>>,
+    done: mpsc::Receiver<(FileJob, Result<CopiedFile>)>,
+    workers: Vec<thread::JoinHandle<()>>,
+    /// Set on drop, so queued files are skipped after a failure
+    abort: Arc<AtomicBool>,
+    /// Files submitted and not yet returned by [`Pool::finished`]
+    pending: usize,
+}
+
+impl Pool {
+    fn new(workers: usize, checksum: bool) -> Self {
+        let workers = workers.max(1);
+        let (jobs, queue) = mpsc::sync_channel(workers * QUEUED_FILES_PER_JOB);
+        let queue = Arc::new(Mutex::new(queue));
+        let (report, done) = mpsc::channel();
+        let abort = Arc::new(AtomicBool::new(false));
+        // Cleared after the first clone attempt the filesystem rejects
+        let reflink = Arc::new(AtomicBool::new(true));
+
+        // Workers retry and copy as the install they belong to says
+        let context = context::current();
+        let workers = (0..workers)
+            .map(|_| {
+                let context = context.clone();
+                let queue = Arc::clone(&queue);
+                let report = report.clone();
+                let abort = Arc::clone(&abort);
+                let reflink = Arc::clone(&reflink);
+                thread::spawn(move || {
+                    let _context = context.enter();
+                    loop {
+                        let next = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                        let Ok(job) = next else {
+                            return;
+                        };
+                        if abort.load(Ordering::SeqCst) {
+                            continue;
+                        }
+                        let result = fill_file(&job, &reflink, checksum);
+                        if report.send((job, result)).is_err() {
+                            return;
+                        }
+                    }
+                })
+            })
+            .collect();
+        Self {
+            jobs: Some(jobs),
+            done,
+            workers,
+            abort,
+            pending: 0,
+        }
+    }
+
+    /// Queue `job`, waiting while the queue is full.
+    fn submit(&mut self, job: FileJob) {
+        if let Some(jobs) = &self.jobs {
+            if jobs.send(job).is_ok() {
+                self.pending += 1;
+            }
+        }
+    }
+
+    /// Files the workers are done with; with `wait`, all pending ones.
+    fn finished(&mut self, wait: bool) -> Vec<(FileJob, Result<CopiedFile>)> {
+        let mut finished = Vec::new();
+        while self.pending > 0 {
+            let next = if wait {
+                self.done.recv().ok()
+            } else {
+                self.done.try_recv().ok()
+            };
+            let Some(next) = next else {
+                break;
+            };
+            self.pending -= 1;
+            finished.push(next);
+        }
+        finished
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.abort.store(true, Ordering::SeqCst);
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Copy the data of `job`, retrying transient errors, and check it
+/// against the checksum taken on the way if asked to.
+fn fill_file(job: &FileJob, reflink: &AtomicBool, checksum: bool) -> Result<CopiedFile> {
+    let copied = retry_transient(
+        &format!("copying {}", job.src.display()),
+        false,
+        |e: &io::Error| e.raw_os_error().is_some_and(is_transient_errno),
+        || {
+            // A failed attempt may have left partial data
+            job.output.set_len(0)?;
+            let mut clone = reflink.load(Ordering::Relaxed);
+            let copied = copy_file(&job.src, &job.output, job.meta.len(), &mut clone, checksum);
+            if !clone {
+                reflink.store(false, Ordering::Relaxed);
+            }
+            copied
+        },
+    )
+    .map_err(|e| copy_error(&job.src, e))?;
+    if let Some(expected) = copied.checksum {
+        let actual = stored_checksum(&job.output, &job.dst).map_err(|e| copy_error(&job.dst, e))?;
+        if actual != expected {
+            return Err(RecError::checksum_mismatch(&job.rel.to_string_lossy()));
+        }
+    }
+    Ok(copied)
+}
+
+fn copy_error(path: &Path, e: io::Error) -> RecError {
+    RecError::extraction_failed(&format!("{}: {}", path.display(), e))
+}
+
+fn last_errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+/// Make sure `dst` is a directory, replacing any non-directory in the way.
+fn prepare_dir(dst: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(dst) {
+        Ok(m) if m.is_dir() => Ok(()),
+        Ok(_) => {
+            fs::remove_file(dst)?;
+            fs::create_dir(dst)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(dst),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove whatever currently exists at `dst` so a fresh entry can be created.
+fn remove_existing(dst: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(dst) {
+        Ok(m) if m.is_dir() => fs::remove_dir_all(dst),
+        Ok(_) => fs::remove_file(dst),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// CRC32C of a file's content as it streams past, holes included.
+struct Checksum {
+    crc: u32,
+    /// Bytes of the file accounted for so far
+    pos: u64,
+}
+
+impl Checksum {
+    fn new() -> Self {
+        Self { crc: !0, pos: 0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.crc = crc32c(self.crc, data);
+        self.pos += data.len() as u64;
+    }
+
+    /// Account for a hole (zeros) up to `offset`.
+    fn skip_to(&mut self, offset: u64) {
+        const ZEROS: [u8; 4096] = [0; 4096];
+        while self.pos < offset {
+            let n = (offset - self.pos).min(ZEROS.len() as u64) as usize;
+            self.update(&ZEROS[..n]);
+        }
+    }
+}
+
+/// What [`copy_file`] did.
+struct CopiedFile {
+    /// The data was cloned (reflinked) instead of copied
+    cloned: bool,
+    /// Checksum of the data as it was read, when asked for and copied
+    checksum: Option<u32>,
+}
+
+/// Copy a regular file's contents into the empty `output`, keeping holes
+/// in sparse files.
+///
+/// When `reflink` is set, first tries to clone the file's extents (btrfs,
+/// XFS, bcachefs on the same filesystem) which is near-instant and uses no
+/// extra space. If the filesystem refuses, `reflink` is cleared so later
+/// files skip straight to a normal copy. With `checksum`, nothing is
+/// cloned and copied data goes through userspace to be checksummed on the
+/// way.
+fn copy_file(
+    src: &Path,
+    output: &File,
+    len: u64,
+    reflink: &mut bool,
+    checksum: bool,
+) -> io::Result<CopiedFile> {
+    let input = File::open(src)?;
+
+    if *reflink && len > 0 && !checksum && context::with(|c| c.copy_offload) {
+        if try_clone(&input, output)? {
+            return Ok(CopiedFile {
+                cloned: true,
+                checksum: None,
+            });
+        }
+        *reflink = false;
+    }
+
+    // Size the file first so that any range we don't write stays a hole
+    output.set_len(len)?;
+
+    let mut sum = checksum.then(Checksum::new);
+    let mut copy = |offset: i64, n: i64| match &mut sum {
+        Some(sum) => {
+            sum.skip_to(offset as u64);
+            copy_range_rw(&input, output, offset as u64, n as usize, Some(sum))
+        }
+        None => copy_range(&input, output, offset, n),
+    };
+
+    let fd = input.as_raw_fd();
+    let len = len as i64;
+    let mut pos: i64 = 0;
+    while pos < len {
+        let data = unsafe { libc::lseek(fd, pos, libc::SEEK_DATA) };
+        if data < 0 {
+            match last_errno() {
+                // No more data after pos: the rest of the file is a hole
+                libc::ENXIO => break,
+                // Filesystem can't report holes: treat everything as data
+                libc::EINVAL | libc::EOPNOTSUPP => {
+                    copy(pos, len - pos)?;
+                    break;
+                }
+                _ => return Err(io::Error::last_os_error()),
+            }
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        let end = if hole < 0 { len } else { hole.min(len) };
+        copy(data, end - data)?;
+        pos = end;
+    }
+    Ok(CopiedFile {
+        cloned: false,
+        checksum: sum.map(|mut sum| {
+            sum.skip_to(len as u64);
+            sum.crc
+        }),
+    })
+}
+
+/// Checksum of what the file at `path` holds on disk, not of what is still
+/// in the page cache from writing it: `output` is flushed and its pages
+/// dropped before reading. On tmpfs the cache is the storage.
+fn stored_checksum(output: &File, path: &Path) -> io::Result<u32> {
+    output.sync_data()?;
+    unsafe { libc::posix_fadvise(output.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    file_checksum(path)
+}
+
+/// Checksum of the whole content of the file at `path`, for comparing
+/// with a [`Checksum`] taken while copying it.
+fn file_checksum(path: &Path) -> io::Result<u32> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; COPY_BUF_SIZE];
+    let mut sum = Checksum::new();
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(sum.crc);
+        }
+        sum.update(&buf[..n]);
+    }
+}
+
+/// Try to reflink `input` into `output`.
+/// Returns Ok(false) if cloning isn't possible between these two files.
+fn try_clone(input: &File, output: &File) -> io::Result<bool> {
+    let ret = unsafe { libc::ioctl(output.as_raw_fd(), FICLONE as _, input.as_raw_fd()) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    match last_errno() {
+        // Different filesystems, or a filesystem without reflink support
+        libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL | libc::ENOTTY | libc::EPERM => Ok(false),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Copy `len` bytes at `offset` kernel-side, with `copy_file_range`, else
+/// `sendfile`, else read/write.
+fn copy_range(input: &File, output: &File, offset: i64, len: i64) -> io::Result<()> {
+    if !context::with(|c| c.copy_offload) {
+        return copy_range_rw(input, output, offset as u64, len as usize, None);
+    }
+    let mut off_in = offset;
+    let mut off_out = offset;
+    let mut remaining = len as usize;
+
+    while remaining > 0 {
+        let n = unsafe {
+            libc::copy_file_range(
+                input.as_raw_fd(),
+                &mut off_in,
+                output.as_raw_fd(),
+                &mut off_out,
+                remaining,
+                0,
+            )
+        };
+        if n < 0 {
+            match last_errno() {
+                libc::EINTR => continue,
+                // Cross-filesystem before Linux 5.3, or unsupported
+                libc::EXDEV | libc::ENOSYS | libc::EINVAL | libc::EOPNOTSUPP | libc::EPERM => {
+                    return copy_range_sendfile(input, output, off_in, remaining);
+                }
+                _ => return Err(io::Error::last_os_error()),
+            }
+        }
+        if n == 0 {
+            // Source shrank underneath us
+            break;
+        }
+        remaining -= n as usize;
+    }
+    Ok(())
+}
+
+/// Copy `len` bytes at `offset` with `sendfile`, which still avoids the
+/// round trip through userspace but writes at the output's file position.
+fn copy_range_sendfile(input: &File, output: &File, offset: i64, len: usize) -> io::Result<()> {
+    if unsafe { libc::lseek(output.as_raw_fd(), offset, libc::SEEK_SET) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut off_in = offset;
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let n = unsafe {
+            libc::sendfile(
+                output.as_raw_fd(),
+                input.as_raw_fd(),
+                &mut off_in,
+                remaining,
+            )
+        };
+        if n < 0 {
+            match last_errno() {
+                libc::EINTR => continue,
+                libc::ENOSYS | libc::EINVAL | libc::EOPNOTSUPP => {
+                    return copy_range_rw(input, output, off_in as u64, remaining, None);
+                }
+                _ => return Err(io::Error::last_os_error()),
+            }
+        }
+        if n == 0 {
+            // Source shrank underneath us
+            break;
+        }
+        remaining -= n as usize;
+    }
+    Ok(())
+}
+
+fn copy_range_rw(
+    input: &File,
+    output: &File,
+    offset: u64,
+    len: usize,
+    mut sum: Option<&mut Checksum>,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; COPY_BUF_SIZE.min(len.max(1))];
+    let mut pos = offset;
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let want = remaining.min(buf.len());
+        let n = input.read_at(&mut buf[..want], pos)?;
+        if n == 0 {
+            break;
+        }
+        output.write_all_at(&buf[..n], pos)?;
+        if let Some(sum) = sum.as_deref_mut() {
+            sum.update(&buf[..n]);
+        }
+        pos += n as u64;
+        remaining -= n;
+    }
+    Ok(())
+}
+
+/// Create a device node, FIFO, or socket matching `meta`.
+fn make_node(dst: &Path, meta: &Metadata) -> io::Result<()> {
+    let c_dst = path_to_cstring(dst)?;
+    let ret = unsafe { libc::mknod(c_dst.as_ptr(), meta.mode() as libc::mode_t, meta.rdev()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether a failed privileged operation may be skipped in best-effort mode.
+/// EINVAL is what chown returns for IDs outside the user namespace mapping.
+fn is_privilege_error(errno: i32) -> bool {
+    matches!(errno, libc::EPERM | libc::EINVAL)
+}
+
+/// Apply `owner` plus mode, xattrs, and timestamps from `src` to `dst`.
+///
+/// Order matters: chown clears setuid/setgid bits and file capabilities,
+/// so mode and xattrs are applied after it, and timestamps go last.
+/// Returns how many attributes were skipped (only ever non-zero when
+/// `best_effort` is set).
+fn apply_metadata(
+    src: &Path,
+    dst: &Path,
+    meta: &Metadata,
+    (uid, gid): (u32, u32),
+    best_effort: bool,
+) -> io::Result<u64> {
+    let c_src = path_to_cstring(src)?;
+    let c_dst = path_to_cstring(dst)?;
+    let is_symlink = meta.file_type().is_symlink();
+    let mut skipped = 0;
+
+    if unsafe { libc::lchown(c_dst.as_ptr(), uid, gid) } != 0 {
+        if !(best_effort && is_privilege_error(last_errno())) {
+            return Err(io::Error::last_os_error());
+        }
+        skipped += 1;
+    }
+
+    // Symlink permissions are meaningless on Linux (and chmod would follow them)
+    if !is_symlink && unsafe { libc::chmod(c_dst.as_ptr(), meta.mode() & 0o7777) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    skipped += copy_xattrs(&c_src, &c_dst, is_symlink, best_effort)?;
+
+    let times = [
+        libc::timespec {
+            tv_sec: meta.atime(),
+            tv_nsec: meta.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: meta.mtime(),
+            tv_nsec: meta.mtime_nsec(),
+        },
+    ];
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_dst.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(skipped)
+}
+
+/// List the extended attribute names of `path` (without following symlinks).
+pub fn list_xattrs(path: &CString) -> io::Result<Vec<CString>> {
+    loop {
+        let size = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return match last_errno() {
+                libc::ENOTSUP => Ok(Vec::new()),
+                _ => Err(io::Error::last_os_error()),
+            };
+        }
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::llistxattr(
+                path.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if size < 0 {
+            if last_errno() == libc::ERANGE {
+                continue; // attributes changed between calls
+            }
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(size as usize);
+
+        return Ok(buf
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| CString::new(name).ok())
+            .collect());
+    }
+}
+
+/// Read the value of one extended attribute (without following symlinks).
+pub fn get_xattr(path: &CString, name: &CString) -> io::Result<Vec<u8>> {
+    loop {
+        let size =
+            unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if size < 0 {
+            if last_errno() == libc::ERANGE {
+                continue;
+            }
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(size as usize);
+        return Ok(buf);
+    }
+}
+
+/// Read the file capability xattr of `path`, if it has one.
+pub fn read_capability(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let c_path = path_to_cstring(path)?;
+    let name = CString::new(CAPABILITY_XATTR).expect("static name has no NUL");
+    match get_xattr(&c_path, &name) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENODATA) | Some(libc::ENOTSUP)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn copy_xattrs(
+    src: &CString,
+    dst: &CString,
+    is_symlink: bool,
+    best_effort: bool,
+) -> io::Result<u64> {
+    let mut skipped = 0;
+    for name in list_xattrs(src)? {
+        let value = get_xattr(src, &name)?;
+        let ret = unsafe {
+            libc::lsetxattr(
+                dst.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if ret != 0 {
+            match last_errno() {
+                // Target filesystem has no xattr support (cp -a ignores this too)
+                libc::ENOTSUP => return Ok(skipped),
+                // user.* attributes are not permitted on symlinks
+                libc::EPERM if is_symlink => continue,
+                // trusted.* and friends need privileges we may not have
+                errno if best_effort && is_privilege_error(errno) => skipped += 1,
+                _ => return Err(io::Error::last_os_error()),
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_pair(name: &str) -> (PathBuf, PathBuf) {
+        let base = std::env::temp_dir().join(format!("recstrap_test_copy_{}", name));
+        let _ = fs::remove_dir_all(&base);
+        let src = base.join("src");
+        let dst = base.join("dst");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        (src, dst)
+    }
+
+    #[test]
+    fn test_copy_tree_preserves_structure() {
+        let (src, dst) = temp_pair("structure");
+        fs::create_dir_all(src.join("etc/ssh")).unwrap();
+        fs::write(src.join("etc/os-release"), b"NAME=\"TestOS\"\n").unwrap();
+        fs::write(src.join("etc/ssh/sshd_config"), b"PermitRootLogin no\n").unwrap();
+        fs::set_permissions(
+            src.join("etc/ssh/sshd_config"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("etc/os-release", src.join("os-release")).unwrap();
+
+        let stats = copy_tree(&src, &dst, &CopyOptions::default()).unwrap();
+
+        assert_eq!(
+            fs::read(dst.join("etc/os-release")).unwrap(),
+            b"NAME=\"TestOS\"\n"
+        );
+        let mode = fs::metadata(dst.join("etc/ssh/sshd_config"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o600);
+        assert_eq!(
+            fs::read_link(dst.join("os-release")).unwrap(),
+            Path::new("etc/os-release")
+        );
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.dirs, 2);
+        assert_eq!(stats.symlinks, 1);
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_tree_preserves_mtime() {
+        let (src, dst) = temp_pair("mtime");
+        fs::write(src.join("file"), b"x").unwrap();
+        let c_path = path_to_cstring(&src.join("file")).unwrap();
+        let times = [
+            libc::timespec {
+                tv_sec: 1_000_000,
+                tv_nsec: 0,
+            },
+            libc::timespec {
+                tv_sec: 1_234_567,
+                tv_nsec: 0,
+            },
+        ];
+        unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) };
+
+        copy_tree(&src, &dst, &CopyOptions::default()).unwrap();
+
+        let meta = fs::metadata(dst.join("file")).unwrap();
+        assert_eq!(meta.mtime(), 1_234_567);
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_tree_preserves_hardlinks() {
+        let (src, dst) = temp_pair("hardlinks");
+        fs::write(src.join("a"), b"shared").unwrap();
+        fs::hard_link(src.join("a"), src.join("b")).unwrap();
+
+        let stats = copy_tree(&src, &dst, &CopyOptions::default()).unwrap();
+
+        let a = fs::metadata(dst.join("a")).unwrap();
+        let b = fs::metadata(dst.join("b")).unwrap();
+        assert_eq!(a.ino(), b.ino(), "hardlinked files should share an inode");
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.hardlinks, 1);
+        assert_eq!(
+            stats.hardlink_groups,
+            vec![vec![PathBuf::from("a"), PathBuf::from("b")]]
+        );
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_read_capability_absent() {
+        let (src, _dst) = temp_pair("nocap");
+        fs::write(src.join("file"), b"x").unwrap();
+
+        assert_eq!(read_capability(&src.join("file")).unwrap(), None);
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_tree_sparse_file_content() {
+        let (src, dst) = temp_pair("sparse");
+        let f = File::create(src.join("sparse")).unwrap();
+        f.set_len(4 * 1024 * 1024).unwrap();
+        f.write_all_at(b"middle", 2 * 1024 * 1024).unwrap();
+        drop(f);
+
+        copy_tree(&src, &dst, &CopyOptions::default()).unwrap();
+
+        let copied = fs::read(dst.join("sparse")).unwrap();
+        assert_eq!(copied.len(), 4 * 1024 * 1024);
+        assert_eq!(&copied[2 * 1024 * 1024..2 * 1024 * 1024 + 6], b"middle");
+        assert!(copied[..2 * 1024 * 1024].iter().all(|b| *b == 0));
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_tree_checksum() {
+        let (src, dst) = temp_pair("checksum");
+        fs::write(src.join("file"), b"some data").unwrap();
+        let f = File::create(src.join("sparse")).unwrap();
+        f.set_len(64 * 1024).unwrap();
+        f.write_all_at(b"middle", 10_000).unwrap();
+        drop(f);
+
+        let options = CopyOptions {
+            checksum: true,
+            ..Default::default()
+        };
+        let stats = copy_tree(&src, &dst, &options).unwrap();
+        assert_eq!(stats.checksummed, 2);
+        assert_eq!(stats.reflinked, 0);
+        assert_eq!(
+            fs::read(dst.join("sparse")).unwrap(),
+            fs::read(src.join("sparse")).unwrap()
+        );
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_pool_fills_files() {
+        let (src, dst) = temp_pair("pool");
+        let mut pool = Pool::new(4, true);
+        for i in 0..64 {
+            let name = format!("f{}", i);
+            fs::write(src.join(&name), name.repeat(1000)).unwrap();
+            let meta = fs::metadata(src.join(&name)).unwrap();
+            pool.submit(FileJob {
+                src: src.join(&name),
+                dst: dst.join(&name),
+                rel: PathBuf::from(&name),
+                output: File::create(dst.join(&name)).unwrap(),
+                meta,
+            });
+        }
+        let finished = pool.finished(true);
+        assert_eq!(finished.len(), 64);
+        for (job, result) in finished {
+            assert!(result.is_ok());
+            assert_eq!(fs::read(&job.dst).unwrap(), fs::read(&job.src).unwrap());
+        }
+        assert!(pool.finished(false).is_empty());
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_range_fallbacks() {
+        let (src, dst) = temp_pair("ranges");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(src.join("file"), &data).unwrap();
+        let input = File::open(src.join("file")).unwrap();
+
+        let copy = |name: &str, f: &dyn Fn(&File) -> io::Result<()>| {
+            let output = File::create(dst.join(name)).unwrap();
+            output.set_len(data.len() as u64).unwrap();
+            f(&output).unwrap();
+            let copied = fs::read(dst.join(name)).unwrap();
+            assert_eq!(copied[..1000], [0; 1000], "{}", name);
+            assert_eq!(copied[1000..150_000], data[1000..150_000], "{}", name);
+        };
+        copy("offload", &|out| copy_range(&input, out, 1000, 149_000));
+        copy("sendfile", &|out| {
+            copy_range_sendfile(&input, out, 1000, 149_000)
+        });
+        copy("rw", &|out| copy_range_rw(&input, out, 1000, 149_000, None));
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_checksum_counts_holes() {
+        let mut data = vec![0u8; 10_000];
+        data[..2].copy_from_slice(b"ab");
+        data[9_999] = b'c';
+
+        let mut sum = Checksum::new();
+        sum.update(b"ab");
+        sum.skip_to(9_999);
+        sum.update(b"c");
+        assert_eq!(sum.crc, crc32c(!0, &data));
+    }
+
+    #[test]
+    fn test_copy_tree_overwrites_existing() {
+        let (src, dst) = temp_pair("overwrite");
+        fs::write(src.join("etc"), b"file now").unwrap();
+        fs::create_dir_all(dst.join("etc/old")).unwrap();
+
+        copy_tree(&src, &dst, &CopyOptions::default()).unwrap();
+
+        assert_eq!(fs::read(dst.join("etc")).unwrap(), b"file now");
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_tree_overlay_keeps_existing_dirs() {
+        // Overlay copies chown to root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let (src, dst) = temp_pair("overlay");
+        fs::create_dir(src.join("etc")).unwrap();
+        fs::set_permissions(src.join("etc"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::write(src.join("etc/motd"), b"site\n").unwrap();
+        fs::set_permissions(src.join("etc/motd"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::create_dir(dst.join("etc")).unwrap();
+        fs::set_permissions(dst.join("etc"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o700)).unwrap();
+
+        let options = CopyOptions {
+            overlay: true,
+            ..Default::default()
+        };
+        let stats = copy_tree(&src, &dst, &options).unwrap();
+
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&dst.join("etc")), 0o755);
+        assert_ne!(mode(&dst), 0o700);
+        assert_eq!(mode(&dst.join("etc/motd")), 0o640);
+        assert_eq!(fs::metadata(dst.join("etc/motd")).unwrap().uid(), 0);
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.dirs, 0);
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_tree_overlay_follows_dir_symlinks() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let (src, dst) = temp_pair("overlay_symlink");
+        fs::create_dir_all(src.join("bin")).unwrap();
+        fs::write(src.join("bin/site-tool"), b"#!/bin/sh\n").unwrap();
+        fs::create_dir_all(dst.join("usr/bin")).unwrap();
+        std::os::unix::fs::symlink("/usr/bin", dst.join("bin")).unwrap();
+
+        let options = CopyOptions {
+            overlay: true,
+            ..Default::default()
+        };
+        copy_tree(&src, &dst, &options).unwrap();
+
+        assert!(fs::symlink_metadata(dst.join("bin"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(dst.join("usr/bin/site-tool").is_file());
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_tree_records_root_symlinks() {
+        let (src, dst) = temp_pair("root_symlinks");
+        fs::create_dir_all(src.join("usr/bin")).unwrap();
+        std::os::unix::fs::symlink("usr/bin", src.join("bin")).unwrap();
+        std::os::unix::fs::symlink("../bin", src.join("usr/sbin")).unwrap();
+
+        let stats = copy_tree(&src, &dst, &CopyOptions::default()).unwrap();
+
+        assert_eq!(
+            stats.root_symlinks,
+            vec![(PathBuf::from("bin"), PathBuf::from("usr/bin"))]
+        );
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_tree_filter_skips_entries() {
+        let (src, dst) = temp_pair("filter");
+        fs::create_dir_all(src.join("keep")).unwrap();
+        fs::create_dir_all(src.join("skip")).unwrap();
+        fs::write(src.join("skip/file"), b"x").unwrap();
+
+        let filter = |rel: &Path| rel != Path::new("skip");
+        let options = CopyOptions {
+            filter: Some(&filter),
+            ..Default::default()
+        };
+        copy_tree(&src, &dst, &options).unwrap();
+
+        assert!(dst.join("keep").is_dir());
+        assert!(!dst.join("skip").exists());
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_path_single_file_and_subtree() {
+        let (src, dst) = temp_pair("copy_path");
+        fs::create_dir_all(src.join("etc/skel")).unwrap();
+        fs::write(src.join("etc/os-release"), b"ID=levitateos").unwrap();
+        fs::write(src.join("etc/skel/.bashrc"), b"# bashrc").unwrap();
+
+        let stats = copy_path(
+            &src.join("etc/os-release"),
+            &dst.join("os-release"),
+            &CopyOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.files, 1);
+        assert_eq!(fs::read(dst.join("os-release")).unwrap(), b"ID=levitateos");
+
+        let stats = copy_path(&src.join("etc"), &dst.join("etc"), &CopyOptions::default()).unwrap();
+        assert_eq!(stats.dirs, 2);
+        assert_eq!(fs::read(dst.join("etc/skel/.bashrc")).unwrap(), b"# bashrc");
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+
+    #[test]
+    fn test_copy_tree_cancelled() {
+        let (src, dst) = temp_pair("cancel");
+        fs::write(src.join("file"), b"x").unwrap();
+
+        let options = CopyOptions {
+            cancel: Some(&|| true),
+            ..Default::default()
+        };
+        let err = copy_tree(&src, &dst, &options).unwrap_err();
+
+        assert!(
+            err.to_string().contains("interrupted"),
+            "Error was: {}",
+            err
+        );
+        assert!(!dst.join("file").exists());
+
+        let _ = fs::remove_dir_all(src.parent().unwrap());
+    }
+}
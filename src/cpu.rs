@@ -0,0 +1,130 @@
+//! x86-64 micro-architecture level checks.
+//!
+//! Images built for x86-64-v2/v3/v4 contain binaries that die with SIGILL
+//! on older CPUs. The image declares its level in os-release
+//! (`X86_64_LEVEL=v3`); we compare it against the flags in `/proc/cpuinfo`
+//! before installing anything.
+
+use std::collections::HashSet;
+use std::fs;
+
+/// os-release key declaring the image's required level.
+pub const OS_RELEASE_LEVEL_KEY: &str = "X86_64_LEVEL";
+
+/// `/proc/cpuinfo` flags each level adds on top of the previous one
+/// (x86-64 psABI). v1 is baseline x86-64 and needs nothing extra.
+const LEVEL_FLAGS: &[(u8, &[&str])] = &[
+    (
+        2,
+        &["cx16", "lahf_lm", "popcnt", "sse4_1", "sse4_2", "ssse3"],
+    ),
+    (
+        3,
+        &[
+            "avx", "avx2", "bmi1", "bmi2", "f16c", "fma", "abm", "movbe", "xsave",
+        ],
+    ),
+    (
+        4,
+        &["avx512f", "avx512bw", "avx512cd", "avx512dq", "avx512vl"],
+    ),
+];
+
+/// Parse a level like `v3` or `x86-64-v3`. Returns 1-4.
+pub fn parse_level(value: &str) -> Option<u8> {
+    let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+    let digit = value
+        .strip_prefix("x86-64-")
+        .unwrap_or(value)
+        .strip_prefix('v')?;
+    match digit.parse() {
+        Ok(level @ 1..=4) => Some(level),
+        _ => None,
+    }
+}
+
+/// The level an os-release file declares, if any.
+pub fn required_level(os_release: &str) -> Option<u8> {
+    os_release.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == OS_RELEASE_LEVEL_KEY)
+            .then(|| parse_level(value))
+            .flatten()
+    })
+}
+
+/// CPU flags from `/proc/cpuinfo` content (first processor only; they match).
+pub fn cpu_flags(cpuinfo: &str) -> HashSet<&str> {
+    cpuinfo
+        .lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == "flags").then_some(value)
+        })
+        .map(|flags| flags.split_whitespace().collect())
+        .unwrap_or_default()
+}
+
+/// Flags needed for `level` that `flags` lacks.
+pub fn missing_flags(flags: &HashSet<&str>, level: u8) -> Vec<&'static str> {
+    LEVEL_FLAGS
+        .iter()
+        .filter(|(l, _)| *l <= level)
+        .flat_map(|(_, needed)| needed.iter().copied())
+        .filter(|f| !flags.contains(f))
+        .collect()
+}
+
+/// Check the running CPU against `level`. Returns the missing flags; empty
+/// when the CPU is capable, or when this isn't x86-64 or cpuinfo is
+/// unreadable (nothing to compare against).
+pub fn check_host(level: u8) -> Vec<&'static str> {
+    if !cfg!(target_arch = "x86_64") {
+        return Vec::new();
+    }
+    match fs::read_to_string("/proc/cpuinfo") {
+        Ok(cpuinfo) => missing_flags(&cpu_flags(&cpuinfo), level),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPUINFO: &str = "\
+processor\t: 0
+model name\t: Some CPU
+flags\t\t: fpu cx16 lahf_lm popcnt sse4_1 sse4_2 ssse3 avx avx2 bmi1 bmi2 f16c fma abm movbe
+";
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("v3"), Some(3));
+        assert_eq!(parse_level("\"x86-64-v2\""), Some(2));
+        assert_eq!(parse_level("v5"), None);
+        assert_eq!(parse_level("3"), None);
+    }
+
+    #[test]
+    fn test_required_level() {
+        let os_release = "NAME=\"LevitateOS\"\nX86_64_LEVEL=v3\n";
+        assert_eq!(required_level(os_release), Some(3));
+        assert_eq!(required_level("NAME=LevitateOS\n"), None);
+    }
+
+    #[test]
+    fn test_missing_flags() {
+        let flags = cpu_flags(CPUINFO);
+        assert!(missing_flags(&flags, 1).is_empty());
+        assert!(missing_flags(&flags, 2).is_empty());
+        // xsave is absent from the sample
+        assert_eq!(missing_flags(&flags, 3), vec!["xsave"]);
+        assert!(missing_flags(&flags, 4).contains(&"avx512f"));
+    }
+
+    #[test]
+    fn test_cpu_flags_missing_line() {
+        assert!(cpu_flags("processor : 0\n").is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+//! Building a bootable-layout disk image file (`--image`).
+//!
+//! The image gets a GPT with an EFI system partition and a root partition,
+//! is attached to a loop device, formatted, and its root partition mounted.
+//! The normal extraction flow then runs against the mounted root, exactly as
+//! it would for a physical disk. Everything is torn down again on drop.
+//!
+//! Like a physical install, the ESP is formatted but left empty: installing
+//! a bootloader is still the user's job.
+
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::make_temp_dir;
+use crate::runner;
+
+/// Size of the EFI system partition in the generated image.
+pub const ESP_SIZE_MIB: u64 = 512;
+
+/// Filesystem for the root partition of a generated image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RootFs {
+    Ext4,
+    Btrfs,
+    Xfs,
+}
+
+impl RootFs {
+    /// mkfs tool, its arguments, and the package providing it.
+    pub fn mkfs(self) -> (&'static str, &'static [&'static str], &'static str) {
+        match self {
+            RootFs::Ext4 => ("mkfs.ext4", &["-q", "-L", "root"], "e2fsprogs"),
+            RootFs::Btrfs => ("mkfs.btrfs", &["-q", "-L", "root"], "btrfs-progs"),
+            RootFs::Xfs => ("mkfs.xfs", &["-q", "-L", "root"], "xfsprogs"),
+        }
+    }
+}
+
+/// Parse a size like `20G`, `512M`, or `1T` (binary units) into bytes.
+/// A bare number is taken as bytes.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit.trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" | "m" => 20,
+        "G" | "g" => 30,
+        "T" | "t" => 40,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// A disk image attached to a loop device with its root partition mounted.
+///
+/// Dropping it unmounts everything and detaches the loop device. Unless
+/// [`DiskImage::keep`] was called, the image file is deleted as well, so a
+/// failed build leaves nothing behind.
+pub struct DiskImage {
+    file: PathBuf,
+    loop_device: Option<String>,
+    root: Option<PathBuf>,
+    mounted: bool,
+    keep: bool,
+}
+
+impl DiskImage {
+    /// Create `file` with `size` bytes, partition, format, and mount it.
+    pub fn create(file: &Path, size: u64, fs: RootFs, workdir: &Path, quiet: bool) -> Result<Self> {
+        let min = (ESP_SIZE_MIB + 1024) << 20;
+        if size < min {
+            return Err(image_error(format!(
+                "image size must be at least {} MB",
+                min >> 20
+            )));
+        }
+
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(file)
+            .and_then(|f| f.set_len(size))
+            .map_err(|e| image_error(format!("cannot create {}: {}", file.display(), e)))?;
+
+        // From here on, drop cleans up
+        let mut image = Self {
+            file: file.to_path_buf(),
+            loop_device: None,
+            root: None,
+            mounted: false,
+            keep: false,
+        };
+
+        if !quiet {
+            eprintln!("Partitioning {} ({} MB)...", file.display(), size >> 20);
+        }
+        let layout = format!(
+            "label: gpt\nsize={}MiB, type=U, name=esp\ntype=L, name=root\n",
+            ESP_SIZE_MIB
+        );
+        run_with_input("sfdisk", &["--quiet"], Some(file), &layout, "util-linux")?;
+
+        let output = runner::output(
+            Command::new("losetup")
+                .args(["--find", "--show", "--partscan"])
+                .arg(file),
+        )
+        .map_err(|e| spawn_error("losetup", "util-linux", e))?;
+        if !output.status.success() {
+            return Err(image_error(format!(
+                "losetup failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let device = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        image.loop_device = Some(device.clone());
+        let esp = wait_for_device(&format!("{}p1", device))?;
+        let root = wait_for_device(&format!("{}p2", device))?;
+
+        if !quiet {
+            eprintln!("Formatting partitions (vfat ESP, {:?} root)...", fs);
+        }
+        run_tool("mkfs.vfat", &["-F", "32", "-n", "ESP"], &esp, "dosfstools")?;
+        let (mkfs, args, package) = fs.mkfs();
+        run_tool(mkfs, args, &root, package)?;
+
+        let mount_point = make_temp_dir(workdir, "recstrap-image-")
+            .map_err(|e| image_error(format!("cannot create mount point: {}", e)))?;
+        image.root = Some(mount_point.clone());
+        let status = runner::status(Command::new("mount").arg(&root).arg(&mount_point))
+            .map_err(|e| image_error(format!("failed to run mount: {}", e)))?;
+        if !status.success() {
+            return Err(image_error(format!(
+                "mounting {} on {} failed",
+                root,
+                mount_point.display()
+            )));
+        }
+        image.mounted = true;
+
+        Ok(image)
+    }
+
+    /// Where the root partition is mounted - the extraction target.
+    pub fn root(&self) -> &Path {
+        self.root.as_deref().unwrap_or(Path::new("/nonexistent"))
+    }
+
+    /// Keep the image file when this is dropped (the build succeeded).
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for DiskImage {
+    fn drop(&mut self) {
+        if let Some(root) = &self.root {
+            if self.mounted {
+                let _ = runner::status(Command::new("umount").arg(root));
+            }
+            let _ = fs::remove_dir(root);
+        }
+        if let Some(device) = &self.loop_device {
+            let _ = runner::status(Command::new("losetup").args(["-d", device]));
+        }
+        if !self.keep {
+            let _ = fs::remove_file(&self.file);
+        }
+    }
+}
+
+fn image_error(message: String) -> RecError {
+    RecError::new(ErrorCode::ExtractionFailed, message)
+}
+
+pub fn spawn_error(name: &str, package: &str, e: std::io::Error) -> RecError {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => RecError::tool_not_installed(name, package),
+        _ => image_error(format!("failed to run {}: {}", name, e)),
+    }
+}
+
+/// Run `name args device`, failing with its stderr.
+pub fn run_tool(name: &str, args: &[&str], device: &str, package: &str) -> Result<()> {
+    let output = runner::output(Command::new(name).args(args).arg(device))
+        .map_err(|e| spawn_error(name, package, e))?;
+    if !output.status.success() {
+        return Err(image_error(format!(
+            "{} {} failed: {}",
+            name,
+            device,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Run `name args [file]` with `input` on stdin, failing with its stderr.
+pub fn run_with_input(
+    name: &str,
+    args: &[&str],
+    file: Option<&Path>,
+    input: &str,
+    package: &str,
+) -> Result<()> {
+    let mut cmd = Command::new(name);
+    cmd.args(args);
+    if let Some(file) = file {
+        cmd.arg(file);
+    }
+    let output = runner::output_with_input(
+        cmd.stdout(Stdio::null()).stderr(Stdio::piped()),
+        input.as_bytes(),
+    )
+    .map_err(|e| spawn_error(name, package, e))?;
+    if !output.status.success() {
+        return Err(image_error(format!(
+            "{} failed: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Partition device nodes appear asynchronously after `losetup --partscan`
+/// or a partition table change.
+pub fn wait_for_device(path: &str) -> Result<String> {
+    for _ in 0..50 {
+        if Path::new(path).exists() {
+            return Ok(path.to_string());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(image_error(format!("{} did not appear", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("20G"), Some(20 << 30));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("512MiB"), Some(512 << 20));
+        assert_eq!(parse_size("1T"), Some(1 << 40));
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("20X"), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+}
@@ -0,0 +1,510 @@
+//! EROFS superblock parsing and kernel feature compatibility.
+//!
+//! The superblock lives at offset 1024 of the image. Besides the magic, it
+//! records which on-disk features the image uses. Mounting an image whose
+//! incompatible features the running kernel doesn't know fails with a bare
+//! "wrong fs type" - so we compare them against the kernel version first.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::constants::EROFS_MAGIC;
+
+/// Byte offset of the superblock inside the image.
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+
+/// Size of the on-disk superblock structure.
+pub const SUPERBLOCK_SIZE: usize = 128;
+
+/// First kernel with EROFS outside of staging.
+pub const EROFS_MIN_KERNEL: KernelVersion = (5, 4);
+
+/// Kernel version as (major, minor).
+pub type KernelVersion = (u32, u32);
+
+/// An on-disk feature flag and the first kernel that understands it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Feature {
+    pub bit: u32,
+    pub name: &'static str,
+    pub since: KernelVersion,
+}
+
+const fn feature(bit: u32, name: &'static str, since: KernelVersion) -> Feature {
+    Feature { bit, name, since }
+}
+
+/// `feature_compat` flags. Unknown compat flags are safe to ignore, so these
+/// are only used for display.
+pub const COMPAT_FEATURES: &[Feature] = &[
+    feature(0x01, "sb_chksum", (5, 5)),
+    feature(0x02, "mtime", (5, 15)),
+    feature(0x04, "xattr_filter", (6, 7)),
+];
+
+/// `feature_incompat` flags. A kernel older than `since` refuses the image.
+pub const INCOMPAT_FEATURES: &[Feature] = &[
+    feature(0x01, "zero_padding", (5, 4)),
+    feature(0x02, "compr_cfgs", (5, 13)),
+    feature(0x04, "chunked_file", (5, 15)),
+    feature(0x08, "device_table", (5, 16)),
+    feature(0x10, "ztailpacking", (5, 17)),
+    feature(0x20, "fragments", (6, 1)),
+    feature(0x40, "xattr_prefixes", (6, 4)),
+    feature(0x80, "48bit", (6, 15)),
+    feature(0x100, "metabox", (6, 17)),
+];
+
+/// Compression algorithms, by bit position in `available_compr_algs`.
+pub const COMPRESSION_ALGORITHMS: &[Feature] = &[
+    feature(0x01, "lz4", (5, 4)),
+    feature(0x02, "lzma", (5, 16)),
+    feature(0x04, "deflate", (6, 6)),
+    feature(0x08, "zstd", (6, 10)),
+];
+
+const COMPAT_SB_CHKSUM: u32 = 0x01;
+const INCOMPAT_ZERO_PADDING: u32 = 0x01;
+const INCOMPAT_COMPR_CFGS: u32 = 0x02;
+
+/// The parts of the EROFS superblock recstrap cares about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Superblock {
+    pub checksum: u32,
+    pub feature_compat: u32,
+    pub blkszbits: u8,
+    pub inos: u64,
+    pub build_time: u64,
+    pub blocks: u32,
+    pub uuid: [u8; 16],
+    pub volume_name: [u8; 16],
+    pub feature_incompat: u32,
+    /// `available_compr_algs` (only meaningful with `compr_cfgs`)
+    pub compr_algs: u16,
+}
+
+fn le_u16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+fn le_u32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().expect("4-byte slice"))
+}
+
+fn le_u64(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().expect("8-byte slice"))
+}
+
+impl Superblock {
+    /// Parse a raw superblock (the 128 bytes starting at offset 1024).
+    pub fn parse(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < SUPERBLOCK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "EROFS superblock is truncated",
+            ));
+        }
+
+        let magic = le_u32(buf, 0);
+        if magic != EROFS_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "not a valid EROFS image (magic: 0x{:08x}, expected: 0x{:08x})",
+                    magic, EROFS_MAGIC
+                ),
+            ));
+        }
+
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&buf[48..64]);
+        let mut volume_name = [0u8; 16];
+        volume_name.copy_from_slice(&buf[64..80]);
+
+        Ok(Self {
+            checksum: le_u32(buf, 4),
+            feature_compat: le_u32(buf, 8),
+            blkszbits: buf[12],
+            inos: le_u64(buf, 16),
+            build_time: le_u64(buf, 24),
+            blocks: le_u32(buf, 36),
+            uuid,
+            volume_name,
+            feature_incompat: le_u32(buf, 80),
+            compr_algs: le_u16(buf, 84),
+        })
+    }
+
+    /// Read and parse the superblock of the image at `path`.
+    pub fn read_from(path: &Path) -> io::Result<Self> {
+        let mut f = File::open(path)?;
+        f.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        let mut buf = [0u8; SUPERBLOCK_SIZE];
+        f.read_exact(&mut buf)?;
+        Self::parse(&buf)
+    }
+
+    /// Block size in bytes.
+    pub fn block_size(&self) -> u64 {
+        1u64 << self.blkszbits.min(63)
+    }
+
+    /// Size of the filesystem as recorded in the superblock (blocks x block size).
+    /// For compressed images this is the on-disk size, not the unpacked size.
+    pub fn filesystem_size(&self) -> u64 {
+        u64::from(self.blocks) * self.block_size()
+    }
+
+    /// UUID in the usual 8-4-4-4-12 hex form.
+    pub fn uuid_string(&self) -> String {
+        let hex: String = self.uuid.iter().map(|b| format!("{:02x}", b)).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+
+    /// Volume label, if one was set at mkfs time.
+    pub fn volume_name(&self) -> Option<String> {
+        let end = self
+            .volume_name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.volume_name.len());
+        let name = String::from_utf8_lossy(&self.volume_name[..end]).into_owned();
+        (!name.is_empty()).then_some(name)
+    }
+
+    /// Compatible features the image uses.
+    pub fn compat_features(&self) -> Vec<&'static Feature> {
+        COMPAT_FEATURES
+            .iter()
+            .filter(|f| self.feature_compat & f.bit != 0)
+            .collect()
+    }
+
+    /// Incompatible features the image uses.
+    pub fn incompat_features(&self) -> Vec<&'static Feature> {
+        INCOMPAT_FEATURES
+            .iter()
+            .filter(|f| self.feature_incompat & f.bit != 0)
+            .collect()
+    }
+
+    /// Compression algorithms the image may use.
+    ///
+    /// With `compr_cfgs` the superblock lists them explicitly. Older images
+    /// can only use lz4, which mkfs.erofs marks with `zero_padding`.
+    pub fn compression_algorithms(&self) -> Vec<&'static Feature> {
+        if self.feature_incompat & INCOMPAT_COMPR_CFGS != 0 {
+            COMPRESSION_ALGORITHMS
+                .iter()
+                .filter(|c| u32::from(self.compr_algs) & c.bit != 0)
+                .collect()
+        } else if self.feature_incompat & INCOMPAT_ZERO_PADDING != 0 {
+            vec![&COMPRESSION_ALGORITHMS[0]]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// CRC-32C (Castagnoli) lookup table, reflected polynomial.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32C the way the kernel's `crc32c()` computes it: starting from
+/// `crc`, without the final inversion.
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Checksum of the superblock area (`data` starts at [`SUPERBLOCK_OFFSET`]
+/// and runs to the end of the first block), with the checksum field
+/// itself taken as zero.
+pub fn superblock_checksum(data: &[u8]) -> u32 {
+    let crc = crc32c(!0, &data[..4]);
+    let crc = crc32c(crc, &[0; 4]);
+    crc32c(crc, &data[8..])
+}
+
+/// Verify the superblock checksum of the image at `path`, if it has one
+/// (`sb_chksum`; mkfs.erofs sets it by default). The kernel refuses to
+/// mount an image whose checksum doesn't match.
+pub fn verify_superblock_checksum(path: &Path) -> io::Result<()> {
+    let sb = Superblock::read_from(path)?;
+    if sb.feature_compat & COMPAT_SB_CHKSUM == 0 {
+        return Ok(());
+    }
+    if !(9..=16).contains(&sb.blkszbits) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "EROFS superblock has a bogus block size (2^{})",
+                sb.blkszbits
+            ),
+        ));
+    }
+    // Like the kernel: the rest of the first block, or one whole block
+    // when blocks are smaller than the superblock offset
+    let block = 1usize << sb.blkszbits;
+    let len = if block > SUPERBLOCK_OFFSET as usize {
+        block - SUPERBLOCK_OFFSET as usize
+    } else {
+        block
+    };
+    let mut data = vec![0u8; len];
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+    f.read_exact(&mut data)?;
+
+    let computed = superblock_checksum(&data);
+    if computed != sb.checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "EROFS superblock checksum mismatch (stored 0x{:08x}, computed 0x{:08x}) - \
+                 the image is corrupt or the medium is failing",
+                sb.checksum, computed
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the image at `path` is at least as large as its superblock
+/// says. A truncated download or copy keeps a valid superblock and only
+/// fails once the mount reads past the end of the file.
+pub fn verify_image_size(path: &Path) -> io::Result<()> {
+    let sb = Superblock::read_from(path)?;
+    let expected = sb.filesystem_size();
+    let actual = std::fs::metadata(path)?.len();
+    if actual < expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "image is truncated: {} bytes, but the EROFS superblock records {} \
+                 ({} blocks of {}) - the download or copy is incomplete",
+                actual,
+                expected,
+                sb.blocks,
+                sb.block_size()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a kernel of version `kernel` can mount an image with `sb`.
+///
+/// Returns a human-readable explanation naming the first unsupported
+/// feature, e.g. "image uses zstd compression, kernel 6.6 lacks support
+/// (needs 6.10+)".
+pub fn check_kernel_support(sb: &Superblock, kernel: KernelVersion) -> Result<(), String> {
+    let (major, minor) = kernel;
+
+    if kernel < EROFS_MIN_KERNEL {
+        return Err(format!(
+            "kernel {}.{} predates EROFS support (needs {}.{}+)",
+            major, minor, EROFS_MIN_KERNEL.0, EROFS_MIN_KERNEL.1
+        ));
+    }
+
+    if let Some(algo) = sb
+        .compression_algorithms()
+        .into_iter()
+        .find(|a| kernel < a.since)
+    {
+        return Err(format!(
+            "image uses {} compression, kernel {}.{} lacks support (needs {}.{}+)",
+            algo.name, major, minor, algo.since.0, algo.since.1
+        ));
+    }
+
+    if let Some(feature) = sb
+        .incompat_features()
+        .into_iter()
+        .find(|f| kernel < f.since)
+    {
+        return Err(format!(
+            "image uses the '{}' feature, kernel {}.{} lacks support (needs {}.{}+)",
+            feature.name, major, minor, feature.since.0, feature.since.1
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn superblock_bytes(incompat: u32, compr_algs: u16) -> Vec<u8> {
+        let mut buf = vec![0u8; SUPERBLOCK_SIZE];
+        buf[0..4].copy_from_slice(&EROFS_MAGIC.to_le_bytes());
+        buf[12] = 12; // 4096-byte blocks
+        buf[16..24].copy_from_slice(&42u64.to_le_bytes());
+        buf[36..40].copy_from_slice(&100u32.to_le_bytes());
+        buf[80..84].copy_from_slice(&incompat.to_le_bytes());
+        buf[84..86].copy_from_slice(&compr_algs.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_parse_superblock_fields() {
+        let sb = Superblock::parse(&superblock_bytes(0, 0)).unwrap();
+        assert_eq!(sb.blkszbits, 12);
+        assert_eq!(sb.inos, 42);
+        assert_eq!(sb.blocks, 100);
+        assert!(sb.incompat_features().is_empty());
+        assert!(sb.compression_algorithms().is_empty());
+        assert_eq!(sb.block_size(), 4096);
+        assert_eq!(sb.filesystem_size(), 409600);
+        assert_eq!(sb.volume_name(), None);
+    }
+
+    #[test]
+    fn test_uuid_string_format() {
+        let mut sb = Superblock::parse(&superblock_bytes(0, 0)).unwrap();
+        sb.uuid = [
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+            0xcd, 0xef,
+        ];
+        assert_eq!(sb.uuid_string(), "12345678-9abc-def0-0123-456789abcdef");
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_magic() {
+        let mut buf = superblock_bytes(0, 0);
+        buf[0..4].copy_from_slice(b"NOPE");
+        let err = Superblock::parse(&buf).unwrap_err();
+        assert!(
+            err.to_string().contains("not a valid EROFS"),
+            "Error was: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_crc32c() {
+        // The standard check value, 0xe3069283, before the final inversion
+        assert_eq!(crc32c(!0, b"123456789"), !0xe306_9283);
+    }
+
+    #[test]
+    fn test_superblock_checksum() {
+        let mut block = vec![0u8; 4096 - SUPERBLOCK_OFFSET as usize];
+        block[..SUPERBLOCK_SIZE].copy_from_slice(&superblock_bytes(0, 0));
+        block[8..12].copy_from_slice(&COMPAT_SB_CHKSUM.to_le_bytes());
+        let checksum = superblock_checksum(&block);
+        block[4..8].copy_from_slice(&checksum.to_le_bytes());
+        // The stored checksum doesn't feed into itself
+        assert_eq!(superblock_checksum(&block), checksum);
+
+        let image = std::env::temp_dir().join("recstrap_test_sb_checksum.erofs");
+        let mut data = vec![0u8; SUPERBLOCK_OFFSET as usize];
+        data.extend_from_slice(&block);
+        std::fs::write(&image, &data).unwrap();
+        assert!(verify_superblock_checksum(&image).is_ok());
+
+        // One flipped bit past the parsed fields
+        data[SUPERBLOCK_OFFSET as usize + 2000] ^= 0x10;
+        std::fs::write(&image, &data).unwrap();
+        let err = verify_superblock_checksum(&image).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+
+        // Without sb_chksum there is nothing to check
+        data[SUPERBLOCK_OFFSET as usize + 8] = 0;
+        std::fs::write(&image, &data).unwrap();
+        assert!(verify_superblock_checksum(&image).is_ok());
+
+        let _ = std::fs::remove_file(&image);
+    }
+
+    #[test]
+    fn test_verify_image_size() {
+        // superblock_bytes records 100 blocks of 4 KiB
+        let image = std::env::temp_dir().join("recstrap_test_image_size.erofs");
+        let mut data = vec![0u8; SUPERBLOCK_OFFSET as usize];
+        data.extend_from_slice(&superblock_bytes(0, 0));
+        data.resize(100 * 4096, 0);
+        std::fs::write(&image, &data).unwrap();
+        assert!(verify_image_size(&image).is_ok());
+
+        data.truncate(99 * 4096);
+        std::fs::write(&image, &data).unwrap();
+        let err = verify_image_size(&image).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+
+        let _ = std::fs::remove_file(&image);
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated() {
+        assert!(Superblock::parse(&[0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_compression_from_compr_cfgs() {
+        let sb = Superblock::parse(&superblock_bytes(INCOMPAT_COMPR_CFGS, 0x09)).unwrap();
+        let names: Vec<_> = sb.compression_algorithms().iter().map(|a| a.name).collect();
+        assert_eq!(names, ["lz4", "zstd"]);
+    }
+
+    #[test]
+    fn test_legacy_lz4_detected_from_zero_padding() {
+        let sb = Superblock::parse(&superblock_bytes(INCOMPAT_ZERO_PADDING, 0)).unwrap();
+        let names: Vec<_> = sb.compression_algorithms().iter().map(|a| a.name).collect();
+        assert_eq!(names, ["lz4"]);
+    }
+
+    #[test]
+    fn test_zstd_needs_recent_kernel() {
+        let sb = Superblock::parse(&superblock_bytes(INCOMPAT_COMPR_CFGS, 0x08)).unwrap();
+        let err = check_kernel_support(&sb, (6, 6)).unwrap_err();
+        assert!(err.contains("zstd"), "Error was: {}", err);
+        assert!(err.contains("6.6"), "Error was: {}", err);
+        assert!(err.contains("6.10+"), "Error was: {}", err);
+        assert!(check_kernel_support(&sb, (6, 10)).is_ok());
+    }
+
+    #[test]
+    fn test_incompat_feature_needs_recent_kernel() {
+        let sb = Superblock::parse(&superblock_bytes(0x20, 0)).unwrap();
+        let err = check_kernel_support(&sb, (5, 15)).unwrap_err();
+        assert!(err.contains("fragments"), "Error was: {}", err);
+        assert!(check_kernel_support(&sb, (6, 1)).is_ok());
+    }
+
+    #[test]
+    fn test_ancient_kernel_rejected() {
+        let sb = Superblock::parse(&superblock_bytes(0, 0)).unwrap();
+        assert!(check_kernel_support(&sb, (4, 19)).is_err());
+        assert!(check_kernel_support(&sb, (5, 4)).is_ok());
+    }
+}
@@ -22,7 +22,6 @@ pub enum ErrorCode {
     /// E006: Extracted system verification failed
     ExtractionVerificationFailed = 6,
     /// E007: Required tool not installed
-    #[allow(dead_code)]
     ToolNotInstalled = 7,
     /// E008: Must run as root
     NotRoot = 8,
@@ -44,6 +43,30 @@ pub enum ErrorCode {
     InvalidRootfsFormat = 16,
     /// E017: EROFS kernel module not available
     ErofsNotSupported = 17,
+    /// E018: Rootless mode could not be set up
+    RootlessUnavailable = 18,
+    /// E019: CPU lacks the instruction set level the image was built for
+    CpuNotSupported = 19,
+    /// E020: Post-extraction configuration of the target failed
+    ConfigurationFailed = 20,
+    /// E021: Target partition's GPT type is not a Linux type (--strict)
+    WrongPartitionType = 21,
+    /// E022: A helper command (mount, modprobe) hit --command-timeout
+    CommandTimedOut = 22,
+    /// E023: Something was warned about and --strict makes warnings fatal
+    StrictWarnings = 23,
+    /// E024: Several pre-flight checks failed (--check --all)
+    ChecksFailed = 24,
+    /// E025: The image failed its dm-verity check (tampered or corrupted)
+    VerityFailed = 25,
+    /// E026: No free loop device to mount the image on
+    LoopDevicesExhausted = 26,
+    /// E027: `recstrap prepare` could not partition, format or mount the disk
+    PrepareFailed = 27,
+    /// E028: --workdir is inside the target directory
+    WorkdirInsideTarget = 28,
+    /// E029: The --copy-into directory contains the target
+    OverlayContainsTarget = 29,
 }
 
 impl ToolErrorCode for ErrorCode {
@@ -66,6 +89,18 @@ impl ToolErrorCode for ErrorCode {
             ErrorCode::RootfsInsideTarget => "E015",
             ErrorCode::InvalidRootfsFormat => "E016",
             ErrorCode::ErofsNotSupported => "E017",
+            ErrorCode::RootlessUnavailable => "E018",
+            ErrorCode::CpuNotSupported => "E019",
+            ErrorCode::ConfigurationFailed => "E020",
+            ErrorCode::WrongPartitionType => "E021",
+            ErrorCode::CommandTimedOut => "E022",
+            ErrorCode::StrictWarnings => "E023",
+            ErrorCode::ChecksFailed => "E024",
+            ErrorCode::VerityFailed => "E025",
+            ErrorCode::LoopDevicesExhausted => "E026",
+            ErrorCode::PrepareFailed => "E027",
+            ErrorCode::WorkdirInsideTarget => "E028",
+            ErrorCode::OverlayContainsTarget => "E029",
         }
     }
 
@@ -115,6 +150,16 @@ impl RecError {
         )
     }
 
+    pub fn target_read_only(path: &str, mount_point: &str) -> Self {
+        Self::new(
+            ErrorCode::NotWritable,
+            format!(
+                "target '{}' is on a read-only mount ({}); remount it read-write",
+                path, mount_point
+            ),
+        )
+    }
+
     pub fn rootfs_not_found(paths_tried: &[&str]) -> Self {
         Self::new(
             ErrorCode::RootfsNotFound,
@@ -125,7 +170,6 @@ impl RecError {
         )
     }
 
-    #[allow(dead_code)]
     pub fn extraction_failed(detail: &str) -> Self {
         let detail = if detail.is_empty() {
             "unknown error (check dmesg for details)".to_string()
@@ -148,7 +192,88 @@ impl RecError {
         )
     }
 
-    #[allow(dead_code)]
+    pub fn hardlinks_broken(paths: &[String]) -> Self {
+        Self::new(
+            ErrorCode::ExtractionVerificationFailed,
+            format!(
+                "extraction verification failed - hardlinks not preserved: {}",
+                paths.join(", ")
+            ),
+        )
+    }
+
+    pub fn system_unbootable(problems: &[String]) -> Self {
+        Self::new(
+            ErrorCode::ExtractionVerificationFailed,
+            format!(
+                "extraction verification failed - system would not boot: {}",
+                problems.join("; ")
+            ),
+        )
+    }
+
+    pub fn usrmerge_broken(problems: &[String]) -> Self {
+        Self::new(
+            ErrorCode::ExtractionVerificationFailed,
+            format!(
+                "extraction verification failed - usrmerge layout broken: {}",
+                problems.join("; ")
+            ),
+        )
+    }
+
+    pub fn package_database_missing(problems: &[String]) -> Self {
+        Self::new(
+            ErrorCode::ExtractionVerificationFailed,
+            format!(
+                "extraction verification failed - package manager would not work: {}",
+                problems.join("; ")
+            ),
+        )
+    }
+
+    pub fn capabilities_stripped(paths: &[String]) -> Self {
+        Self::new(
+            ErrorCode::ExtractionVerificationFailed,
+            format!(
+                "extraction verification failed - file capabilities stripped: {} (use --relaxed to continue anyway)",
+                paths.join(", ")
+            ),
+        )
+    }
+
+    pub fn checksum_mismatch(path: &str) -> Self {
+        Self::new(
+            ErrorCode::ExtractionVerificationFailed,
+            format!(
+                "extraction verification failed - {} reads back differently than it was \
+                 written (failing target disk or memory?)",
+                path
+            ),
+        )
+    }
+
+    pub fn target_differs(differences: &[String]) -> Self {
+        const SHOWN: usize = 5;
+        let mut shown = differences
+            .iter()
+            .take(SHOWN)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ");
+        if differences.len() > SHOWN {
+            shown.push_str(&format!(", ... ({} more)", differences.len() - SHOWN));
+        }
+        Self::new(
+            ErrorCode::ExtractionVerificationFailed,
+            format!(
+                "target differs from image in {} place(s): {}",
+                differences.len(),
+                shown
+            ),
+        )
+    }
+
     pub fn tool_not_installed(tool: &str, package: &str) -> Self {
         Self::new(
             ErrorCode::ToolNotInstalled,
@@ -170,6 +295,38 @@ impl RecError {
         )
     }
 
+    pub fn overwrite_not_confirmed(path: &str) -> Self {
+        Self::new(
+            ErrorCode::TargetNotEmpty,
+            format!(
+                "target directory '{}' is not empty and overwriting it was not confirmed",
+                path
+            ),
+        )
+    }
+
+    pub fn prior_install(path: &str, version: &str, installed: &str) -> Self {
+        Self::new(
+            ErrorCode::TargetNotEmpty,
+            format!(
+                "target directory '{}' holds a LevitateOS install ({} installed {}); \
+                 use --reinstall to replace it",
+                path, version, installed
+            ),
+        )
+    }
+
+    pub fn no_prior_install(path: &str) -> Self {
+        Self::new(
+            ErrorCode::TargetNotEmpty,
+            format!(
+                "--reinstall: '{}' has no /etc/recstrap-release, so it is not a previous \
+                 LevitateOS install (use --force to overwrite arbitrary data)",
+                path
+            ),
+        )
+    }
+
     pub fn protected_path(path: &str) -> Self {
         Self::new(
             ErrorCode::ProtectedPath,
@@ -200,6 +357,16 @@ impl RecError {
         )
     }
 
+    pub fn insufficient_inodes(required: u64, available: u64) -> Self {
+        Self::new(
+            ErrorCode::InsufficientSpace,
+            format!(
+                "insufficient free inodes: image has {} files, target has {} inodes left",
+                required, available
+            ),
+        )
+    }
+
     pub fn rootfs_not_file(path: &str) -> Self {
         Self::new(
             ErrorCode::RootfsNotFile,
@@ -224,6 +391,26 @@ impl RecError {
         )
     }
 
+    pub fn workdir_inside_target(workdir: &str, target: &str) -> Self {
+        Self::new(
+            ErrorCode::WorkdirInsideTarget,
+            format!(
+                "workdir '{}' is inside target '{}' - the image would be copied into itself",
+                workdir, target
+            ),
+        )
+    }
+
+    pub fn overlay_contains_target(overlay: &str, target: &str) -> Self {
+        Self::new(
+            ErrorCode::OverlayContainsTarget,
+            format!(
+                "--copy-into directory '{}' contains the target '{}' (would copy into itself)",
+                overlay, target
+            ),
+        )
+    }
+
     pub fn invalid_rootfs_format(path: &str, detail: &str) -> Self {
         Self::new(
             ErrorCode::InvalidRootfsFormat,
@@ -237,6 +424,103 @@ impl RecError {
             "EROFS filesystem not supported by kernel (try: modprobe erofs)",
         )
     }
+
+    pub fn erofs_feature_unsupported(detail: &str) -> Self {
+        Self::new(
+            ErrorCode::ErofsNotSupported,
+            format!("EROFS image not supported by running kernel: {}", detail),
+        )
+    }
+
+    pub fn rootless_unavailable(detail: &str) -> Self {
+        Self::new(
+            ErrorCode::RootlessUnavailable,
+            format!("rootless mode unavailable: {}", detail),
+        )
+    }
+
+    pub fn cpu_not_supported(required: &str, missing: &[&str]) -> Self {
+        Self::new(
+            ErrorCode::CpuNotSupported,
+            format!(
+                "image requires x86-64-{} but this CPU lacks: {}",
+                required,
+                missing.join(", ")
+            ),
+        )
+    }
+
+    pub fn configuration_failed(what: &str, detail: &str) -> Self {
+        Self::new(
+            ErrorCode::ConfigurationFailed,
+            format!("cannot configure {}: {}", what, detail),
+        )
+    }
+
+    pub fn wrong_partition_type(device: &str, problem: &str) -> Self {
+        Self::new(
+            ErrorCode::WrongPartitionType,
+            format!(
+                "target partition {} {}; mount the Linux root partition instead",
+                device, problem
+            ),
+        )
+    }
+
+    pub fn command_timed_out(command: &str, secs: u64) -> Self {
+        Self::new(
+            ErrorCode::CommandTimedOut,
+            format!(
+                "{} did not finish within {}s and was killed (wedged media? raise --command-timeout)",
+                command, secs
+            ),
+        )
+    }
+
+    pub fn strict_warnings(warnings: &[String]) -> Self {
+        Self::new(
+            ErrorCode::StrictWarnings,
+            format!(
+                "{} warning(s) with --strict: {}",
+                warnings.len(),
+                warnings.join("; ")
+            ),
+        )
+    }
+
+    pub fn checks_failed(failures: &[RecError]) -> Self {
+        let failures: Vec<String> = failures.iter().map(|e| e.to_string()).collect();
+        Self::new(
+            ErrorCode::ChecksFailed,
+            format!(
+                "{} pre-flight checks failed: {}",
+                failures.len(),
+                failures.join("; ")
+            ),
+        )
+    }
+
+    pub fn verity_failed(detail: &str) -> Self {
+        Self::new(
+            ErrorCode::VerityFailed,
+            format!("dm-verity check failed: {}", detail),
+        )
+    }
+
+    pub fn prepare_failed(detail: &str) -> Self {
+        Self::new(ErrorCode::PrepareFailed, detail)
+    }
+
+    pub fn loop_devices_exhausted(image: &str) -> Self {
+        Self::new(
+            ErrorCode::LoopDevicesExhausted,
+            format!(
+                "no free loop device to mount {} (see losetup --list; \
+                 raise the loop module's max_loop)",
+                image
+            ),
+        )
+    }
 }
 
 impl fmt::Display for RecError {
@@ -272,6 +556,18 @@ mod tests {
         assert_eq!(ErrorCode::RootfsInsideTarget.code(), "E015");
         assert_eq!(ErrorCode::InvalidRootfsFormat.code(), "E016");
         assert_eq!(ErrorCode::ErofsNotSupported.code(), "E017");
+        assert_eq!(ErrorCode::RootlessUnavailable.code(), "E018");
+        assert_eq!(ErrorCode::CpuNotSupported.code(), "E019");
+        assert_eq!(ErrorCode::ConfigurationFailed.code(), "E020");
+        assert_eq!(ErrorCode::WrongPartitionType.code(), "E021");
+        assert_eq!(ErrorCode::CommandTimedOut.code(), "E022");
+        assert_eq!(ErrorCode::StrictWarnings.code(), "E023");
+        assert_eq!(ErrorCode::ChecksFailed.code(), "E024");
+        assert_eq!(ErrorCode::VerityFailed.code(), "E025");
+        assert_eq!(ErrorCode::LoopDevicesExhausted.code(), "E026");
+        assert_eq!(ErrorCode::PrepareFailed.code(), "E027");
+        assert_eq!(ErrorCode::WorkdirInsideTarget.code(), "E028");
+        assert_eq!(ErrorCode::OverlayContainsTarget.code(), "E029");
     }
 
     #[test]
@@ -293,6 +589,18 @@ mod tests {
         assert_eq!(ErrorCode::RootfsInsideTarget.exit_code(), 15);
         assert_eq!(ErrorCode::InvalidRootfsFormat.exit_code(), 16);
         assert_eq!(ErrorCode::ErofsNotSupported.exit_code(), 17);
+        assert_eq!(ErrorCode::RootlessUnavailable.exit_code(), 18);
+        assert_eq!(ErrorCode::CpuNotSupported.exit_code(), 19);
+        assert_eq!(ErrorCode::ConfigurationFailed.exit_code(), 20);
+        assert_eq!(ErrorCode::WrongPartitionType.exit_code(), 21);
+        assert_eq!(ErrorCode::CommandTimedOut.exit_code(), 22);
+        assert_eq!(ErrorCode::StrictWarnings.exit_code(), 23);
+        assert_eq!(ErrorCode::ChecksFailed.exit_code(), 24);
+        assert_eq!(ErrorCode::VerityFailed.exit_code(), 25);
+        assert_eq!(ErrorCode::LoopDevicesExhausted.exit_code(), 26);
+        assert_eq!(ErrorCode::PrepareFailed.exit_code(), 27);
+        assert_eq!(ErrorCode::WorkdirInsideTarget.exit_code(), 28);
+        assert_eq!(ErrorCode::OverlayContainsTarget.exit_code(), 29);
     }
 
     #[test]
@@ -319,6 +627,30 @@ mod tests {
         assert!(msg.contains("not writable"), "Error was: {}", msg);
     }
 
+    #[test]
+    fn test_error_target_read_only() {
+        let err = RecError::target_read_only("/mnt/sub", "/mnt");
+        let msg = err.to_string();
+        assert!(msg.starts_with("E003:"), "Error was: {}", msg);
+        assert!(msg.contains("read-only mount (/mnt)"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_insufficient_inodes() {
+        let err = RecError::insufficient_inodes(120000, 5000);
+        let msg = err.to_string();
+        assert!(msg.starts_with("E012:"), "Error was: {}", msg);
+        assert!(msg.contains("120000 files"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_overlay_contains_target() {
+        let err = RecError::overlay_contains_target("/", "/mnt");
+        let msg = err.to_string();
+        assert!(msg.starts_with("E029:"), "Error was: {}", msg);
+        assert!(msg.contains("--copy-into"), "Error was: {}", msg);
+    }
+
     #[test]
     fn test_error_rootfs_not_found() {
         let err = RecError::rootfs_not_found(&["/path/to/rootfs"]);
@@ -353,6 +685,55 @@ mod tests {
         assert!(msg.contains("missing directories"), "Error was: {}", msg);
     }
 
+    #[test]
+    fn test_error_hardlinks_broken() {
+        let err = RecError::hardlinks_broken(&["usr/bin/a = usr/bin/b".to_string()]);
+        let msg = err.to_string();
+        assert!(msg.starts_with("E006:"), "Error was: {}", msg);
+        assert!(msg.contains("hardlinks"), "Error was: {}", msg);
+        assert!(msg.contains("usr/bin/a"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_system_unbootable() {
+        let err = RecError::system_unbootable(&["usr/bin/sh: not an ELF binary".to_string()]);
+        let msg = err.to_string();
+        assert!(msg.starts_with("E006:"), "Error was: {}", msg);
+        assert!(msg.contains("would not boot"), "Error was: {}", msg);
+        assert!(msg.contains("usr/bin/sh"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_usrmerge_broken() {
+        let err = RecError::usrmerge_broken(&["/bin is a directory".to_string()]);
+        let msg = err.to_string();
+        assert!(msg.starts_with("E006:"), "Error was: {}", msg);
+        assert!(msg.contains("usrmerge"), "Error was: {}", msg);
+        assert!(msg.contains("/bin"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_capabilities_stripped() {
+        let err = RecError::capabilities_stripped(&["usr/bin/ping".to_string()]);
+        let msg = err.to_string();
+        assert!(msg.starts_with("E006:"), "Error was: {}", msg);
+        assert!(msg.contains("capabilities"), "Error was: {}", msg);
+        assert!(msg.contains("usr/bin/ping"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_target_differs() {
+        let diffs: Vec<String> = (0..7)
+            .map(|i| format!("/f{}: content differs", i))
+            .collect();
+        let msg = RecError::target_differs(&diffs).to_string();
+        assert!(msg.starts_with("E006:"), "Error was: {}", msg);
+        assert!(msg.contains("7 place(s)"), "Error was: {}", msg);
+        assert!(msg.contains("/f4"), "Error was: {}", msg);
+        assert!(!msg.contains("/f5"), "Error was: {}", msg);
+        assert!(msg.contains("(2 more)"), "Error was: {}", msg);
+    }
+
     #[test]
     fn test_error_tool_not_installed() {
         let err = RecError::tool_not_installed("mount", "util-linux");
@@ -369,6 +750,19 @@ mod tests {
         assert!(msg.contains("root"), "Error was: {}", msg);
     }
 
+    #[test]
+    fn test_error_prior_install() {
+        let err = RecError::prior_install("/mnt", "1.2", "2026-01-31T12:00:00Z");
+        let msg = err.to_string();
+        assert!(msg.starts_with("E009:"), "Error was: {}", msg);
+        assert!(msg.contains("--reinstall"), "Error was: {}", msg);
+
+        let err = RecError::no_prior_install("/mnt");
+        let msg = err.to_string();
+        assert!(msg.starts_with("E009:"), "Error was: {}", msg);
+        assert!(msg.contains("--force"), "Error was: {}", msg);
+    }
+
     #[test]
     fn test_error_target_not_empty() {
         let err = RecError::target_not_empty("/mnt");
@@ -428,6 +822,14 @@ mod tests {
         assert!(msg.contains("recursive"), "Error was: {}", msg);
     }
 
+    #[test]
+    fn test_error_workdir_inside_target() {
+        let err = RecError::workdir_inside_target("/mnt/tmp", "/mnt");
+        let msg = err.to_string();
+        assert!(msg.starts_with("E028:"), "Error was: {}", msg);
+        assert!(msg.contains("/mnt/tmp"), "Error was: {}", msg);
+    }
+
     #[test]
     fn test_error_invalid_rootfs_format() {
         let err = RecError::invalid_rootfs_format("/path/to/file.erofs", "bad magic");
@@ -446,6 +848,55 @@ mod tests {
         assert!(msg.contains("modprobe"), "Error was: {}", msg);
     }
 
+    #[test]
+    fn test_error_erofs_feature_unsupported() {
+        let err = RecError::erofs_feature_unsupported("image uses zstd compression");
+        let msg = err.to_string();
+        assert!(msg.starts_with("E017:"), "Error was: {}", msg);
+        assert!(msg.contains("zstd"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_rootless_unavailable() {
+        let err = RecError::rootless_unavailable("user namespaces are disabled");
+        let msg = err.to_string();
+        assert!(msg.starts_with("E018:"), "Error was: {}", msg);
+        assert!(msg.contains("namespaces"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_cpu_not_supported() {
+        let err = RecError::cpu_not_supported("v3", &["avx2", "fma"]);
+        let msg = err.to_string();
+        assert!(msg.starts_with("E019:"), "Error was: {}", msg);
+        assert!(msg.contains("x86-64-v3"), "Error was: {}", msg);
+        assert!(msg.contains("avx2, fma"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_configuration_failed() {
+        let err = RecError::configuration_failed("serial console", "no such unit");
+        let msg = err.to_string();
+        assert!(msg.starts_with("E020:"), "Error was: {}", msg);
+        assert!(msg.contains("serial console"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_wrong_partition_type() {
+        let err = RecError::wrong_partition_type("/dev/sda1", "is an EFI system partition");
+        let msg = err.to_string();
+        assert!(msg.starts_with("E021:"), "Error was: {}", msg);
+        assert!(msg.contains("/dev/sda1 is an EFI"), "Error was: {}", msg);
+    }
+
+    #[test]
+    fn test_error_command_timed_out() {
+        let err = RecError::command_timed_out("mount", 300);
+        let msg = err.to_string();
+        assert!(msg.starts_with("E022:"), "Error was: {}", msg);
+        assert!(msg.contains("within 300s"), "Error was: {}", msg);
+    }
+
     #[test]
     fn test_all_error_codes_unique() {
         let codes = [
@@ -466,6 +917,11 @@ mod tests {
             ErrorCode::RootfsInsideTarget,
             ErrorCode::InvalidRootfsFormat,
             ErrorCode::ErofsNotSupported,
+            ErrorCode::RootlessUnavailable,
+            ErrorCode::CpuNotSupported,
+            ErrorCode::ConfigurationFailed,
+            ErrorCode::WrongPartitionType,
+            ErrorCode::CommandTimedOut,
         ];
 
         let mut seen = std::collections::HashSet::new();
@@ -498,6 +954,11 @@ mod tests {
             ErrorCode::RootfsInsideTarget,
             ErrorCode::InvalidRootfsFormat,
             ErrorCode::ErofsNotSupported,
+            ErrorCode::RootlessUnavailable,
+            ErrorCode::CpuNotSupported,
+            ErrorCode::ConfigurationFailed,
+            ErrorCode::WrongPartitionType,
+            ErrorCode::CommandTimedOut,
         ];
 
         let mut seen = std::collections::HashSet::new();
@@ -0,0 +1,329 @@
+//! Tiny EROFS images built in-process, for tests and `recstrap self-test`.
+//!
+//! [`ImageBuilder`] lays out an uncompressed image by hand: 4 KiB blocks,
+//! compact inodes, plain data blocks and no xattrs. That is a subset of
+//! what mkfs.erofs writes and mounts the same way, so tests can run the
+//! real mount, extraction and verification paths without erofs-utils.
+//! squashfs images are not generated; recstrap no longer reads them.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::constants::EROFS_MAGIC;
+use crate::erofs::{superblock_checksum, SUPERBLOCK_OFFSET};
+
+const BLOCK_BITS: u8 = 12;
+const BLOCK_SIZE: usize = 1 << BLOCK_BITS;
+
+/// Compact inodes are 32 bytes; a nid is the inode's slot number.
+const INODE_SIZE: usize = 32;
+
+/// Block where the inode table starts, right after the superblock.
+const META_BLKADDR: usize = 1;
+
+const DIRENT_SIZE: usize = 12;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Dir { mode: u32 },
+    File { mode: u32, data: Vec<u8> },
+    Symlink { target: String },
+}
+
+impl Node {
+    fn mode(&self) -> u32 {
+        match self {
+            Node::Dir { mode } => libc::S_IFDIR | mode,
+            Node::File { mode, .. } => libc::S_IFREG | mode,
+            Node::Symlink { .. } => libc::S_IFLNK | 0o777,
+        }
+    }
+
+    fn file_type(&self) -> u8 {
+        match self {
+            Node::Dir { .. } => FT_DIR,
+            Node::File { .. } => FT_REG_FILE,
+            Node::Symlink { .. } => FT_SYMLINK,
+        }
+    }
+}
+
+/// A directory tree to write as an EROFS image. Paths are relative to the
+/// image root; missing parent directories are added with mode 0755. All
+/// entries are owned by root and carry the image's build time.
+#[derive(Debug, Clone)]
+pub struct ImageBuilder {
+    nodes: BTreeMap<String, Node>,
+    build_time: u64,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageBuilder {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::new(), Node::Dir { mode: 0o755 });
+        Self {
+            nodes,
+            // 2025-01-01, so images are reproducible
+            build_time: 1_735_689_600,
+        }
+    }
+
+    pub fn dir(self, path: &str, mode: u32) -> Self {
+        self.add(path, Node::Dir { mode })
+    }
+
+    pub fn file(self, path: &str, mode: u32, data: impl Into<Vec<u8>>) -> Self {
+        self.add(
+            path,
+            Node::File {
+                mode,
+                data: data.into(),
+            },
+        )
+    }
+
+    pub fn symlink(self, path: &str, target: &str) -> Self {
+        self.add(
+            path,
+            Node::Symlink {
+                target: target.to_string(),
+            },
+        )
+    }
+
+    fn add(mut self, path: &str, node: Node) -> Self {
+        let path = path.trim_matches('/');
+        let mut parent = path;
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            self.nodes
+                .entry(dir.to_string())
+                .or_insert(Node::Dir { mode: 0o755 });
+            parent = dir;
+        }
+        self.nodes.insert(path.to_string(), node);
+        self
+    }
+
+    /// Write the image to `path`.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(&self.build())?;
+        file.sync_all()
+    }
+
+    /// The complete image.
+    fn build(&self) -> Vec<u8> {
+        let paths: Vec<&String> = self.nodes.keys().collect();
+        let nid = |path: &str| paths.iter().position(|p| *p == path).unwrap_or(0) as u64;
+
+        let mut children: BTreeMap<&str, Vec<(&str, u64, u8)>> = BTreeMap::new();
+        for (path, node) in &self.nodes {
+            if !path.is_empty() {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                children.entry(parent_of(path)).or_default().push((
+                    name,
+                    nid(path),
+                    node.file_type(),
+                ));
+            }
+        }
+
+        let inode_blocks = (paths.len() * INODE_SIZE).div_ceil(BLOCK_SIZE);
+        let mut data_blocks: Vec<u8> = Vec::new();
+        let mut inodes = vec![0u8; inode_blocks * BLOCK_SIZE];
+        for (index, (path, node)) in self.nodes.iter().enumerate() {
+            let (data, nlink) = match node {
+                Node::Dir { .. } => {
+                    let entries = children.get(path.as_str()).cloned().unwrap_or_default();
+                    let subdirs = entries.iter().filter(|e| e.2 == FT_DIR).count();
+                    let mut all = vec![
+                        (".", nid(path), FT_DIR),
+                        ("..", nid(parent_of(path)), FT_DIR),
+                    ];
+                    all.extend(entries);
+                    (dir_data(all), 2 + subdirs)
+                }
+                Node::File { data, .. } => (data.clone(), 1),
+                Node::Symlink { target } => (target.clone().into_bytes(), 1),
+            };
+            let blkaddr = if data.is_empty() {
+                0
+            } else {
+                META_BLKADDR + inode_blocks + data_blocks.len() / BLOCK_SIZE
+            };
+            data_blocks.extend_from_slice(&data);
+            data_blocks.resize(data_blocks.len().next_multiple_of(BLOCK_SIZE), 0);
+
+            let inode = &mut inodes[index * INODE_SIZE..(index + 1) * INODE_SIZE];
+            // i_format 0: compact inode, flat plain layout
+            inode[4..6].copy_from_slice(&(node.mode() as u16).to_le_bytes());
+            inode[6..8].copy_from_slice(&(nlink as u16).to_le_bytes());
+            inode[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            inode[16..20].copy_from_slice(&(blkaddr as u32).to_le_bytes());
+            inode[20..24].copy_from_slice(&(index as u32 + 1).to_le_bytes());
+        }
+
+        let blocks = META_BLKADDR + inode_blocks + data_blocks.len() / BLOCK_SIZE;
+        let mut image = vec![0u8; META_BLKADDR * BLOCK_SIZE];
+        let sb = &mut image[SUPERBLOCK_OFFSET as usize..];
+        sb[0..4].copy_from_slice(&EROFS_MAGIC.to_le_bytes());
+        sb[12] = BLOCK_BITS;
+        // root_nid (14) is 0: the root sorts first
+        sb[16..24].copy_from_slice(&(paths.len() as u64).to_le_bytes());
+        sb[24..32].copy_from_slice(&self.build_time.to_le_bytes());
+        sb[36..40].copy_from_slice(&(blocks as u32).to_le_bytes());
+        sb[40..44].copy_from_slice(&(META_BLKADDR as u32).to_le_bytes());
+        sb[48..64].copy_from_slice(b"recstrap-fixture");
+        sb[64..72].copy_from_slice(b"fixture\0");
+        // sb_chksum, as mkfs.erofs sets by default
+        sb[8..12].copy_from_slice(&1u32.to_le_bytes());
+        let checksum = superblock_checksum(&sb[..BLOCK_SIZE - SUPERBLOCK_OFFSET as usize]);
+        sb[4..8].copy_from_slice(&checksum.to_le_bytes());
+        image.extend_from_slice(&inodes);
+        image.extend_from_slice(&data_blocks);
+        image
+    }
+}
+
+fn parent_of(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// Directory data: blocks of dirents sorted by name, each block's names
+/// after its dirents. Only the last block is short.
+fn dir_data(mut entries: Vec<(&str, u64, u8)>) -> Vec<u8> {
+    entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    let mut data = Vec::new();
+    let mut rest = entries.as_slice();
+    while !rest.is_empty() {
+        let mut count = 0;
+        let mut used = 0;
+        for (name, _, _) in rest {
+            if used + DIRENT_SIZE + name.len() > BLOCK_SIZE {
+                break;
+            }
+            used += DIRENT_SIZE + name.len();
+            count += 1;
+        }
+        let (block, tail) = rest.split_at(count.max(1));
+        let start = data.len();
+        let mut nameoff = block.len() * DIRENT_SIZE;
+        for (name, nid, file_type) in block {
+            data.extend_from_slice(&nid.to_le_bytes());
+            data.extend_from_slice(&(nameoff as u16).to_le_bytes());
+            data.push(*file_type);
+            data.push(0);
+            nameoff += name.len();
+        }
+        for (name, _, _) in block {
+            data.extend_from_slice(name.as_bytes());
+        }
+        if !tail.is_empty() {
+            data.resize(start + BLOCK_SIZE, 0);
+        }
+        rest = tail;
+    }
+    data
+}
+
+/// ELF magic, enough for recstrap's "is this an executable" checks.
+const ELF_STUB: &[u8] = b"\x7fELF\x02\x01\x01\0";
+
+/// A small usrmerged system that passes recstrap's post-extraction checks:
+/// accounts, os-release, and ELF stubs for the loader, shell and init.
+/// The binaries don't run; nothing here is meant to boot.
+pub fn minimal_system() -> ImageBuilder {
+    ImageBuilder::new()
+        .symlink("bin", "usr/bin")
+        .symlink("sbin", "usr/sbin")
+        .symlink("lib", "usr/lib")
+        .symlink("lib64", "usr/lib")
+        .file("usr/bin/sh", 0o755, ELF_STUB)
+        .file("usr/lib/ld-linux-x86-64.so.2", 0o755, ELF_STUB)
+        .file("usr/lib/systemd/systemd", 0o755, ELF_STUB)
+        .dir("usr/sbin", 0o755)
+        .file(
+            "etc/os-release",
+            0o644,
+            "NAME=\"LevitateOS\"\nID=levitateos\nVERSION_ID=fixture\n",
+        )
+        .file("etc/passwd", 0o644, "root:x:0:0:root:/root:/bin/sh\n")
+        .file("etc/shadow", 0o600, "root:!:19000:0:99999:7:::\n")
+        .file("etc/hostname", 0o644, "fixture\n")
+        .dir("root", 0o700)
+        .dir("var/lib", 0o755)
+        .dir("var/tmp", 0o1777)
+        .dir("tmp", 0o1777)
+        .dir("proc", 0o555)
+        .dir("sys", 0o555)
+        .dir("dev", 0o755)
+        .dir("run", 0o755)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::erofs::{check_kernel_support, Superblock};
+
+    #[test]
+    fn test_superblock() {
+        let image = minimal_system().build();
+        assert_eq!(image.len() % BLOCK_SIZE, 0);
+        let offset = SUPERBLOCK_OFFSET as usize;
+        let sb = Superblock::parse(&image[offset..offset + 128]).unwrap();
+        assert_eq!(sb.block_size(), BLOCK_SIZE as u64);
+        assert_eq!(sb.filesystem_size(), image.len() as u64);
+        assert_eq!(sb.volume_name().as_deref(), Some("fixture"));
+        assert!(sb.compression_algorithms().is_empty());
+        assert_eq!(sb.compat_features()[0].name, "sb_chksum");
+        assert!(check_kernel_support(&sb, (5, 4)).is_ok());
+    }
+
+    #[test]
+    fn test_parents_added() {
+        let builder = ImageBuilder::new().file("a/b/c", 0o644, "x");
+        let paths: Vec<&str> = builder.nodes.keys().map(|p| p.as_str()).collect();
+        assert_eq!(paths, ["", "a", "a/b", "a/b/c"]);
+    }
+
+    #[test]
+    fn test_dir_data_spans_blocks() {
+        let names: Vec<String> = (0..400).map(|i| format!("entry-{:04}", i)).collect();
+        let entries: Vec<(&str, u64, u8)> = names
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.as_str(), i as u64, FT_REG_FILE))
+            .collect();
+        let data = dir_data(entries);
+        assert!(data.len() > BLOCK_SIZE);
+
+        // Every block starts with its own dirents, and names are sorted
+        let mut seen = Vec::new();
+        for block in data.chunks(BLOCK_SIZE) {
+            let count = u16::from_le_bytes([block[8], block[9]]) as usize / DIRENT_SIZE;
+            for i in 0..count {
+                let off = i * DIRENT_SIZE;
+                let start = u16::from_le_bytes([block[off + 8], block[off + 9]]) as usize;
+                let end = if i + 1 < count {
+                    u16::from_le_bytes([block[off + 20], block[off + 21]]) as usize
+                } else {
+                    block.iter().rposition(|b| *b != 0).unwrap_or(0) + 1
+                };
+                seen.push(String::from_utf8(block[start..end].to_vec()).unwrap());
+            }
+        }
+        assert_eq!(seen, names);
+    }
+}
@@ -0,0 +1,140 @@
+//! Post-extraction fixups for minimal images.
+//!
+//! Some image builders leave out the empty API mount points (`/proc`,
+//! `/sys`, ...), give `/tmp` the wrong mode, or strip the static device
+//! nodes. The kernel, systemd, and `recchroot` all expect them, so they are
+//! put in place right after the copy, before verification.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::constants::API_DIRS;
+use crate::helpers::path_to_cstring;
+
+/// Device nodes needed before devtmpfs is mounted: early userspace writes
+/// to `/dev/console`, and shell redirects in chroots use `/dev/null`.
+/// (name, mode, major, minor)
+const DEVICE_NODES: &[(&str, u32, u32, u32)] = &[("null", 0o666, 1, 3), ("console", 0o600, 5, 1)];
+
+/// Create missing API directories with their standard modes, and restore
+/// 1777 on world-writable temp dirs the image shipped without the sticky
+/// bit. Returns one line per change.
+pub fn ensure_api_dirs(target: &Path) -> io::Result<Vec<String>> {
+    let mut changes = Vec::new();
+    for &(dir, mode) in API_DIRS {
+        let path = target.join(dir);
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => {
+                let current = meta.permissions().mode() & 0o7777;
+                if mode & 0o1000 != 0 && current != mode {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+                    changes.push(format!("/{}: mode {:o} -> {:o}", dir, current, mode));
+                }
+            }
+            // A symlink or file in the way is the image's choice; leave it
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir_all(&path)?;
+                // Not subject to the umask, unlike create_dir
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+                changes.push(format!("/{}: created (mode {:o})", dir, mode));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(changes)
+}
+
+/// Create `/dev/null` and `/dev/console` in the target if the image lacks
+/// them. Returns the nodes created and the ones that failed, with the
+/// reason (mknod needs real root, so rootless runs get EPERM).
+pub fn ensure_device_nodes(target: &Path) -> (Vec<String>, Vec<String>) {
+    let mut created = Vec::new();
+    let mut failed = Vec::new();
+    for &(name, mode, major, minor) in DEVICE_NODES {
+        let path = target.join("dev").join(name);
+        if fs::symlink_metadata(&path).is_ok() {
+            continue;
+        }
+        let display = format!("/dev/{}", name);
+        match make_char_device(&path, mode, major, minor) {
+            Ok(()) => created.push(display),
+            Err(e) => failed.push(format!("{}: {}", display, e)),
+        }
+    }
+    (created, failed)
+}
+
+fn make_char_device(path: &Path, mode: u32, major: u32, minor: u32) -> io::Result<()> {
+    let c_path = path_to_cstring(path)?;
+    let dev = libc::makedev(major, minor);
+    let ret = unsafe { libc::mknod(c_path.as_ptr(), libc::S_IFCHR | mode, dev) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // mknod applies the umask
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_api_dirs() {
+        let temp = std::env::temp_dir().join("recstrap_test_api_dirs");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("tmp")).unwrap();
+        fs::set_permissions(temp.join("tmp"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir_all(temp.join("dev")).unwrap();
+        fs::set_permissions(temp.join("dev"), fs::Permissions::from_mode(0o700)).unwrap();
+        std::os::unix::fs::symlink("/somewhere", temp.join("run")).unwrap();
+
+        let changes = ensure_api_dirs(&temp).unwrap();
+        let mode = |p: &str| fs::metadata(temp.join(p)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("proc"), 0o555);
+        assert_eq!(mode("sys"), 0o555);
+        assert_eq!(mode("tmp"), 0o1777);
+        assert_eq!(mode("var/tmp"), 0o1777);
+        // Only temp dir modes are corrected
+        assert_eq!(mode("dev"), 0o700);
+        assert!(fs::symlink_metadata(temp.join("run"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(changes.len(), 4, "changes: {:?}", changes);
+
+        // Second run has nothing left to do
+        assert!(ensure_api_dirs(&temp).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_ensure_device_nodes() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let temp = std::env::temp_dir().join("recstrap_test_device_nodes");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("dev")).unwrap();
+        // An existing entry is left alone, whatever it is
+        fs::write(temp.join("dev/console"), "").unwrap();
+
+        let (created, failed) = ensure_device_nodes(&temp);
+        if unsafe { libc::geteuid() } == 0 {
+            assert_eq!(created, vec!["/dev/null"]);
+            assert!(failed.is_empty());
+            let meta = fs::metadata(temp.join("dev/null")).unwrap();
+            assert!(meta.file_type().is_char_device());
+            assert_eq!(meta.rdev(), libc::makedev(1, 3));
+            assert_eq!(meta.permissions().mode() & 0o7777, 0o666);
+        } else {
+            assert_eq!(failed.len(), 1);
+        }
+        assert!(fs::metadata(temp.join("dev/console")).unwrap().is_file());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+}
@@ -0,0 +1,260 @@
+//! Rootfs flavors: several images on one live medium.
+//!
+//! An ISO may ship `filesystem-minimal.erofs` next to
+//! `filesystem-desktop.erofs`. A `flavors.toml` manifest beside them names
+//! each variant and which one is the default:
+//!
+//! ```toml
+//! default = "minimal"
+//!
+//! [[flavor]]
+//! name = "minimal"
+//! image = "filesystem-minimal.erofs"
+//! description = "Base system, no desktop"
+//! ```
+//!
+//! Without a manifest, `--flavor NAME` looks for `filesystem-NAME.erofs` in
+//! the directories of the standard search paths.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{ErrorCode, RecError, Result};
+use crate::toml::{self, Value};
+
+/// Manifest file name, next to the images.
+pub const FLAVOR_MANIFEST: &str = "flavors.toml";
+
+/// One installable variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flavor {
+    pub name: String,
+    /// Image path, resolved against the manifest's directory
+    pub image: PathBuf,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub default: Option<String>,
+    pub flavors: Vec<Flavor>,
+}
+
+impl Manifest {
+    /// Parse manifest content; image names are relative to `dir`.
+    pub fn parse(content: &str, dir: &Path) -> std::result::Result<Self, String> {
+        let doc = toml::parse(content)?;
+        let text = |value: Option<&Value>, what: &str| match value {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(format!("'{}' must be a string", what)),
+        };
+
+        let mut flavors: Vec<Flavor> = Vec::new();
+        for table in doc.array("flavor") {
+            let name = text(table.get("name"), "name")?.ok_or("[[flavor]] without 'name'")?;
+            let image = text(table.get("image"), "image")?
+                .ok_or_else(|| format!("flavor '{}' has no 'image'", name))?;
+            if Path::new(&image).is_absolute() || image.split('/').any(|c| c == "..") {
+                return Err(format!(
+                    "flavor '{}': image must be relative to the manifest",
+                    name
+                ));
+            }
+            if flavors.iter().any(|f| f.name == name) {
+                return Err(format!("flavor '{}' listed twice", name));
+            }
+            flavors.push(Flavor {
+                image: dir.join(&image),
+                description: text(table.get("description"), "description")?,
+                name,
+            });
+        }
+
+        let default = text(doc.root.get("default"), "default")?;
+        if let Some(name) = &default {
+            if !flavors.iter().any(|f| &f.name == name) {
+                return Err(format!("default flavor '{}' is not listed", name));
+            }
+        }
+        Ok(Self { default, flavors })
+    }
+
+    /// Read `dir`'s manifest; `Ok(None)` if there is none.
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(FLAVOR_MANIFEST);
+        let Ok(content) = fs::read_to_string(&path) else {
+            return Ok(None);
+        };
+        Self::parse(&content, dir).map(Some).map_err(|e| {
+            RecError::new(
+                ErrorCode::RootfsNotFound,
+                format!("invalid flavor manifest {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Flavor> {
+        self.flavors.iter().find(|f| f.name == name)
+    }
+}
+
+/// Directories holding the search-path images, in search order.
+fn live_dirs(search_paths: &[&str]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for dir in search_paths.iter().filter_map(|p| Path::new(p).parent()) {
+        if dir.is_dir() && !dirs.iter().any(|d| d == dir) {
+            dirs.push(dir.to_path_buf());
+        }
+    }
+    dirs
+}
+
+/// Image for flavor `name`: the first live directory whose manifest lists
+/// it, or that has a `filesystem-NAME.erofs` when it has no manifest.
+pub fn find_flavor(search_paths: &[&str], name: &str) -> Result<PathBuf> {
+    let mut available = Vec::new();
+    for dir in live_dirs(search_paths) {
+        match Manifest::load(&dir)? {
+            Some(manifest) => {
+                if let Some(flavor) = manifest.get(name) {
+                    return Ok(flavor.image.clone());
+                }
+                available.extend(manifest.flavors.into_iter().map(|f| f.name));
+            }
+            None => {
+                let image = dir.join(format!("filesystem-{}.erofs", name));
+                if image.is_file() {
+                    return Ok(image);
+                }
+                available.extend(unlisted_flavors(&dir));
+            }
+        }
+    }
+    available.sort();
+    available.dedup();
+    let hint = if available.is_empty() {
+        "no flavors on this medium".to_string()
+    } else {
+        format!("available: {}", available.join(", "))
+    };
+    Err(RecError::new(
+        ErrorCode::RootfsNotFound,
+        format!("rootfs flavor '{}' not found ({})", name, hint),
+    ))
+}
+
+/// The manifest's default image, from the first live directory that has
+/// a manifest with a default.
+pub fn default_flavor(search_paths: &[&str]) -> Result<Option<PathBuf>> {
+    for dir in live_dirs(search_paths) {
+        if let Some(manifest) = Manifest::load(&dir)? {
+            if let Some(flavor) = manifest.default.as_deref().and_then(|d| manifest.get(d)) {
+                return Ok(Some(flavor.image.clone()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Flavor names from `filesystem-NAME.erofs` files in `dir`.
+fn unlisted_flavors(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            let flavor = name.strip_prefix("filesystem-")?.strip_suffix(".erofs")?;
+            Some(flavor.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+default = "minimal"
+
+[[flavor]]
+name = "minimal"
+image = "filesystem-minimal.erofs"
+description = "Base system"
+
+[[flavor]]
+name = "desktop"
+image = "desktop/filesystem.erofs"
+"#;
+
+    #[test]
+    fn test_manifest_parse() {
+        let manifest = Manifest::parse(MANIFEST, Path::new("/live")).unwrap();
+        assert_eq!(manifest.default.as_deref(), Some("minimal"));
+        assert_eq!(
+            manifest.get("desktop").unwrap().image,
+            Path::new("/live/desktop/filesystem.erofs")
+        );
+        assert_eq!(
+            manifest.get("minimal").unwrap().description.as_deref(),
+            Some("Base system")
+        );
+        assert!(manifest.get("server").is_none());
+    }
+
+    #[test]
+    fn test_manifest_rejects_bad_entries() {
+        let dir = Path::new("/live");
+        let bad_default = "default = \"x\"\n[[flavor]]\nname = \"a\"\nimage = \"a.erofs\"\n";
+        assert!(Manifest::parse(bad_default, dir)
+            .unwrap_err()
+            .contains("not listed"));
+        let escape = "[[flavor]]\nname = \"a\"\nimage = \"../a.erofs\"\n";
+        assert!(Manifest::parse(escape, dir)
+            .unwrap_err()
+            .contains("relative"));
+        let no_image = "[[flavor]]\nname = \"a\"\n";
+        assert!(Manifest::parse(no_image, dir)
+            .unwrap_err()
+            .contains("image"));
+    }
+
+    #[test]
+    fn test_find_flavor() {
+        let temp = std::env::temp_dir().join("recstrap_test_find_flavor");
+        let _ = fs::remove_dir_all(&temp);
+        let plain = temp.join("plain");
+        let listed = temp.join("listed");
+        fs::create_dir_all(&plain).unwrap();
+        fs::create_dir_all(&listed).unwrap();
+        fs::write(plain.join("filesystem-server.erofs"), "").unwrap();
+        fs::write(listed.join(FLAVOR_MANIFEST), MANIFEST).unwrap();
+
+        let plain_path = plain.join("filesystem.erofs");
+        let listed_path = listed.join("filesystem.erofs");
+        let paths = [plain_path.to_str().unwrap(), listed_path.to_str().unwrap()];
+
+        assert_eq!(
+            find_flavor(&paths, "server").unwrap(),
+            plain.join("filesystem-server.erofs")
+        );
+        assert_eq!(
+            find_flavor(&paths, "desktop").unwrap(),
+            listed.join("desktop/filesystem.erofs")
+        );
+        let err = find_flavor(&paths, "kiosk").unwrap_err().to_string();
+        assert!(
+            err.contains("available: desktop, minimal, server"),
+            "{}",
+            err
+        );
+        assert_eq!(
+            default_flavor(&paths).unwrap(),
+            Some(listed.join("filesystem-minimal.erofs"))
+        );
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+}
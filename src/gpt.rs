@@ -0,0 +1,215 @@
+//! GPT partition type checks for the target.
+//!
+//! Mounting the ESP or a Windows partition on /mnt and installing there is
+//! a classic mistake. We read the type GUID of the partition backing the
+//! target straight from the disk's GPT (sysfs gives us the disk and the
+//! partition number) and compare it against the Linux data types.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// "EFI PART", at the start of the GPT header in LBA 1.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// Type GUIDs recstrap is happy to install onto: Linux data partitions
+/// (Discoverable Partitions Specification) and the containers that hold
+/// them.
+const LINUX_TYPES: &[&str] = &[
+    "0FC63DAF-8483-4772-8E79-3D69D8477DE4", // Linux filesystem
+    "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709", // Linux root (x86-64)
+    "B921B045-1DF0-41C3-AF44-4C6F280D3FAE", // Linux root (ARM64)
+    "933AC7E1-2EB4-4F13-B844-0E14E2AEF915", // Linux /home
+    "3B8F8425-20E0-4F3B-907F-1A25A76F98E8", // Linux /srv
+    "4D21B016-B534-45C2-A9FB-5C16E091FD2D", // Linux /var
+    "E6D6D379-F507-44C2-A23C-238F2A3DF928", // Linux LVM
+    "A19D880F-05FC-4D3B-A006-743F0F84911E", // Linux RAID
+    "CA7D7CCB-63ED-4C53-861C-1742536059CC", // Linux LUKS
+];
+
+/// Types that are certainly wrong, with a description the user will
+/// recognize.
+const KNOWN_TYPES: &[(&str, &str)] = &[
+    (
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+        "an EFI system partition",
+    ),
+    (
+        "BC13C2FF-59E6-4262-A352-B275FD6F7172",
+        "an extended boot loader partition",
+    ),
+    (
+        "21686148-6449-6E6F-744E-656564454649",
+        "a BIOS boot partition",
+    ),
+    (
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F",
+        "a Linux swap partition",
+    ),
+    (
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        "a Microsoft basic data partition (Windows)",
+    ),
+    (
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE",
+        "a Microsoft reserved partition",
+    ),
+    (
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC",
+        "a Windows recovery partition",
+    ),
+    (
+        "7C3457EF-0000-11AA-AA11-00306543ECAC",
+        "an Apple APFS container",
+    ),
+];
+
+/// Format a GUID as stored on disk (first three fields little-endian).
+pub fn format_guid(raw: &[u8; 16]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
+        u16::from_le_bytes([raw[4], raw[5]]),
+        u16::from_le_bytes([raw[6], raw[7]]),
+        raw[8],
+        raw[9],
+        raw[10..]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>()
+    )
+}
+
+/// What's wrong with a partition of type `guid` as an install target;
+/// `None` for Linux data types.
+pub fn type_problem(guid: &str) -> Option<String> {
+    let guid = guid.to_ascii_uppercase();
+    if LINUX_TYPES.contains(&guid.as_str()) {
+        return None;
+    }
+    Some(match KNOWN_TYPES.iter().find(|(g, _)| *g == guid) {
+        Some((_, name)) => format!("is {}", name),
+        None => format!("has GPT type {}, not a Linux filesystem type", guid),
+    })
+}
+
+/// Type GUID of `device`'s GPT entry. `Ok(None)` when it isn't a
+/// partition (LVM, dm-crypt, whole disk) or the disk uses an MBR.
+pub fn partition_type(device: &Path) -> io::Result<Option<String>> {
+    let device = device.canonicalize()?;
+    let Some(name) = device.file_name() else {
+        return Ok(None);
+    };
+    let sys = Path::new("/sys/class/block").join(name);
+    let Ok(number) = fs::read_to_string(sys.join("partition")) else {
+        return Ok(None);
+    };
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::other("bad partition number in sysfs"))?;
+    // The partition's sysfs directory sits inside its disk's
+    let disk_sys = sys.canonicalize()?.parent().map(Path::to_path_buf);
+    let Some(disk_name) = disk_sys.as_deref().and_then(Path::file_name) else {
+        return Ok(None);
+    };
+    let sector_size: u64 = fs::read_to_string(
+        Path::new("/sys/class/block")
+            .join(disk_name)
+            .join("queue/logical_block_size"),
+    )
+    .ok()
+    .and_then(|s| s.trim().parse().ok())
+    .unwrap_or(512);
+
+    let mut disk = File::open(PathBuf::from("/dev").join(disk_name))?;
+    read_type_guid(&mut disk, sector_size, number)
+}
+
+/// Read entry `number` (1-based) from the GPT on `disk`.
+fn read_type_guid<R: Read + Seek>(
+    disk: &mut R,
+    sector_size: u64,
+    number: u64,
+) -> io::Result<Option<String>> {
+    let mut header = [0u8; 92];
+    disk.seek(SeekFrom::Start(sector_size))?;
+    disk.read_exact(&mut header)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count = u32::from_le_bytes(header[80..84].try_into().unwrap());
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap());
+    if number == 0 || number > u64::from(entry_count) || entry_size < 16 {
+        return Ok(None);
+    }
+
+    let mut guid = [0u8; 16];
+    disk.seek(SeekFrom::Start(
+        entries_lba * sector_size + (number - 1) * u64::from(entry_size),
+    ))?;
+    disk.read_exact(&mut guid)?;
+    Ok(Some(format_guid(&guid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// On-disk bytes of the EFI system partition type GUID.
+    const ESP_RAW: [u8; 16] = [
+        0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9,
+        0x3B,
+    ];
+
+    #[test]
+    fn test_format_guid() {
+        assert_eq!(
+            format_guid(&ESP_RAW),
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        );
+    }
+
+    #[test]
+    fn test_type_problem() {
+        assert_eq!(type_problem("0fc63daf-8483-4772-8e79-3d69d8477de4"), None);
+        assert_eq!(
+            type_problem("C12A7328-F81F-11D2-BA4B-00A0C93EC93B").as_deref(),
+            Some("is an EFI system partition")
+        );
+        assert_eq!(
+            type_problem("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7").as_deref(),
+            Some("is a Microsoft basic data partition (Windows)")
+        );
+        assert!(type_problem("00000000-0000-0000-0000-000000000001")
+            .unwrap()
+            .contains("not a Linux filesystem type"));
+    }
+
+    #[test]
+    fn test_read_type_guid() {
+        // LBA 0 protective MBR, LBA 1 header, entries from LBA 2
+        let mut disk = vec![0u8; 512 * 4];
+        disk[512..520].copy_from_slice(GPT_SIGNATURE);
+        disk[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+        disk[512 + 80..512 + 84].copy_from_slice(&128u32.to_le_bytes());
+        disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        disk[1024 + 128..1024 + 144].copy_from_slice(&ESP_RAW);
+
+        let mut cursor = Cursor::new(disk.clone());
+        assert_eq!(
+            read_type_guid(&mut cursor, 512, 2).unwrap().as_deref(),
+            Some("C12A7328-F81F-11D2-BA4B-00A0C93EC93B")
+        );
+        assert_eq!(read_type_guid(&mut cursor, 512, 0).unwrap(), None);
+        assert_eq!(read_type_guid(&mut cursor, 512, 129).unwrap(), None);
+
+        disk[512] = 0;
+        assert_eq!(
+            read_type_guid(&mut Cursor::new(disk), 512, 2).unwrap(),
+            None
+        );
+    }
+}
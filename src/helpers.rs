@@ -3,19 +3,32 @@
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
-use std::process::{Command, Stdio};
-
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::accounts::{
+    create_user, hash_password, valid_account_name, write_sudoers, InitialUser, NewUser,
+    SUDOERS_DROP_IN,
+};
 use crate::constants::ROOTFS_SEARCH_PATHS;
+use crate::context;
+use crate::rootfs::split_parts;
+use crate::runner;
+use crate::sandbox;
+use crate::warnings::warn;
 
 // Re-export from distro-spec (single source of truth)
 pub use distro_spec::shared::{is_mount_point, is_protected_path, is_root};
 
-/// Find rootfs from canonical EROFS search paths.
+/// Find rootfs from canonical EROFS search paths, whole or split into
+/// parts.
 pub fn find_rootfs() -> Option<&'static str> {
     ROOTFS_SEARCH_PATHS
         .iter()
-        .find(|path| Path::new(path).exists())
+        .find(|path| Path::new(path).exists() || split_parts(Path::new(path)).is_some())
         .copied()
 }
 
@@ -25,16 +38,83 @@ pub fn find_rootfs() -> Option<&'static str> {
 /// - .recstrap_write_test (leftover from interrupted write permission check)
 pub fn is_dir_empty(path: &Path) -> std::io::Result<bool> {
     for entry in path.read_dir()? {
-        let entry = entry?;
-        let name = entry.file_name();
-        // Ignore filesystem artifacts and our own test files
-        if name != "lost+found" && name != ".recstrap_write_test" {
+        if !is_ignored_entry(&entry?.file_name()) {
             return Ok(false);
         }
     }
     Ok(true)
 }
 
+/// Filesystem artifacts and our own test files, which don't make a target
+/// non-empty.
+pub fn is_ignored_entry(name: &std::ffi::OsStr) -> bool {
+    name == "lost+found" || name == ".recstrap_write_test"
+}
+
+/// What a non-empty target holds: its top-level entries as
+/// [`is_dir_empty`] counts them (sorted, directories with a trailing `/`)
+/// and the bytes of regular files below it. Symlinks aren't followed and
+/// filesystems mounted inside aren't entered.
+pub fn target_contents(path: &Path) -> std::io::Result<(Vec<String>, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    fn size_below(path: &Path, dev: u64) -> u64 {
+        let Ok(entries) = path.read_dir() else {
+            return 0;
+        };
+        entries
+            .flatten()
+            .map(|entry| match entry.metadata() {
+                Ok(meta) if meta.is_file() => meta.len(),
+                Ok(meta) if meta.is_dir() && meta.dev() == dev => size_below(&entry.path(), dev),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    let dev = fs::metadata(path)?.dev();
+    let mut entries = Vec::new();
+    for entry in path.read_dir()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if is_ignored_entry(&name) {
+            continue;
+        }
+        let suffix = if entry.file_type()?.is_dir() { "/" } else { "" };
+        entries.push(format!("{}{}", name.to_string_lossy(), suffix));
+    }
+    entries.sort();
+    Ok((entries, size_below(path, dev)))
+}
+
+/// Show what `--force` is about to overwrite in `target` and ask for
+/// "yes" on the terminal. Returns whether the user typed it.
+pub fn confirm_overwrite(target: &Path) -> std::io::Result<bool> {
+    const SHOWN: usize = 10;
+
+    let (entries, bytes) = target_contents(target)?;
+    eprintln!();
+    eprintln!(
+        "{} is not empty: {} entries, {} MB of files",
+        target.display(),
+        entries.len(),
+        bytes / (1024 * 1024)
+    );
+    for entry in entries.iter().take(SHOWN) {
+        eprintln!("  {}", entry);
+    }
+    if entries.len() > SHOWN {
+        eprintln!("  ... and {} more", entries.len() - SHOWN);
+    }
+    eprintln!("Files the image also contains will be overwritten.");
+    eprint!("Type yes to continue: ");
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
 // Note: is_mount_point() is now in distro-spec::shared::system (single source of truth)
 // Re-exported above from distro_spec::shared::is_mount_point
 
@@ -60,6 +140,26 @@ pub fn get_available_space(path: &Path) -> std::io::Result<u64> {
     Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
 }
 
+/// Get free inodes on filesystem containing path.
+///
+/// Returns `None` for filesystems that allocate inodes dynamically (btrfs
+/// reports a total of zero) and so cannot run out of them.
+#[allow(clippy::unnecessary_cast)] // Cast needed - types vary by platform
+pub fn get_available_inodes(path: &Path) -> std::io::Result<Option<u64>> {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let c_path = path_to_cstring(path)?;
+
+    let ret = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    if stat.f_files == 0 {
+        return Ok(None);
+    }
+    Ok(Some(stat.f_favail as u64))
+}
+
 /// Check if rootfs path is inside target directory
 pub fn is_rootfs_inside_target(rootfs: &Path, target: &Path) -> bool {
     rootfs.starts_with(target)
@@ -93,24 +193,351 @@ pub fn ensure_erofs_module() -> bool {
     }
 
     // Try to load the module (requires root, which we already checked)
-    let _ = Command::new("modprobe")
-        .arg("erofs")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status();
+    let _ = output_with_timeout(
+        Command::new("modprobe")
+            .arg("erofs")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+    );
 
     // Check again
     erofs_supported()
 }
 
+/// Running kernel release string (`uname -r`), e.g. "6.12.9-levitate".
+pub fn kernel_release() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) };
+    Some(release.to_string_lossy().into_owned())
+}
+
+/// Running kernel version as (major, minor).
+pub fn kernel_version() -> Option<(u32, u32)> {
+    parse_kernel_version(&kernel_release()?)
+}
+
+/// Parse "major.minor" from a kernel release string like "6.12.9-levitate".
+pub fn parse_kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Format a Unix timestamp as an RFC 3339 UTC string ("2026-01-31T12:00:00Z").
+pub fn format_utc(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil-from-days (Howard Hinnant's algorithm), valid for all u64 days we care about
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        (rem % 3_600) / 60,
+        rem % 60
+    )
+}
+
+/// Resolve `path` inside `root` as if `root` were `/`, like a chroot would.
+///
+/// Symlinks in intermediate components are followed relative to `root`
+/// (absolute link targets never escape it); the final component is not
+/// followed, so a symlink resolves to itself. `..` stops at `root`.
+pub fn resolve_in_root(root: &Path, path: &Path) -> std::io::Result<PathBuf> {
+    use std::path::Component;
+
+    // Components still to resolve, in reverse so we can push link targets.
+    let mut pending: Vec<PathBuf> = path
+        .components()
+        .rev()
+        .filter_map(|c| match c {
+            Component::Normal(n) => Some(PathBuf::from(n)),
+            Component::ParentDir => Some(PathBuf::from("..")),
+            _ => None,
+        })
+        .collect();
+    let mut resolved = PathBuf::new();
+    let mut links_followed = 0;
+
+    while let Some(part) = pending.pop() {
+        if part == Path::new("..") {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&part);
+        if pending.is_empty() {
+            resolved = candidate;
+            break;
+        }
+        let meta = fs::symlink_metadata(root.join(&candidate))?;
+        if !meta.file_type().is_symlink() {
+            resolved = candidate;
+            continue;
+        }
+
+        links_followed += 1;
+        if links_followed > 40 {
+            return Err(std::io::Error::from_raw_os_error(libc::ELOOP));
+        }
+        let target = fs::read_link(root.join(&candidate))?;
+        if target.is_absolute() {
+            resolved = PathBuf::new();
+        }
+        for c in target.components().rev() {
+            match c {
+                Component::Normal(n) => pending.push(PathBuf::from(n)),
+                Component::ParentDir => pending.push(PathBuf::from("..")),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(root.join(resolved))
+}
+
+/// Like [`resolve_in_root`], but also follows a symlink in the final
+/// component, so the result is what the chrooted system would open.
+pub fn follow_in_root(root: &Path, path: &Path) -> std::io::Result<PathBuf> {
+    let mut rel = path.to_path_buf();
+    for _ in 0..40 {
+        let resolved = resolve_in_root(root, &rel)?;
+        let meta = fs::symlink_metadata(&resolved)?;
+        if !meta.file_type().is_symlink() {
+            return Ok(resolved);
+        }
+        let link = fs::read_link(&resolved)?;
+        let parent = resolved
+            .strip_prefix(root)
+            .ok()
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        rel = if link.is_absolute() {
+            link
+        } else {
+            parent.join(link)
+        };
+    }
+    Err(std::io::Error::from_raw_os_error(libc::ELOOP))
+}
+
+/// Create a fresh, uniquely named directory (mode 0700) inside `parent`,
+/// like mkdtemp(3). The name is `prefix` followed by six random characters.
+pub fn make_temp_dir(parent: &Path, prefix: &str) -> std::io::Result<PathBuf> {
+    let template = parent.join(format!("{}XXXXXX", prefix));
+    let mut bytes = path_to_cstring(&template)?.into_bytes_with_nul();
+    let ret = unsafe { libc::mkdtemp(bytes.as_mut_ptr() as *mut libc::c_char) };
+    if ret.is_null() {
+        return Err(std::io::Error::last_os_error());
+    }
+    bytes.pop(); // trailing NUL
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(&bytes)))
+}
+
+/// Set by the signal handler installed by [`InterruptGuard`].
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// RAII guard that turns SIGINT/SIGTERM/SIGHUP into a cancellation flag.
+///
+/// While the guard is alive, those signals no longer kill the process - they
+/// set a flag that long-running work (the copier) polls through
+/// [`InterruptGuard::interrupted`], so it can stop and let MountGuard clean
+/// up. Default signal dispositions are restored on drop. Signals are only
+/// taken over when the install's context asks for it (the command line);
+/// an embedded install is stopped through its cancellation token alone.
+pub struct InterruptGuard {
+    previous: Vec<(libc::c_int, libc::sighandler_t)>,
+}
+
+impl InterruptGuard {
+    pub fn install() -> Self {
+        if !context::with(|c| c.signals) {
+            return Self {
+                previous: Vec::new(),
+            };
+        }
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let handler = handle_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let previous = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP]
+            .iter()
+            .map(|&sig| (sig, unsafe { libc::signal(sig, handler) }))
+            .collect();
+        Self { previous }
+    }
+
+    /// Whether a signal arrived or the install was cancelled.
+    pub fn interrupted(&self) -> bool {
+        (!self.previous.is_empty() && INTERRUPTED.load(Ordering::SeqCst)) || context::cancelled()
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        for &(sig, handler) in &self.previous {
+            unsafe { libc::signal(sig, handler) };
+        }
+    }
+}
+
+/// Default for `--retries`.
+pub const DEFAULT_IO_RETRIES: u32 = 3;
+
+/// Delay before the first retry; doubled for each further attempt.
+const RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+/// Backoff before retry number `attempt` (0-based): 0.5s, 1s, 2s, ...
+pub fn retry_delay(attempt: u32) -> Duration {
+    RETRY_INITIAL_DELAY * 2u32.saturating_pow(attempt.min(6))
+}
+
+/// Errors worth retrying on flaky media: the device was busy, asked us to
+/// try again, or (optical/USB) returned a one-off read error.
+pub fn is_transient_errno(errno: i32) -> bool {
+    matches!(
+        errno,
+        libc::EAGAIN | libc::EBUSY | libc::EINTR | libc::EIO | libc::ETIMEDOUT
+    )
+}
+
+/// Run `op`, retrying with exponential backoff while it fails with an
+/// error `is_transient` accepts, up to the `--retries` count. `what` names
+/// the operation in the warning printed before each retry.
+pub fn retry_transient<T, E: std::fmt::Display>(
+    what: &str,
+    quiet: bool,
+    is_transient: impl Fn(&E) -> bool,
+    mut op: impl FnMut() -> std::result::Result<T, E>,
+) -> std::result::Result<T, E> {
+    let retries = context::with(|c| c.retries);
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let delay = retry_delay(attempt);
+                warn(
+                    quiet,
+                    &format!(
+                        "{} failed ({}), retrying in {:.1}s ({}/{})",
+                        what,
+                        e,
+                        delay.as_secs_f64(),
+                        attempt + 1,
+                        retries
+                    ),
+                    &[],
+                );
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Default for `--command-timeout`.
+pub const DEFAULT_COMMAND_TIMEOUT_SECS: u64 = 300;
+
+/// Seconds a helper command (mount, modprobe, umount) may run before it
+/// is killed, from the install's context; 0 waits forever.
+pub fn command_timeout_secs() -> u64 {
+    context::with(|c| c.command_timeout)
+}
+
+/// Like [`Command::output`], but kill the child once `--command-timeout`
+/// has passed and fail with [`std::io::ErrorKind::TimedOut`], so wedged
+/// USB media can't hang recstrap forever. Streams the caller didn't pipe
+/// stay inherited and come back empty.
+pub fn output_with_timeout(cmd: &mut Command) -> std::io::Result<Output> {
+    runner::output_timed(cmd, command_timeout_secs())
+}
+
+/// [`output_with_timeout`] with an explicit timeout (0: none), always
+/// running the command for real.
+pub fn output_within(cmd: &mut Command, secs: u64) -> std::io::Result<Output> {
+    let mut child = cmd.spawn()?;
+    // Drain pipes concurrently so a chatty child can't block on a full pipe
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        pipe.map(|mut p| {
+            std::thread::spawn(move || {
+                let mut buf = Vec::new();
+                let _ = p.read_to_end(&mut buf);
+                buf
+            })
+        })
+    };
+    let stdout = drain(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = drain(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let deadline = (secs > 0).then(|| Instant::now() + Duration::from_secs(secs));
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            let _ = child.kill();
+            let _ = child.wait();
+            // Readers are left behind: a grandchild may still hold the pipes
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("killed after {}s", secs),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    let collect = |reader: Option<std::thread::JoinHandle<Vec<u8>>>| {
+        reader.and_then(|r| r.join().ok()).unwrap_or_default()
+    };
+    Ok(Output {
+        status,
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    })
+}
+
+/// Flush everything written to the filesystem holding `path` (syncfs(2)),
+/// including directory entries, so a power-off right after doesn't lose it.
+pub fn sync_filesystem(path: &Path) -> std::io::Result<()> {
+    let dir = File::open(path)?;
+    if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 /// Check if ssh-keygen is available
 pub fn ssh_keygen_available() -> bool {
-    Command::new("ssh-keygen")
-        .arg("--help")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .is_ok()
+    tool_available("ssh-keygen")
+}
+
+/// Check if an external tool can be executed (exists in PATH)
+pub fn tool_available(tool: &str) -> bool {
+    runner::status(
+        Command::new(tool)
+            .arg("--help")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+    )
+    .is_ok()
 }
 
 /// Regenerate SSH host keys in the target system.
@@ -130,18 +557,21 @@ pub fn regenerate_ssh_host_keys(target: &Path, quiet: bool) -> std::io::Result<(
 
     // Skip if /etc/ssh doesn't exist (unusual, but handle gracefully)
     if !ssh_dir.is_dir() {
-        if !quiet {
-            eprintln!("recstrap: warning: /etc/ssh not found, skipping SSH key regeneration");
-        }
+        warn(
+            quiet,
+            "/etc/ssh not found, skipping SSH key regeneration",
+            &[],
+        );
         return Ok(());
     }
 
     // Check if ssh-keygen is available
     if !ssh_keygen_available() {
-        if !quiet {
-            eprintln!("recstrap: warning: ssh-keygen not found, skipping SSH key regeneration");
-            eprintln!("         (installed system will use shared keys - regenerate manually!)");
-        }
+        warn(
+            quiet,
+            "ssh-keygen not found, skipping SSH key regeneration",
+            &["(installed system will use shared keys - regenerate manually!)"],
+        );
         return Ok(());
     }
 
@@ -168,8 +598,9 @@ pub fn regenerate_ssh_host_keys(target: &Path, quiet: bool) -> std::io::Result<(
         if bits > 0 {
             cmd.arg("-b").arg(bits.to_string());
         }
+        sandbox::confine(&mut cmd);
 
-        let status = cmd.status()?;
+        let status = runner::status(&mut cmd)?;
         if !status.success() {
             return Err(std::io::Error::other(format!(
                 "ssh-keygen failed for {} key",
@@ -193,18 +624,96 @@ pub fn regenerate_ssh_host_keys(target: &Path, quiet: bool) -> std::io::Result<(
     Ok(())
 }
 
+/// Read a line from stdin without echoing it, for passwords. Echo is only
+/// turned off when stdin is a terminal; piped input is read as it comes.
+fn read_secret(prompt: &str) -> std::io::Result<String> {
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+
+    let stdin = std::io::stdin();
+    let fd = stdin.as_raw_fd();
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    let is_tty = unsafe { libc::tcgetattr(fd, &mut saved) } == 0;
+    if is_tty {
+        let mut quiet = saved;
+        quiet.c_lflag &= !(libc::ECHO | libc::ECHONL);
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &quiet) };
+    }
+    let mut line = String::new();
+    let read = stdin.read_line(&mut line);
+    if is_tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &saved) };
+        // The Enter key wasn't echoed either
+        eprintln!();
+    }
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Ask for a new password twice with `read`, giving up after three
+/// mismatches. Returns None if the user gave up or entered nothing.
+fn read_new_password(
+    username: &str,
+    mut read: impl FnMut(&str) -> std::io::Result<String>,
+) -> std::io::Result<Option<String>> {
+    const ATTEMPTS: usize = 3;
+
+    for _ in 0..ATTEMPTS {
+        let password = read(&format!("Password for {}: ", username))?;
+        if password.is_empty() {
+            eprintln!("Password cannot be empty.");
+            return Ok(None);
+        }
+        if read("Retype password: ")? == password {
+            return Ok(Some(password));
+        }
+        eprintln!("Passwords do not match, try again.");
+    }
+    Ok(None)
+}
+
+/// Read a crypt(3) password hash from file descriptor `fd` (e.g. `3<hash`
+/// in a script), so the password never appears on a terminal or command
+/// line. A trailing newline is dropped.
+pub fn read_password_hash(fd: i32) -> std::io::Result<String> {
+    use std::os::fd::FromRawFd;
+
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(std::io::Error::other(format!(
+            "{} is not an open file descriptor",
+            fd
+        )));
+    }
+    let mut content = String::new();
+    unsafe { File::from_raw_fd(fd) }.read_to_string(&mut content)?;
+    let hash = content.strip_suffix('\n').unwrap_or(&content);
+    if !valid_password_hash(hash) {
+        return Err(std::io::Error::other(
+            "expected a single crypt(3) hash, e.g. from 'openssl passwd -6'",
+        ));
+    }
+    Ok(hash.to_string())
+}
+
+/// Whether `hash` can go into the password field of /etc/shadow.
+pub fn valid_password_hash(hash: &str) -> bool {
+    !hash.is_empty() && !hash.contains([':', '\n'])
+}
+
 /// Interactively prompt for creating an initial user account.
 ///
 /// This implements Option A from the installation plan: prompts for initial user
-/// creation before chrooting. If accepted, creates user and adds to wheel group
-/// for passwordless sudo access.
+/// creation before chrooting. If accepted, creates the user directly in the
+/// target's account files (see [`crate::accounts`]) and adds it to the wheel
+/// group for sudo access. The password is read without echo and confirmed,
+/// unless `options` has a hash from `--password-hash-fd`. With `--sudo`,
+/// wheel's rights come from our own sudoers drop-in.
 ///
 /// Returns Ok if operation completed (user created or skipped), Err if something failed.
-pub fn prompt_for_user_creation(target: &Path) -> std::io::Result<()> {
-    // Check if we can write to target
-    let root_dir = target.join("root");
-    if !root_dir.exists() {
-        return Ok(()); // root dir doesn't exist yet, skip
+pub fn prompt_for_user_creation(target: &Path, options: &InitialUser) -> std::io::Result<()> {
+    // Nothing to add a user to, e.g. a partial image
+    if !target.join("etc/passwd").exists() {
+        return Ok(());
     }
 
     eprintln!();
@@ -233,68 +742,208 @@ pub fn prompt_for_user_creation(target: &Path) -> std::io::Result<()> {
     std::io::stdin().read_line(&mut username)?;
     let username = username.trim();
 
-    if username.is_empty() {
-        eprintln!("Invalid username. Skipping user creation.");
+    if !valid_account_name(username) {
+        eprintln!(
+            "Invalid username (lowercase letters, digits, '_' and '-'). Skipping user creation."
+        );
         return Ok(());
     }
 
-    if !username
-        .chars()
-        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
-    {
-        eprintln!("Username contains invalid characters. Skipping user creation.");
-        return Ok(());
+    let password_hash = match options.password_hash {
+        Some(hash) => hash.to_string(),
+        None => match read_new_password(username, read_secret)? {
+            Some(password) => hash_password(&password)?,
+            None => {
+                eprintln!("No password set. Skipping user creation.");
+                return Ok(());
+            }
+        },
+    };
+    let shell = if follow_in_root(target, Path::new("/bin/bash")).is_ok_and(|p| p.is_file()) {
+        "/bin/bash"
+    } else {
+        "/bin/sh"
+    };
+
+    create_user(
+        target,
+        &NewUser {
+            name: username,
+            password_hash: &password_hash,
+            groups: &["wheel"],
+            shell,
+            skel: options.skel,
+        },
+    )?;
+    eprintln!("Created user {} (member of wheel).", username);
+    if let Some(policy) = options.sudo {
+        if let Some(problem) = write_sudoers(target, policy)? {
+            warn(false, &problem, &[]);
+        }
+        eprintln!("Wrote {} for wheel.", SUDOERS_DROP_IN);
     }
+    Ok(())
+}
 
-    // Prompt for password
-    eprint!("Password for {}: ", username);
-    std::io::stderr().flush()?;
-    let mut password = String::new();
-    std::io::stdin().read_line(&mut password)?;
-    let password = password.trim();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if password.is_empty() {
-        eprintln!("Password cannot be empty. Skipping user creation.");
-        return Ok(());
+    #[test]
+    fn test_resolve_in_root_stays_inside() {
+        let root = std::env::temp_dir().join("recstrap_test_resolve_root");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        std::os::unix::fs::symlink("usr/lib", root.join("lib")).unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("etc-abs")).unwrap();
+        std::os::unix::fs::symlink("/nowhere", root.join("etc/localtime")).unwrap();
+
+        let r = |p: &str| resolve_in_root(&root, Path::new(p)).unwrap();
+        assert_eq!(r("/lib/libc.so"), root.join("usr/lib/libc.so"));
+        assert_eq!(r("/etc-abs/passwd"), root.join("etc/passwd"));
+        assert_eq!(r("/../../etc/passwd"), root.join("etc/passwd"));
+        // Final component is not followed
+        assert_eq!(r("/etc/localtime"), root.join("etc/localtime"));
+
+        let _ = fs::remove_dir_all(&root);
     }
 
-    // Create a temporary script to run useradd and set password in chroot
-    // We can't useradd directly because the target root doesn't have /etc/passwd etc. yet
-    // Instead, we'll have the user run it in chroot
-
-    let script_path = root_dir.join("setup-initial-user.sh");
-    let script_content = format!(
-        "#!/bin/bash\n\
-         set -e\n\
-         echo 'Creating user: {}'\n\
-         useradd -m -s /bin/bash -G wheel '{}'\n\
-         echo 'Setting password for {}...'\n\
-         echo '{}:{}' | chpasswd\n\
-         echo 'User setup complete!'\n\
-         echo 'You can now logout and login as {}'\n",
-        username, username, username, username, password, username
-    );
+    #[test]
+    fn test_follow_in_root_final_component() {
+        let root = std::env::temp_dir().join("recstrap_test_follow_root");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/bash"), "").unwrap();
+        std::os::unix::fs::symlink("bash", root.join("usr/bin/sh")).unwrap();
+        std::os::unix::fs::symlink("/usr/bin/sh", root.join("usr/bin/abs-sh")).unwrap();
+        std::os::unix::fs::symlink("usr/bin", root.join("bin")).unwrap();
+
+        let f = |p: &str| follow_in_root(&root, Path::new(p)).unwrap();
+        assert_eq!(f("usr/bin/sh"), root.join("usr/bin/bash"));
+        assert_eq!(f("usr/bin/abs-sh"), root.join("usr/bin/bash"));
+        assert_eq!(f("bin"), root.join("usr/bin"));
+
+        let _ = fs::remove_dir_all(&root);
+    }
 
-    fs::write(&script_path, &script_content)?;
+    #[test]
+    fn test_read_new_password() {
+        let answers = |list: &'static [&'static str]| {
+            let mut list = list.iter();
+            move |_: &str| Ok(list.next().unwrap().to_string())
+        };
+        assert_eq!(
+            read_new_password("alice", answers(&["s3cret", "s3cret"])).unwrap(),
+            Some("s3cret".to_string())
+        );
+        // A typo in the confirmation asks again
+        assert_eq!(
+            read_new_password("alice", answers(&["s3cret", "s3cert", "s3cret", "s3cret"])).unwrap(),
+            Some("s3cret".to_string())
+        );
+        assert_eq!(read_new_password("alice", answers(&[""])).unwrap(), None);
+        assert_eq!(
+            read_new_password("alice", answers(&["a", "b", "a", "b", "a", "b"])).unwrap(),
+            None
+        );
+    }
 
-    // Make it executable
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755))?;
+    #[test]
+    fn test_read_password_hash() {
+        let pipe = |content: &str| {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            let mut write = unsafe { <File as std::os::fd::FromRawFd>::from_raw_fd(fds[1]) };
+            write.write_all(content.as_bytes()).unwrap();
+            fds[0]
+        };
+        assert_eq!(
+            read_password_hash(pipe("$6$salt$hash\n")).unwrap(),
+            "$6$salt$hash"
+        );
+        assert!(read_password_hash(pipe("")).is_err());
+        assert!(read_password_hash(pipe("$6$a\n$6$b\n")).is_err());
+        assert!(read_password_hash(pipe("alice:$6$salt$hash")).is_err());
+        assert!(read_password_hash(-1).is_err());
     }
 
-    eprintln!();
-    eprintln!("User setup script created at: /root/setup-initial-user.sh");
-    eprintln!("Run this in chroot: bash /root/setup-initial-user.sh");
-    eprintln!();
+    #[test]
+    fn test_retry_delay_backoff() {
+        assert_eq!(retry_delay(0), Duration::from_millis(500));
+        assert_eq!(retry_delay(2), Duration::from_secs(2));
+        // Capped so a large --retries doesn't sleep for hours
+        assert_eq!(retry_delay(20), retry_delay(6));
+    }
 
-    Ok(())
-}
+    #[test]
+    fn test_retry_transient_stops_on_fatal_error() {
+        let mut calls = 0;
+        let result: std::result::Result<(), String> = retry_transient(
+            "test",
+            true,
+            |e: &String| e == "busy",
+            || {
+                calls += 1;
+                Err("fatal".to_string())
+            },
+        );
+        assert_eq!(result, Err("fatal".to_string()));
+        assert_eq!(calls, 1);
+        assert!(is_transient_errno(libc::EBUSY));
+        assert!(!is_transient_errno(libc::ENOENT));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_output_within_kills_on_timeout() {
+        let started = Instant::now();
+        let err = output_within(Command::new("sleep").arg("30"), 1).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let output = output_within(
+            Command::new("sh")
+                .args(["-c", "echo out; echo err >&2; exit 3"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+            0,
+        )
+        .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+    }
+
+    #[test]
+    fn test_ensure_erofs_module_runs_modprobe() {
+        let (fake, _runner) = crate::runner::FakeRunner::install();
+        let loaded = erofs_supported();
+        ensure_erofs_module();
+        let expected: &[&str] = if loaded { &[] } else { &["modprobe erofs"] };
+        assert_eq!(fake.calls(), expected);
+    }
+
+    #[test]
+    fn test_sync_filesystem() {
+        sync_filesystem(&std::env::temp_dir()).unwrap();
+        assert!(sync_filesystem(Path::new("/nonexistent/recstrap")).is_err());
+    }
+
+    #[test]
+    fn test_make_temp_dir_unique() {
+        let parent = std::env::temp_dir();
+        let a = make_temp_dir(&parent, "recstrap_test_mkdtemp-").unwrap();
+        let b = make_temp_dir(&parent, "recstrap_test_mkdtemp-").unwrap();
+        assert_ne!(a, b);
+        assert!(a.is_dir() && b.is_dir());
+        assert!(a
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("recstrap_test_mkdtemp-"));
+        let _ = fs::remove_dir(&a);
+        let _ = fs::remove_dir(&b);
+    }
 
     #[test]
     fn test_is_mount_point_root() {
@@ -362,6 +1011,23 @@ mod tests {
         assert_eq!(result.unwrap().as_bytes(), b"/tmp/test");
     }
 
+    #[test]
+    fn test_target_contents() {
+        let temp = std::env::temp_dir().join("recstrap_test_target_contents");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("home/user")).unwrap();
+        fs::create_dir(temp.join("lost+found")).unwrap();
+        fs::write(temp.join("home/user/notes"), vec![0u8; 3000]).unwrap();
+        fs::write(temp.join("disk.img"), vec![0u8; 1000]).unwrap();
+        std::os::unix::fs::symlink("/usr", temp.join("usr")).unwrap();
+
+        let (entries, bytes) = target_contents(&temp).unwrap();
+        assert_eq!(entries, ["disk.img", "home/", "usr"]);
+        assert_eq!(bytes, 4000);
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
     #[test]
     fn test_is_dir_empty_with_lost_found() {
         // Create temp dir with lost+found - should be considered empty
@@ -437,6 +1103,22 @@ mod tests {
         let _ = fs::remove_dir_all(&temp);
     }
 
+    #[test]
+    fn test_parse_kernel_version() {
+        assert_eq!(parse_kernel_version("6.12.9-levitate"), Some((6, 12)));
+        assert_eq!(parse_kernel_version("5.4.0"), Some((5, 4)));
+        assert_eq!(parse_kernel_version("6.1"), Some((6, 1)));
+        assert_eq!(parse_kernel_version("garbage"), None);
+        assert!(kernel_version().is_some());
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_utc(1_767_225_599), "2025-12-31T23:59:59Z");
+    }
+
     #[test]
     fn test_erofs_supported_checks_proc_filesystems() {
         // This test just verifies the function runs without panic
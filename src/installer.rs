@@ -0,0 +1,364 @@
+//! Library entry point for embedding recstrap.
+//!
+//! [`Installer`] runs the install phases of the command line directly -
+//! pre-flight checks, extraction, verification and configuration, each a
+//! function of its own in `pipeline.rs` - so an embedded install gets
+//! every check, the verification and the install record without shelling
+//! out to the binary. The options fill in an [`Args`] that the phases read
+//! (the command line's defaults otherwise; `RECSTRAP_*` environment
+//! variables are not consulted), in a [`Context`] of the install's own:
+//! its settings and its cancellation don't reach other installs in the
+//! same process, and the embedding program keeps its signal handlers.
+//! What only the command line does - prompts, the heartbeat, the closing
+//! instructions - is left out.
+//!
+//! ```no_run
+//! use recstrap::{CancellationToken, ExtractOptions, Installer, VerifyLevel};
+//!
+//! let cancel = CancellationToken::new();
+//! let options = ExtractOptions {
+//!     verify: VerifyLevel::Sample,
+//!     cancel: Some(cancel.clone()),
+//!     ..Default::default()
+//! }
+//! .on_progress(|p| eprintln!("{}: {} MB", p.phase, p.bytes >> 20));
+//! let installer = Installer::new("/mnt").with_options(options);
+//! let report = installer.preflight();
+//! if report.passed() {
+//!     let stats = installer.install().unwrap();
+//!     println!("{} files installed", stats.files);
+//! }
+//! ```
+//!
+//! Cancelling stops the copy (or verification) at the next file. The image
+//! is always unmounted; a partial extraction stays recorded in the target
+//! for `recstrap clean`, as after Ctrl-C on the command line.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use clap::ValueEnum;
+
+use crate::checks::Check;
+use crate::cli::{Args, VerifyLevel};
+use crate::context::{CancellationToken, Context};
+use crate::error::{RecError, Result};
+use crate::heartbeat::{Progress, ProgressHook, ProgressHookGuard};
+use crate::pipeline;
+use crate::rootfs::SharedMounts;
+use crate::stats::InstallStats;
+use crate::validation::{self, take_diagnostics, take_results, CheckResult};
+use crate::warnings::{self, take_warnings};
+
+/// How to install. The defaults match the command line, except that
+/// output is quiet.
+#[derive(Clone)]
+pub struct ExtractOptions {
+    /// Image to install; `None` searches the standard locations. Not `-`:
+    /// stdin belongs to the embedding program
+    pub rootfs: Option<PathBuf>,
+    /// Rootfs variant from the medium's flavors.toml (exclusive with `rootfs`)
+    pub flavor: Option<String>,
+    /// Install over existing content
+    pub force: bool,
+    /// Replace a previous recstrap install
+    pub reinstall: bool,
+    pub verify: VerifyLevel,
+    /// Directory for temporary mount points; `None` is `$TMPDIR`
+    pub workdir: Option<PathBuf>,
+    /// Turn warnings into failures (E021 for the partition type, else E023)
+    pub strict: bool,
+    /// No progress or messages on stderr (warnings are still collected)
+    pub quiet: bool,
+    /// Called on the installing thread at each phase and every few
+    /// dozen copied files; see [`ExtractOptions::on_progress`]
+    pub progress: Option<ProgressHook>,
+    pub cancel: Option<CancellationToken>,
+}
+
+impl ExtractOptions {
+    /// Report progress to `callback`.
+    pub fn on_progress(mut self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+}
+
+impl std::fmt::Debug for ExtractOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtractOptions")
+            .field("rootfs", &self.rootfs)
+            .field("flavor", &self.flavor)
+            .field("force", &self.force)
+            .field("reinstall", &self.reinstall)
+            .field("verify", &self.verify)
+            .field("workdir", &self.workdir)
+            .field("strict", &self.strict)
+            .field("quiet", &self.quiet)
+            .field("progress", &self.progress.is_some())
+            .field("cancel", &self.cancel)
+            .finish()
+    }
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            rootfs: None,
+            flavor: None,
+            force: false,
+            reinstall: false,
+            verify: VerifyLevel::Basic,
+            workdir: None,
+            strict: false,
+            quiet: true,
+            progress: None,
+            cancel: None,
+        }
+    }
+}
+
+/// Outcome of the pre-flight checks.
+#[derive(Debug)]
+pub struct PreflightReport {
+    /// Checks in the order they ran; they stop at the first failure
+    pub checks: Vec<CheckResult>,
+    pub warnings: Vec<String>,
+    /// Documentation of the failed check, if one failed
+    pub diagnostics: Vec<&'static Check>,
+    /// Why the target can't be installed to, if it can't
+    pub error: Option<RecError>,
+}
+
+impl PreflightReport {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// One install target and how to install into it.
+#[derive(Debug, Clone)]
+pub struct Installer {
+    target: PathBuf,
+    options: ExtractOptions,
+}
+
+impl Installer {
+    pub fn new(target: impl Into<PathBuf>) -> Self {
+        Self {
+            target: target.into(),
+            options: ExtractOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: ExtractOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Run the pre-flight checks (`--check`) without touching the target.
+    pub fn preflight(&self) -> PreflightReport {
+        discard_collected();
+        let error = self
+            .args(true)
+            .and_then(|args| self.run(&args, |target| pipeline::preflight(&args, target)))
+            .err();
+        PreflightReport {
+            checks: take_results(),
+            warnings: take_warnings(),
+            diagnostics: take_diagnostics(),
+            error,
+        }
+    }
+
+    /// Install into the target: checks, extraction, verification and the
+    /// install record. Nothing is prompted for.
+    pub fn install(&self) -> Result<InstallStats> {
+        discard_collected();
+        let args = self.args(false)?;
+        if self
+            .options
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.is_cancelled())
+        {
+            return Err(RecError::extraction_failed("cancelled"));
+        }
+        let _progress = self
+            .options
+            .progress
+            .clone()
+            .map(ProgressHookGuard::install);
+        let started = Instant::now();
+        self.run(&args, |target| {
+            let preflight = pipeline::preflight(&args, target)?;
+            let mut extraction = pipeline::extract(&args, &preflight)?;
+            pipeline::verify(&args, &preflight, &mut extraction)?;
+            let (stats, _) = pipeline::configure(&args, &preflight, &extraction, started)?;
+            warnings::check_strict(args.strict)?;
+            Ok(stats)
+        })
+    }
+
+    /// Run `phases` on the target, in a context of this install's own.
+    fn run<T>(&self, args: &Args, phases: impl FnOnce(Option<&str>) -> Result<T>) -> Result<T> {
+        let _context = Context {
+            signals: false,
+            cancel: self.options.cancel.clone().unwrap_or_default(),
+            ..Context::from_args(args)
+        }
+        .enter();
+        validation::set_banners(!args.quiet);
+        let _mounts = SharedMounts::begin();
+        phases(args.target.as_deref())
+    }
+
+    /// The options as the install phases take them.
+    fn args(&self, check: bool) -> Result<Args> {
+        let options = &self.options;
+        let exclusive = |what: &str| {
+            RecError::configuration_failed("installer options", &format!("{} are exclusive", what))
+        };
+        if options.rootfs.is_some() && options.flavor.is_some() {
+            return Err(exclusive("rootfs and flavor"));
+        }
+        if options.force && options.reinstall {
+            return Err(exclusive("force and reinstall"));
+        }
+        // The embedding program's stdin is not ours to read
+        if options.rootfs.as_deref() == Some(Path::new("-")) {
+            return Err(RecError::configuration_failed(
+                "installer options",
+                "rootfs '-' (stdin) isn't available to library callers; save the image to a file",
+            ));
+        }
+
+        let mut args = Args::for_target(&self.target).map_err(|e| {
+            RecError::configuration_failed(
+                "installer target",
+                e.to_string()
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .trim_start_matches("error: "),
+            )
+        })?;
+        let path = |p: &PathBuf| p.to_string_lossy().into_owned();
+        args.rootfs = options.rootfs.as_ref().map(path);
+        args.flavor = options.flavor.clone();
+        args.workdir = options.workdir.as_ref().map(path);
+        args.verify = options.verify;
+        args.force = options.force;
+        args.reinstall = options.reinstall;
+        args.strict = options.strict;
+        args.quiet = options.quiet;
+        args.check = check;
+        args.unattended = true;
+        args.argv = self.command_line();
+        Ok(args)
+    }
+
+    /// The same install spelled as `recstrap` arguments, for the install
+    /// record.
+    fn command_line(&self) -> Vec<String> {
+        let options = &self.options;
+        let mut argv = vec!["--unattended".to_string()];
+        if let Some(rootfs) = &options.rootfs {
+            argv.push(format!("--rootfs={}", rootfs.display()));
+        }
+        if let Some(flavor) = &options.flavor {
+            argv.push(format!("--flavor={}", flavor));
+        }
+        if let Some(workdir) = &options.workdir {
+            argv.push(format!("--workdir={}", workdir.display()));
+        }
+        if let Some(level) = options.verify.to_possible_value() {
+            argv.push(format!("--verify={}", level.get_name()));
+        }
+        for (set, flag) in [
+            (options.force, "--force"),
+            (options.reinstall, "--reinstall"),
+            (options.strict, "--strict"),
+            (options.quiet, "--quiet"),
+        ] {
+            if set {
+                argv.push(flag.to_string());
+            }
+        }
+        argv.push(self.target.display().to_string());
+        argv
+    }
+}
+
+/// Drop anything an earlier run on this thread left in the collectors.
+fn discard_collected() {
+    take_results();
+    take_warnings();
+    take_diagnostics();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_installer_args() {
+        let installer = Installer::new("/mnt").with_options(ExtractOptions {
+            rootfs: Some(PathBuf::from("/media/filesystem.erofs")),
+            verify: VerifyLevel::Full,
+            force: true,
+            ..Default::default()
+        });
+        let args = installer.args(true).unwrap();
+        assert_eq!(args.target.as_deref(), Some("/mnt"));
+        assert_eq!(args.rootfs.as_deref(), Some("/media/filesystem.erofs"));
+        assert_eq!(args.verify, VerifyLevel::Full);
+        assert!(args.force && args.check && args.quiet && args.unattended);
+        assert!(!args.reinstall);
+        assert_eq!(
+            args.argv.join(" "),
+            "--unattended --rootfs=/media/filesystem.erofs --verify=full --force --quiet /mnt"
+        );
+    }
+
+    #[test]
+    fn test_installer_rejects_conflicting_options() {
+        let installer = Installer::new("/mnt").with_options(ExtractOptions {
+            rootfs: Some(PathBuf::from("/media/filesystem.erofs")),
+            flavor: Some("desktop".to_string()),
+            ..Default::default()
+        });
+        let Err(err) = installer.args(false) else {
+            panic!("--rootfs and --flavor accepted together");
+        };
+        let err = err.to_string();
+        assert!(err.starts_with("E020:"), "{}", err);
+
+        let stdin = Installer::new("/mnt").with_options(ExtractOptions {
+            rootfs: Some(PathBuf::from("-")),
+            ..Default::default()
+        });
+        let Err(err) = stdin.args(true) else {
+            panic!("rootfs '-' accepted");
+        };
+        let err = err.to_string();
+        assert!(
+            err.contains("isn't available to library callers"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_cancelled_before_start() {
+        let cancel = CancellationToken::new();
+        cancel.clone().cancel();
+        let installer = Installer::new("/nonexistent/path/12345").with_options(ExtractOptions {
+            cancel: Some(cancel),
+            ..Default::default()
+        });
+        let err = installer.install().unwrap_err().to_string();
+        assert!(err.contains("cancelled"), "{}", err);
+    }
+}
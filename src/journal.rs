@@ -0,0 +1,113 @@
+//! Structured entries in the systemd journal, next to stderr.
+//!
+//! On a live system booted with systemd, the command line also logs each
+//! phase, every warning, the outcome and the error code of a failure to
+//! the journal over its native protocol, tagged `recstrap`, so
+//! `journalctl -t recstrap` is a filterable install history
+//! (`journalctl -t recstrap ERROR_CODE=E012`). Entries carry `PHASE=`,
+//! `TARGET=` and, for failures, `ERROR_CODE=`.
+//!
+//! Logging is switched on by `cli_main` only; the library API stays
+//! silent. Anything that goes wrong here is ignored: stderr has the same
+//! information.
+
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use crate::heartbeat;
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Exists when the system was booted with systemd (`sd_booted()`).
+const SYSTEMD_RUNTIME_DIR: &str = "/run/systemd/system";
+
+const IDENTIFIER: &str = "recstrap";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TARGET: Mutex<Option<String>> = Mutex::new(None);
+
+/// Syslog priorities used here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+/// Log to the journal from now on, if there is one.
+pub fn enable() {
+    ENABLED.store(Path::new(SYSTEMD_RUNTIME_DIR).is_dir(), Ordering::Relaxed);
+}
+
+/// The target (or disk image) that later entries are about.
+pub fn set_target(target: Option<&str>) {
+    if let Ok(mut current) = TARGET.lock() {
+        *current = target.map(str::to_string);
+    }
+}
+
+/// Log `message` with the current phase and target, plus `fields`.
+pub fn send(priority: Priority, message: &str, fields: &[(&str, &str)]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    static SOCKET_HANDLE: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+    let Some(socket) = SOCKET_HANDLE.get_or_init(|| UnixDatagram::unbound().ok()) else {
+        return;
+    };
+    let priority = (priority as u8).to_string();
+    let phase = heartbeat::current_phase();
+    let target = TARGET.lock().ok().and_then(|t| t.clone());
+    let mut entry = vec![
+        ("MESSAGE", message),
+        ("PRIORITY", priority.as_str()),
+        ("SYSLOG_IDENTIFIER", IDENTIFIER),
+        ("PHASE", phase),
+    ];
+    if let Some(target) = &target {
+        entry.push(("TARGET", target));
+    }
+    entry.extend_from_slice(fields);
+    let _ = socket.send_to(&encode(&entry), SOCKET);
+}
+
+/// One entry in the native protocol: `KEY=value` lines, or for values
+/// with a newline, the key, a little-endian 64-bit length and the raw
+/// value.
+fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (key, value) in fields {
+        out.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            out.push(b'\n');
+            out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            out.push(b'=');
+        }
+        out.extend_from_slice(value.as_bytes());
+        out.push(b'\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encode(&[("MESSAGE", "extracting"), ("PHASE", "extracting")]),
+            b"MESSAGE=extracting\nPHASE=extracting\n"
+        );
+        let mut multiline = b"MESSAGE\n".to_vec();
+        multiline.extend_from_slice(&5u64.to_le_bytes());
+        multiline.extend_from_slice(b"a\nbcd\nERROR_CODE=E012\n");
+        assert_eq!(
+            encode(&[("MESSAGE", "a\nbcd"), ("ERROR_CODE", "E012")]),
+            multiline
+        );
+    }
+}
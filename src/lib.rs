@@ -0,0 +1,553 @@
+//! recstrap - LevitateOS system extractor
+//!
+//! Like pacstrap for Arch Linux - extracts the EROFS rootfs to target directory.
+//! User does EVERYTHING else manually (partitioning, formatting, fstab, bootloader).
+//!
+//! Usage:
+//!   recstrap /mnt                    # Extract rootfs to /mnt
+//!   recstrap /mnt --rootfs /path     # Custom rootfs location (.erofs)
+//!   recstrap /mnt --force            # Overwrite existing files
+//!   recstrap /mnt --quiet            # Scripting mode (minimal output)
+//!
+//! This is NOT archinstall. This is pacstrap.
+//!
+//! The `recstrap` binary is a thin wrapper around this library. GUI
+//! installers and provisioning daemons embed the same pipeline through
+//! [`Installer`] instead of shelling out.
+//! After running recstrap, you must manually:
+//!   - Generate /etc/fstab
+//!   - Install bootloader (bootctl install, or GRUB when booted via BIOS)
+//!   - Set root password (passwd)
+//!   - Configure timezone, locale, hostname
+//!
+//! ## Cheat-Aware Design
+//!
+//! This tool uses cheat-guarded validation based on Anthropic's research on
+//! emergent misalignment. Each validation check documents:
+//! - What user scenario it protects
+//! - How the check could be "cheated" (weakened to falsely pass)
+//! - What users would experience if the check were cheated
+//!
+//! This creates friction against shortcuts and makes cheating more expensive
+//! than honest implementation.
+//!
+//! ## Error Codes
+//!
+//! | Code | Description |
+//! |------|-------------|
+//! | E001 | Target directory does not exist |
+//! | E002 | Target is not a directory |
+//! | E003 | Target directory not writable |
+//! | E004 | Rootfs image not found |
+//! | E005 | Rootfs extraction command failed |
+//! | E006 | Extracted system verification failed |
+//! | E007 | Required extraction tool not installed |
+//! | E008 | Must run as root |
+//! | E009 | Target directory not empty (use --force) |
+//! | E010 | Target is a protected system path |
+//! | E011 | Target is not a mount point |
+//! | E012 | Insufficient disk space |
+//! | E013 | Rootfs is not a regular file |
+//! | E014 | Rootfs is not readable |
+//! | E015 | Rootfs is inside target directory |
+//! | E016 | Rootfs format is invalid |
+//! | E017 | EROFS kernel support is missing |
+//! | E018 | Rootless mode could not be set up |
+//! | E019 | CPU lacks the image's x86-64 level |
+//! | E020 | Target configuration failed |
+//! | E021 | Target partition is not a Linux type (--strict) |
+//! | E022 | Helper command timed out (--command-timeout) |
+//! | E023 | Warnings were issued with --strict |
+//! | E024 | Several pre-flight checks failed (--check --all) |
+//! | E025 | Image failed its dm-verity check |
+//! | E026 | No free loop device |
+//! | E027 | `recstrap prepare` failed |
+//! | E028 | Workdir is inside target directory |
+//! | E029 | --copy-into directory contains the target |
+
+mod accounts;
+mod answers;
+mod audit;
+mod boot;
+mod cgroup;
+pub mod checks;
+mod chroot;
+pub mod cli;
+mod collisions;
+mod commands;
+mod configure;
+mod constants;
+mod context;
+mod copy;
+mod cpu;
+mod disk;
+mod erofs;
+pub mod error;
+mod extractor;
+#[doc(hidden)]
+pub mod fixture;
+mod fixup;
+mod flavor;
+mod gpt;
+mod heartbeat;
+mod helpers;
+mod installer;
+mod journal;
+mod json;
+mod logfile;
+mod loopdev;
+mod mount;
+mod mountinfo;
+mod next_steps;
+mod notify;
+mod output;
+mod pipeline;
+mod preserve;
+mod privsep;
+mod probe;
+mod record;
+mod rootfs;
+mod rootless;
+mod runner;
+mod sandbox;
+mod sanity;
+mod serve;
+mod snapshot;
+mod state;
+mod stats;
+mod toml;
+mod validation;
+mod verify;
+mod verity;
+mod warnings;
+
+pub use cli::VerifyLevel;
+pub use context::CancellationToken;
+pub use heartbeat::Progress;
+pub use installer::{ExtractOptions, Installer, PreflightReport};
+pub use rootfs::{RootfsInfo, RootfsType};
+pub use stats::InstallStats;
+pub use validation::CheckResult;
+
+use clap::ValueEnum;
+use distro_spec::shared::error::ToolErrorCode;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use accounts::InitialUser;
+use cli::{Args, CheckOutput};
+use configure::write_autologin;
+use context::Context;
+use error::{ErrorCode, RecError, Result};
+use heartbeat::Heartbeat;
+use helpers::{
+    confirm_overwrite, is_dir_empty, prompt_for_user_creation, sync_filesystem, InterruptGuard,
+};
+use journal::Priority;
+use logfile::StderrTee;
+use probe::format_duration;
+use rootfs::SharedMounts;
+use validation::{render_tap, take_diagnostics, take_results};
+use warnings::{take_warnings, warn};
+
+/// Everything the `recstrap` binary does after parsing the command line:
+/// run, emit TAP and the stats file if asked, print the error, and map it
+/// to the exit code.
+pub fn cli_main(args: &Args) -> ExitCode {
+    // Before output::init, so the log doesn't get color codes
+    let _log = args.log.as_deref().and_then(|path| {
+        StderrTee::start(Path::new(path), &args.argv)
+            .inspect_err(|e| {
+                output::warning(&format!("cannot write log file {}: {}", path, e), &[])
+            })
+            .ok()
+    });
+    journal::enable();
+    output::init(args.verbose, args.no_color, args.quiet);
+    let result = run(args);
+    if args.output == CheckOutput::Tap {
+        let error = result.as_ref().err().map(|e| e.to_string());
+        print!("{}", render_tap(&take_results(), error.as_deref()));
+    }
+    // Only a single install has statistics to report. Ignored rather
+    // than refused, since RECSTRAP_STATS_FILE may be set for every run
+    if let Some(path) = &args.stats_file {
+        if !args.check && args.targets.is_none() {
+            write_stats_file(Path::new(path), args, &result);
+        }
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            output::error(&e.to_string());
+            journal::send(
+                Priority::Error,
+                &e.to_string(),
+                &[("ERROR_CODE", e.code.code())],
+            );
+            ExitCode::from(e.code.exit_code())
+        }
+    }
+}
+
+/// Run what `args` describe: a subcommand, an answers file, or an
+/// install.
+pub fn run(args: &Args) -> Result<()> {
+    let _context = Context::from_args(args).enter();
+    validation::set_banners(!args.machine_readable());
+
+    if let Some(command) = &args.command {
+        return commands::run(command);
+    }
+    if let Some(file) = &args.answers {
+        return answers::install(file, args.quiet);
+    }
+    if let Some(socket) = &args.serve {
+        return serve::run(socket, args.quiet);
+    }
+    let started = Instant::now();
+    let result = match &args.targets {
+        Some(targets) => install_targets(args, targets),
+        None => install(args, args.target.as_deref()).map_err(validation::with_collected),
+    };
+    audit::finish(&result);
+    if args.notify {
+        let what = match &args.targets {
+            Some(targets) => targets.join(", "),
+            None => args
+                .target
+                .clone()
+                .or_else(|| args.image.clone())
+                .unwrap_or_default(),
+        };
+        notify::finished(&result, &what, started.elapsed(), args.quiet);
+    }
+    result
+}
+
+/// Install into each of `targets` in turn, mounting the image only once.
+/// Stops at the first target that fails.
+fn install_targets(args: &Args, targets: &[String]) -> Result<()> {
+    if args.rootfs.as_deref() == Some("-") {
+        return Err(RecError::new(
+            ErrorCode::RootfsNotReadable,
+            "stdin can only be read once; save the image to a file for --targets",
+        ));
+    }
+    // Declared first so it is dropped last: Ctrl-C between targets stops
+    // the run instead of killing it with the image still mounted
+    let interrupt = InterruptGuard::install();
+    let _mounts = SharedMounts::begin();
+
+    for (i, target) in targets.iter().enumerate() {
+        if interrupt.interrupted() {
+            return Err(RecError::extraction_failed("interrupted"));
+        }
+        if !args.quiet {
+            output::step(&format!(
+                "Target {} of {}: {}",
+                i + 1,
+                targets.len(),
+                target
+            ));
+        }
+        if let Err(e) = install(args, Some(target)).map_err(validation::with_collected) {
+            if i > 0 {
+                output::note(&format!(
+                    "installed to {} before {} failed",
+                    targets[..i].join(", "),
+                    target
+                ));
+            }
+            return Err(e);
+        }
+    }
+    if !args.quiet {
+        eprintln!(
+            "Installed to {} targets from one image mount.",
+            targets.len()
+        );
+    }
+    Ok(())
+}
+
+/// The main extraction flow into `target` (`None` with --image): the
+/// [`pipeline`] phases, plus what only the command line does.
+fn install(args: &Args, target: Option<&str>) -> Result<()> {
+    let started = Instant::now();
+    journal::set_target(target.or(args.image.as_deref()));
+    validation::collect_failures(args.check && args.all);
+
+    // Lives until we return, so snapshots (periodic, or on SIGUSR1) cover
+    // every phase
+    let _heartbeat = Heartbeat::start(args.heartbeat.map(Duration::from_secs));
+
+    // Dropped after the phases: they all use the one mount of the image
+    let _mounts = SharedMounts::begin();
+    let mut preflight = pipeline::preflight(args, target)?;
+    let target = preflight.target.clone();
+    let target_str = target.to_string_lossy();
+
+    // If --check mode, exit successfully without extracting
+    if args.check {
+        if !args.quiet {
+            print_check_summary(&preflight)?;
+        }
+        return Ok(());
+    }
+
+    // A mistyped path with --force would overwrite someone's data: from a
+    // terminal, show what is there and have them confirm
+    if args.force
+        && !args.assume_yes
+        && !args.unattended
+        && std::io::stdin().is_terminal()
+        && !is_dir_empty(&target).unwrap_or(true)
+    {
+        let confirmed = confirm_overwrite(&target).map_err(|e| {
+            RecError::new(
+                ErrorCode::TargetNotEmpty,
+                format!("cannot ask for confirmation: {}", e),
+            )
+        })?;
+        if !confirmed {
+            return Err(RecError::overwrite_not_confirmed(&target_str));
+        }
+    }
+
+    let mut extraction = pipeline::extract(args, &preflight)?;
+    pipeline::verify(args, &preflight, &mut extraction)?;
+    let (install_stats, record) = pipeline::configure(args, &preflight, &extraction, started)?;
+
+    if !args.quiet {
+        eprint!("{}", install_stats.render());
+    }
+    // What the user still has to do, unless --image, --umount-after or
+    // --answers end the run differently
+    let steps = if preflight.disk_image.is_none() && !args.umount_after && !args.unattended {
+        next_steps::manual(
+            &target_str,
+            preflight.boot_mode,
+            args.serial_console.as_ref(),
+        )
+    } else {
+        Vec::new()
+    };
+    if args.json {
+        let mut json = install_stats.to_json();
+        json.push("next_steps", next_steps::to_json(&steps));
+        print!("{}", json.to_pretty_string());
+    }
+    stats::record(&install_stats);
+    warnings::check_strict(args.strict)?;
+    audit::complete(record.image_sha256.as_deref());
+    journal::send(
+        Priority::Info,
+        &format!(
+            "installed {} files ({} MB) in {}",
+            install_stats.files,
+            install_stats.extracted_bytes / (1024 * 1024),
+            format_duration(install_stats.total_secs as u64)
+        ),
+        &[],
+    );
+
+    // An image is never prompted for a user: it must ship the account
+    if let (Some(user), Some(_)) = (&args.autologin, &preflight.disk_image) {
+        enable_autologin(&target, user, args)?;
+    }
+
+    // The image is complete once it is unmounted and detached
+    if let Some(mut image) = preflight.disk_image.take() {
+        image.keep();
+        drop(image);
+        if !args.quiet {
+            let file = args.image.as_deref().unwrap_or_default();
+            eprintln!();
+            eprintln!("Done! Disk image written to {}", file);
+            eprintln!();
+            eprintln!("  # Attach it to finish the installation (fstab, bootloader)");
+            eprintln!("  losetup --find --show --partscan {}", file);
+            eprintln!();
+            eprintln!("The EFI system partition (partition 1) is formatted but empty.");
+            if let Some(console) = &args.serial_console {
+                eprintln!("Serial console kernel arguments: {}", console.kernel_args());
+            }
+        }
+        return Ok(());
+    }
+
+    // =========================================================================
+    // Optional User Creation Setup
+    // =========================================================================
+
+    // Prompt for initial user creation (Option A: Arch-style)
+    // The account is written straight into the target's /etc
+    if !args.quiet && !args.force && !args.reinstall && !args.unattended {
+        // Only prompt if running interactively (not with --force, --reinstall,
+        // --quiet, or from an answers file)
+        let initial_user = InitialUser {
+            password_hash: preflight.password_hash.as_deref(),
+            sudo: args.sudo,
+            skel: args.skel.as_deref().map(Path::new),
+        };
+        if let Err(e) = prompt_for_user_creation(&target, &initial_user) {
+            warn(
+                args.quiet,
+                &format!("initial user not created: {}", e),
+                &["Create it in chroot with useradd and passwd"],
+            );
+        }
+        if !args.no_sync {
+            let _ = sync_filesystem(&target);
+        }
+    } else if preflight.password_hash.is_some() || args.sudo.is_some() || args.skel.is_some() {
+        warn(
+            args.quiet,
+            "--password-hash-fd/--sudo/--skel: no initial user was prompted for",
+            &["Create the user in the target with 'useradd -p'"],
+        );
+    }
+
+    // After the initial user exists, if that's who logs in
+    if let Some(user) = &args.autologin {
+        enable_autologin(&target, user, args)?;
+    }
+
+    if args.umount_after {
+        unmount_target(&target, args.quiet)?;
+        if !args.quiet {
+            eprintln!();
+            eprintln!("Done! {} is unmounted and ready to boot.", target_str);
+            eprintln!("To finish manually (fstab, bootloader), mount it again and run");
+            eprintln!("recfstab and recchroot as usual.");
+        }
+        return Ok(());
+    }
+
+    // --answers takes it from here (users, bootloader, hooks)
+    if args.unattended {
+        return Ok(());
+    }
+
+    if !args.quiet {
+        eprintln!();
+        eprintln!("Done! Now complete the installation manually:");
+        eprint!("{}", next_steps::render(&steps));
+    }
+
+    Ok(())
+}
+
+/// The `--check` report: what would be installed where, and what a
+/// `--force` or `--reinstall` run would overwrite.
+fn print_check_summary(preflight: &pipeline::Preflight) -> Result<()> {
+    let target = &preflight.target;
+    let rootfs = &preflight.image.path;
+    eprintln!();
+    eprintln!("{}", "=".repeat(70));
+    eprintln!("PRE-FLIGHT CHECK PASSED");
+    eprintln!("{}", "=".repeat(70));
+    eprintln!();
+    eprintln!("Target:    {}", target.display());
+    eprintln!(
+        "Rootfs:    {} ({:?})",
+        rootfs.display(),
+        preflight.rootfs_type
+    );
+    eprintln!("Backend:   {}", preflight.extractor.name());
+    eprintln!("Boot mode: {}", preflight.boot_mode);
+    eprintln!();
+    // How much a --force or --reinstall run would overwrite
+    if !is_dir_empty(target).unwrap_or(true) {
+        eprint!(
+            "{}",
+            preflight
+                .extractor
+                .collisions(rootfs, target, &preflight.workdir)?
+                .render()
+        );
+        eprintln!();
+    }
+    eprintln!("All {} validation checks passed.", validation::checks_run());
+    eprintln!("Ready to extract. Run without --check to proceed.");
+    eprintln!();
+    Ok(())
+}
+
+/// `--autologin`: log `user` in on the target's consoles and display
+/// manager, then flush.
+fn enable_autologin(target: &Path, user: &str, args: &Args) -> Result<()> {
+    if !args.quiet {
+        eprintln!("Enabling autologin for {}...", user);
+    }
+    let (configured, warning) = write_autologin(target, user, args.serial_console.as_ref())?;
+    if !args.quiet {
+        eprintln!("  {}", configured.join(", "));
+    }
+    if let Some(warning) = warning {
+        warn(args.quiet, &warning, &[]);
+    }
+    if !args.no_sync {
+        let _ = sync_filesystem(target);
+    }
+    Ok(())
+}
+
+/// Write the `--stats-file` report for a finished run. Failing to write
+/// it is only a warning; the install itself is done either way.
+fn write_stats_file(path: &Path, args: &Args, result: &Result<()>) {
+    let stats = stats::take_recorded();
+    let verified = match result {
+        Ok(()) => stats.as_ref().map(|_| true),
+        Err(e) if e.code == ErrorCode::ExtractionVerificationFailed => Some(false),
+        // Verification runs right after extraction: if statistics exist,
+        // it passed before the later failure
+        Err(_) => stats.as_ref().map(|_| true),
+    };
+    let level = args
+        .verify
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default();
+    let error = result.as_ref().err().map(|e| e.to_string());
+    let report = stats::report(
+        stats.as_ref(),
+        &level,
+        verified,
+        error.as_deref(),
+        &take_diagnostics(),
+        &take_warnings(),
+    );
+    if let Err(e) = stats::write_report(path, &report) {
+        output::warning(
+            &format!("cannot write stats file {}: {}", path.display(), e),
+            &[],
+        );
+    }
+}
+
+/// Unmount the target and everything mounted below it (ESP, /home, ...),
+/// deepest first, as `umount -R` does.
+fn unmount_target(target: &Path, quiet: bool) -> Result<()> {
+    if !quiet {
+        eprintln!("Unmounting {} and nested mounts...", target.display());
+    }
+    let fail = |detail: String| {
+        RecError::new(
+            ErrorCode::ExtractionFailed,
+            format!("cannot unmount {}: {}", target.display(), detail),
+        )
+    };
+    let status = runner::status(std::process::Command::new("umount").arg("-R").arg(target))
+        .map_err(|e| fail(e.to_string()))?;
+    if !status.success() {
+        return Err(fail(format!(
+            "umount exited with {} (something still using it?)",
+            status.code().unwrap_or(-1)
+        )));
+    }
+    Ok(())
+}
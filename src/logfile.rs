@@ -0,0 +1,82 @@
+//! `--log FILE`: a copy of the run's stderr.
+//!
+//! stderr is swapped for a pipe whose reader passes everything on to the
+//! real stderr and appends it to the file, so the log holds what the user
+//! saw - messages, warnings, the error, and the output of helper commands
+//! recstrap runs - not a separate, sparser account of it. Color is off
+//! while logging, since stderr is no longer a terminal.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::thread::JoinHandle;
+
+use crate::record::now_utc;
+
+/// Copies stderr to the log file until dropped.
+pub struct StderrTee {
+    original: OwnedFd,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl StderrTee {
+    /// Append stderr to `path` from now on, after a line naming the run.
+    pub fn start(path: &Path, argv: &[String]) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "--- recstrap {} ({})", argv.join(" "), now_utc())?;
+
+        let original = cvt(unsafe { libc::fcntl(2, libc::F_DUPFD_CLOEXEC, 3) })?;
+        let original = unsafe { OwnedFd::from_raw_fd(original) };
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        let (read_end, write_end) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        // The copy on fd 2 is inherited by helper commands, as stderr is
+        cvt(unsafe { libc::dup2(write_end.as_raw_fd(), 2) })?;
+        drop(write_end);
+
+        let mut terminal = File::from(original.try_clone()?);
+        let reader = std::thread::spawn(move || {
+            let mut pipe = File::from(read_end);
+            let mut buf = [0u8; 8192];
+            loop {
+                match pipe.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        let _ = terminal.write_all(&buf[..n]);
+                        let _ = file.write_all(&buf[..n]);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        });
+        Ok(Self {
+            original,
+            reader: Some(reader),
+        })
+    }
+}
+
+impl Drop for StderrTee {
+    fn drop(&mut self) {
+        // Closes the pipe's last writer, so the reader drains it and ends
+        unsafe { libc::dup2(self.original.as_raw_fd(), 2) };
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
@@ -0,0 +1,216 @@
+//! Loop devices for kernel mounts of image files.
+//!
+//! Instead of `mount -o loop`, images are attached with the loop ioctls
+//! directly: read-only, and with autoclear, so the kernel detaches the
+//! device by itself once it is unmounted and closed, even when our
+//! `umount` failed and a lazy unmount finishes later. Running out of loop
+//! devices is reported as such (E026) instead of as a failed mount.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// `LOOP_CTL_GET_FREE`: number of a free loop device (allocating one).
+const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4C82;
+/// `LOOP_CONFIGURE` (Linux 5.8): set backing file and status at once.
+const LOOP_CONFIGURE: libc::c_ulong = 0x4C0A;
+/// `LOOP_SET_FD` and `LOOP_SET_STATUS64`, for older kernels.
+const LOOP_SET_FD: libc::c_ulong = 0x4C00;
+const LOOP_SET_STATUS64: libc::c_ulong = 0x4C04;
+/// `LOOP_CLR_FD`: detach, or only set autoclear while still in use.
+const LOOP_CLR_FD: libc::c_ulong = 0x4C01;
+
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+/// Free devices another process grabs first are skipped this many times.
+const ATTACH_ATTEMPTS: usize = 8;
+
+/// `struct loop_info64` from `<linux/loop.h>`.
+#[repr(C)]
+struct LoopInfo64 {
+    device: u64,
+    inode: u64,
+    rdevice: u64,
+    offset: u64,
+    sizelimit: u64,
+    number: u32,
+    encrypt_type: u32,
+    encrypt_key_size: u32,
+    flags: u32,
+    file_name: [u8; 64],
+    crypt_name: [u8; 64],
+    encrypt_key: [u8; 32],
+    init: [u64; 2],
+}
+
+/// `struct loop_config` from `<linux/loop.h>`.
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+/// An attached loop device; detached when dropped and no longer mounted.
+pub struct LoopDevice {
+    path: PathBuf,
+    /// `None` for devices handed out by a fake runner in tests
+    file: Option<File>,
+}
+
+impl LoopDevice {
+    /// Attach `image` read-only to a free loop device.
+    ///
+    /// Fails with [`io::ErrorKind::ResourceBusy`] when no loop device is
+    /// free and none can be allocated.
+    pub fn attach(image: &Path) -> io::Result<Self> {
+        let backing = File::open(image)?;
+        let control = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/loop-control")?;
+
+        for _ in 0..ATTACH_ATTEMPTS {
+            let n = unsafe { libc::ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE as _) };
+            if n < 0 {
+                return Err(exhausted());
+            }
+            let path = PathBuf::from(format!("/dev/loop{}", n));
+            let device = match File::open(&path) {
+                Ok(device) => device,
+                // The node of a freshly allocated device may not exist yet
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            match configure(&device, &backing, image) {
+                Ok(()) => {
+                    return Ok(Self {
+                        path,
+                        file: Some(device),
+                    })
+                }
+                // Taken by someone else between GET_FREE and now
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(exhausted())
+    }
+
+    /// A device that is not backed by anything, for fake runners.
+    #[cfg(test)]
+    pub fn fake(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            file: None,
+        }
+    }
+
+    /// The block device, e.g. `/dev/loop3`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        // Detaches right away if unmounted; if the mount is still there,
+        // autoclear detaches it when that goes away
+        if let Some(file) = &self.file {
+            unsafe { libc::ioctl(file.as_raw_fd(), LOOP_CLR_FD as _, 0) };
+        }
+    }
+}
+
+fn exhausted() -> io::Error {
+    io::Error::new(io::ErrorKind::ResourceBusy, "no free loop device")
+}
+
+fn loop_info(image: &Path) -> LoopInfo64 {
+    let mut info = LoopInfo64 {
+        device: 0,
+        inode: 0,
+        rdevice: 0,
+        offset: 0,
+        sizelimit: 0,
+        number: 0,
+        encrypt_type: 0,
+        encrypt_key_size: 0,
+        flags: LO_FLAGS_READ_ONLY | LO_FLAGS_AUTOCLEAR,
+        file_name: [0; 64],
+        crypt_name: [0; 64],
+        encrypt_key: [0; 32],
+        init: [0; 2],
+    };
+    // Shown by losetup; truncated like losetup does, keeping a NUL
+    let name = image.as_os_str().as_encoded_bytes();
+    let len = name.len().min(info.file_name.len() - 1);
+    info.file_name[..len].copy_from_slice(&name[..len]);
+    info
+}
+
+/// Point `device` at `backing`, read-only with autoclear.
+fn configure(device: &File, backing: &File, image: &Path) -> io::Result<()> {
+    let config = LoopConfig {
+        fd: backing.as_raw_fd() as u32,
+        block_size: 0,
+        info: loop_info(image),
+        reserved: [0; 8],
+    };
+    let ret = unsafe { libc::ioctl(device.as_raw_fd(), LOOP_CONFIGURE as _, &config) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if !matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOTTY)) {
+        return Err(e);
+    }
+
+    // Kernels before 5.8 take the backing file and the flags separately
+    let ret = unsafe { libc::ioctl(device.as_raw_fd(), LOOP_SET_FD as _, backing.as_raw_fd()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe { libc::ioctl(device.as_raw_fd(), LOOP_SET_STATUS64 as _, &config.info) };
+    if ret != 0 {
+        let e = io::Error::last_os_error();
+        unsafe { libc::ioctl(device.as_raw_fd(), LOOP_CLR_FD as _, 0) };
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_struct_layout() {
+        // Sizes from <linux/loop.h>; the kernel rejects anything else
+        assert_eq!(std::mem::size_of::<LoopInfo64>(), 232);
+        assert_eq!(std::mem::size_of::<LoopConfig>(), 304);
+    }
+
+    #[test]
+    fn test_loop_info() {
+        let info = loop_info(Path::new("/run/live/filesystem.erofs"));
+        assert_eq!(info.flags, LO_FLAGS_READ_ONLY | LO_FLAGS_AUTOCLEAR);
+        assert!(info.file_name.starts_with(b"/run/live/filesystem.erofs\0"));
+
+        let long = format!("/{}", "x".repeat(100));
+        let info = loop_info(Path::new(&long));
+        assert_eq!(info.file_name[62], b'x');
+        assert_eq!(info.file_name[63], 0);
+    }
+
+    #[test]
+    fn test_attach_missing_image() {
+        let Err(e) = LoopDevice::attach(Path::new("/nonexistent/filesystem.erofs")) else {
+            panic!("missing image attached");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,229 @@
+//! Kernel mounts of the image without util-linux.
+//!
+//! The EROFS is mounted with the new mount API (fsopen, fsconfig, fsmount,
+//! move_mount), falling back to mount(2) on kernels older than 5.2, and
+//! unmounted with umount2(2). Minimal initramfs environments need no
+//! `mount` binary, and failures come back as an errno that can be told
+//! apart ([`crate::rootfs`] maps them), plus the kernel's own message
+//! where the new API provides one.
+//!
+//! A syscall stuck on dead media can't be killed, so each one runs on its
+//! own thread that is abandoned after `--command-timeout`.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::helpers::path_to_cstring;
+
+const FSOPEN_CLOEXEC: libc::c_uint = 1;
+const FSCONFIG_SET_FLAG: libc::c_uint = 0;
+const FSCONFIG_SET_STRING: libc::c_uint = 1;
+const FSCONFIG_CMD_CREATE: libc::c_uint = 6;
+const FSMOUNT_CLOEXEC: libc::c_uint = 1;
+const MOUNT_ATTR_RDONLY: libc::c_uint = 1;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 4;
+
+/// A failed mount: the errno, and what the kernel logged about it.
+#[derive(Debug)]
+pub struct MountError {
+    pub error: io::Error,
+    pub message: Option<String>,
+}
+
+impl From<io::Error> for MountError {
+    fn from(error: io::Error) -> Self {
+        Self {
+            error,
+            message: None,
+        }
+    }
+}
+
+/// Mount the EROFS on the block device `source` read-only at `target`,
+/// giving up after `timeout_secs` (0: never).
+pub fn mount_erofs(
+    source: &Path,
+    target: &Path,
+    timeout_secs: u64,
+) -> std::result::Result<(), MountError> {
+    let (source, target) = (source.to_path_buf(), target.to_path_buf());
+    within(timeout_secs, move || mount_now(&source, &target))?
+}
+
+/// Unmount `target`; `lazy` detaches it even while busy.
+pub fn unmount(target: &Path, lazy: bool, timeout_secs: u64) -> io::Result<()> {
+    let target = target.to_path_buf();
+    within(timeout_secs, move || {
+        let c_target = path_to_cstring(&target)?;
+        let flags = if lazy { libc::MNT_DETACH } else { 0 };
+        if unsafe { libc::umount2(c_target.as_ptr(), flags) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    })?
+}
+
+/// Run `op` on its own thread, failing with [`io::ErrorKind::TimedOut`]
+/// if it hasn't finished after `secs`.
+fn within<T: Send + 'static>(secs: u64, op: impl FnOnce() -> T + Send + 'static) -> io::Result<T> {
+    if secs == 0 {
+        return Ok(op());
+    }
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(op());
+    });
+    rx.recv_timeout(Duration::from_secs(secs))
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "system call did not return"))
+}
+
+fn mount_now(source: &Path, target: &Path) -> std::result::Result<(), MountError> {
+    let fstype = CString::new("erofs").unwrap_or_default();
+    let fs = unsafe { libc::syscall(libc::SYS_fsopen, fstype.as_ptr(), FSOPEN_CLOEXEC) };
+    if fs < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ENOSYS) {
+            return legacy_mount(source, target);
+        }
+        return Err(e.into());
+    }
+    let fs = unsafe { OwnedFd::from_raw_fd(fs as _) };
+    let with_log = |error: io::Error| MountError {
+        error,
+        message: kernel_message(&fs),
+    };
+
+    let c_source = path_to_cstring(source)?;
+    let config = |cmd: libc::c_uint, key: Option<&CString>, value: Option<&CString>| {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_fsconfig,
+                fs.as_raw_fd(),
+                cmd,
+                key.map_or(std::ptr::null(), |k| k.as_ptr()),
+                value.map_or(std::ptr::null(), |v| v.as_ptr()),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    let key = |k: &str| CString::new(k).unwrap_or_default();
+    config(FSCONFIG_SET_STRING, Some(&key("source")), Some(&c_source)).map_err(with_log)?;
+    config(FSCONFIG_SET_FLAG, Some(&key("ro")), None).map_err(with_log)?;
+    config(FSCONFIG_CMD_CREATE, None, None).map_err(with_log)?;
+
+    let mnt = unsafe {
+        libc::syscall(
+            libc::SYS_fsmount,
+            fs.as_raw_fd(),
+            FSMOUNT_CLOEXEC,
+            MOUNT_ATTR_RDONLY,
+        )
+    };
+    if mnt < 0 {
+        return Err(with_log(io::Error::last_os_error()));
+    }
+    let mnt = unsafe { OwnedFd::from_raw_fd(mnt as _) };
+
+    let c_target = path_to_cstring(target)?;
+    let empty = key("");
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            mnt.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_FDCWD,
+            c_target.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// mount(2), for kernels without the new mount API.
+fn legacy_mount(source: &Path, target: &Path) -> std::result::Result<(), MountError> {
+    let c_source = path_to_cstring(source)?;
+    let c_target = path_to_cstring(target)?;
+    let fstype = CString::new("erofs").unwrap_or_default();
+    let ret = unsafe {
+        libc::mount(
+            c_source.as_ptr(),
+            c_target.as_ptr(),
+            fstype.as_ptr(),
+            libc::MS_RDONLY,
+            std::ptr::null(),
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// The last error or warning the filesystem context logged, e.g.
+/// `erofs: (device loop0): cannot find valid erofs superblock`.
+fn kernel_message(fs: &OwnedFd) -> Option<String> {
+    let mut file = File::from(fs.try_clone().ok()?);
+    let mut last = None;
+    let mut buf = [0u8; 1024];
+    // One message per read; ENODATA once the log is empty
+    while let Ok(n @ 1..) = file.read(&mut buf) {
+        last = parse_log_line(&String::from_utf8_lossy(&buf[..n])).or(last);
+    }
+    last
+}
+
+/// Log lines are `e <message>` (error), `w` (warning) or `i` (info).
+fn parse_log_line(line: &str) -> Option<String> {
+    match line.trim_end().split_once(' ') {
+        Some(("e" | "w", message)) => Some(message.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_line() {
+        assert_eq!(
+            parse_log_line("e erofs: cannot find valid erofs superblock\n").as_deref(),
+            Some("erofs: cannot find valid erofs superblock")
+        );
+        assert_eq!(
+            parse_log_line("w erofs: unknown option").as_deref(),
+            Some("erofs: unknown option")
+        );
+        assert_eq!(parse_log_line("i erofs: mounted"), None);
+    }
+
+    #[test]
+    fn test_within() {
+        assert_eq!(within(0, || 7).unwrap(), 7);
+        assert_eq!(within(5, || 7).unwrap(), 7);
+        let err = within(1, || std::thread::sleep(Duration::from_secs(3))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_unmount_not_mounted() {
+        let dir = std::env::temp_dir().join("recstrap_test_unmount");
+        let _ = std::fs::create_dir_all(&dir);
+        // EINVAL as root, EPERM otherwise; never mistaken for success
+        assert!(unmount(&dir, false, 5).is_err());
+        let _ = std::fs::remove_dir(&dir);
+    }
+}
@@ -0,0 +1,192 @@
+//! Parsing of `/proc/self/mountinfo`.
+//!
+//! Used to find mounted removable media and to reason about what is mounted
+//! where without shelling out to `findmnt`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One line of `/proc/self/mountinfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry {
+    /// Where the filesystem is mounted.
+    pub mount_point: PathBuf,
+    /// Per-mount options (`ro`, `nosuid`, ...).
+    pub mount_options: String,
+    /// Filesystem type (`ext4`, `iso9660`, ...).
+    pub fs_type: String,
+    /// Mount source, usually a device path.
+    pub source: String,
+}
+
+/// Per-mount options that leave an extracted system subtly broken:
+/// setuid binaries (sudo, passwd) and device nodes stop working, and nothing
+/// under the target can be executed for checks or chroot.
+pub const HOSTILE_OPTIONS: &[&str] = &["noexec", "nodev", "nosuid"];
+
+impl MountEntry {
+    /// Whether the per-mount options include `option` exactly.
+    pub fn has_option(&self, option: &str) -> bool {
+        self.mount_options.split(',').any(|o| o == option)
+    }
+
+    /// The [`HOSTILE_OPTIONS`] this mount has.
+    pub fn hostile_options(&self) -> Vec<&'static str> {
+        HOSTILE_OPTIONS
+            .iter()
+            .copied()
+            .filter(|o| self.has_option(o))
+            .collect()
+    }
+}
+
+/// Read and parse the mount table of the current process.
+pub fn read() -> std::io::Result<Vec<MountEntry>> {
+    Ok(parse(&fs::read_to_string("/proc/self/mountinfo")?))
+}
+
+/// Parse mountinfo content. Malformed lines are skipped.
+///
+/// Format (see proc(5)):
+/// `id parent major:minor root mount_point options [optional...] - fstype source super_options`
+pub fn parse(content: &str) -> Vec<MountEntry> {
+    content.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<MountEntry> {
+    let (left, right) = line.split_once(" - ")?;
+    let left: Vec<&str> = left.split(' ').collect();
+    let mut right = right.split(' ');
+    if left.len() < 6 {
+        return None;
+    }
+    Some(MountEntry {
+        mount_point: PathBuf::from(unescape(left[4])),
+        mount_options: left[5].to_string(),
+        fs_type: right.next()?.to_string(),
+        source: unescape(right.next()?),
+    })
+}
+
+/// The mount containing `path`: the longest mount point that is a prefix of
+/// it. Later entries win ties, as they are mounted on top. `path` should be
+/// canonical.
+pub fn containing(entries: &[MountEntry], path: &Path) -> Option<MountEntry> {
+    entries
+        .iter()
+        .filter(|e| path.starts_with(&e.mount_point))
+        // max_by_key keeps the last of equal maxima
+        .max_by_key(|e| e.mount_point.components().count())
+        .cloned()
+}
+
+/// Decode the octal escapes (`\040` for space etc.) the kernel uses.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && is_octal(&bytes[i + 1..i + 4]) {
+            let v = (bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0');
+            out.push(v);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn is_octal(digits: &[u8]) -> bool {
+    digits.len() == 3 && digits[0] <= b'3' && digits.iter().all(|d| (b'0'..=b'7').contains(d))
+}
+
+/// Whether a mount looks like removable install media: optical filesystems,
+/// block devices flagged removable in sysfs, or desktop automount locations.
+pub fn is_removable(entry: &MountEntry) -> bool {
+    if matches!(entry.fs_type.as_str(), "iso9660" | "udf") {
+        return true;
+    }
+    if entry.mount_point.starts_with("/media") || entry.mount_point.starts_with("/run/media") {
+        return true;
+    }
+    entry
+        .source
+        .strip_prefix("/dev/")
+        .is_some_and(block_device_removable)
+}
+
+fn block_device_removable(dev: &str) -> bool {
+    let sys = Path::new("/sys/class/block").join(dev);
+    // Partitions carry the flag on their parent disk.
+    [sys.join("removable"), sys.join("../removable")]
+        .iter()
+        .any(|p| fs::read_to_string(p).is_ok_and(|s| s.trim() == "1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+35 22 0:30 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+61 22 11:0 / /run/media/live\\040usb ro,relatime shared:30 - iso9660 /dev/sr0 ro
+bogus line
+";
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let entries = parse(SAMPLE);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].mount_point, PathBuf::from("/"));
+        assert_eq!(entries[0].fs_type, "ext4");
+        assert_eq!(entries[0].source, "/dev/nvme0n1p2");
+        assert_eq!(entries[1].mount_options, "rw,nosuid,nodev,noexec,relatime");
+        assert_eq!(entries[2].mount_point, PathBuf::from("/run/media/live usb"));
+    }
+
+    #[test]
+    fn test_is_removable() {
+        let entries = parse(SAMPLE);
+        assert!(!is_removable(&entries[1]));
+        assert!(is_removable(&entries[2]));
+    }
+
+    #[test]
+    fn test_hostile_options() {
+        let entries = parse(SAMPLE);
+        assert!(entries[0].hostile_options().is_empty());
+        assert_eq!(
+            entries[1].hostile_options(),
+            vec!["noexec", "nodev", "nosuid"]
+        );
+        assert!(entries[2].has_option("ro"));
+        assert!(!entries[2].has_option("r"));
+    }
+
+    #[test]
+    fn test_containing_mount() {
+        let entries = parse(SAMPLE);
+        let mount = |p: &str| containing(&entries, Path::new(p)).map(|e| e.mount_point);
+        assert_eq!(mount("/mnt"), Some(PathBuf::from("/")));
+        assert_eq!(mount("/proc/self"), Some(PathBuf::from("/proc")));
+        assert_eq!(mount("/procfoo"), Some(PathBuf::from("/")));
+        assert_eq!(mount("relative"), None);
+    }
+
+    #[test]
+    fn test_containing_prefers_top_mount() {
+        let entries =
+            parse("1 0 8:1 / /mnt rw - ext4 /dev/sda1 rw\n2 1 8:2 / /mnt ro - ext4 /dev/sda2 ro\n");
+        let top = containing(&entries, Path::new("/mnt")).unwrap();
+        assert_eq!(top.source, "/dev/sda2");
+    }
+
+    #[test]
+    fn test_unescape_leaves_plain_backslash() {
+        assert_eq!(unescape("a\\b"), "a\\b");
+        assert_eq!(unescape("tab\\011x"), "tab\tx");
+    }
+}
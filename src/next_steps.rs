@@ -0,0 +1,129 @@
+//! What is left to do after an install, for the user and for wrappers.
+//!
+//! The closing "Now complete the installation manually" block is built
+//! from [`manual`], and `--json` carries the same steps as `next_steps`,
+//! so a graphical installer can show them as a checklist or run the ones
+//! it knows how to.
+
+use crate::boot::BootMode;
+use crate::configure::SerialConsole;
+use crate::json::Value;
+
+/// One step of the plan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub description: String,
+    /// Commands to run, in order; empty for steps that only say what to do
+    pub commands: Vec<String>,
+    /// Run in the shell `recchroot` opens rather than on the live system
+    pub chroot: bool,
+}
+
+impl Step {
+    fn new(description: impl Into<String>, commands: &[&str], chroot: bool) -> Self {
+        Self {
+            description: description.into(),
+            commands: commands.iter().map(|c| c.to_string()).collect(),
+            chroot,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("description", Value::from(self.description.as_str())),
+            ("commands", Value::from(self.commands.clone())),
+            ("chroot", Value::from(self.chroot)),
+        ])
+    }
+}
+
+/// The steps that finish an install into `target` by hand: fstab, a root
+/// password (unless an initial user was created), the bootloader, reboot.
+pub fn manual(target: &str, boot_mode: BootMode, serial: Option<&SerialConsole>) -> Vec<Step> {
+    let mut steps = vec![
+        Step::new(
+            "Generate fstab",
+            &[&format!("recfstab {} >> {}/etc/fstab", target, target)],
+            false,
+        ),
+        Step::new(
+            "Chroot into new system",
+            &[&format!("recchroot {}", target)],
+            false,
+        ),
+        Step::new(
+            "Set root password if you created no user above (account is locked by default)",
+            &["passwd root"],
+            true,
+        ),
+        Step::new(
+            format!("Install bootloader (live system booted {})", boot_mode),
+            boot_mode.bootloader_commands(),
+            true,
+        ),
+    ];
+    if let Some(console) = serial {
+        steps.push(Step::new(
+            format!(
+                "Add to the kernel command line for the serial console: {}",
+                console.kernel_args()
+            ),
+            &[],
+            true,
+        ));
+    }
+    steps.push(Step::new(
+        "Exit chroot and reboot",
+        &["exit", "reboot"],
+        true,
+    ));
+    steps
+}
+
+/// The steps as the indented, commented block printed after "Done!".
+pub fn render(steps: &[Step]) -> String {
+    let mut out = String::new();
+    for step in steps {
+        out.push_str(&format!("\n  # {}\n", step.description));
+        for command in &step.commands {
+            out.push_str(&format!("  {}\n", command));
+        }
+    }
+    out
+}
+
+pub fn to_json(steps: &[Step]) -> Value {
+    Value::from(steps.iter().map(Step::to_json).collect::<Vec<_>>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual() {
+        let serial = SerialConsole::parse("ttyS0").unwrap();
+        let steps = manual("/mnt", BootMode::Uefi, Some(&serial));
+        assert_eq!(steps.len(), 6);
+        assert_eq!(steps[0].commands, ["recfstab /mnt >> /mnt/etc/fstab"]);
+        assert!(!steps[1].chroot && steps[2].chroot);
+        assert_eq!(steps[3].commands, ["bootctl install"]);
+        assert!(steps[4]
+            .description
+            .ends_with("console=tty0 console=ttyS0,115200"));
+        assert!(steps[4].commands.is_empty());
+
+        let text = render(&steps[..2]);
+        assert_eq!(
+            text,
+            "\n  # Generate fstab\n  recfstab /mnt >> /mnt/etc/fstab\n\
+             \n  # Chroot into new system\n  recchroot /mnt\n"
+        );
+        let json = to_json(&steps[5..]).to_string();
+        assert_eq!(
+            json,
+            "[{\"description\":\"Exit chroot and reboot\",\
+             \"commands\":[\"exit\",\"reboot\"],\"chroot\":true}]"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! `--notify`: a desktop notification when the install ends.
+//!
+//! A long install is easy to lose track of once the user has switched to
+//! a browser in the live session. recstrap runs as root, so the
+//! notification is sent with `notify-send` as the session's user, on that
+//! user's session bus: the user who ran sudo or pkexec, else the one
+//! whose `/run/user/UID/bus` exists (the lowest UID, preferring non-root).
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::probe::format_duration;
+use crate::runner;
+use crate::warnings::warn;
+
+/// Give up on a session bus that doesn't answer.
+const NOTIFY_TIMEOUT_SECS: u64 = 10;
+
+/// Notify the session user that the install into `what` ended with
+/// `result` after `elapsed`. Problems are warnings.
+pub fn finished(result: &Result<()>, what: &str, elapsed: Duration, quiet: bool) {
+    let (summary, body, urgency) = message(result, what, elapsed);
+    let Some(uid) = session_uid() else {
+        warn(quiet, "--notify: no desktop session bus found", &[]);
+        return;
+    };
+    let user = fs::read_to_string("/etc/passwd")
+        .ok()
+        .and_then(|passwd| user_name(&passwd, uid));
+    let Some(user) = user else {
+        warn(quiet, &format!("--notify: no user with UID {}", uid), &[]);
+        return;
+    };
+    if let Err(e) = send(uid, &user, &summary, &body, urgency) {
+        warn(
+            quiet,
+            &format!("cannot send the desktop notification: {}", e),
+            &[],
+        );
+    }
+}
+
+/// Summary, body and urgency of the notification.
+fn message(result: &Result<()>, what: &str, elapsed: Duration) -> (String, String, &'static str) {
+    let took = format_duration(elapsed.as_secs());
+    match result {
+        Ok(()) => (
+            "LevitateOS installed".to_string(),
+            format!("Installed into {} in {}", what, took),
+            "normal",
+        ),
+        Err(e) => (
+            "LevitateOS installation failed".to_string(),
+            format!("{} (after {})", e, took),
+            "critical",
+        ),
+    }
+}
+
+fn send(uid: u32, user: &str, summary: &str, body: &str, urgency: &str) -> std::io::Result<()> {
+    let output = runner::output_timed(
+        Command::new("runuser")
+            .args(["-u", user, "--", "env"])
+            .arg(format!(
+                "DBUS_SESSION_BUS_ADDRESS=unix:path=/run/user/{}/bus",
+                uid
+            ))
+            .args([
+                "notify-send",
+                "--app-name=recstrap",
+                "--icon=system-software-install",
+            ])
+            .arg(format!("--urgency={}", urgency))
+            .args([summary, body]),
+        NOTIFY_TIMEOUT_SECS,
+    )?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(match stderr.trim() {
+            "" => format!("notify-send exited with {}", output.status),
+            message => message.to_string(),
+        }));
+    }
+    Ok(())
+}
+
+/// The UID whose session should see the notification.
+fn session_uid() -> Option<u32> {
+    let has_bus = |uid: u32| Path::new(&format!("/run/user/{}/bus", uid)).exists();
+    for var in ["SUDO_UID", "PKEXEC_UID"] {
+        if let Some(uid) = std::env::var(var).ok().and_then(|v| v.parse().ok()) {
+            if has_bus(uid) {
+                return Some(uid);
+            }
+        }
+    }
+    let mut uids: Vec<u32> = fs::read_dir("/run/user")
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter(|&uid| has_bus(uid))
+        .collect();
+    // Root last: a live session's desktop user is usually someone else
+    uids.sort_by_key(|&uid| (uid == 0, uid));
+    uids.first().copied()
+}
+
+/// The name of `uid` in an `/etc/passwd` text.
+fn user_name(passwd: &str, uid: u32) -> Option<String> {
+    passwd.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let entry_uid: u32 = fields.nth(1)?.parse().ok()?;
+        (entry_uid == uid).then(|| name.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RecError;
+    use crate::runner::FakeRunner;
+
+    #[test]
+    fn test_message() {
+        let elapsed = Duration::from_secs(252);
+        assert_eq!(
+            message(&Ok(()), "/mnt", elapsed),
+            (
+                "LevitateOS installed".to_string(),
+                "Installed into /mnt in 4m 12s".to_string(),
+                "normal"
+            )
+        );
+        let (_, body, urgency) = message(
+            &Err(RecError::insufficient_space(2048, 100)),
+            "/mnt",
+            elapsed,
+        );
+        assert_eq!(
+            body,
+            "E012: insufficient disk space: need ~2048MB, have 100MB (after 4m 12s)"
+        );
+        assert_eq!(urgency, "critical");
+    }
+
+    #[test]
+    fn test_user_name() {
+        let passwd = "root:x:0:0:root:/root:/bin/sh\nlive:x:1000:1000::/home/live:/bin/bash\n";
+        assert_eq!(user_name(passwd, 1000).as_deref(), Some("live"));
+        assert_eq!(user_name(passwd, 0).as_deref(), Some("root"));
+        assert_eq!(user_name(passwd, 1001), None);
+    }
+
+    #[test]
+    fn test_send() {
+        let (fake, _runner) = FakeRunner::install();
+        send(1000, "live", "LevitateOS installed", "done", "normal").unwrap();
+        assert_eq!(
+            fake.calls(),
+            [
+                "runuser -u live -- env DBUS_SESSION_BUS_ADDRESS=unix:path=/run/user/1000/bus \
+              notify-send --app-name=recstrap --icon=system-software-install --urgency=normal \
+              LevitateOS installed done"
+            ]
+        );
+
+        fake.reply("runuser", 1, "Cannot autolaunch D-Bus");
+        let error = send(1000, "live", "x", "y", "normal").unwrap_err();
+        assert_eq!(error.to_string(), "Cannot autolaunch D-Bus");
+    }
+}
@@ -0,0 +1,144 @@
+//! Console output: message prefixes, color and verbosity.
+//!
+//! Lines that stand out from the running commentary (errors, warnings,
+//! notes, verification mismatches, `==>` steps) are printed here, so they
+//! look the same everywhere. On a terminal the prefixes are colored,
+//! unless `--no-color`, `NO_COLOR` or `TERM=dumb` say otherwise.
+//!
+//! `-v` also echoes every command recstrap runs (`+ mount -t erofs ...`),
+//! `-vv` every entry extracted. Both, and color, are off with `--quiet`,
+//! so its output stays byte for byte what scripts expect. Like the
+//! journal, this is set up by `cli_main` only; the library API prints
+//! plain text and no commands.
+
+use std::io::IsTerminal;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+const RED: &str = "1;31";
+const YELLOW: &str = "1;33";
+const CYAN: &str = "1;36";
+const BLUE: &str = "1;34";
+const DIM: &str = "2";
+
+/// Set the verbosity (count of `-v`) and whether to color, for the rest
+/// of the run.
+pub fn init(verbose: u8, no_color: bool, quiet: bool) {
+    let color = !quiet
+        && !no_color
+        && use_color(
+            std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()),
+            std::env::var("TERM").ok().as_deref(),
+            std::io::stderr().is_terminal(),
+        );
+    COLOR.store(color, Ordering::Relaxed);
+    VERBOSITY.store(if quiet { 0 } else { verbose }, Ordering::Relaxed);
+}
+
+/// How many `-v` were given (0 with `--quiet`).
+pub fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// `recstrap: <message>`, for the error that ends the run.
+pub fn error(message: &str) {
+    eprintln!("{} {}", paint(RED, "recstrap:"), message);
+}
+
+/// `recstrap: warning: <message>` followed by indented `details` lines.
+pub fn warning(message: &str, details: &[&str]) {
+    eprintln!("recstrap: {} {}", paint(YELLOW, "warning:"), message);
+    for line in details {
+        eprintln!("         {}", line);
+    }
+}
+
+/// `recstrap: note: <message>`.
+pub fn note(message: &str) {
+    eprintln!("recstrap: {} {}", paint(CYAN, "note:"), message);
+}
+
+/// `recstrap: mismatch: <message>`, for a verification difference.
+pub fn mismatch(message: &str) {
+    eprintln!("recstrap: {} {}", paint(RED, "mismatch:"), message);
+}
+
+/// `==> <message>`, heading a step of a longer run.
+pub fn step(message: &str) {
+    eprintln!("{} {}", paint(BLUE, "==>"), message);
+}
+
+/// Echo `cmd` as `+ program args` at `-v`.
+pub fn command(cmd: &Command) {
+    if verbosity() >= 1 {
+        eprintln!("{}", paint(DIM, &format!("+ {}", command_line(cmd))));
+    }
+}
+
+/// List an extracted entry at `-vv`.
+pub fn file(path: &Path) {
+    if verbosity() >= 2 {
+        eprintln!("  /{}", path.display());
+    }
+}
+
+/// Whether to color: not asked not to, and a terminal that can show it.
+fn use_color(no_color_env: bool, term: Option<&str>, is_terminal: bool) -> bool {
+    !no_color_env && term != Some("dumb") && is_terminal
+}
+
+fn paint(code: &str, text: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// `cmd` as it could be pasted into a shell.
+fn command_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+            if plain {
+                arg.into_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_use_color() {
+        assert!(use_color(false, Some("xterm-256color"), true));
+        assert!(use_color(false, None, true));
+        assert!(!use_color(true, Some("xterm-256color"), true));
+        assert!(!use_color(false, Some("dumb"), true));
+        assert!(!use_color(false, Some("xterm"), false));
+    }
+
+    #[test]
+    fn test_command_line() {
+        let mut cmd = Command::new("mount");
+        cmd.args(["-t", "erofs", "-o", "ro,loop", "/run/my image", "it's", ""]);
+        assert_eq!(
+            command_line(&cmd),
+            r"mount -t erofs -o ro,loop '/run/my image' 'it'\''s' ''"
+        );
+    }
+}
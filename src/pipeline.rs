@@ -0,0 +1,1227 @@
+//! The install, phase by phase.
+//!
+//! [`preflight`] checks the environment, the target and the image without
+//! writing anything. [`extract`] copies the image into the target, into a
+//! staging directory when the target is empty. [`verify`] checks what
+//! arrived and moves it into place. [`configure`] applies the options that
+//! change the installed system, writes the install record and flushes.
+//!
+//! The command line runs them in order and adds what only it does: the
+//! `--check` summary, the overwrite prompt, the initial user and the
+//! closing instructions. [`crate::Installer`] calls them directly.
+
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::audit;
+use crate::boot::{self, BootMode};
+use crate::cgroup::HelperCgroup;
+use crate::checks;
+use crate::chroot;
+use crate::cli::{Args, VerifyLevel};
+use crate::configure::{
+    disable_unit, enable_serial_console, enable_unit, inherit_live_config, regenerate_initramfs,
+    selinux_relabel, write_network_config, write_resolv_conf, write_vconsole, ResolvConf,
+    NETWORK_FILE, RESOLV_CONF, VCONSOLE_FILE,
+};
+use crate::constants::{MIN_REQUIRED_BYTES, ROOTFS_SEARCH_PATHS};
+use crate::copy::{copy_tree, CopyOptions, CopyStats};
+use crate::cpu;
+use crate::disk::DiskImage;
+use crate::erofs::{check_kernel_support, Superblock};
+use crate::error::{ErrorCode, RecError, Result};
+use crate::extractor::{self, Backend, Extractor};
+use crate::fixup::{ensure_api_dirs, ensure_device_nodes};
+use crate::flavor::{default_flavor, find_flavor};
+use crate::gpt;
+use crate::guarded_ensure;
+use crate::heartbeat;
+use crate::helpers::{
+    can_read_rootfs, ensure_erofs_module, find_rootfs, get_available_inodes, get_available_space,
+    is_dir_empty, is_mount_point, is_protected_path, is_root, is_rootfs_inside_target,
+    kernel_version, read_password_hash, regenerate_ssh_host_keys, sync_filesystem, tool_available,
+};
+use crate::mountinfo;
+use crate::output;
+use crate::preserve::{self, Preserved};
+use crate::probe::{format_duration, measure_write_speed, PROBE_BYTES, SLOW_TARGET_BYTES_PER_SEC};
+use crate::record::{now_utc, os_release_value, sha256_file, InstallRecord, RECORD_FILE};
+use crate::rootfs::{
+    create_staging, promote_staging, resolve_workdir, split_parts, validate_rootfs_magic,
+    verify_capabilities, verify_extraction, verify_hardlinks, MountMethod, RootfsInfo, RootfsType,
+    SpooledImage,
+};
+use crate::rootless::{enter_user_namespace, IdMapping};
+use crate::runner;
+use crate::sandbox::WriteRoots;
+use crate::sanity::{verify_package_database, verify_system_sanity, verify_usrmerge};
+use crate::snapshot::{is_subvolume, snapshot_target};
+use crate::state::{ExtractionState, STATE_FILE};
+use crate::stats::InstallStats;
+use crate::validation;
+use crate::verify::Scope;
+use crate::warnings::{self, warn};
+
+/// The image to install, found and, if needed, spooled or joined into
+/// one file.
+pub struct Image {
+    /// The file to mount
+    pub path: PathBuf,
+    /// What the install record and audit log name as the image (`-` for
+    /// stdin, the base name of a split image)
+    pub source: PathBuf,
+    /// Keeps a stdin-spooled or joined image alive, deleting it on drop
+    _spooled: Option<SpooledImage>,
+}
+
+impl Image {
+    /// The image named by `rootfs` (`-` for stdin), else the `flavor` from
+    /// the medium's manifest, else its default flavor or the plain search
+    /// paths. Split images are joined, and stdin spooled, in `workdir`.
+    pub fn locate(
+        rootfs: Option<&str>,
+        flavor: Option<&str>,
+        workdir: &Path,
+        quiet: bool,
+    ) -> Result<Self> {
+        let found = match rootfs {
+            Some("-") => {
+                let spooled = SpooledImage::from_stdin(workdir, quiet)?;
+                return Ok(Self {
+                    path: spooled.path().to_path_buf(),
+                    source: PathBuf::from("-"),
+                    _spooled: Some(spooled),
+                });
+            }
+            Some(path) => {
+                let p = Path::new(path);
+                if split_parts(p).is_none() {
+                    guarded_ensure!(
+                        p.exists(),
+                        RecError::rootfs_not_found(&[path]),
+                        check = &checks::ROOTFS_EXISTS
+                    );
+
+                    guarded_ensure!(
+                        p.is_file(),
+                        RecError::rootfs_not_file(path),
+                        check = &checks::ROOTFS_IS_FILE
+                    );
+                }
+                p.to_path_buf()
+            }
+            None => {
+                let found = match flavor {
+                    Some(name) => Some(find_flavor(ROOTFS_SEARCH_PATHS, name)?),
+                    None => default_flavor(ROOTFS_SEARCH_PATHS)?
+                        .or_else(|| find_rootfs().map(PathBuf::from)),
+                };
+                guarded_ensure!(
+                    found.is_some(),
+                    RecError::rootfs_not_found(ROOTFS_SEARCH_PATHS),
+                    check = &checks::ROOTFS_FOUND
+                );
+
+                let p = found.unwrap();
+                if split_parts(&p).is_none() {
+                    guarded_ensure!(
+                        p.is_file(),
+                        RecError::rootfs_not_file(&p.to_string_lossy()),
+                        check = &checks::FOUND_ROOTFS_IS_FILE
+                    );
+                }
+                p
+            }
+        };
+
+        if let Some(parts) = split_parts(&found) {
+            let spooled = SpooledImage::join(&parts, workdir, quiet)?;
+            return Ok(Self {
+                path: spooled.path().to_path_buf(),
+                source: parts[0].with_extension(""),
+                _spooled: Some(spooled),
+            });
+        }
+        let path = found
+            .canonicalize()
+            .map_err(|e| RecError::new(ErrorCode::RootfsNotFound, e.to_string()))?;
+        Ok(Self {
+            source: path.clone(),
+            path,
+            _spooled: None,
+        })
+    }
+}
+
+/// What the pre-flight checks found out, for the phases after them. Holds
+/// the helper cgroup, the helpers' write roots and the disk image of
+/// `--image` until dropped.
+pub struct Preflight {
+    pub target: PathBuf,
+    pub workdir: PathBuf,
+    pub image: Image,
+    pub rootfs_type: RootfsType,
+    pub extractor: Box<dyn Extractor>,
+    pub boot_mode: BootMode,
+    /// `--copy-into`, canonicalized
+    pub overlay: Option<PathBuf>,
+    pub os_release: Option<String>,
+    pub superblock: Superblock,
+    pub image_info: RootfsInfo,
+    /// Read from `--password-hash-fd`
+    pub password_hash: Option<String>,
+    /// `--image`: the disk image whose root partition is the target
+    pub disk_image: Option<DiskImage>,
+    _write_roots: WriteRoots,
+    _helper_cgroup: Option<HelperCgroup>,
+}
+
+/// Run every check that can be made before writing to `target` (`None`
+/// with `--image`, which creates its own).
+pub fn preflight(args: &Args, target: Option<&str>) -> Result<Preflight> {
+    heartbeat::set_phase("pre-flight checks");
+
+    let mount_method = mount_method(args)?;
+
+    guarded_ensure!(
+        is_root(),
+        RecError::not_root(),
+        check = &checks::RUNS_AS_ROOT
+    );
+
+    // Read now, so a bad hash fails before a long extraction
+    let password_hash = args
+        .password_hash_fd
+        .map(read_password_hash)
+        .transpose()
+        .map_err(|e| RecError::configuration_failed("--password-hash-fd", &e.to_string()))?;
+    if let Some(skel) = &args.skel {
+        if !Path::new(skel).is_dir() {
+            return Err(RecError::configuration_failed(
+                "--skel",
+                &format!("{} is not a directory", skel),
+            ));
+        }
+    }
+
+    // Lives as long as the result, so every helper of the install is
+    // confined
+    let helper_cgroup = match args.memory_limit {
+        Some(bytes) if !args.check => HelperCgroup::create(bytes)
+            .inspect(|cgroup| {
+                if !args.quiet {
+                    eprintln!(
+                        "Helper processes limited to {} MB ({})",
+                        bytes / (1024 * 1024),
+                        cgroup.path().display()
+                    );
+                }
+            })
+            .map_err(|e| {
+                warn(
+                    args.quiet,
+                    &format!("--memory-limit: cannot set up a cgroup: {}", e),
+                    &["Helper processes run without a memory limit"],
+                )
+            })
+            .ok(),
+        _ => None,
+    };
+
+    // --image: build a disk image and install into its mounted root
+    // partition, going through the same checks as a physical target
+    let disk_image = match (&args.image, args.size) {
+        (Some(file), Some(size)) => {
+            let workdir = resolve_workdir(args.workdir.as_deref())?;
+            Some(DiskImage::create(
+                Path::new(file),
+                size,
+                args.fs,
+                &workdir,
+                args.quiet,
+            )?)
+        }
+        _ => None,
+    };
+    // clap guarantees TARGET (or --image) when no subcommand is given
+    let target_arg = match &disk_image {
+        Some(image) => image.root().to_string_lossy().into_owned(),
+        None => target.unwrap_or_default().to_string(),
+    };
+
+    let checked = check_target(args, &target_arg, disk_image.is_some())?;
+    let target = checked.target;
+    let workdir = checked.workdir;
+
+    let image = Image::locate(
+        args.rootfs.as_deref(),
+        args.flavor.as_deref(),
+        &workdir,
+        args.quiet,
+    )?;
+    let inspected = check_image(
+        args,
+        &image.path,
+        &target,
+        &workdir,
+        mount_method,
+        checked.available_space,
+    )?;
+
+    // --check --all: everything has been checked
+    validation::collected()?;
+
+    // --strict: nothing has been written yet, so stop here rather than
+    // after a full extraction
+    warnings::check_strict(args.strict)?;
+
+    Ok(Preflight {
+        target,
+        workdir,
+        image,
+        rootfs_type: inspected.rootfs_type,
+        extractor: inspected.extractor,
+        boot_mode: checked.boot_mode,
+        overlay: checked.overlay,
+        os_release: inspected.os_release,
+        superblock: inspected.superblock,
+        image_info: inspected.image_info,
+        password_hash,
+        disk_image,
+        _write_roots: checked.write_roots,
+        _helper_cgroup: helper_cgroup,
+    })
+}
+
+/// How the image will be mounted. Rootless mode becomes root inside a user
+/// namespace here; the root check that follows still applies - it just
+/// passes there.
+fn mount_method(args: &Args) -> Result<MountMethod> {
+    if args.rootless {
+        if args.backend == Backend::ErofsMount {
+            return Err(RecError::rootless_unavailable(
+                "the erofs-mount backend needs real root; use erofs-fuse",
+            ));
+        }
+        if is_root() {
+            return Err(RecError::rootless_unavailable(
+                "already running as root; drop --rootless for a real install",
+            ));
+        }
+        if !tool_available("erofsfuse") {
+            return Err(RecError::tool_not_installed("erofsfuse", "erofs-utils"));
+        }
+        let mapping = enter_user_namespace().map_err(|e| {
+            RecError::rootless_unavailable(&format!("cannot create user namespace: {}", e))
+        })?;
+        if mapping == IdMapping::SingleId {
+            warn(
+                args.quiet,
+                "no subordinate IDs for this user (/etc/subuid)",
+                &["File ownership will not be preserved"],
+            );
+        }
+        Ok(MountMethod::Fuse)
+    } else if args.backend == Backend::ErofsFuse {
+        if !tool_available("erofsfuse") {
+            return Err(RecError::tool_not_installed("erofsfuse", "erofs-utils"));
+        }
+        Ok(MountMethod::Fuse)
+    } else {
+        Ok(MountMethod::Kernel)
+    }
+}
+
+/// What [`check_target`] found out.
+struct CheckedTarget {
+    target: PathBuf,
+    workdir: PathBuf,
+    write_roots: WriteRoots,
+    overlay: Option<PathBuf>,
+    boot_mode: BootMode,
+    available_space: Option<u64>,
+}
+
+/// Check that `target_arg` can be installed to: exists, writable, not a
+/// system path, mounted, empty unless `--force` or `--reinstall`, and with
+/// room for a minimal system. Also checks the workdir and `--copy-into`
+/// against it.
+fn check_target(args: &Args, target_arg: &str, disk_image: bool) -> Result<CheckedTarget> {
+    let target = Path::new(target_arg);
+
+    guarded_ensure!(
+        target.exists(),
+        RecError::target_not_found(target_arg),
+        check = &checks::TARGET_EXISTS
+    );
+
+    guarded_ensure!(
+        target.is_dir(),
+        RecError::not_a_directory(target_arg),
+        check = &checks::TARGET_IS_DIRECTORY
+    );
+
+    // Canonicalize path to resolve symlinks and ..
+    let target = target
+        .canonicalize()
+        .map_err(|e| RecError::new(ErrorCode::TargetNotFound, e.to_string()))?;
+    let target_str = target.to_string_lossy();
+
+    guarded_ensure!(
+        !is_protected_path(&target),
+        RecError::protected_path(&target_str),
+        check = &checks::TARGET_NOT_PROTECTED
+    );
+
+    // Mount options: a read-only target is fatal; noexec/nodev/nosuid
+    // produce a system whose setuid binaries and device nodes don't work
+    if let Some(mount) = mountinfo::read()
+        .ok()
+        .and_then(|entries| mountinfo::containing(&entries, &target))
+    {
+        let mount_str = mount.mount_point.to_string_lossy();
+        guarded_ensure!(
+            !mount.has_option("ro"),
+            RecError::target_read_only(&target_str, &mount_str),
+            check = &checks::TARGET_READ_WRITE
+        );
+
+        let hostile = mount.hostile_options();
+        if !hostile.is_empty() && !args.rootless {
+            // --check stays read-only
+            if args.remount && !args.check {
+                remount_permissive(&mount.mount_point, args.quiet)?;
+            } else {
+                warn(
+                    args.quiet,
+                    &format!("{} is mounted {}", mount_str, hostile.join(",")),
+                    &[
+                        "setuid binaries and device nodes in the installed system",
+                        "may not work until it is remounted (or use --remount)",
+                    ],
+                );
+            }
+        }
+    }
+
+    // Write permission check
+    let test_file = target.join(".recstrap_write_test");
+    let can_write = fs::write(&test_file, b"test").is_ok();
+    if can_write {
+        let _ = fs::remove_file(&test_file);
+    }
+
+    guarded_ensure!(
+        can_write,
+        RecError::not_writable(&target_str),
+        check = &checks::TARGET_WRITABLE
+    );
+
+    // Work directory for temporary mount points and stdin spooling
+    let workdir = resolve_workdir(args.workdir.as_deref())?;
+    let workdir_str = workdir.to_string_lossy();
+
+    guarded_ensure!(
+        !workdir.starts_with(&target),
+        RecError::workdir_inside_target(&workdir_str, &target_str),
+        check = &checks::WORKDIR_OUTSIDE_TARGET
+    );
+
+    // Helpers working on the target (chrooted tools, ssh-keygen) may only
+    // write beneath it and the workdir
+    let write_roots = WriteRoots::set(vec![target.clone(), workdir.clone()]);
+
+    // --uki: the ESP must be a block device; catch typos before extracting
+    if let Some(esp) = &args.uki {
+        let is_block = fs::metadata(esp).is_ok_and(|m| m.file_type().is_block_device());
+        if !is_block {
+            return Err(RecError::configuration_failed(
+                "UKI",
+                &format!("{} is not a block device", esp),
+            ));
+        }
+    }
+
+    // --copy-into overlay: an existing directory that doesn't contain the
+    // target (copying / over /mnt would recurse forever)
+    let overlay = match &args.copy_into {
+        Some(dir) => {
+            let path = Path::new(dir)
+                .canonicalize()
+                .ok()
+                .filter(|p| p.is_dir())
+                .ok_or_else(|| RecError::not_a_directory(dir))?;
+            guarded_ensure!(
+                !target.starts_with(&path),
+                RecError::overlay_contains_target(dir, &target_str),
+                check = &checks::OVERLAY_OUTSIDE_TARGET
+            );
+            Some(path)
+        }
+        None => None,
+    };
+
+    // Mount point check (unless --force; rootless targets are scratch dirs)
+    if !args.force && !args.rootless {
+        let is_mp = is_mount_point(&target).unwrap_or(false);
+        guarded_ensure!(
+            is_mp,
+            RecError::not_mount_point(&target_str),
+            check = &checks::TARGET_MOUNTED
+        );
+    }
+
+    // The bootloader the user installs later must match how this live
+    // system booted; catch a disk that can't hold it now
+    let boot_mode = BootMode::detect();
+    if !disk_image {
+        if let Some(warning) = boot::layout_warning(boot_mode, &target) {
+            warn(
+                args.quiet,
+                &warning,
+                &["The bootloader install step will fail on this layout"],
+            );
+        }
+    }
+
+    // Installing onto the ESP or a Windows partition mounted by mistake
+    if !disk_image && !args.rootless {
+        if let Some(mount) = mountinfo::read()
+            .ok()
+            .and_then(|entries| mountinfo::containing(&entries, &target))
+        {
+            let problem = gpt::partition_type(Path::new(&mount.source))
+                .ok()
+                .flatten()
+                .and_then(|guid| gpt::type_problem(&guid));
+            if let Some(problem) = problem {
+                if args.strict {
+                    return Err(RecError::wrong_partition_type(&mount.source, &problem));
+                }
+                warn(
+                    args.quiet,
+                    &format!("target partition {} {}", mount.source, problem),
+                    &["Is the right partition mounted? (--strict makes this fatal)"],
+                );
+            }
+        }
+    }
+
+    if target.join(STATE_FILE).exists() && !args.quiet {
+        output::note(&format!(
+            "{} holds an interrupted extraction; run 'recstrap clean {}' first",
+            target_str, target_str
+        ));
+    }
+
+    // A previous install is told apart from arbitrary data by its record:
+    // --reinstall replaces only the former, --force overwrites anything
+    let prior = InstallRecord::read(&target).ok().flatten();
+    if args.reinstall {
+        guarded_ensure!(
+            prior.is_some(),
+            RecError::no_prior_install(&target_str),
+            check = &checks::PRIOR_INSTALL
+        );
+        if let Some(prior) = &prior {
+            if !args.quiet {
+                eprintln!(
+                    "Reinstalling over LevitateOS {} (installed {} from {})",
+                    prior
+                        .image_version
+                        .as_deref()
+                        .unwrap_or("(unknown version)"),
+                    prior.installed,
+                    prior.image.display()
+                );
+            }
+        }
+    }
+
+    // Empty check (unless --force or --reinstall)
+    if !args.force && !args.reinstall {
+        let is_empty = is_dir_empty(&target).unwrap_or(false);
+        guarded_ensure!(
+            is_empty,
+            match &prior {
+                Some(prior) => RecError::prior_install(
+                    &target_str,
+                    prior.image_version.as_deref().unwrap_or("unknown version"),
+                    &prior.installed,
+                ),
+                None => RecError::target_not_empty(&target_str),
+            },
+            check = &checks::TARGET_EMPTY
+        );
+    }
+
+    // Disk space check: a floor here, the image's own size once it is known
+    let available_space = get_available_space(&target).ok();
+    let min_free = args.min_free.unwrap_or(MIN_REQUIRED_BYTES);
+    if let Some(available) = available_space {
+        guarded_ensure!(
+            available >= min_free,
+            RecError::insufficient_space(min_free / (1024 * 1024), available / (1024 * 1024)),
+            check = &checks::MIN_FREE_SPACE
+        );
+    } else {
+        warn(args.quiet, "cannot check disk space", &[]);
+    }
+
+    Ok(CheckedTarget {
+        target,
+        workdir,
+        write_roots,
+        overlay,
+        boot_mode,
+        available_space,
+    })
+}
+
+/// What [`check_image`] found out.
+struct InspectedImage {
+    rootfs_type: RootfsType,
+    extractor: Box<dyn Extractor>,
+    os_release: Option<String>,
+    superblock: Superblock,
+    image_info: RootfsInfo,
+}
+
+/// Check that the image at `rootfs` can be installed to `target` from
+/// here: a readable EROFS image outside the target, mountable by this
+/// kernel, built for this CPU, and fitting in the target's free space and
+/// inodes.
+fn check_image(
+    args: &Args,
+    rootfs: &Path,
+    target: &Path,
+    workdir: &Path,
+    mount_method: MountMethod,
+    available_space: Option<u64>,
+) -> Result<InspectedImage> {
+    let rootfs_str = rootfs.to_string_lossy();
+    let target_str = target.to_string_lossy();
+
+    // Detect rootfs type from extension (EROFS only).
+    let rootfs_type = RootfsType::from_path(rootfs).ok_or_else(|| {
+        RecError::invalid_rootfs_format(
+            &rootfs_str,
+            "expected .erofs extension (squashfs is no longer supported)",
+        )
+    })?;
+    let extractor = extractor::select(rootfs_type, mount_method);
+
+    guarded_ensure!(
+        can_read_rootfs(rootfs),
+        RecError::rootfs_not_readable(&rootfs_str),
+        check = &checks::ROOTFS_READABLE
+    );
+
+    guarded_ensure!(
+        !is_rootfs_inside_target(rootfs, target),
+        RecError::rootfs_inside_target(&rootfs_str, &target_str),
+        check = &checks::ROOTFS_OUTSIDE_TARGET
+    );
+
+    // Validate magic bytes match expected format
+    if let Err(e) = validate_rootfs_magic(rootfs, rootfs_type) {
+        return Err(RecError::invalid_rootfs_format(&rootfs_str, &e.to_string()));
+    }
+
+    // erofsfuse decodes the image in userspace, so kernel support only
+    // matters for a kernel mount
+    if mount_method == MountMethod::Kernel {
+        check_kernel_erofs(rootfs, &rootfs_str)?;
+    }
+
+    // Name the exact image build, so logs and the statistics show what was
+    // installed
+    let image_info = RootfsInfo::read(rootfs)
+        .map_err(|e| RecError::invalid_rootfs_format(&rootfs_str, &e.to_string()))?;
+    if !args.quiet {
+        eprintln!("Image: {}", image_info.summary());
+    }
+
+    // CPU level: binaries built for x86-64-v3 die with SIGILL on older CPUs,
+    // long after the install "succeeded"
+    let os_release = extractor.read_os_release(rootfs, workdir)?;
+    if let Some(level) = os_release.as_deref().and_then(cpu::required_level) {
+        let missing = cpu::check_host(level);
+        guarded_ensure!(
+            missing.is_empty(),
+            RecError::cpu_not_supported(&format!("v{}", level), &missing),
+            check = &checks::CPU_LEVEL
+        );
+    }
+
+    // Inode check: a filesystem made with few inodes (mkfs.ext4 -T largefile)
+    // otherwise runs out mid-extraction with ENOSPC while bytes are free
+    let superblock = Superblock::read_from(rootfs)
+        .map_err(|e| RecError::invalid_rootfs_format(&rootfs_str, &e.to_string()))?;
+    match get_available_inodes(target) {
+        Ok(Some(available)) => {
+            guarded_ensure!(
+                available >= superblock.inos,
+                RecError::insufficient_inodes(superblock.inos, available),
+                check = &checks::FREE_INODES
+            );
+        }
+        // Dynamic inode allocation (btrfs)
+        Ok(None) => {}
+        Err(_) => warn(args.quiet, "cannot check free inodes", &[]),
+    }
+
+    // Space for the image itself: everything it unpacks to, plus
+    // --space-margin for directories and block rounding
+    let data = extractor.data_size(rootfs, workdir)?;
+    if let Some(available) = available_space {
+        let needed = data.saturating_mul(100 + args.space_margin) / 100;
+        guarded_ensure!(
+            available >= needed,
+            RecError::insufficient_space(needed / (1024 * 1024), available / (1024 * 1024)),
+            check = &checks::IMAGE_FITS
+        );
+    }
+
+    // Optional throughput probe: how long will this take, and is the target
+    // a slow USB stick?
+    if args.probe_speed {
+        match measure_write_speed(target, PROBE_BYTES) {
+            Ok(speed) => {
+                if !args.quiet {
+                    eprintln!(
+                        "Target write speed: {:.1} MB/s; estimated extraction time: ~{} ({} MB)",
+                        speed / (1024.0 * 1024.0),
+                        format_duration((data as f64 / speed) as u64),
+                        data / (1024 * 1024)
+                    );
+                }
+                if speed < SLOW_TARGET_BYTES_PER_SEC {
+                    warn(
+                        args.quiet,
+                        &format!(
+                            "target writes at {:.1} MB/s - this looks like a slow USB stick or \
+                             SD card",
+                            speed / (1024.0 * 1024.0)
+                        ),
+                        &["Consider a faster device for a usable system"],
+                    );
+                }
+            }
+            Err(e) => warn(args.quiet, &format!("write speed probe failed: {}", e), &[]),
+        }
+    }
+
+    Ok(InspectedImage {
+        rootfs_type,
+        extractor,
+        os_release,
+        superblock,
+        image_info,
+    })
+}
+
+/// An extraction that [`verify`] has yet to check and move into place.
+pub struct Extraction {
+    /// Where the image was copied to: a staging directory inside the
+    /// target, or the target itself when it wasn't empty
+    pub dest: PathBuf,
+    pub staged: bool,
+    /// Paths set aside by `--preserve` and `--reinstall`
+    preserved: Option<Preserved>,
+    pub stats: CopyStats,
+    pub secs: f64,
+    /// Target space the extraction used, if measurable
+    pub disk_bytes: Option<u64>,
+}
+
+/// Copy the image into the target, as [`preflight`] found them.
+pub fn extract(args: &Args, preflight: &Preflight) -> Result<Extraction> {
+    let target = &preflight.target;
+    let target_str = target.to_string_lossy();
+    let rootfs = &preflight.image.path;
+    let rootfs_str = rootfs.to_string_lossy();
+
+    heartbeat::set_phase("extracting");
+    if args.audit {
+        audit::begin(
+            &target_str,
+            &preflight.image.source.to_string_lossy(),
+            args.quiet,
+        );
+    }
+
+    if !args.quiet {
+        eprintln!(
+            "Extracting {} ({:?}) to {}...",
+            rootfs_str, preflight.rootfs_type, target_str
+        );
+    }
+
+    // Extract into a hidden staging directory and promote it only after
+    // verification passes, so a failed run never leaves a root that looks
+    // installable. A non-empty target (--force) is updated in place.
+    let staged = is_dir_empty(target).unwrap_or(false);
+
+    // Record the extraction so an interrupted run can be cleaned up
+    // with `recstrap clean`
+    ExtractionState::begin(target, rootfs)
+        .and_then(|state| state.write(target))
+        .map_err(|e| {
+            RecError::new(
+                ErrorCode::NotWritable,
+                format!("cannot write {} in {}: {}", STATE_FILE, target_str, e),
+            )
+        })?;
+
+    // Safety net for overwriting existing content: snapshot btrfs targets
+    if !staged && !args.no_snapshot && is_subvolume(target) {
+        let snapshot = snapshot_target(target)?;
+        if !args.quiet {
+            eprintln!(
+                "Snapshot of previous contents: {} (read-only)",
+                snapshot.display()
+            );
+            eprintln!("  To roll back: remove the extracted files, then");
+            eprintln!(
+                "    cp -a --reflink=always {}/. {}/",
+                snapshot.display(),
+                target_str
+            );
+            eprintln!(
+                "  When no longer needed: btrfs subvolume delete {}",
+                snapshot.display()
+            );
+        }
+    }
+
+    // --preserve and --reinstall: set the kept paths aside until the
+    // image is in place and verified
+    let mut keep = args.preserve.clone();
+    if args.reinstall {
+        keep.extend(preserve::REINSTALL_DEFAULTS.iter().map(|p| p.to_string()));
+    }
+    let preserved = if staged || keep.is_empty() {
+        None
+    } else {
+        let (preserved, skipped) = Preserved::stash(target, &keep).map_err(|e| {
+            RecError::extraction_failed(&format!("cannot set preserved paths aside: {}", e))
+        })?;
+        if !args.quiet && !preserved.paths().is_empty() {
+            eprintln!("Preserving: /{}", preserved.paths().join(", /"));
+        }
+        for path in skipped {
+            warn(
+                args.quiet,
+                &format!("/{} is a mount point and is not preserved", path),
+                &["The image's files for it are extracted into the mounted filesystem"],
+            );
+        }
+        Some(preserved)
+    };
+
+    let dest = if staged {
+        create_staging(target)?
+    } else {
+        warn(
+            args.quiet,
+            "target is not empty, extracting in place (not transactional)",
+            &[],
+        );
+        target.clone()
+    };
+
+    // Mount (or whatever the backend does) + native copy + unmount
+    let space_before = get_available_space(target).ok();
+    let started = Instant::now();
+    let checksum = args.verify == VerifyLevel::Inline;
+    let stats =
+        preflight
+            .extractor
+            .extract(rootfs, &dest, &preflight.workdir, checksum, args.quiet)?;
+    let secs = started.elapsed().as_secs_f64();
+    let disk_bytes = space_before
+        .zip(get_available_space(target).ok())
+        .map(|(before, after)| before.saturating_sub(after));
+    if stats.skipped > 0 {
+        warn(
+            args.quiet,
+            &format!(
+                "{} ownership changes, xattrs, or device nodes could not be reproduced \
+                 without real root",
+                stats.skipped
+            ),
+            &[],
+        );
+    }
+
+    // Minimal images may lack /proc, /sys, /tmp, ... (or their modes)
+    let fixed = ensure_api_dirs(&dest).map_err(|e| {
+        RecError::extraction_failed(&format!("cannot create API directories: {}", e))
+    })?;
+    if !fixed.is_empty() && !args.quiet {
+        eprintln!("Fixed API directories the image lacks:");
+        for change in &fixed {
+            eprintln!("  {}", change);
+        }
+    }
+
+    // ...and /dev/null and /dev/console, which some build systems strip
+    let (created, failed) = ensure_device_nodes(&dest);
+    if !created.is_empty() && !args.quiet {
+        eprintln!("Created missing device nodes: {}", created.join(", "));
+    }
+    if !failed.is_empty() {
+        warn(
+            args.quiet,
+            &format!("cannot create device nodes: {}", failed.join(", ")),
+            &["Early boot and chroot may fail until they exist"],
+        );
+    }
+
+    Ok(Extraction {
+        dest,
+        staged,
+        preserved,
+        stats,
+        secs,
+        disk_bytes,
+    })
+}
+
+/// Check that `extraction` produced a complete, bootable copy of the image
+/// (byte for byte with `--verify sample` or `full`), then move it into
+/// place and put preserved paths back.
+pub fn verify(args: &Args, preflight: &Preflight, extraction: &mut Extraction) -> Result<()> {
+    let dest = &extraction.dest;
+    let stats = &extraction.stats;
+    let target = &preflight.target;
+
+    heartbeat::set_phase("verifying");
+
+    // Verify extraction produced a valid system
+    verify_extraction(dest)?;
+
+    // Verify the files everything else depends on: loader, shell, init, accounts
+    verify_system_sanity(dest)?;
+
+    // Verify the image's package manager knows what is installed
+    verify_package_database(dest)?;
+
+    // Verify /bin, /sbin, /lib, /lib64 are still the image's symlinks into /usr
+    verify_usrmerge(dest, &stats.root_symlinks)?;
+
+    // Verify hardlink groups from the image were not split into copies
+    verify_hardlinks(dest, &stats.hardlink_groups)?;
+
+    // Verify file capabilities (ping, etc.) were not stripped by the target fs.
+    // Rootless extractions are never bootable installs, so only warn there.
+    let stripped = verify_capabilities(dest, &stats.capabilities, args.relaxed || args.rootless)?;
+    if !stripped.is_empty() {
+        warn(
+            args.quiet,
+            &format!("file capabilities stripped from: {}", stripped.join(", ")),
+            &["Restore them in chroot with setcap, or some tools won't work"],
+        );
+    }
+
+    // Byte-for-byte comparison against the image (--verify sample/full)
+    let scope = match args.verify {
+        // Inline already checked every file while copying
+        VerifyLevel::Basic | VerifyLevel::Inline => None,
+        VerifyLevel::Sample => Some(Scope::Sample(args.verify_samples)),
+        VerifyLevel::Full => Some(Scope::All),
+    };
+    if let Some(scope) = scope {
+        let report = preflight.extractor.verify(
+            &preflight.image.path,
+            dest,
+            &preflight.workdir,
+            scope,
+            args.quiet,
+        )?;
+        let differences: Vec<String> = report.differences.iter().map(|d| d.to_string()).collect();
+        for difference in &differences {
+            output::mismatch(difference);
+        }
+
+        guarded_ensure!(
+            differences.is_empty(),
+            RecError::target_differs(&differences),
+            check = &checks::MATCHES_IMAGE
+        );
+    }
+
+    // Verified - move the staged system into place
+    if extraction.staged {
+        promote_staging(dest, target)?;
+    }
+    if let Some(preserved) = extraction.preserved.take() {
+        preserved.restore().map_err(|e| {
+            RecError::extraction_failed(&format!(
+                "cannot restore preserved paths (they are in {}): {}",
+                target.join(preserve::STASH_DIR).display(),
+                e
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Finish the verified install: apply the options that configure the
+/// installed system, write the install record, give it its own SSH host
+/// keys and flush it to disk. Returns the statistics of the whole install,
+/// which began at `started`, and the record.
+pub fn configure(
+    args: &Args,
+    preflight: &Preflight,
+    extraction: &Extraction,
+    started: Instant,
+) -> Result<(InstallStats, InstallRecord)> {
+    let target = &preflight.target;
+    let target_str = target.to_string_lossy();
+    let stats = &extraction.stats;
+
+    heartbeat::set_phase("configuring");
+    // This is no longer a partial extraction
+    if let Err(e) = ExtractionState::finish(target) {
+        warn(
+            args.quiet,
+            &format!("cannot remove {}: {}", STATE_FILE, e),
+            &[],
+        );
+    }
+
+    // Site-specific files (configs, units, branding) layered over the
+    // verified system. Existing directories keep the image's metadata.
+    if let Some(overlay) = &preflight.overlay {
+        if !args.quiet {
+            eprintln!("Copying {} into target...", overlay.display());
+        }
+        let options = CopyOptions {
+            best_effort: args.rootless,
+            overlay: true,
+            ..Default::default()
+        };
+        let overlay_stats = copy_tree(overlay, target, &options)?;
+        if !args.quiet {
+            eprintln!("  {} entries copied", overlay_stats.entries());
+        }
+        verify_usrmerge(target, &stats.root_symlinks)?;
+    }
+
+    if let Some(console) = &args.serial_console {
+        if !args.quiet {
+            eprintln!("Enabling login on serial console {}...", console.device);
+        }
+        enable_serial_console(target, console)?;
+    }
+
+    if args.inherit_live_config {
+        if !args.quiet {
+            eprintln!("Copying the live session's locale, console and time zone...");
+        }
+        for file in inherit_live_config(target, Path::new("/"))? {
+            if !args.quiet {
+                eprintln!("  /{}", file);
+            }
+        }
+    }
+
+    // Console keymap/font: explicit flags, else whatever the live session
+    // uses, so non-US users can type their password on first boot
+    let live_vconsole = fs::read_to_string(Path::new("/").join(VCONSOLE_FILE)).unwrap_or_default();
+    let keymap = args
+        .keymap
+        .clone()
+        .or_else(|| os_release_value(&live_vconsole, "KEYMAP"));
+    let font = args
+        .console_font
+        .clone()
+        .or_else(|| os_release_value(&live_vconsole, "FONT"));
+    if keymap.is_some() || font.is_some() {
+        if !args.quiet {
+            eprintln!("Writing /{}...", VCONSOLE_FILE);
+        }
+        write_vconsole(target, keymap.as_deref(), font.as_deref())?;
+    }
+
+    if let Some(network) = &args.network {
+        if !args.quiet {
+            eprintln!("Writing /{}...", NETWORK_FILE);
+        }
+        write_network_config(target, network)?;
+    }
+
+    if args.resolv_conf != ResolvConf::None {
+        if !args.quiet {
+            eprintln!(
+                "Setting up /{} ({})...",
+                RESOLV_CONF,
+                format!("{:?}", args.resolv_conf).to_lowercase()
+            );
+        }
+        write_resolv_conf(target, args.resolv_conf)?;
+    }
+
+    for unit in &args.enable {
+        if !args.quiet {
+            eprintln!("Enabling {}...", unit);
+        }
+        enable_unit(target, unit)?;
+    }
+    for unit in &args.disable {
+        if !args.quiet {
+            eprintln!("Disabling {}...", unit);
+        }
+        disable_unit(target, unit)?;
+    }
+
+    if args.selinux_relabel {
+        if !args.quiet {
+            eprintln!("Scheduling SELinux relabel on first boot...");
+        }
+        let warning = selinux_relabel(target, args.selinux_copy_policy)?;
+        if let Some(warning) = warning {
+            warn(args.quiet, &warning, &[]);
+        }
+    }
+
+    // Last of the configuration steps, so the initramfs sees the final
+    // vconsole and unit setup
+    if args.regen_initramfs {
+        if !args.quiet {
+            eprintln!("Regenerating initramfs inside the target...");
+        }
+        chroot::warn_kernel_mismatch(target, args.quiet);
+        regenerate_initramfs(target)?;
+    }
+
+    if let Some(esp) = &args.uki {
+        if !args.quiet {
+            eprintln!("Building Unified Kernel Images on {}...", esp);
+        }
+        let serial_args = args.serial_console.as_ref().map(|c| c.kernel_args());
+        boot::build_ukis(target, Path::new(esp), serial_args.as_deref())?;
+    }
+
+    // Record where this system came from, for support, audits, and later
+    // reinstalls. Not fatal: the installed system itself is complete.
+    if !args.quiet {
+        eprintln!("Writing /{}...", RECORD_FILE);
+    }
+    let record = InstallRecord {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        image: preflight.image.source.clone(),
+        image_sha256: sha256_file(&preflight.image.path),
+        image_uuid: preflight.superblock.uuid_string(),
+        image_format: "erofs".to_string(),
+        image_version: preflight
+            .os_release
+            .as_deref()
+            .and_then(|o| os_release_value(o, "VERSION_ID")),
+        installed: now_utc(),
+        options: args.argv.join(" "),
+    };
+    if let Err(e) = record.write(target) {
+        warn(
+            args.quiet,
+            &format!("cannot write /{}: {}", RECORD_FILE, e),
+            &[],
+        );
+    }
+
+    // SECURITY: Regenerate SSH host keys to prevent MITM attacks.
+    // The rootfs image contains pre-generated keys shared by all installations.
+    // Each installed system needs unique keys.
+    if !args.quiet {
+        eprintln!("Regenerating SSH host keys...");
+    }
+    if let Err(e) = regenerate_ssh_host_keys(target, args.quiet) {
+        // Warning only - not fatal since user can regenerate manually
+        warn(
+            args.quiet,
+            &format!("SSH key regeneration failed: {}", e),
+            &["Run 'ssh-keygen -A' in chroot to generate keys manually"],
+        );
+    }
+
+    heartbeat::set_phase("flushing to disk");
+    // Everything verified so far may still only be in the page cache
+    if !args.no_sync {
+        if !args.quiet {
+            eprintln!("Flushing target to disk...");
+        }
+        sync_filesystem(target).map_err(|e| {
+            RecError::new(
+                ErrorCode::ExtractionFailed,
+                format!("cannot flush {} to disk: {}", target_str, e),
+            )
+        })?;
+    }
+
+    let mut image_info = preflight.image_info.clone();
+    image_info.uncompressed_size = Some(stats.bytes);
+    let stats = InstallStats {
+        image_bytes: image_info.image_size,
+        extracted_bytes: stats.bytes,
+        disk_bytes: extraction.disk_bytes,
+        files: stats.files,
+        dirs: stats.dirs,
+        symlinks: stats.symlinks,
+        extraction_secs: extraction.secs,
+        total_secs: started.elapsed().as_secs_f64(),
+        image: Some(image_info),
+        warnings: warnings::recorded(),
+    };
+    Ok((stats, record))
+}
+
+/// Remount `mount_point` with exec, dev, and suid so the extracted system
+/// behaves as it will when booted.
+fn remount_permissive(mount_point: &Path, quiet: bool) -> Result<()> {
+    if !quiet {
+        eprintln!("Remounting {} with exec,dev,suid...", mount_point.display());
+    }
+    let status = runner::status(
+        std::process::Command::new("mount")
+            .args(["-o", "remount,exec,dev,suid"])
+            .arg(mount_point),
+    )
+    .map_err(|e| RecError::new(ErrorCode::NotWritable, format!("cannot run mount: {}", e)))?;
+    if !status.success() {
+        return Err(RecError::new(
+            ErrorCode::NotWritable,
+            format!(
+                "failed to remount {} with exec,dev,suid",
+                mount_point.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the running kernel can mount the image: EROFS support is
+/// present and every on-disk feature the image uses is understood.
+fn check_kernel_erofs(rootfs: &Path, rootfs_str: &str) -> Result<()> {
+    guarded_ensure!(
+        ensure_erofs_module(),
+        RecError::erofs_not_supported(),
+        check = &checks::KERNEL_EROFS
+    );
+
+    // Compare the image's on-disk features against what this kernel can mount
+    let superblock = Superblock::read_from(rootfs)
+        .map_err(|e| RecError::invalid_rootfs_format(rootfs_str, &e.to_string()))?;
+    let unsupported = kernel_version().and_then(|k| check_kernel_support(&superblock, k).err());
+
+    guarded_ensure!(
+        unsupported.is_none(),
+        RecError::erofs_feature_unsupported(unsupported.as_deref().unwrap_or_default()),
+        check = &checks::KERNEL_EROFS_FEATURES
+    );
+
+    Ok(())
+}
@@ -0,0 +1,190 @@
+//! Keeping chosen paths of a target across an install over it.
+//!
+//! `--preserve` (and `--reinstall`, which always keeps the machine's
+//! identity and user data) moves those paths into a stash directory in the
+//! target before extraction and moves them back once the image is in place
+//! and verified, replacing what the image put there. Both moves are
+//! renames within the target filesystem, so even a large /home costs
+//! nothing. Paths that are mount points stay where they are.
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::helpers::is_mount_point;
+use crate::output;
+
+/// Kept by every `--reinstall`.
+pub const REINSTALL_DEFAULTS: &[&str] = &["etc/fstab", "etc/machine-id", "home"];
+
+/// Where stashed paths wait in the target; deliberately not `.recstrap_`
+/// so `recstrap clean` never removes them.
+pub const STASH_DIR: &str = ".recstrap-preserved";
+
+/// `--preserve` values: a path inside the target, absolute or relative to
+/// it, without `..`.
+pub fn parse_path(s: &str) -> Result<String, String> {
+    let mut parts = Vec::new();
+    for component in Path::new(s).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            Component::RootDir | Component::CurDir => {}
+            _ => return Err(format!("'{}' must not contain '..'", s)),
+        }
+    }
+    if parts.is_empty() {
+        return Err(format!("'{}' names the whole target", s));
+    }
+    Ok(parts.join("/"))
+}
+
+/// Stashed paths, moved back by [`Preserved::restore`] or, if the install
+/// fails first, when dropped.
+pub struct Preserved {
+    target: PathBuf,
+    paths: Vec<String>,
+    restored: bool,
+}
+
+impl Preserved {
+    /// Move those of `paths` that exist in `target` (and aren't mount
+    /// points) into the stash. Returns the mount points that were skipped.
+    pub fn stash(target: &Path, paths: &[String]) -> io::Result<(Self, Vec<String>)> {
+        let mut preserved = Self {
+            target: target.to_path_buf(),
+            paths: Vec::new(),
+            restored: false,
+        };
+        let mut skipped = Vec::new();
+        // Parents first, so a nested path is covered by its parent
+        let mut paths = paths.to_vec();
+        paths.sort();
+        for path in &paths {
+            let source = target.join(path);
+            if fs::symlink_metadata(&source).is_err() || preserved.covers(path) {
+                continue;
+            }
+            if is_mount_point(&source).unwrap_or(false) {
+                skipped.push(path.clone());
+                continue;
+            }
+            let stashed = preserved.stash_dir().join(path);
+            if let Some(parent) = stashed.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&source, &stashed)?;
+            preserved.paths.push(path.clone());
+        }
+        Ok((preserved, skipped))
+    }
+
+    /// The paths that were moved, relative to the target.
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// Put the stashed paths back, replacing whatever is there now.
+    pub fn restore(mut self) -> io::Result<()> {
+        self.restored = true;
+        self.move_back()
+    }
+
+    fn move_back(&self) -> io::Result<()> {
+        for path in &self.paths {
+            let dest = self.target.join(path);
+            match fs::symlink_metadata(&dest) {
+                Ok(meta) if meta.is_dir() => fs::remove_dir_all(&dest)?,
+                Ok(_) => fs::remove_file(&dest)?,
+                Err(_) => {}
+            }
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(self.stash_dir().join(path), &dest)?;
+        }
+        match fs::remove_dir_all(self.stash_dir()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn stash_dir(&self) -> PathBuf {
+        self.target.join(STASH_DIR)
+    }
+
+    /// Whether `path` is, or is inside, one that is already stashed.
+    fn covers(&self, path: &str) -> bool {
+        self.paths
+            .iter()
+            .any(|p| Path::new(path).starts_with(Path::new(p)))
+    }
+}
+
+impl Drop for Preserved {
+    fn drop(&mut self) {
+        if !self.restored {
+            if let Err(e) = self.move_back() {
+                output::warning(
+                    &format!(
+                        "cannot restore preserved paths ({}); they are in {}",
+                        e,
+                        self.stash_dir().display()
+                    ),
+                    &[],
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(parse_path("/etc/fstab").unwrap(), "etc/fstab");
+        assert_eq!(parse_path("home/").unwrap(), "home");
+        assert_eq!(parse_path("./srv//data").unwrap(), "srv/data");
+        assert!(parse_path("/").is_err());
+        assert!(parse_path("/etc/../root").is_err());
+    }
+
+    #[test]
+    fn test_stash_and_restore() {
+        let target = std::env::temp_dir().join("recstrap_test_preserve");
+        let _ = fs::remove_dir_all(&target);
+        fs::create_dir_all(target.join("etc")).unwrap();
+        fs::create_dir_all(target.join("home/user")).unwrap();
+        fs::write(target.join("etc/fstab"), "mine\n").unwrap();
+        fs::write(target.join("home/user/notes"), "keep\n").unwrap();
+
+        let paths = ["etc/fstab", "etc/machine-id", "home", "home/user"].map(String::from);
+        let (preserved, skipped) = Preserved::stash(&target, &paths).unwrap();
+        assert_eq!(preserved.paths(), ["etc/fstab", "home"]);
+        assert!(skipped.is_empty());
+        assert!(!target.join("etc/fstab").exists());
+        assert!(!target.join("home").exists());
+
+        // What the image brings
+        fs::write(target.join("etc/fstab"), "image\n").unwrap();
+        fs::create_dir_all(target.join("home/skel")).unwrap();
+        preserved.restore().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(target.join("etc/fstab")).unwrap(),
+            "mine\n"
+        );
+        assert!(target.join("home/user/notes").is_file());
+        assert!(!target.join("home/skel").exists());
+        assert!(!target.join(STASH_DIR).exists());
+
+        // A failed install puts them back too
+        let (preserved, _) = Preserved::stash(&target, &paths).unwrap();
+        drop(preserved);
+        assert!(target.join("etc/fstab").is_file());
+        assert!(!target.join(STASH_DIR).exists());
+
+        let _ = fs::remove_dir_all(&target);
+    }
+}
@@ -0,0 +1,117 @@
+//! Privilege separation for hashing the image.
+//!
+//! Root is needed to mount the image and to copy it with owners intact,
+//! but not to hash it. `sha256sum`, which reads every byte of an
+//! untrusted image, runs as `nobody` in a process of its own, with the
+//! image opened while still root and handed over on stdin. A bug in it
+//! then can't open, write or execute anything root could.
+//!
+//! Nothing else is separated. The rest of what recstrap reads from the
+//! medium is parsed as root, in the recstrap process: the EROFS
+//! superblock (fixed-offset fields copied out of 128 bytes),
+//! `flavors.toml`, the image's os-release, and an answers file (which
+//! comes from the user, not the medium). Those parsers are plain Rust
+//! over bounded input and are covered by tests; the kernel or erofsfuse
+//! does the real decoding of the image while mounting it.
+//!
+//! When not running as root, or when `nobody` doesn't exist in the user
+//! namespace (rootless mode), there is nothing to drop and the command
+//! runs as it is.
+
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use crate::cgroup;
+
+/// uid and gid of `nobody` (the overflow id).
+pub const NOBODY: u32 = 65534;
+
+/// Whether there are privileges to drop: root, with `nobody` mapped.
+fn can_drop() -> bool {
+    if unsafe { libc::geteuid() } != 0 {
+        return false;
+    }
+    ["/proc/self/uid_map", "/proc/self/gid_map"]
+        .iter()
+        .all(|map| std::fs::read_to_string(map).is_ok_and(|m| is_mapped(&m, NOBODY)))
+}
+
+/// Whether `id` is inside one of the ranges of a `uid_map`/`gid_map`.
+fn is_mapped(map: &str, id: u32) -> bool {
+    map.lines().any(|line| {
+        let fields: Vec<u64> = line
+            .split_whitespace()
+            .filter_map(|f| f.parse().ok())
+            .collect();
+        matches!(fields[..], [inside, _, count] if (inside..inside + count).contains(&u64::from(id)))
+    })
+}
+
+/// Give up root for good, in a child between fork and exec: the raw
+/// syscalls skip the C library, whose wrappers may take locks the forking
+/// thread's siblings held. Only async-signal-safe calls.
+fn drop_privileges() -> io::Result<()> {
+    let check = |ret: libc::c_long| {
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    unsafe {
+        check(libc::syscall(
+            libc::SYS_setgroups,
+            0,
+            std::ptr::null::<libc::gid_t>(),
+        ))?;
+        check(libc::syscall(libc::SYS_setresgid, NOBODY, NOBODY, NOBODY))?;
+        check(libc::syscall(libc::SYS_setresuid, NOBODY, NOBODY, NOBODY))?;
+        check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0).into())?;
+    }
+    Ok(())
+}
+
+/// Have `cmd` run as `nobody`, without supplementary groups. Anything it
+/// needs must be readable by anyone or handed over open (e.g. as stdin).
+pub fn unprivileged_command(cmd: &mut Command) {
+    if !can_drop() {
+        return;
+    }
+    // pre_exec hooks run in order and after the uid std would set, so the
+    // helper cgroup has to be joined before dropping root here
+    cgroup::confine(cmd);
+    unsafe {
+        cmd.pre_exec(drop_privileges);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_mapped() {
+        assert!(is_mapped("         0          0 4294967295\n", NOBODY));
+        assert!(!is_mapped("         0       1000          1\n", NOBODY));
+        assert!(is_mapped("0 1000 1\n1 100000 65536\n", NOBODY));
+        assert!(!is_mapped("", NOBODY));
+    }
+
+    #[test]
+    fn test_unprivileged_command() {
+        let mut cmd = Command::new("id");
+        cmd.arg("-u");
+        unprivileged_command(&mut cmd);
+        let output = cmd.output().unwrap();
+        let uid: u32 = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .unwrap();
+        let expected = if can_drop() {
+            NOBODY
+        } else {
+            unsafe { libc::geteuid() }
+        };
+        assert_eq!(uid, expected);
+    }
+}
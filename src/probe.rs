@@ -0,0 +1,92 @@
+//! Target write throughput probe (`--probe-speed`).
+//!
+//! Writes a short burst of incompressible data to the target, forces it to
+//! disk, and times it. Combined with the image's data size this gives a
+//! rough extraction time, and flags targets (usually cheap USB sticks) that
+//! will take far longer than users expect.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::verify::random_seed;
+
+/// Bytes written by the probe.
+pub const PROBE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Probe file in the target; the `.recstrap_` prefix lets `recstrap clean`
+/// remove it if we're killed mid-probe.
+const PROBE_FILE: &str = ".recstrap_probe";
+
+/// Below this, the target is slow enough to warn about (bytes per second).
+pub const SLOW_TARGET_BYTES_PER_SEC: f64 = 10.0 * 1024.0 * 1024.0;
+
+/// Write `bytes` to a scratch file in `dir`, fsync it, and return the
+/// throughput in bytes per second. The file is removed afterwards.
+pub fn measure_write_speed(dir: &Path, bytes: u64) -> io::Result<f64> {
+    let path = dir.join(PROBE_FILE);
+    let result = write_probe(&path, bytes);
+    let _ = fs::remove_file(&path);
+    result
+}
+
+fn write_probe(path: &Path, bytes: u64) -> io::Result<f64> {
+    // Random data, so compressing or deduplicating filesystems can't skip
+    // the actual writes
+    let mut state = random_seed() | 1;
+    let chunk: Vec<u8> = (0..1024 * 1024 / 8)
+        .flat_map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state.to_ne_bytes()
+        })
+        .collect();
+
+    let mut file = File::create(path)?;
+    let start = Instant::now();
+    let mut written = 0u64;
+    while written < bytes {
+        let n = (bytes - written).min(chunk.len() as u64) as usize;
+        file.write_all(&chunk[..n])?;
+        written += n as u64;
+    }
+    file.sync_all()?;
+    let secs = start.elapsed().as_secs_f64().max(1e-3);
+    Ok(written as f64 / secs)
+}
+
+/// Seconds as a short human duration: `45s`, `3m 20s`, `1h 05m`.
+pub fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, (secs % 3600) / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(45), "45s");
+        assert_eq!(format_duration(200), "3m 20s");
+        assert_eq!(format_duration(3900), "1h 05m");
+    }
+
+    #[test]
+    fn test_measure_write_speed_cleans_up() {
+        let temp = std::env::temp_dir().join("recstrap_test_probe");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(&temp).unwrap();
+
+        let speed = measure_write_speed(&temp, 3 * 1024 * 1024 + 17).unwrap();
+        assert!(speed > 0.0);
+        assert!(!temp.join(PROBE_FILE).exists());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+}
@@ -0,0 +1,222 @@
+//! Installation record written into the target after a successful install.
+//!
+//! `/etc/recstrap-release` describes where a system came from: the image,
+//! its hash and version, when it was installed, and with which options.
+//! It uses os-release style `KEY=value` lines so it is easy to read from
+//! shell scripts, support requests, and later recstrap runs.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::helpers::format_utc;
+use crate::privsep;
+use crate::runner;
+
+/// Location of the record, relative to the target root.
+pub const RECORD_FILE: &str = "etc/recstrap-release";
+
+/// Contents of the installation record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallRecord {
+    /// recstrap version that did the install
+    pub version: String,
+    /// Image the system was extracted from
+    pub image: PathBuf,
+    /// SHA-256 of the image, when `sha256sum` was available
+    pub image_sha256: Option<String>,
+    /// EROFS filesystem UUID of the image
+    pub image_uuid: String,
+    /// Image format (`erofs`)
+    pub image_format: String,
+    /// `VERSION_ID` from the image's os-release, if any
+    pub image_version: Option<String>,
+    /// When the install finished (RFC 3339 UTC)
+    pub installed: String,
+    /// The install's options, as command-line arguments
+    pub options: String,
+}
+
+impl InstallRecord {
+    /// Render as `KEY=value` lines.
+    pub fn to_file_content(&self) -> String {
+        let mut out = String::from("# Written by recstrap - how this system was installed\n");
+        out.push_str(&format!("RECSTRAP_VERSION={}\n", self.version));
+        out.push_str(&format!("IMAGE={}\n", self.image.display()));
+        if let Some(hash) = &self.image_sha256 {
+            out.push_str(&format!("IMAGE_SHA256={}\n", hash));
+        }
+        out.push_str(&format!("IMAGE_UUID={}\n", self.image_uuid));
+        out.push_str(&format!("IMAGE_FORMAT={}\n", self.image_format));
+        if let Some(version) = &self.image_version {
+            out.push_str(&format!("IMAGE_VERSION={}\n", version));
+        }
+        out.push_str(&format!("INSTALLED={}\n", self.installed));
+        out.push_str(&format!("OPTIONS={}\n", self.options));
+        out
+    }
+
+    pub fn write(&self, target: &Path) -> io::Result<()> {
+        fs::write(target.join(RECORD_FILE), self.to_file_content())
+    }
+
+    /// Read the record from `target`; `Ok(None)` if there is none.
+    pub fn read(target: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(target.join(RECORD_FILE)) {
+            Ok(content) => Self::parse(&content).map(Some).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "malformed install record")
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parse(content: &str) -> Option<Self> {
+        let mut version = None;
+        let mut image = None;
+        let mut record = Self {
+            version: String::new(),
+            image: PathBuf::new(),
+            image_sha256: None,
+            image_uuid: String::new(),
+            image_format: String::new(),
+            image_version: None,
+            installed: String::new(),
+            options: String::new(),
+        };
+        for line in content.lines().filter(|l| !l.starts_with('#')) {
+            match line.split_once('=') {
+                Some(("RECSTRAP_VERSION", v)) => version = Some(v.to_string()),
+                Some(("IMAGE", v)) => image = Some(PathBuf::from(v)),
+                Some(("IMAGE_SHA256", v)) => record.image_sha256 = Some(v.to_string()),
+                Some(("IMAGE_UUID", v)) => record.image_uuid = v.to_string(),
+                Some(("IMAGE_FORMAT", v)) => record.image_format = v.to_string(),
+                Some(("IMAGE_VERSION", v)) => record.image_version = Some(v.to_string()),
+                Some(("INSTALLED", v)) => record.installed = v.to_string(),
+                Some(("OPTIONS", v)) => record.options = v.to_string(),
+                _ => {}
+            }
+        }
+        record.version = version?;
+        record.image = image?;
+        Some(record)
+    }
+}
+
+/// Current time as RFC 3339 UTC.
+pub fn now_utc() -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    format_utc(now)
+}
+
+/// SHA-256 of `path` via `sha256sum`; `None` if the tool is missing or fails.
+/// The file is opened here and hashed from stdin by an unprivileged
+/// `sha256sum`.
+pub fn sha256_file(path: &Path) -> Option<String> {
+    let file = File::open(path).ok()?;
+    let mut cmd = Command::new("sha256sum");
+    cmd.stdin(file);
+    privsep::unprivileged_command(&mut cmd);
+    let output = runner::output(&mut cmd).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .filter(|h| h.len() == 64)
+        .map(str::to_string)
+}
+
+/// Value of `key` in os-release content, with quotes removed.
+pub fn os_release_value(os_release: &str, key: &str) -> Option<String> {
+    os_release.lines().find_map(|line| {
+        let (k, v) = line.split_once('=')?;
+        (k.trim() == key).then(|| v.trim().trim_matches('"').to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> InstallRecord {
+        InstallRecord {
+            version: "0.1.0".to_string(),
+            image: PathBuf::from("/run/live/filesystem.erofs"),
+            image_sha256: Some("ab".repeat(32)),
+            image_uuid: "12345678-9abc-def0-0123-456789abcdef".to_string(),
+            image_format: "erofs".to_string(),
+            image_version: Some("1.2".to_string()),
+            installed: "2026-01-31T12:00:00Z".to_string(),
+            options: "/mnt --verify full".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let temp = std::env::temp_dir().join("recstrap_test_record");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("etc")).unwrap();
+
+        assert_eq!(InstallRecord::read(&temp).unwrap(), None);
+        sample().write(&temp).unwrap();
+        assert_eq!(InstallRecord::read(&temp).unwrap(), Some(sample()));
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_record_optional_fields() {
+        let record = InstallRecord {
+            image_sha256: None,
+            image_version: None,
+            ..sample()
+        };
+        let content = record.to_file_content();
+        assert!(!content.contains("IMAGE_SHA256"));
+        assert_eq!(InstallRecord::parse(&content), Some(record));
+    }
+
+    #[test]
+    fn test_record_parse_rejects_missing_fields() {
+        assert_eq!(InstallRecord::parse("IMAGE=/x\n"), None);
+        assert!(InstallRecord::parse("RECSTRAP_VERSION=1\nIMAGE=/x\n").is_some());
+    }
+
+    #[test]
+    fn test_os_release_value() {
+        let os_release = "NAME=\"LevitateOS\"\nVERSION_ID=1.2\n";
+        assert_eq!(
+            os_release_value(os_release, "NAME").as_deref(),
+            Some("LevitateOS")
+        );
+        assert_eq!(
+            os_release_value(os_release, "VERSION_ID").as_deref(),
+            Some("1.2")
+        );
+        assert_eq!(os_release_value(os_release, "ID"), None);
+    }
+
+    #[test]
+    fn test_sha256_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        if runner::output(Command::new("sha256sum").arg("--version")).is_err() {
+            return;
+        }
+        // Root-only: sha256sum gets it open on stdin even when unprivileged
+        let file = std::env::temp_dir().join("recstrap_test_sha256");
+        fs::write(&file, "hello\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(
+            sha256_file(&file).as_deref(),
+            Some("5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03")
+        );
+        assert_eq!(sha256_file(Path::new("/nonexistent")), None);
+        let _ = fs::remove_file(&file);
+    }
+}
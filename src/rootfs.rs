@@ -1,13 +1,35 @@
 //! Rootfs type detection, validation, and extraction.
 
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::rc::Rc;
 
-use crate::constants::{EROFS_MAGIC, ESSENTIAL_DIRS};
+use crate::checks;
+use crate::collisions::{self, CollisionReport};
+use crate::constants::{EROFS_MAGIC, ESSENTIAL_DIRS, HARDLINK_SAMPLE_GROUPS};
+use crate::copy::{copy_tree, read_capability, CopyOptions, CopyStats};
+use crate::erofs::{verify_image_size, verify_superblock_checksum, Superblock};
 use crate::error::{ErrorCode, RecError, Result};
 use crate::guarded_ensure;
+use crate::helpers::{
+    command_timeout_secs, format_utc, get_available_space, make_temp_dir, output_with_timeout,
+    path_to_cstring, resolve_in_root, retry_transient, InterruptGuard,
+};
+use crate::json::Value;
+use crate::loopdev::LoopDevice;
+use crate::mount::MountError;
+use crate::mountinfo;
+use crate::runner;
+use crate::verify::{
+    compare_paths, compare_trees, random_seed, sample_files, Scope, VerifyOptions, VerifyReport,
+};
+use crate::verity::{self, VerityDevice};
+use crate::warnings::warn;
 
 /// Rootfs type detected from file extension
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -24,8 +46,84 @@ impl RootfsType {
     }
 }
 
-/// Validate rootfs magic bytes match expected format.
-/// Returns Ok(()) or Err if magic doesn't match.
+/// Which image build this is: identity and build metadata from the
+/// superblock, printed at the start of a run and part of the install
+/// statistics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootfsInfo {
+    pub path: PathBuf,
+    pub format: RootfsType,
+    pub uuid: String,
+    pub volume_name: Option<String>,
+    /// Size of the image file
+    pub image_size: u64,
+    /// File data once unpacked; only known after extraction
+    pub uncompressed_size: Option<u64>,
+    pub inodes: u64,
+    /// Build time, seconds since the epoch
+    pub created: u64,
+    /// Compression algorithms the image may use; empty if uncompressed
+    pub compression: Vec<&'static str>,
+}
+
+impl RootfsInfo {
+    /// Read the superblock of the image at `path`.
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        let sb = Superblock::read_from(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            format: RootfsType::Erofs,
+            uuid: sb.uuid_string(),
+            volume_name: sb.volume_name(),
+            image_size: fs::metadata(path)?.len(),
+            uncompressed_size: None,
+            inodes: sb.inos,
+            created: sb.build_time,
+            compression: sb.compression_algorithms().iter().map(|c| c.name).collect(),
+        })
+    }
+
+    /// One line for logs.
+    pub fn summary(&self) -> String {
+        let mut line = format!("EROFS {}", self.uuid);
+        if let Some(name) = &self.volume_name {
+            line.push_str(&format!(" \"{}\"", name));
+        }
+        line.push_str(&format!(
+            ", built {}, {} inodes, {} MB, compression: {}",
+            format_utc(self.created),
+            self.inodes,
+            self.image_size / (1024 * 1024),
+            if self.compression.is_empty() {
+                "none".to_string()
+            } else {
+                self.compression.join(", ")
+            }
+        ));
+        line
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("path", Value::from(self.path.display().to_string())),
+            ("format", Value::from("erofs")),
+            ("uuid", Value::from(self.uuid.as_str())),
+            ("volume_name", Value::from(self.volume_name.clone())),
+            ("image_size", Value::from(self.image_size)),
+            ("uncompressed_size", Value::from(self.uncompressed_size)),
+            ("inodes", Value::from(self.inodes)),
+            ("created", Value::from(self.created)),
+            ("created_utc", Value::from(format_utc(self.created))),
+            ("compression", Value::from(self.compression.clone())),
+        ])
+    }
+}
+
+/// Validate rootfs magic bytes match expected format, the superblock
+/// checksum if the image has one, and that the file isn't shorter than the
+/// superblock says, so bit-rotted media and truncated downloads fail here
+/// rather than halfway through the copy.
+/// Returns Ok(()) or Err if any of them doesn't match.
 pub fn validate_rootfs_magic(path: &Path, expected: RootfsType) -> std::io::Result<()> {
     let mut f = File::open(path)?;
 
@@ -44,16 +142,235 @@ pub fn validate_rootfs_magic(path: &Path, expected: RootfsType) -> std::io::Resu
                 ),
             ));
         }
+        verify_superblock_checksum(path)?;
+        verify_image_size(path)?;
     }
 
     Ok(())
 }
 
+/// Resolve and validate a rootfs image named on the command line.
+///
+/// Used by the read-only subcommands: checks that the path is a readable
+/// regular file with a supported extension and valid magic bytes, and returns
+/// its canonical path.
+pub fn resolve_image(path: &str) -> Result<(PathBuf, RootfsType)> {
+    let p = Path::new(path);
+    if !p.exists() {
+        return Err(RecError::rootfs_not_found(&[path]));
+    }
+    if !p.is_file() {
+        return Err(RecError::rootfs_not_file(path));
+    }
+    let p = p
+        .canonicalize()
+        .map_err(|e| RecError::new(ErrorCode::RootfsNotFound, e.to_string()))?;
+
+    let rootfs_type = RootfsType::from_path(&p)
+        .ok_or_else(|| RecError::invalid_rootfs_format(path, "expected .erofs extension"))?;
+
+    if let Err(e) = validate_rootfs_magic(&p, rootfs_type) {
+        return Err(match e.kind() {
+            std::io::ErrorKind::PermissionDenied => RecError::rootfs_not_readable(path),
+            _ => RecError::invalid_rootfs_format(path, &e.to_string()),
+        });
+    }
+
+    Ok((p, rootfs_type))
+}
+
+/// Rootfs image read from stdin (`--rootfs -`), or joined from split
+/// parts, into a temporary file.
+///
+/// EROFS has to be mounted, which needs random access and a single file,
+/// so the data is spooled to disk first. The file is removed on drop.
+pub struct SpooledImage {
+    path: PathBuf,
+}
+
+impl SpooledImage {
+    pub fn from_stdin(dir: &Path, quiet: bool) -> Result<Self> {
+        if unsafe { libc::isatty(0) } == 1 {
+            return Err(RecError::new(
+                ErrorCode::RootfsNotFound,
+                "--rootfs - reads the image from stdin, but stdin is a terminal",
+            ));
+        }
+
+        let interrupt = InterruptGuard::install();
+        let (spooled, mut file) = Self::create(dir, "stdin")?;
+        if !quiet {
+            eprintln!("Reading rootfs image from stdin...");
+        }
+        let total = spooled.fill(&mut file, &mut std::io::stdin().lock(), "stdin", &interrupt)?;
+        if !quiet {
+            eprintln!("Read {} MB from stdin", total / (1024 * 1024));
+        }
+        Ok(spooled)
+    }
+
+    /// Concatenate the parts of a split image (see [`split_parts`]).
+    ///
+    /// The joined copy is as large as the image, and `dir` (`--workdir`,
+    /// by default `$TMPDIR`) is often a tmpfs in RAM on the live system,
+    /// so its free space is checked before anything is written.
+    pub fn join(parts: &[PathBuf], dir: &Path, quiet: bool) -> Result<Self> {
+        let mut needed = 0;
+        for part in parts {
+            let meta = fs::metadata(part).map_err(|e| {
+                RecError::new(
+                    ErrorCode::RootfsNotReadable,
+                    format!("{}: {}", part.display(), e),
+                )
+            })?;
+            needed += meta.len();
+        }
+        if let Ok(available) = get_available_space(dir) {
+            check_join_space(parts.len(), needed, available, dir)?;
+        }
+
+        let interrupt = InterruptGuard::install();
+        let (spooled, mut file) = Self::create(dir, "joined")?;
+        if !quiet {
+            eprintln!("Joining {} image parts...", parts.len());
+        }
+        let mut total = 0;
+        for part in parts {
+            let what = part.to_string_lossy();
+            let mut input = File::open(part).map_err(|e| {
+                RecError::new(ErrorCode::RootfsNotReadable, format!("{}: {}", what, e))
+            })?;
+            total += spooled.fill(&mut file, &mut input, &what, &interrupt)?;
+        }
+        if !quiet {
+            eprintln!("Joined {} MB", total / (1024 * 1024));
+        }
+        Ok(spooled)
+    }
+
+    fn create(dir: &Path, kind: &str) -> Result<(Self, File)> {
+        let path = dir.join(format!("recstrap-{}-{}.erofs", kind, std::process::id()));
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|e| spool_error(&path, e))?;
+        // From here on the guard owns the file
+        Ok((Self { path }, file))
+    }
+
+    /// Append everything `input` (named `what` in errors) has to `file`.
+    fn fill(
+        &self,
+        file: &mut File,
+        input: &mut dyn Read,
+        what: &str,
+        interrupt: &InterruptGuard,
+    ) -> Result<u64> {
+        let mut buf = vec![0u8; 1024 * 1024];
+        let mut total: u64 = 0;
+        loop {
+            if interrupt.interrupted() {
+                return Err(RecError::new(
+                    ErrorCode::RootfsNotReadable,
+                    format!("{}: interrupted", what),
+                ));
+            }
+            let n = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return Err(RecError::new(
+                        ErrorCode::RootfsNotReadable,
+                        format!("{}: {}", what, e),
+                    ))
+                }
+            };
+            file.write_all(&buf[..n])
+                .map_err(|e| spool_error(&self.path, e))?;
+            total += n as u64;
+        }
+        Ok(total)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpooledImage {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Refuse to join `parts` image parts of `needed` bytes in `dir`, which
+/// has `available` bytes free, when they don't fit.
+fn check_join_space(parts: usize, needed: u64, available: u64, dir: &Path) -> Result<()> {
+    if needed <= available {
+        return Ok(());
+    }
+    Err(RecError::new(
+        ErrorCode::InsufficientSpace,
+        format!(
+            "joining the {} image parts needs {}MB in {}, which has {}MB free; \
+             use --workdir to join them on a larger disk",
+            parts,
+            needed.div_ceil(1024 * 1024),
+            dir.display(),
+            available / (1024 * 1024)
+        ),
+    ))
+}
+
+fn spool_error(path: &Path, e: std::io::Error) -> RecError {
+    RecError::new(
+        ErrorCode::ExtractionFailed,
+        format!("cannot spool image to {}: {}", path.display(), e),
+    )
+}
+
+/// Parts of an image split for FAT32 media (4 GiB file limit):
+/// `filesystem.erofs.000`, `.001`, ... up to the first missing number.
+///
+/// `image` is either the first part or the image name without a suffix,
+/// which only counts as split when no such file exists. `None` if there
+/// is no `.000` part.
+pub fn split_parts(image: &Path) -> Option<Vec<PathBuf>> {
+    let name = image.file_name()?.to_str()?;
+    let base = match name.rsplit_once('.') {
+        Some((base, "000")) => image.with_file_name(base),
+        _ if image.exists() => return None,
+        _ => image.to_path_buf(),
+    };
+    let part = |n: usize| {
+        let mut path = base.clone().into_os_string();
+        path.push(format!(".{:03}", n));
+        PathBuf::from(path)
+    };
+    let parts: Vec<PathBuf> = (0..1000).map(part).take_while(|p| p.is_file()).collect();
+    (!parts.is_empty()).then_some(parts)
+}
+
+/// How an image is mounted for copying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountMethod {
+    /// Kernel EROFS driver on a loop device (real root only)
+    Kernel,
+    /// `erofsfuse` userspace driver (works inside a user namespace)
+    Fuse,
+}
+
 /// RAII guard for EROFS mount cleanup.
 /// Ensures unmount and directory removal happen even on panic or interrupt.
-struct MountGuard {
+pub struct MountGuard {
     mount_point: PathBuf,
     mounted: bool,
+    // Released after the unmount, as fields drop after `Drop::drop`
+    loop_device: Option<LoopDevice>,
+    verity: Option<VerityDevice>,
 }
 
 impl MountGuard {
@@ -61,112 +378,745 @@ impl MountGuard {
         Self {
             mount_point,
             mounted: false,
+            loop_device: None,
+            verity: None,
         }
     }
 
     fn set_mounted(&mut self) {
         self.mounted = true;
     }
+
+    /// Where the image is mounted.
+    pub fn path(&self) -> &Path {
+        &self.mount_point
+    }
+
+    /// `error` from reading the mount, as E025 if dm-verity caught a
+    /// corrupted block behind it.
+    pub fn explain(&self, error: RecError) -> RecError {
+        match &self.verity {
+            Some(verity) => verity.explain(error),
+            None => error,
+        }
+    }
 }
 
 impl Drop for MountGuard {
     fn drop(&mut self) {
         if self.mounted {
-            let _ = Command::new("umount").arg(&self.mount_point).status();
+            let secs = command_timeout_secs();
+            let umount = runner::unmount(&self.mount_point, false, secs);
+            // A umount stuck on dead media would hang us too; detach lazily
+            if umount.is_err_and(|e| e.kind() == std::io::ErrorKind::TimedOut) {
+                let _ = runner::unmount(&self.mount_point, true, secs);
+            }
         }
-        let _ = fs::remove_dir_all(&self.mount_point);
+        // remove_dir, not remove_dir_all: if the unmount failed, the image
+        // contents are still visible here and must not be deleted
+        let _ = fs::remove_dir(&self.mount_point);
     }
 }
 
-/// Extract EROFS image by mounting and copying.
+/// A writable tmpfs-backed overlay over a mounted image; writes are
+/// discarded when it is dropped.
+pub struct Overlay {
+    dir: PathBuf,
+    tmpfs_mounted: bool,
+    overlay_mounted: bool,
+}
+
+impl Overlay {
+    /// Mount an overlay of `lower` in a fresh directory inside `workdir`.
+    pub fn mount(lower: &Path, workdir: &Path) -> Result<Self> {
+        let fail = |e: std::io::Error| {
+            RecError::new(
+                ErrorCode::ExtractionFailed,
+                format!("cannot set up a writable overlay: {}", e),
+            )
+        };
+        let dir = make_temp_dir(workdir, "recstrap-overlay-").map_err(fail)?;
+        let mut overlay = Self {
+            dir,
+            tmpfs_mounted: false,
+            overlay_mounted: false,
+        };
+
+        mount_at(&["-t", "tmpfs", "tmpfs"], &overlay.dir).map_err(fail)?;
+        overlay.tmpfs_mounted = true;
+        for sub in ["upper", "work", "merged"] {
+            fs::create_dir(overlay.dir.join(sub)).map_err(fail)?;
+        }
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower.display(),
+            overlay.dir.join("upper").display(),
+            overlay.dir.join("work").display()
+        );
+        mount_at(
+            &["-t", "overlay", "overlay", "-o", &options],
+            &overlay.merged(),
+        )
+        .map_err(fail)?;
+        overlay.overlay_mounted = true;
+        Ok(overlay)
+    }
+
+    /// The writable view of the image.
+    pub fn merged(&self) -> PathBuf {
+        self.dir.join("merged")
+    }
+}
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        if self.overlay_mounted {
+            let _ = runner::status(Command::new("umount").arg(self.merged()));
+        }
+        if self.tmpfs_mounted {
+            let _ = runner::status(Command::new("umount").arg(&self.dir));
+        }
+        // remove_dir: if an unmount failed, leave whatever is visible alone
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
+
+fn mount_at(args: &[&str], path: &Path) -> std::io::Result<()> {
+    let status = runner::status(Command::new("mount").args(args).arg(path))?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "mounting {} failed",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Image path and its mount.
+type SharedMount = (PathBuf, Rc<MountGuard>);
+
+thread_local! {
+    /// Mounts kept for reuse while a [`SharedMounts`] is alive.
+    static SHARED: RefCell<Option<Vec<SharedMount>>> = const { RefCell::new(None) };
+}
+
+/// While alive, [`mount_erofs`] mounts each image once and hands out the
+/// same mount to every later caller (the probe, extraction and checks of
+/// one install; `--targets` installs one image into several targets). The
+/// mounts are released when the outermost guard is dropped.
+pub struct SharedMounts {
+    outermost: bool,
+}
+
+impl SharedMounts {
+    pub fn begin() -> Self {
+        let outermost = SHARED.with(|s| {
+            let mut shared = s.borrow_mut();
+            let outermost = shared.is_none();
+            shared.get_or_insert_with(Vec::new);
+            outermost
+        });
+        Self { outermost }
+    }
+}
+
+impl Drop for SharedMounts {
+    fn drop(&mut self) {
+        if self.outermost {
+            let mounts = SHARED.with(|s| s.borrow_mut().take());
+            drop(mounts);
+        }
+    }
+}
+
+/// `--workdir` if given (must be an existing directory), else `$TMPDIR`.
+/// Canonicalized so it can be compared against the target.
+pub fn resolve_workdir(workdir: Option<&str>) -> Result<PathBuf> {
+    let dir = workdir.map_or_else(std::env::temp_dir, PathBuf::from);
+    if !dir.is_dir() {
+        return Err(RecError::new(
+            ErrorCode::NotADirectory,
+            format!("workdir '{}' is not a directory", dir.display()),
+        ));
+    }
+    Ok(dir.canonicalize().unwrap_or(dir))
+}
+
+/// Mount point directories are `<workdir>/recstrap-erofs-<pid>-XXXXXX`.
+const MOUNT_DIR_PREFIX: &str = "recstrap-erofs-";
+
+/// Unmount and remove mount points left behind by recstrap runs that died
+/// without cleaning up (SIGKILL, power loss of a live session, ...).
 ///
-/// EROFS cannot be extracted with a simple tool like unsquashfs.
-/// We mount it read-only, cp -a all files, then unmount.
-/// Uses cp -a instead of rsync as it's always available on minimal systems.
-///
-/// Uses a RAII guard to ensure cleanup even on panic/interrupt.
-pub fn extract_erofs(rootfs: &Path, target: &Path, quiet: bool) -> Result<()> {
-    // Create temporary mount point
-    let mount_point = std::env::temp_dir().join("recstrap-erofs-mount");
-    if mount_point.exists() {
-        // Try to unmount if leftover from previous run
-        let _ = Command::new("umount").arg(&mount_point).status();
-        fs::remove_dir_all(&mount_point).ok();
-    }
-    fs::create_dir_all(&mount_point).map_err(|e| {
+/// Only directories in `workdir` whose owning process is gone are touched,
+/// so other instances running right now are left alone.
+pub fn cleanup_stale_mounts(workdir: &Path, quiet: bool) {
+    let Ok(entries) = fs::read_dir(workdir) else {
+        return;
+    };
+    let mounted: Vec<PathBuf> = mountinfo::read()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|m| m.mount_point)
+        .collect();
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(rest) = name.to_str().and_then(|n| n.strip_prefix(MOUNT_DIR_PREFIX)) else {
+            continue;
+        };
+        if !is_stale_mount_dir(rest) {
+            continue;
+        }
+        let path = entry.path();
+        if mounted.contains(&path) {
+            warn(
+                quiet,
+                &format!("unmounting stale mount {}", path.display()),
+                &[],
+            );
+            let _ = runner::status(Command::new("umount").arg(&path));
+        }
+        // Only removes the directory if the unmount worked (it is empty then)
+        let _ = fs::remove_dir(&path);
+    }
+}
+
+/// Decide from the part after [`MOUNT_DIR_PREFIX`] whether a mount directory
+/// belongs to a dead process. The old fixed name (`recstrap-erofs-mount`)
+/// has no PID and is always stale.
+fn is_stale_mount_dir(suffix: &str) -> bool {
+    match suffix.split_once('-') {
+        Some((pid, _)) => match pid.parse::<u32>() {
+            Ok(pid) => pid != std::process::id() && !Path::new(&format!("/proc/{}", pid)).exists(),
+            Err(_) => false,
+        },
+        None => suffix == "mount",
+    }
+}
+
+/// Mount an EROFS image read-only on a temporary mount point.
+///
+/// The mount point is a fresh directory inside `workdir`. The returned guard
+/// unmounts the image and removes the mount point when the last reference
+/// is dropped; under [`SharedMounts`] an earlier mount may be reused.
+pub fn mount_erofs(
+    rootfs: &Path,
+    method: MountMethod,
+    workdir: &Path,
+    quiet: bool,
+) -> Result<Rc<MountGuard>> {
+    let key = rootfs
+        .canonicalize()
+        .unwrap_or_else(|_| rootfs.to_path_buf());
+    let shared = SHARED.with(|s| {
+        s.borrow()
+            .as_ref()
+            .and_then(|mounts| mounts.iter().find(|(image, _)| *image == key))
+            .map(|(_, mount)| Rc::clone(mount))
+    });
+    if let Some(mount) = shared {
+        return Ok(mount);
+    }
+
+    let mount = Rc::new(mount_image(rootfs, method, workdir, quiet)?);
+    SHARED.with(|s| {
+        if let Some(mounts) = s.borrow_mut().as_mut() {
+            mounts.push((key, Rc::clone(&mount)));
+        }
+    });
+    Ok(mount)
+}
+
+fn mount_image(
+    rootfs: &Path,
+    method: MountMethod,
+    workdir: &Path,
+    quiet: bool,
+) -> Result<MountGuard> {
+    cleanup_stale_mounts(workdir, quiet);
+
+    // Unique per run, so concurrent instances never share a mount point
+    let prefix = format!("{}{}-", MOUNT_DIR_PREFIX, std::process::id());
+    let mount_point = make_temp_dir(workdir, &prefix).map_err(|e| {
         RecError::new(
             ErrorCode::ExtractionFailed,
-            format!("failed to create mount point: {}", e),
+            format!(
+                "failed to create mount point in {}: {}",
+                workdir.display(),
+                e
+            ),
         )
     })?;
 
     // Guard ensures cleanup on any exit path
     let mut guard = MountGuard::new(mount_point.clone());
 
-    // Mount EROFS read-only
+    // Images with a hash tree are read through dm-verity
+    if let Some(verity) = verity::detect(rootfs)? {
+        match method {
+            MountMethod::Kernel => {
+                if !quiet {
+                    eprintln!("Setting up dm-verity...");
+                }
+                guard.verity = Some(VerityDevice::open(rootfs, &verity)?);
+            }
+            MountMethod::Fuse => warn(
+                quiet,
+                "erofsfuse can't use dm-verity; the image's hash tree is not checked",
+                &[],
+            ),
+        }
+    }
+    let verity_device = guard.verity.as_ref().map(VerityDevice::path);
+
+    // Mount EROFS read-only; the kernel needs a block device, so an image
+    // file is attached to a loop device first
     if !quiet {
         eprintln!("Mounting EROFS image...");
     }
-    let mount_status = Command::new("mount")
-        .args(["-t", "erofs", "-o", "ro,loop"])
-        .arg(rootfs)
-        .arg(&mount_point)
-        .status()
-        .map_err(|e| {
+    let loop_device = retry_transient(
+        "mounting the image",
+        quiet,
+        |f: &MountFailure| f.transient,
+        || {
+            let device = match (&verity_device, method) {
+                (None, MountMethod::Kernel) => Some(attach_loop(rootfs)?),
+                _ => None,
+            };
+            let source = match (&device, &verity_device) {
+                (Some(device), _) => device.path(),
+                (None, Some(path)) => path,
+                (None, None) => rootfs,
+            };
+            run_mount(source, &mount_point, method, rootfs)?;
+            Ok(device)
+        },
+    )
+    .map_err(|f| {
+        // A killed mount may have got as far as mounting the device
+        if f.error.code == ErrorCode::CommandTimedOut {
+            guard.set_mounted();
+        }
+        f.error
+    })?;
+
+    // Mark as mounted so guard will unmount on drop
+    guard.loop_device = loop_device;
+    guard.set_mounted();
+    Ok(guard)
+}
+
+/// Attach `rootfs` read-only to a free loop device. Running out of them
+/// is transient: a concurrent setup may be about to release one.
+fn attach_loop(rootfs: &Path) -> std::result::Result<LoopDevice, MountFailure> {
+    runner::attach_loop(rootfs).map_err(|e| {
+        let exhausted = e.kind() == std::io::ErrorKind::ResourceBusy;
+        let error = if exhausted {
+            RecError::loop_devices_exhausted(&rootfs.to_string_lossy())
+        } else {
             RecError::new(
                 ErrorCode::ExtractionFailed,
-                format!("failed to run mount: {}", e),
+                format!(
+                    "cannot set up a loop device for {}: {}",
+                    rootfs.display(),
+                    e
+                ),
             )
+        };
+        MountFailure {
+            error,
+            transient: exhausted,
+        }
+    })
+}
+
+/// A failed mount, and whether trying again might help.
+struct MountFailure {
+    error: RecError,
+    transient: bool,
+}
+
+impl std::fmt::Display for MountFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+/// Messages from mount(8) for conditions that clear up on their own:
+/// a busy device, all loop devices taken by a concurrent setup, or a
+/// one-off read error from optical/USB media.
+fn mount_error_is_transient(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    [
+        "busy",
+        "loop device",
+        "temporarily unavailable",
+        "input/output error",
+    ]
+    .iter()
+    .any(|pattern| stderr.contains(pattern))
+}
+
+/// Mount `source`: a block device for the kernel, the image for erofsfuse.
+/// `rootfs` names the image in errors.
+fn run_mount(
+    source: &Path,
+    mount_point: &Path,
+    method: MountMethod,
+    rootfs: &Path,
+) -> std::result::Result<(), MountFailure> {
+    if method == MountMethod::Kernel {
+        return runner::mount_erofs(source, mount_point, command_timeout_secs())
+            .map_err(|e| kernel_mount_failure(e, rootfs));
+    }
+
+    let fatal = |error| MountFailure {
+        error,
+        transient: false,
+    };
+    // erofsfuse keeps running in the background and would hold a stderr
+    // pipe open forever
+    let output = output_with_timeout(Command::new("erofsfuse").arg(source).arg(mount_point))
+        .map_err(|e| {
+            fatal(match e.kind() {
+                std::io::ErrorKind::NotFound => {
+                    RecError::tool_not_installed("erofsfuse", "erofs-utils")
+                }
+                std::io::ErrorKind::TimedOut => {
+                    RecError::command_timed_out("erofsfuse", command_timeout_secs())
+                }
+                _ => RecError::new(
+                    ErrorCode::ExtractionFailed,
+                    format!("failed to run erofsfuse: {}", e),
+                ),
+            })
         })?;
 
-    if !mount_status.success() {
-        return Err(RecError::new(
-            ErrorCode::ExtractionFailed,
-            format!(
-                "mount failed (exit {}). Is the kernel EROFS module loaded?",
-                mount_status.code().unwrap_or(-1)
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim();
+        let detail = if stderr.is_empty() {
+            String::new()
+        } else {
+            format!(": {}", stderr.trim_end_matches('.'))
+        };
+        return Err(MountFailure {
+            error: RecError::new(
+                ErrorCode::ExtractionFailed,
+                format!(
+                    "erofsfuse failed (exit {}){}. Is /dev/fuse available?",
+                    output.status.code().unwrap_or(-1),
+                    detail,
+                ),
             ),
-        ));
+            transient: mount_error_is_transient(stderr),
+        });
     }
+    Ok(())
+}
 
-    // Mark as mounted so guard will unmount on drop
-    guard.set_mounted();
+/// Map a failed kernel mount by its errno: no EROFS driver, an image the
+/// driver rejects, or a busy device or read error that may clear up.
+fn kernel_mount_failure(e: MountError, rootfs: &Path) -> MountFailure {
+    let image = rootfs.to_string_lossy();
+    let detail = match &e.message {
+        Some(message) => format!("{} ({})", e.error, message),
+        None => e.error.to_string(),
+    };
+    let errno = e.error.raw_os_error();
+    let error = match errno {
+        _ if e.error.kind() == std::io::ErrorKind::TimedOut => {
+            RecError::command_timed_out("mount", command_timeout_secs())
+        }
+        Some(libc::ENODEV) => RecError::erofs_not_supported(),
+        Some(libc::EINVAL) => RecError::invalid_rootfs_format(
+            &image,
+            &format!("the kernel's EROFS driver rejected it: {}", detail),
+        ),
+        _ => RecError::new(
+            ErrorCode::ExtractionFailed,
+            format!("mounting {} failed: {}", image, detail),
+        ),
+    };
+    MountFailure {
+        error,
+        transient: matches!(errno, Some(libc::EBUSY | libc::EAGAIN | libc::EIO)),
+    }
+}
 
-    // Copy all files using cp -aT (preserves permissions, symlinks, etc.)
-    // -a = archive mode (recursive, preserves everything)
-    // -T = treat destination as normal file (copy contents, not subdir)
-    // cp is always available, unlike rsync
-    if !quiet {
-        eprintln!("Copying files from EROFS to target (this may take a while)...");
+/// Read the image's os-release without extracting it: `/etc/os-release`,
+/// falling back to `/usr/lib/os-release` as os-release(5) specifies.
+/// Returns `None` when the image has neither.
+pub fn read_os_release(
+    rootfs: &Path,
+    method: MountMethod,
+    workdir: &Path,
+) -> Result<Option<String>> {
+    let mount = mount_erofs(rootfs, method, workdir, true)?;
+    for candidate in ["etc/os-release", "usr/lib/os-release"] {
+        // Resolved inside the image; a symlinked /etc/os-release falls
+        // through to the next candidate
+        let Ok(resolved) = resolve_in_root(mount.path(), Path::new(candidate)) else {
+            continue;
+        };
+        let path = mount.path().join(resolved);
+        if fs::symlink_metadata(&path).is_ok_and(|m| m.is_file()) {
+            let content = fs::read_to_string(&path).map_err(|e| {
+                RecError::new(
+                    ErrorCode::ExtractionFailed,
+                    format!("cannot read /{} from image: {}", candidate, e),
+                )
+            })?;
+            return Ok(Some(content));
+        }
     }
+    Ok(None)
+}
 
-    let cp_status = Command::new("cp")
-        .args(["-aT"])
-        .arg(&mount_point)
-        .arg(target)
-        .status()
-        .map_err(|e| {
+/// Total size of the regular files in the image (hardlinks counted once),
+/// which is roughly what extraction will write.
+pub fn image_data_size(rootfs: &Path, method: MountMethod, workdir: &Path) -> Result<u64> {
+    let mount = mount_erofs(rootfs, method, workdir, true)?;
+    let mut seen = HashSet::new();
+    let mut total = 0u64;
+    let mut pending = vec![mount.path().to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = fs::read_dir(&dir).map_err(|e| {
             RecError::new(
                 ErrorCode::ExtractionFailed,
-                format!("failed to run cp: {}", e),
+                format!("cannot read {} in image: {}", dir.display(), e),
             )
         })?;
+        for entry in entries.flatten() {
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            if meta.is_dir() {
+                pending.push(entry.path());
+            } else if meta.is_file() && (meta.nlink() == 1 || seen.insert(meta.ino())) {
+                total += meta.len();
+            }
+        }
+    }
+    Ok(total)
+}
 
-    if !cp_status.success() {
-        return Err(RecError::new(
+/// What extracting the image into the non-empty `target` would overwrite.
+pub fn image_collisions(
+    rootfs: &Path,
+    target: &Path,
+    method: MountMethod,
+    workdir: &Path,
+) -> Result<CollisionReport> {
+    let mount = mount_erofs(rootfs, method, workdir, true)?;
+    collisions::find(mount.path(), target).map_err(|e| {
+        RecError::new(
             ErrorCode::ExtractionFailed,
-            format!("cp failed (exit {})", cp_status.code().unwrap_or(-1)),
-        ));
+            format!("cannot compare {} with the image: {}", target.display(), e),
+        )
+    })
+}
+
+/// Extract EROFS image by mounting and copying.
+///
+/// EROFS cannot be extracted with a simple tool like unsquashfs.
+/// We mount it read-only, copy all files with the native copier, then unmount.
+/// The copier preserves everything `cp -a` did, but keeps error reporting,
+/// progress, and cancellation in our hands.
+///
+/// Uses RAII guards to ensure cleanup even on panic/interrupt: Ctrl-C stops
+/// the copy and the mount is still released.
+///
+/// With [`MountMethod::Fuse`] we are running rootless, so ownership and
+/// privileged attributes are copied on a best-effort basis.
+pub fn extract_erofs(
+    rootfs: &Path,
+    target: &Path,
+    method: MountMethod,
+    workdir: &Path,
+    checksum: bool,
+    quiet: bool,
+) -> Result<CopyStats> {
+    // Declared first so it is dropped last - signals stay trapped until
+    // the mount guard has cleaned up.
+    let interrupt = InterruptGuard::install();
+
+    let mount = mount_erofs(rootfs, method, workdir, quiet)?;
+
+    // Copy the mounted tree into the target (equivalent of cp -aT:
+    // contents of the mount land directly in target, not in a subdir)
+    if !quiet {
+        eprintln!("Copying files from EROFS to target (this may take a while)...");
     }
 
+    let options = CopyOptions {
+        cancel: Some(&|| interrupt.interrupted()),
+        show_progress: !quiet,
+        best_effort: method == MountMethod::Fuse,
+        checksum,
+        ..Default::default()
+    };
+    let mut stats = copy_tree(mount.path(), target, &options).map_err(|e| mount.explain(e))?;
+
+    // What verify_hardlinks checks comes from the image itself, not from
+    // the copier's own record of the links it made
+    stats.hardlink_groups = hardlink_groups(mount.path()).map_err(|e| {
+        mount.explain(RecError::new(
+            ErrorCode::ExtractionVerificationFailed,
+            format!("cannot count hardlinks in the image: {}", e),
+        ))
+    })?;
+
     if !quiet {
-        eprintln!("Extraction complete, cleaning up...");
+        eprintln!(
+            "Extraction complete ({} files, {} MB), cleaning up...",
+            stats.files,
+            stats.bytes / (1024 * 1024)
+        );
+        if checksum {
+            eprintln!(
+                "Checked {} files on disk against checksums taken while copying",
+                stats.checksummed
+            );
+        }
     }
 
     // Guard drop will handle unmount and cleanup
+    Ok(stats)
+}
+
+/// Hidden directory inside the target that the image is extracted into
+/// first. It is promoted into place only after verification passes.
+pub const STAGING_DIR: &str = ".recstrap_staging";
+
+/// Create the staging directory inside `target`.
+pub fn create_staging(target: &Path) -> Result<PathBuf> {
+    let staging = target.join(STAGING_DIR);
+    fs::create_dir(&staging).map_err(|e| {
+        RecError::new(
+            ErrorCode::NotWritable,
+            format!("cannot create {}: {}", staging.display(), e),
+        )
+    })?;
+    Ok(staging)
+}
+
+/// Move every top-level entry of `staging` into `target`, then give
+/// `target` the owner and mode of the image root and remove `staging`.
+///
+/// Both live on the same filesystem, so each move is a rename: no data is
+/// copied and the window in which the target is half-populated is a few
+/// dozen syscalls instead of the whole extraction.
+pub fn promote_staging(staging: &Path, target: &Path) -> Result<()> {
+    let promote_error = |path: &Path, e: std::io::Error| {
+        RecError::new(
+            ErrorCode::ExtractionFailed,
+            format!("cannot promote staged {}: {}", path.display(), e),
+        )
+    };
+
+    let mut entries: Vec<_> = fs::read_dir(staging)
+        .and_then(|entries| entries.collect::<std::io::Result<_>>())
+        .map_err(|e| promote_error(staging, e))?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let src = entry.path();
+        let dst = target.join(entry.file_name());
+        match rename_noreplace(&src, &dst) {
+            Ok(()) => {}
+            // An empty placeholder such as lost+found may already exist
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {
+                fs::remove_dir(&dst).map_err(|_| promote_error(&src, e))?;
+                rename_noreplace(&src, &dst).map_err(|e| promote_error(&src, e))?;
+            }
+            Err(e) => return Err(promote_error(&src, e)),
+        }
+    }
+
+    let meta = fs::metadata(staging).map_err(|e| promote_error(staging, e))?;
+    std::os::unix::fs::chown(target, Some(meta.uid()), Some(meta.gid()))
+        .and_then(|()| fs::set_permissions(target, meta.permissions()))
+        .map_err(|e| promote_error(target, e))?;
+    fs::remove_dir(staging).map_err(|e| promote_error(staging, e))
+}
+
+/// rename(2) that fails with EEXIST instead of replacing `dst`.
+fn rename_noreplace(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let c_src = path_to_cstring(src)?;
+    let c_dst = path_to_cstring(dst)?;
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            c_src.as_ptr(),
+            libc::AT_FDCWD,
+            c_dst.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
     Ok(())
 }
 
+/// Mount the image again and compare it against the extracted target.
+///
+/// A [`Scope::Sample`] only compares randomly chosen regular files, which
+/// still catches systematic corruption such as bad RAM or a failing
+/// disk at a fraction of the cost.
+///
+/// Ownership is only compared for kernel mounts; rootless extractions can't
+/// preserve it.
+pub fn verify_against_image(
+    rootfs: &Path,
+    target: &Path,
+    method: MountMethod,
+    workdir: &Path,
+    scope: Scope,
+    quiet: bool,
+) -> Result<VerifyReport> {
+    let interrupt = InterruptGuard::install();
+    let mount = mount_erofs(rootfs, method, workdir, quiet)?;
+
+    let options = VerifyOptions {
+        cancel: Some(&|| interrupt.interrupted()),
+        check_ownership: method == MountMethod::Kernel,
+    };
+    let report = match scope {
+        Scope::All => {
+            if !quiet {
+                eprintln!("Comparing target against image (this may take a while)...");
+            }
+            compare_trees(mount.path(), target, &options).map_err(|e| mount.explain(e))?
+        }
+        Scope::Sample(count) => {
+            let seed = random_seed();
+            let (paths, total) =
+                sample_files(mount.path(), count, seed).map_err(|e| mount.explain(e))?;
+            if !quiet {
+                eprintln!(
+                    "Comparing {} of {} files against image (seed {:#x})...",
+                    paths.len(),
+                    total,
+                    seed
+                );
+            }
+            compare_paths(mount.path(), target, &paths, &options).map_err(|e| mount.explain(e))?
+        }
+    };
+
+    if !quiet {
+        eprintln!(
+            "Compared {} entries ({} MB)",
+            report.entries,
+            report.bytes / (1024 * 1024)
+        );
+    }
+    Ok(report)
+}
+
 /// Verify that essential directories exist after extraction.
 /// These directories are required for a functioning Linux system.
 ///
@@ -191,24 +1141,158 @@ pub fn verify_extraction(target: &Path) -> Result<()> {
     guarded_ensure!(
         missing.is_empty(),
         RecError::extraction_verification_failed(&missing),
-        protects = "Extracted system has all essential directories",
-        severity = "CRITICAL",
-        cheats = [
-            "Reduce ESSENTIAL_DIRS list",
-            "Move missing dirs to 'optional' list",
-            "Check exists() instead of is_dir()",
-            "Skip verification entirely",
-            "Only check one directory"
-        ],
-        consequence = "System extracts 'successfully' but is incomplete - /bin, /usr, or /etc missing, unbootable"
+        check = &checks::ESSENTIAL_DIRS
     );
 
     Ok(())
 }
 
+/// Hardlink groups of the tree at `root`, counted on the tree itself: the
+/// paths (relative to `root`) of each non-directory inode with more than
+/// one link, keyed by (dev, ino).
+pub fn hardlink_groups(root: &Path) -> std::io::Result<Vec<Vec<PathBuf>>> {
+    let mut inodes: HashMap<(u64, u64), Vec<PathBuf>> = HashMap::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let rel = dir.join(entry.file_name());
+            // Not following symlinks
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                dirs.push(rel);
+            } else if meta.nlink() > 1 {
+                inodes
+                    .entry((meta.dev(), meta.ino()))
+                    .or_default()
+                    .push(rel);
+            }
+        }
+    }
+    let mut groups: Vec<Vec<PathBuf>> = inodes
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort();
+            group
+        })
+        .collect();
+    groups.sort();
+    Ok(groups)
+}
+
+/// Verify that hardlinked files from the image still share an inode in the target.
+///
+/// `groups` are counted on the mounted image ([`hardlink_groups`]), not
+/// taken from the copier; at most [`HARDLINK_SAMPLE_GROUPS`] groups, spread
+/// evenly across the list, are checked.
+///
+/// # Cheat Vectors
+///
+/// - EASY: Only compare file contents instead of inode numbers
+/// - EASY: Sample zero groups
+/// - MEDIUM: Only check that each path exists
+/// - MEDIUM: Check the groups the copier says it linked, which share its bugs
+///
+/// # Consequence if Cheated
+///
+/// Hardlinks silently become copies: disk usage doubles for linked files and
+/// package layouts that rely on shared inodes break.
+pub fn verify_hardlinks(target: &Path, groups: &[Vec<PathBuf>]) -> Result<()> {
+    let step = groups.len().div_ceil(HARDLINK_SAMPLE_GROUPS).max(1);
+
+    let broken: Vec<String> = groups
+        .iter()
+        .step_by(step)
+        .filter(|group| !shares_inode(target, group))
+        .map(|group| {
+            group
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" = ")
+        })
+        .collect();
+
+    guarded_ensure!(
+        broken.is_empty(),
+        RecError::hardlinks_broken(&broken),
+        check = &checks::HARDLINKS
+    );
+
+    Ok(())
+}
+
+/// Verify that file capabilities (`security.capability`) from the image exist
+/// on the extracted files with the same value.
+///
+/// Returns the list of stripped paths. If `relaxed` is false, any stripped
+/// capability is a verification failure; otherwise the caller reports them
+/// as warnings.
+///
+/// # Cheat Vectors
+///
+/// - EASY: Only check that the xattr exists, not its value
+/// - EASY: Always run in relaxed mode
+/// - MEDIUM: Check a hardcoded list of binaries instead of what the image has
+///
+/// # Consequence if Cheated
+///
+/// `ping` and friends fail with "Operation not permitted" for regular users
+/// on the installed system, with nothing pointing at the installer.
+pub fn verify_capabilities(
+    target: &Path,
+    capabilities: &[(PathBuf, Vec<u8>)],
+    relaxed: bool,
+) -> Result<Vec<String>> {
+    let stripped: Vec<String> = capabilities
+        .iter()
+        .filter(|(path, value)| {
+            !matches!(read_capability(&target.join(path)), Ok(Some(v)) if &v == value)
+        })
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+
+    if !relaxed {
+        guarded_ensure!(
+            stripped.is_empty(),
+            RecError::capabilities_stripped(&stripped),
+            check = &checks::CAPABILITIES
+        );
+    }
+
+    Ok(stripped)
+}
+
+/// Check that every path in `group` (relative to `target`) is the same inode.
+fn shares_inode(target: &Path, group: &[PathBuf]) -> bool {
+    let mut inodes = group
+        .iter()
+        .map(|p| fs::symlink_metadata(target.join(p)).map(|m| (m.dev(), m.ino())));
+    match inodes.next() {
+        Some(Ok(first)) => inodes.all(|ino| matches!(ino, Ok(i) if i == first)),
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runner::FakeRunner;
+
+    #[test]
+    fn test_mount_error_is_transient() {
+        assert!(mount_error_is_transient(
+            "mount: /mnt/x: failed to setup loop device for /img."
+        ));
+        assert!(mount_error_is_transient(
+            "mount: /mnt/x: /dev/loop3 already mounted or mount point busy."
+        ));
+        assert!(!mount_error_is_transient(
+            "mount: /mnt/x: unknown filesystem type 'erofs'."
+        ));
+        assert!(!mount_error_is_transient(""));
+    }
 
     #[test]
     fn test_rootfs_type_from_path() {
@@ -245,4 +1329,364 @@ mod tests {
 
         let _ = fs::remove_file(&temp);
     }
+
+    #[test]
+    fn test_promote_staging() {
+        let target = std::env::temp_dir().join("recstrap_test_promote");
+        let _ = fs::remove_dir_all(&target);
+        fs::create_dir_all(target.join("lost+found")).unwrap();
+        let staging = create_staging(&target).unwrap();
+        fs::create_dir_all(staging.join("usr/bin")).unwrap();
+        fs::create_dir_all(staging.join("lost+found")).unwrap();
+        fs::write(staging.join("usr/bin/sh"), b"#!").unwrap();
+        fs::hard_link(staging.join("usr/bin/sh"), staging.join("usr/bin/bash")).unwrap();
+
+        promote_staging(&staging, &target).unwrap();
+
+        assert!(!staging.exists());
+        assert_eq!(fs::read(target.join("usr/bin/sh")).unwrap(), b"#!");
+        assert!(shares_inode(
+            &target,
+            &[PathBuf::from("usr/bin/sh"), PathBuf::from("usr/bin/bash")]
+        ));
+        assert!(target.join("lost+found").is_dir());
+
+        let _ = fs::remove_dir_all(&target);
+    }
+
+    #[test]
+    fn test_split_parts() {
+        let dir = std::env::temp_dir().join("recstrap_test_split_parts");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("filesystem.erofs");
+        assert_eq!(split_parts(&image), None);
+
+        for (n, data) in ["000", "001", "003"].iter().zip(["ab", "cd", "ef"]) {
+            fs::write(dir.join(format!("filesystem.erofs.{}", n)), data).unwrap();
+        }
+        // Up to the first gap, by the base name or the first part
+        let parts = vec![
+            dir.join("filesystem.erofs.000"),
+            dir.join("filesystem.erofs.001"),
+        ];
+        assert_eq!(split_parts(&image), Some(parts.clone()));
+        assert_eq!(split_parts(&parts[0]), Some(parts.clone()));
+        assert_eq!(split_parts(&parts[1]), None);
+
+        let joined = SpooledImage::join(&parts, &dir, true).unwrap();
+        assert_eq!(fs::read(joined.path()).unwrap(), b"abcd");
+        assert_eq!(
+            RootfsType::from_path(joined.path()),
+            Some(RootfsType::Erofs)
+        );
+        let path = joined.path().to_path_buf();
+        drop(joined);
+        assert!(!path.exists());
+
+        // Parts that don't fit in the workdir are refused up front
+        assert!(check_join_space(2, 4, 4, &dir).is_ok());
+        let err = check_join_space(3, 5 << 30, 1 << 30, &dir)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("E012:"), "{}", err);
+        assert!(err.contains("needs 5120MB"), "{}", err);
+        assert!(err.contains("--workdir"), "{}", err);
+
+        // A whole image is not split, even with parts next to it
+        fs::write(&image, "whole").unwrap();
+        assert_eq!(split_parts(&image), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_mount_image_commands() {
+        let workdir = std::env::temp_dir().join("recstrap_test_mount_commands");
+        let _ = fs::remove_dir_all(&workdir);
+        fs::create_dir_all(&workdir).unwrap();
+        let image = workdir.join("filesystem.erofs");
+        let (fake, _runner) = FakeRunner::install();
+
+        let mount = mount_image(&image, MountMethod::Kernel, &workdir, true).unwrap();
+        let point = mount.path().to_path_buf();
+        drop(mount);
+        assert_eq!(
+            fake.calls(),
+            [
+                format!("attach-loop {}", image.display()),
+                format!("mount -t erofs -o ro /dev/loop0 {}", point.display()),
+                format!("umount {}", point.display()),
+            ]
+        );
+        assert!(!point.exists());
+
+        // A failed mount leaves nothing to unmount
+        fake.reply("erofsfuse", 1, "fuse: device not found");
+        let Err(err) = mount_image(&image, MountMethod::Fuse, &workdir, true) else {
+            panic!("failed erofsfuse accepted");
+        };
+        assert!(err.to_string().contains("device not found"), "{}", err);
+        assert_eq!(fake.calls().len(), 4);
+        assert!(fake.calls()[3].starts_with("erofsfuse "));
+
+        // A mount killed by the timeout may have attached the image, so it
+        // is unmounted anyway, lazily when umount hangs too
+        fake.time_out("mount");
+        fake.time_out("umount");
+        let Err(err) = mount_image(&image, MountMethod::Kernel, &workdir, true) else {
+            panic!("timed out mount accepted");
+        };
+        assert_eq!(err.code, ErrorCode::CommandTimedOut);
+        let calls = fake.calls();
+        let point = calls[5].rsplit(' ').next().unwrap();
+        assert_eq!(
+            calls[6..],
+            [format!("umount {}", point), format!("umount -l {}", point)]
+        );
+
+        let _ = fs::remove_dir_all(&workdir);
+    }
+
+    #[test]
+    fn test_kernel_mount_failure() {
+        let image = Path::new("/run/live/filesystem.erofs");
+        let failure = |errno, message: Option<&str>| {
+            kernel_mount_failure(
+                MountError {
+                    error: std::io::Error::from_raw_os_error(errno),
+                    message: message.map(str::to_string),
+                },
+                image,
+            )
+        };
+
+        let no_driver = failure(libc::ENODEV, None);
+        assert_eq!(no_driver.error.code, ErrorCode::ErofsNotSupported);
+        assert!(!no_driver.transient);
+
+        let rejected = failure(
+            libc::EINVAL,
+            Some("erofs: cannot find valid erofs superblock"),
+        );
+        assert_eq!(rejected.error.code, ErrorCode::InvalidRootfsFormat);
+        assert!(
+            rejected
+                .error
+                .message
+                .ends_with("(erofs: cannot find valid erofs superblock)"),
+            "{}",
+            rejected.error
+        );
+
+        let busy = failure(libc::EBUSY, None);
+        assert_eq!(busy.error.code, ErrorCode::ExtractionFailed);
+        assert!(busy.transient);
+    }
+
+    #[test]
+    fn test_attach_loop_errors() {
+        let image = Path::new("/run/live/filesystem.erofs");
+        let (fake, _runner) = FakeRunner::install();
+
+        // Out of loop devices: reported as such, and worth retrying
+        fake.reply("attach-loop", libc::EBUSY, "");
+        let Err(failure) = attach_loop(image) else {
+            panic!("exhausted loop devices accepted");
+        };
+        assert_eq!(failure.error.code, ErrorCode::LoopDevicesExhausted);
+        assert!(failure.transient);
+
+        fake.reply("attach-loop", libc::EACCES, "");
+        let Err(failure) = attach_loop(image) else {
+            panic!("failed attach accepted");
+        };
+        assert_eq!(failure.error.code, ErrorCode::ExtractionFailed);
+        assert!(
+            failure
+                .error
+                .message
+                .starts_with("cannot set up a loop device for /run/live/filesystem.erofs:"),
+            "{}",
+            failure.error
+        );
+        assert!(!failure.transient);
+    }
+
+    #[test]
+    fn test_mount_image_verity() {
+        let workdir = std::env::temp_dir().join("recstrap_test_mount_verity");
+        let _ = fs::remove_dir_all(&workdir);
+        fs::create_dir_all(&workdir).unwrap();
+        let image = workdir.join("filesystem.erofs");
+        crate::fixture::minimal_system().write(&image).unwrap();
+        fs::write(workdir.join("filesystem.verity"), b"verity\0\0").unwrap();
+        fs::write(workdir.join("filesystem.roothash"), "ab".repeat(32)).unwrap();
+        let (fake, _runner) = FakeRunner::install();
+
+        // Mounted from the verity device, which is closed after the unmount
+        let mount = mount_image(&image, MountMethod::Kernel, &workdir, true).unwrap();
+        let point = mount.path().to_path_buf();
+        drop(mount);
+        let calls = fake.calls();
+        assert_eq!(calls.len(), 4, "{:?}", calls);
+        assert!(calls[0].starts_with(&format!(
+            "veritysetup open {} recstrap-verity-",
+            image.display()
+        )));
+        let name = calls[0].split(' ').nth(3).unwrap();
+        assert_eq!(
+            calls[1..],
+            [
+                format!(
+                    "mount -t erofs -o ro /dev/mapper/{} {}",
+                    name,
+                    point.display()
+                ),
+                format!("umount {}", point.display()),
+                format!("veritysetup close {}", name),
+            ]
+        );
+
+        // A root hash mismatch fails before anything is mounted
+        fake.reply("veritysetup", 1, "Verification of root hash failed.");
+        let Err(err) = mount_image(&image, MountMethod::Kernel, &workdir, true) else {
+            panic!("mismatching root hash accepted");
+        };
+        assert_eq!(err.code, ErrorCode::VerityFailed);
+        assert!(!fake.calls()[4..].iter().any(|c| c.starts_with("mount ")));
+
+        let _ = fs::remove_dir_all(&workdir);
+    }
+
+    #[test]
+    fn test_shared_mounts_nest() {
+        let active = || SHARED.with(|s| s.borrow().is_some());
+        let outer = SharedMounts::begin();
+        let inner = SharedMounts::begin();
+        drop(inner);
+        assert!(active(), "an inner guard must not end the outer sharing");
+        drop(outer);
+        assert!(!active());
+    }
+
+    #[test]
+    fn test_rootfs_info() {
+        let image = std::env::temp_dir().join("recstrap_test_rootfs_info.erofs");
+        crate::fixture::minimal_system().write(&image).unwrap();
+
+        let info = RootfsInfo::read(&image).unwrap();
+        assert_eq!(info.format, RootfsType::Erofs);
+        assert_eq!(info.volume_name.as_deref(), Some("fixture"));
+        assert_eq!(info.image_size, fs::metadata(&image).unwrap().len());
+        assert_eq!(info.uncompressed_size, None);
+        assert!(info.compression.is_empty());
+        assert!(
+            info.summary()
+                .contains("\"fixture\", built 2025-01-01T00:00:00Z"),
+            "{}",
+            info.summary()
+        );
+        let json = info.to_json().to_string();
+        assert!(
+            json.contains(&format!("\"uuid\":\"{}\"", info.uuid)),
+            "{}",
+            json
+        );
+        assert!(json.contains("\"uncompressed_size\":null"), "{}", json);
+
+        let _ = fs::remove_file(&image);
+    }
+
+    #[test]
+    fn test_is_stale_mount_dir() {
+        // Legacy fixed mount point
+        assert!(is_stale_mount_dir("mount"));
+        // Our own and other live processes are never stale
+        assert!(!is_stale_mount_dir(&format!(
+            "{}-Ab12Cd",
+            std::process::id()
+        )));
+        assert!(!is_stale_mount_dir("1-Ab12Cd"));
+        // A PID that cannot exist
+        assert!(is_stale_mount_dir("4294967295-Ab12Cd"));
+        assert!(!is_stale_mount_dir("notapid-Ab12Cd"));
+    }
+
+    #[test]
+    fn test_resolve_image_errors() {
+        let err = resolve_image("/nonexistent/fs.erofs").unwrap_err();
+        assert_eq!(err.code, ErrorCode::RootfsNotFound);
+
+        let err = resolve_image("/tmp").unwrap_err();
+        assert_eq!(err.code, ErrorCode::RootfsNotFile);
+
+        let temp = std::env::temp_dir().join("recstrap_test_resolve.squashfs");
+        fs::write(&temp, b"hsqs").unwrap();
+        let err = resolve_image(temp.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRootfsFormat);
+        let _ = fs::remove_file(&temp);
+    }
+
+    #[test]
+    fn test_hardlink_groups() {
+        let temp = std::env::temp_dir().join("recstrap_test_hardlink_groups");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("usr/bin")).unwrap();
+        fs::write(temp.join("usr/bin/gzip"), b"x").unwrap();
+        fs::hard_link(temp.join("usr/bin/gzip"), temp.join("usr/bin/gunzip")).unwrap();
+        fs::hard_link(temp.join("usr/bin/gzip"), temp.join("zcat")).unwrap();
+        fs::write(temp.join("usr/bin/ls"), b"x").unwrap();
+        std::os::unix::fs::symlink("gzip", temp.join("usr/bin/uncompress")).unwrap();
+
+        assert_eq!(
+            hardlink_groups(&temp).unwrap(),
+            [vec![
+                PathBuf::from("usr/bin/gunzip"),
+                PathBuf::from("usr/bin/gzip"),
+                PathBuf::from("zcat")
+            ]]
+        );
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_verify_hardlinks() {
+        let temp = std::env::temp_dir().join("recstrap_test_verify_hardlinks");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(&temp).unwrap();
+        fs::write(temp.join("a"), b"x").unwrap();
+        fs::hard_link(temp.join("a"), temp.join("b")).unwrap();
+        fs::write(temp.join("c"), b"x").unwrap();
+
+        let linked = vec![vec![PathBuf::from("a"), PathBuf::from("b")]];
+        assert!(verify_hardlinks(&temp, &linked).is_ok());
+
+        let broken = vec![vec![PathBuf::from("a"), PathBuf::from("c")]];
+        let err = verify_hardlinks(&temp, &broken).unwrap_err();
+        assert_eq!(err.code, ErrorCode::ExtractionVerificationFailed);
+        assert!(err.to_string().contains("a = c"), "Error was: {}", err);
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_verify_capabilities_detects_stripped() {
+        let temp = std::env::temp_dir().join("recstrap_test_verify_caps");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(&temp).unwrap();
+        fs::write(temp.join("ping"), b"x").unwrap();
+
+        let caps = vec![(PathBuf::from("ping"), vec![1, 2, 3])];
+        let err = verify_capabilities(&temp, &caps, false).unwrap_err();
+        assert_eq!(err.code, ErrorCode::ExtractionVerificationFailed);
+
+        let stripped = verify_capabilities(&temp, &caps, true).unwrap();
+        assert_eq!(stripped, vec!["ping".to_string()]);
+
+        assert!(verify_capabilities(&temp, &[], false).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
 }
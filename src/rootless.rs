@@ -0,0 +1,160 @@
+//! Unprivileged (rootless) extraction support.
+//!
+//! `--rootless` lets a normal user extract an image for development,
+//! inspection, or container rootfs preparation. We enter a new user and
+//! mount namespace in which we are uid 0, mount the image with `erofsfuse`,
+//! and copy as usual.
+//!
+//! If the user has subordinate ID ranges (`/etc/subuid`, `/etc/subgid`) and
+//! `newuidmap`/`newgidmap` are installed, those ranges are mapped so files
+//! keep their real owners (as seen from inside the namespace). Otherwise
+//! only the user's own ID is mapped and ownership is not preserved.
+
+use std::fs;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use crate::helpers::tool_available;
+
+/// How IDs were mapped into the user namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdMapping {
+    /// Our ID maps to 0 and subordinate ranges cover 1..: ownership is kept.
+    Full,
+    /// Only our ID maps to 0; files owned by anyone else become ours.
+    SingleId,
+}
+
+/// A subordinate ID range from /etc/subuid or /etc/subgid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubIdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+/// Find the first range for `user` (matched by name or numeric ID).
+pub fn parse_subid(content: &str, name: &str, id: u32) -> Option<SubIdRange> {
+    content.lines().find_map(|line| {
+        let mut fields = line.trim().split(':');
+        let owner = fields.next()?;
+        let start = fields.next()?.parse().ok()?;
+        let count = fields.next()?.parse().ok()?;
+        let matches = owner == name || owner.parse() == Ok(id);
+        (matches && count > 0).then_some(SubIdRange { start, count })
+    })
+}
+
+fn current_user_name(uid: u32) -> Option<String> {
+    let pw = unsafe { libc::getpwuid(uid) };
+    if pw.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr((*pw).pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+fn subid_range(file: &str, name: &str, id: u32) -> Option<SubIdRange> {
+    parse_subid(&fs::read_to_string(file).ok()?, name, id)
+}
+
+/// Move this process into a new user + mount namespace where it is root.
+///
+/// Must be called while the process is still single-threaded.
+pub fn enter_user_namespace() -> io::Result<IdMapping> {
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    let pid = std::process::id();
+    let name = current_user_name(uid).unwrap_or_default();
+
+    let ranges = match (
+        subid_range("/etc/subuid", &name, uid),
+        subid_range("/etc/subgid", &name, gid),
+    ) {
+        (Some(u), Some(g)) if tool_available("newuidmap") && tool_available("newgidmap") => {
+            Some((u, g))
+        }
+        _ => None,
+    };
+
+    // newuidmap must run from the parent namespace, so start the helper
+    // now and let it wait until we have unshared.
+    let helper = match ranges {
+        Some((u, g)) => Some(
+            Command::new("sh")
+                .args([
+                    "-c",
+                    "read _ && newuidmap \"$1\" 0 \"$2\" 1 1 \"$3\" \"$4\" \
+                     && newgidmap \"$1\" 0 \"$5\" 1 1 \"$6\" \"$7\"",
+                    "sh",
+                ])
+                .args([pid, uid, u.start, u.count, gid, g.start, g.count].map(|n| n.to_string()))
+                .stdin(Stdio::piped())
+                .spawn()?,
+        ),
+        None => None,
+    };
+
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mapping = match helper {
+        Some(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(b"go\n")?;
+            }
+            if !child.wait()?.success() {
+                return Err(io::Error::other("newuidmap/newgidmap failed"));
+            }
+            IdMapping::Full
+        }
+        None => {
+            // Unprivileged processes must give up setgroups() before
+            // writing their own gid_map.
+            fs::write("/proc/self/setgroups", "deny")?;
+            fs::write("/proc/self/uid_map", format!("0 {} 1", uid))?;
+            fs::write("/proc/self/gid_map", format!("0 {} 1", gid))?;
+            IdMapping::SingleId
+        }
+    };
+
+    // Keep our FUSE mount from propagating anywhere (and vice versa).
+    let root = c"/";
+    let ret = unsafe {
+        libc::mount(
+            std::ptr::null(),
+            root.as_ptr(),
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_subid() {
+        let content = "alice:100000:65536\nbob:165536:65536\n1001:231072:65536\n";
+        assert_eq!(
+            parse_subid(content, "bob", 1000),
+            Some(SubIdRange {
+                start: 165536,
+                count: 65536
+            })
+        );
+        assert_eq!(
+            parse_subid(content, "carol", 1001).map(|r| r.start),
+            Some(231072)
+        );
+        assert_eq!(parse_subid(content, "dave", 1002), None);
+        assert_eq!(parse_subid("eve:1:0\n", "eve", 1), None);
+    }
+}
@@ -0,0 +1,338 @@
+//! Running external commands.
+//!
+//! Everything recstrap runs (mount, umount, modprobe, losetup, tar, ...)
+//! goes through [`status`], [`output`] and [`output_with_input`], which use
+//! the [`CommandRunner`] installed on this thread, by default the real one.
+//! Tests install a [`FakeRunner`] to see what would have run and to script
+//! results, so mount and module-loading logic can be tested without root.
+//! At `-v` each command is echoed before it runs ([`crate::output`]).
+//! The only exception is the newuidmap helper in `rootless`, which has to
+//! stay running alongside us. Attaching loop devices and mounting the image
+//! are system calls, not commands, but go through the runner too
+//! ([`attach_loop`], [`mount_erofs`], [`unmount`]) so mounts stay testable.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::rc::Rc;
+
+use crate::cgroup;
+use crate::helpers::output_within;
+use crate::loopdev::LoopDevice;
+use crate::mount::{self, MountError};
+use crate::output;
+
+pub trait CommandRunner {
+    /// Run `cmd` to completion, like [`Command::status`].
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus>;
+
+    /// Run `cmd` and collect its output. With a timeout it is killed once
+    /// that has passed ([`io::ErrorKind::TimedOut`]) and only the streams
+    /// the caller piped are captured; without, like [`Command::output`].
+    fn output(&self, cmd: &mut Command, timeout_secs: Option<u64>) -> io::Result<Output>;
+
+    /// Run `cmd` with `input` on stdin and wait for it. Streams the caller
+    /// didn't pipe stay inherited.
+    fn output_with_input(&self, cmd: &mut Command, input: &[u8]) -> io::Result<Output>;
+
+    /// Attach `image` read-only to a free loop device.
+    fn attach_loop(&self, image: &Path) -> io::Result<LoopDevice>;
+
+    /// Mount the EROFS on `source` read-only at `target`, giving up after
+    /// `timeout_secs` (0: never).
+    fn mount_erofs(
+        &self,
+        source: &Path,
+        target: &Path,
+        timeout_secs: u64,
+    ) -> std::result::Result<(), MountError>;
+
+    /// Unmount `target`, detaching it even while busy if `lazy`.
+    fn unmount(&self, target: &Path, lazy: bool, timeout_secs: u64) -> io::Result<()>;
+}
+
+/// Runs commands for real.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        cgroup::confine(cmd);
+        cmd.status()
+    }
+
+    fn output(&self, cmd: &mut Command, timeout_secs: Option<u64>) -> io::Result<Output> {
+        cgroup::confine(cmd);
+        match timeout_secs {
+            Some(secs) => output_within(cmd, secs),
+            None => cmd.output(),
+        }
+    }
+
+    fn output_with_input(&self, cmd: &mut Command, input: &[u8]) -> io::Result<Output> {
+        cgroup::confine(cmd);
+        let mut child = cmd.stdin(Stdio::piped()).spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input)?;
+        }
+        child.wait_with_output()
+    }
+
+    fn attach_loop(&self, image: &Path) -> io::Result<LoopDevice> {
+        LoopDevice::attach(image)
+    }
+
+    fn mount_erofs(
+        &self,
+        source: &Path,
+        target: &Path,
+        timeout_secs: u64,
+    ) -> std::result::Result<(), MountError> {
+        mount::mount_erofs(source, target, timeout_secs)
+    }
+
+    fn unmount(&self, target: &Path, lazy: bool, timeout_secs: u64) -> io::Result<()> {
+        mount::unmount(target, lazy, timeout_secs)
+    }
+}
+
+thread_local! {
+    static RUNNER: RefCell<Option<Rc<dyn CommandRunner>>> = const { RefCell::new(None) };
+}
+
+/// Sends this thread's commands to another runner while alive.
+#[cfg(test)]
+pub struct RunnerGuard(Option<Rc<dyn CommandRunner>>);
+
+#[cfg(test)]
+impl RunnerGuard {
+    pub fn install(runner: Rc<dyn CommandRunner>) -> Self {
+        Self(RUNNER.with(|r| r.replace(Some(runner))))
+    }
+}
+
+#[cfg(test)]
+impl Drop for RunnerGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        RUNNER.with(|r| *r.borrow_mut() = previous);
+    }
+}
+
+fn current() -> Rc<dyn CommandRunner> {
+    RUNNER
+        .with(|r| r.borrow().clone())
+        .unwrap_or_else(|| Rc::new(SystemRunner))
+}
+
+/// See [`CommandRunner::status`].
+pub fn status(cmd: &mut Command) -> io::Result<ExitStatus> {
+    output::command(cmd);
+    current().status(cmd)
+}
+
+/// See [`CommandRunner::output`]; without a timeout.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    output::command(cmd);
+    current().output(cmd, None)
+}
+
+/// See [`CommandRunner::output`].
+pub fn output_timed(cmd: &mut Command, secs: u64) -> io::Result<Output> {
+    output::command(cmd);
+    current().output(cmd, Some(secs))
+}
+
+/// See [`CommandRunner::output_with_input`].
+pub fn output_with_input(cmd: &mut Command, input: &[u8]) -> io::Result<Output> {
+    output::command(cmd);
+    current().output_with_input(cmd, input)
+}
+
+/// See [`CommandRunner::attach_loop`].
+pub fn attach_loop(image: &Path) -> io::Result<LoopDevice> {
+    current().attach_loop(image)
+}
+
+/// See [`CommandRunner::mount_erofs`].
+pub fn mount_erofs(
+    source: &Path,
+    target: &Path,
+    timeout_secs: u64,
+) -> std::result::Result<(), MountError> {
+    current().mount_erofs(source, target, timeout_secs)
+}
+
+/// See [`CommandRunner::unmount`].
+pub fn unmount(target: &Path, lazy: bool, timeout_secs: u64) -> io::Result<()> {
+    current().unmount(target, lazy, timeout_secs)
+}
+
+/// Records commands instead of running them. Each one succeeds without
+/// output unless scripted otherwise.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeRunner {
+    calls: RefCell<Vec<String>>,
+    replies: RefCell<Vec<(String, FakeReply)>>,
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+enum FakeReply {
+    Exit(i32, String),
+    TimedOut,
+}
+
+#[cfg(test)]
+impl FakeRunner {
+    /// A fresh fake, installed on this thread until the guard drops.
+    pub fn install() -> (Rc<Self>, RunnerGuard) {
+        let fake = Rc::new(Self::default());
+        let guard = RunnerGuard::install(Rc::clone(&fake) as Rc<dyn CommandRunner>);
+        (fake, guard)
+    }
+
+    /// Make `program` exit with `code`, printing `stderr`.
+    pub fn reply(&self, program: &str, code: i32, stderr: &str) {
+        self.replies.borrow_mut().push((
+            program.to_string(),
+            FakeReply::Exit(code, stderr.to_string()),
+        ));
+    }
+
+    /// Make `program` fail as if killed by the command timeout.
+    pub fn time_out(&self, program: &str) {
+        self.replies
+            .borrow_mut()
+            .push((program.to_string(), FakeReply::TimedOut));
+    }
+
+    /// Command lines run so far, program and arguments joined by spaces.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+
+    fn run(&self, cmd: &Command) -> io::Result<Output> {
+        use std::os::unix::process::ExitStatusExt;
+
+        let program = cmd.get_program().to_string_lossy().into_owned();
+        let line = std::iter::once(program.clone())
+            .chain(cmd.get_args().map(|a| a.to_string_lossy().into_owned()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.calls.borrow_mut().push(line);
+
+        // The latest reply for a program wins
+        let reply = self
+            .replies
+            .borrow()
+            .iter()
+            .rev()
+            .find(|(p, _)| *p == program)
+            .map(|(_, reply)| reply.clone());
+        let (code, stderr) = match reply {
+            None => (0, String::new()),
+            Some(FakeReply::Exit(code, stderr)) => (code, stderr),
+            Some(FakeReply::TimedOut) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "killed (fake)"))
+            }
+        };
+        Ok(Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: Vec::new(),
+            stderr: stderr.into_bytes(),
+        })
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for FakeRunner {
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        self.run(cmd).map(|o| o.status)
+    }
+
+    fn output(&self, cmd: &mut Command, _timeout_secs: Option<u64>) -> io::Result<Output> {
+        self.run(cmd)
+    }
+
+    fn output_with_input(&self, cmd: &mut Command, _input: &[u8]) -> io::Result<Output> {
+        self.run(cmd)
+    }
+
+    /// Recorded as `attach-loop IMAGE`; a scripted exit code is the errno.
+    fn attach_loop(&self, image: &Path) -> io::Result<LoopDevice> {
+        let output = self.run(Command::new("attach-loop").arg(image))?;
+        match errno(&output) {
+            None => Ok(LoopDevice::fake("/dev/loop0")),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// Recorded as the equivalent mount(8) command line; a scripted exit
+    /// code is the errno and stderr the kernel's message.
+    fn mount_erofs(
+        &self,
+        source: &Path,
+        target: &Path,
+        _timeout_secs: u64,
+    ) -> std::result::Result<(), MountError> {
+        let output = self.run(
+            Command::new("mount")
+                .args(["-t", "erofs", "-o", "ro"])
+                .arg(source)
+                .arg(target),
+        )?;
+        match errno(&output) {
+            None => Ok(()),
+            Some(error) => Err(MountError {
+                error,
+                message: Some(String::from_utf8_lossy(&output.stderr).into_owned())
+                    .filter(|m| !m.is_empty()),
+            }),
+        }
+    }
+
+    /// Recorded as `umount [-l] TARGET`.
+    fn unmount(&self, target: &Path, lazy: bool, _timeout_secs: u64) -> io::Result<()> {
+        let mut cmd = Command::new("umount");
+        if lazy {
+            cmd.arg("-l");
+        }
+        errno(&self.run(cmd.arg(target))?).map_or(Ok(()), Err)
+    }
+}
+
+/// A fake's scripted exit code as an errno.
+#[cfg(test)]
+fn errno(output: &Output) -> Option<io::Error> {
+    match output.status.code() {
+        Some(0) => None,
+        code => Some(io::Error::from_raw_os_error(code.unwrap_or(libc::EIO))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fake_runner() {
+        let (fake, guard) = FakeRunner::install();
+        fake.reply("mount", 32, "mount point busy");
+        fake.time_out("umount");
+
+        assert!(status(Command::new("true").arg("x")).unwrap().success());
+        let mounted = output(Command::new("mount").args(["-t", "erofs"])).unwrap();
+        assert_eq!(mounted.status.code(), Some(32));
+        assert_eq!(mounted.stderr, b"mount point busy");
+        let err = output_timed(Command::new("umount").arg("/x"), 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(fake.calls(), ["true x", "mount -t erofs", "umount /x"]);
+
+        // Real commands again once the guard is gone
+        drop(guard);
+        assert!(!status(&mut Command::new("false")).unwrap().success());
+        assert_eq!(fake.calls().len(), 3);
+    }
+}
@@ -0,0 +1,241 @@
+//! Landlock confinement of helpers that work on the target.
+//!
+//! While an install runs, helpers that only have business inside the
+//! target (tools run chrooted into it, ssh-keygen) are started under a
+//! Landlock ruleset that allows writing beneath the target and the work
+//! directory, and nowhere else, so a buggy or compromised helper can't
+//! scribble over the live system even though it runs as root. Reading and
+//! executing stay unrestricted.
+//!
+//! Landlock can't be applied to helpers that mount (erofsfuse, mount
+//! itself): a confined process may not change the mount topology. On
+//! kernels without Landlock (before 5.13, or disabled) helpers run
+//! unconfined, which `-v` mentions.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::context;
+use crate::output;
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+/// ABI 2: renaming and linking between directories
+const ACCESS_FS_REFER: u64 = 1 << 13;
+/// ABI 3: truncate(2)
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// Everything that modifies the filesystem, in Landlock ABI 1.
+const WRITE_ACCESS_V1: u64 = ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_CHAR
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SOCK
+    | ACCESS_FS_MAKE_FIFO
+    | ACCESS_FS_MAKE_BLOCK
+    | ACCESS_FS_MAKE_SYM;
+
+/// Files helpers may always write to, wherever they are.
+const WRITABLE_FILES: &[&str] = &["/dev/null"];
+
+/// `struct landlock_ruleset_attr`, without the later network field.
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+/// `struct landlock_path_beneath_attr` (packed in the kernel header).
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Confines the install's helpers to writing beneath some directories
+/// until dropped.
+pub struct WriteRoots(());
+
+impl WriteRoots {
+    pub fn set(roots: Vec<PathBuf>) -> Self {
+        context::update(|c| c.write_roots = roots);
+        Self(())
+    }
+}
+
+impl Drop for WriteRoots {
+    fn drop(&mut self) {
+        context::update(|c| c.write_roots.clear());
+    }
+}
+
+/// Start `cmd` confined to the install's write roots, if any are set and
+/// the kernel has Landlock.
+pub fn confine(cmd: &mut Command) {
+    let roots = context::with(|c| c.write_roots.clone());
+    if roots.is_empty() {
+        return;
+    }
+    if let Err(e) = confine_to(cmd, &roots) {
+        if output::verbosity() >= 1 {
+            output::note(&format!(
+                "running {} without Landlock: {}",
+                cmd.get_program().to_string_lossy(),
+                e
+            ));
+        }
+    }
+}
+
+/// The Landlock ABI version, or why there is none.
+fn abi_version() -> io::Result<i64> {
+    let version = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if version < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(version)
+}
+
+/// Build the ruleset now and apply it in the child before exec. Only
+/// async-signal-safe calls run in the child.
+fn confine_to(cmd: &mut Command, roots: &[PathBuf]) -> io::Result<()> {
+    let abi = abi_version()?;
+    let mut dir_access = WRITE_ACCESS_V1;
+    let mut file_access = ACCESS_FS_WRITE_FILE;
+    if abi >= 2 {
+        dir_access |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        dir_access |= ACCESS_FS_TRUNCATE;
+        file_access |= ACCESS_FS_TRUNCATE;
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: dir_access,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as _) };
+
+    for root in roots {
+        allow(&ruleset, root, dir_access)?;
+    }
+    for file in WRITABLE_FILES {
+        // A system without it doesn't need it allowed
+        let _ = allow(&ruleset, Path::new(file), file_access);
+    }
+
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ret = libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0);
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Allow `access` beneath `path` (or on it, for a file).
+fn allow(ruleset: &OwnedFd, path: &Path, access: u64) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(
+            e.kind(),
+            format!("{}: {}", path.display(), e),
+        ));
+    }
+    let parent = unsafe { OwnedFd::from_raw_fd(fd) };
+    let rule = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: parent.as_raw_fd(),
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &rule,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_struct_layout() {
+        assert_eq!(std::mem::size_of::<PathBeneathAttr>(), 12);
+    }
+
+    #[test]
+    fn test_confine_to() {
+        if abi_version().is_err() {
+            return;
+        }
+        let dir = std::env::temp_dir().join("recstrap_test_sandbox");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+
+        let write = |path: &Path| {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c")
+                .arg("echo x > \"$1\" && echo y > /dev/null")
+                .arg("sh")
+                .arg(path);
+            confine_to(&mut cmd, &[dir.join("root")]).unwrap();
+            cmd.status().unwrap().success()
+        };
+        assert!(write(&dir.join("root/inside")));
+        assert!(!write(&dir.join("outside")));
+        assert!(!dir.join("outside").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
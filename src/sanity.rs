@@ -0,0 +1,467 @@
+//! Deep sanity checks on an extracted system.
+//!
+//! `verify_extraction` only proves the top-level directories exist. A rootfs
+//! can pass that and still be unbootable: no dynamic loader, no shell, no
+//! init, or account databases the login stack can't parse. These checks
+//! look at the handful of files everything else depends on, plus the
+//! usrmerge symlinks (`/bin -> usr/bin`, ...) that a careless copy can turn
+//! into real directories, and the database of whatever package manager the
+//! image ships.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::checks;
+use crate::error::{RecError, Result};
+use crate::guarded_ensure;
+use crate::helpers::{follow_in_root, resolve_in_root};
+
+/// Where the dynamic loader lives (lib64 on x86-64, lib elsewhere).
+const LOADER_DIRS: &[&str] = &["usr/lib64", "usr/lib"];
+
+/// Top-level directories that usrmerged images replace with symlinks.
+const USRMERGE_DIRS: &[&str] = &["bin", "sbin", "lib", "lib64"];
+
+/// Init binaries the kernel may start, in the order it tries them.
+const INIT_PATHS: &[&str] = &["usr/lib/systemd/systemd", "sbin/init"];
+
+/// A package manager, found by its binary, and where it keeps the list of
+/// installed packages.
+struct PackageDb {
+    tool: &'static str,
+    binaries: &'static [&'static str],
+    /// Candidates in order; rpm moved from /var/lib to /usr/lib/sysimage
+    databases: &'static [&'static str],
+    /// Whether the database at that path lists anything
+    populated: fn(&Path) -> bool,
+}
+
+const PACKAGE_DBS: &[PackageDb] = &[
+    PackageDb {
+        tool: "rpm",
+        binaries: &["usr/bin/rpm"],
+        databases: &["usr/lib/sysimage/rpm", "var/lib/rpm"],
+        populated: |dir| {
+            ["rpmdb.sqlite", "Packages", "Packages.db"]
+                .iter()
+                .any(|f| non_empty_file(&dir.join(f)))
+        },
+    },
+    PackageDb {
+        tool: "dpkg",
+        binaries: &["usr/bin/dpkg"],
+        databases: &["var/lib/dpkg/status"],
+        populated: non_empty_file,
+    },
+    PackageDb {
+        tool: "pacman",
+        binaries: &["usr/bin/pacman"],
+        databases: &["var/lib/pacman/local"],
+        populated: |dir| {
+            fs::read_dir(dir).is_ok_and(|entries| {
+                entries
+                    .flatten()
+                    .any(|e| non_empty_file(&e.path().join("desc")))
+            })
+        },
+    },
+    PackageDb {
+        tool: "apk",
+        binaries: &["usr/sbin/apk", "sbin/apk"],
+        databases: &["usr/lib/apk/db/installed", "lib/apk/db/installed"],
+        populated: non_empty_file,
+    },
+];
+
+/// Verify that the target has a dynamic loader, `/usr/bin/sh`, and an init
+/// (all executable ELF files), and that `/etc/passwd` and `/etc/shadow`
+/// parse and contain root.
+///
+/// # Cheat Vectors
+///
+/// - EASY: Only check that the files exist
+/// - EASY: Accept any file as an ELF binary
+/// - MEDIUM: Skip the account database checks
+///
+/// # Consequence if Cheated
+///
+/// The system "installs" fine and then panics with "No working init found",
+/// or boots to a login prompt nobody can get past.
+pub fn verify_system_sanity(target: &Path) -> Result<()> {
+    let problems = sanity_problems(target);
+
+    guarded_ensure!(
+        problems.is_empty(),
+        RecError::system_unbootable(&problems),
+        check = &checks::SYSTEM_SANITY
+    );
+
+    Ok(())
+}
+
+/// Verify that the usrmerge symlinks of the image (`root_symlinks` from the
+/// copier, e.g. `bin -> usr/bin`) are still identical symlinks in `target`.
+/// Images that keep real `/bin` etc. have nothing to check.
+///
+/// # Cheat Vectors
+///
+/// - EASY: Accept a directory as long as it has the same contents
+/// - MEDIUM: Only check that the path exists
+///
+/// # Consequence if Cheated
+///
+/// `/bin` and `/usr/bin` diverge: packages update one copy, scripts run the
+/// other, and later upgrades fail on file conflicts.
+pub fn verify_usrmerge(target: &Path, root_symlinks: &[(PathBuf, PathBuf)]) -> Result<()> {
+    let broken = usrmerge_problems(target, root_symlinks);
+
+    guarded_ensure!(
+        broken.is_empty(),
+        RecError::usrmerge_broken(&broken),
+        check = &checks::USRMERGE
+    );
+
+    Ok(())
+}
+
+/// Verify that every package manager the image ships (rpm, dpkg, pacman,
+/// apk) has a database listing installed packages. Images without one
+/// have nothing to check.
+///
+/// # Cheat Vectors
+///
+/// - EASY: Only check that the database directory exists
+/// - MEDIUM: Skip images whose package manager isn't recognized
+///
+/// # Consequence if Cheated
+///
+/// The system boots, and weeks later the first update reinstalls or
+/// conflicts with every package because the manager thinks none are there.
+pub fn verify_package_database(target: &Path) -> Result<()> {
+    let problems = package_db_problems(target);
+
+    guarded_ensure!(
+        problems.is_empty(),
+        RecError::package_database_missing(&problems),
+        check = &checks::PACKAGE_DATABASE
+    );
+
+    Ok(())
+}
+
+fn package_db_problems(target: &Path) -> Vec<String> {
+    PACKAGE_DBS
+        .iter()
+        .filter(|db| db.binaries.iter().any(|b| exists_in_root(target, b)))
+        .filter_map(|db| {
+            let populated = db.databases.iter().any(|d| {
+                follow_in_root(target, Path::new(d)).is_ok_and(|path| (db.populated)(&path))
+            });
+            (!populated).then(|| {
+                format!(
+                    "{} is installed but its database (/{}) is missing or empty",
+                    db.tool,
+                    db.databases.join(" or /")
+                )
+            })
+        })
+        .collect()
+}
+
+fn exists_in_root(root: &Path, path: &str) -> bool {
+    follow_in_root(root, Path::new(path)).is_ok_and(|p| p.symlink_metadata().is_ok())
+}
+
+fn non_empty_file(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.len() > 0)
+}
+
+fn usrmerge_problems(target: &Path, root_symlinks: &[(PathBuf, PathBuf)]) -> Vec<String> {
+    root_symlinks
+        .iter()
+        .filter(|(name, link)| {
+            USRMERGE_DIRS.iter().any(|d| name == Path::new(d))
+                && link.strip_prefix("/").unwrap_or(link).starts_with("usr")
+        })
+        .filter_map(|(name, link)| {
+            let path = target.join(name);
+            let problem = match fs::symlink_metadata(&path) {
+                Err(e) => e.to_string(),
+                Ok(m) if m.is_dir() => "is a directory".to_string(),
+                Ok(m) if !m.file_type().is_symlink() => "is not a symlink".to_string(),
+                Ok(_) => match fs::read_link(&path) {
+                    Ok(actual) if actual == *link => return None,
+                    Ok(actual) => format!("points to {}", actual.display()),
+                    Err(e) => e.to_string(),
+                },
+            };
+            Some(format!(
+                "/{} {} (expected symlink to {})",
+                name.display(),
+                problem,
+                link.display()
+            ))
+        })
+        .collect()
+}
+
+/// Everything wrong with `target`, one line per problem.
+fn sanity_problems(target: &Path) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let loader = LOADER_DIRS.iter().find_map(|dir| {
+        let dir = resolve_in_root(target, Path::new(dir)).ok()?;
+        fs::read_dir(dir)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .find(|e| e.file_name().to_string_lossy().starts_with("ld-linux"))
+            .map(|e| e.path())
+    });
+    match loader {
+        Some(path) => problems.extend(check_executable_elf(target, &path)),
+        None => problems.push("no dynamic loader (usr/lib*/ld-linux*.so*)".to_string()),
+    }
+
+    problems.extend(check_executable_elf(target, &target.join("usr/bin/sh")));
+
+    let init = INIT_PATHS
+        .iter()
+        .map(|p| target.join(p))
+        .find(|p| p.symlink_metadata().is_ok());
+    match init {
+        Some(path) => problems.extend(check_executable_elf(target, &path)),
+        None => problems.push(format!("no init ({})", INIT_PATHS.join(" or "))),
+    }
+
+    for (file, check) in [
+        (
+            "etc/passwd",
+            check_passwd as fn(&str) -> std::result::Result<(), String>,
+        ),
+        ("etc/shadow", check_shadow),
+    ] {
+        match fs::read_to_string(target.join(file)) {
+            Ok(content) => {
+                if let Err(e) = check(&content) {
+                    problems.push(format!("{}: {}", file, e));
+                }
+            }
+            Err(e) => problems.push(format!("{}: {}", file, e)),
+        }
+    }
+
+    problems
+}
+
+/// Follow `path` (inside `root`) through symlinks, like the chrooted system
+/// would, and check that it ends at an executable ELF file.
+fn check_executable_elf(root: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    let display = rel.display();
+    let resolved = match follow_in_root(root, rel) {
+        Ok(p) => p,
+        Err(e) => return Some(format!("{}: {}", display, e)),
+    };
+    let meta = match fs::metadata(&resolved) {
+        Ok(m) => m,
+        Err(e) => return Some(format!("{}: {}", display, e)),
+    };
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Some(format!("{}: not an executable file", display));
+    }
+    let mut magic = [0u8; 4];
+    let is_elf = File::open(&resolved)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && &magic == b"\x7fELF";
+    (!is_elf).then(|| format!("{}: not an ELF binary", display))
+}
+
+/// `/etc/passwd`: seven fields per entry, numeric IDs, and root as UID 0.
+fn check_passwd(content: &str) -> std::result::Result<(), String> {
+    let mut has_root = false;
+    for (n, line) in entries(content) {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() != 7 {
+            return Err(format!(
+                "line {}: expected 7 fields, got {}",
+                n,
+                fields.len()
+            ));
+        }
+        if fields[2].parse::<u32>().is_err() || fields[3].parse::<u32>().is_err() {
+            return Err(format!("line {}: non-numeric UID or GID", n));
+        }
+        has_root |= fields[0] == "root" && fields[2] == "0";
+    }
+    if has_root {
+        Ok(())
+    } else {
+        Err("no root entry with UID 0".to_string())
+    }
+}
+
+/// `/etc/shadow`: nine fields per entry, and an entry for root.
+fn check_shadow(content: &str) -> std::result::Result<(), String> {
+    let mut has_root = false;
+    for (n, line) in entries(content) {
+        let fields = line.split(':').count();
+        if fields != 9 {
+            return Err(format!("line {}: expected 9 fields, got {}", n, fields));
+        }
+        has_root |= line.starts_with("root:");
+    }
+    if has_root {
+        Ok(())
+    } else {
+        Err("no root entry".to_string())
+    }
+}
+
+/// Non-empty, non-comment lines with 1-based line numbers.
+fn entries(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l))
+        .filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/bash\nbin:x:1:1:bin:/bin:/sbin/nologin\n";
+    const SHADOW: &str = "root:!:19000:0:99999:7:::\nbin:*:19000:0:99999:7:::\n";
+
+    fn write_elf(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"\x7fELF\x02\x01\x01").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// A minimal usrmerged system that passes every check.
+    fn make_system(name: &str) -> PathBuf {
+        let temp = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&temp);
+        write_elf(&temp.join("usr/lib64/ld-linux-x86-64.so.2"));
+        write_elf(&temp.join("usr/bin/bash"));
+        symlink("bash", temp.join("usr/bin/sh")).unwrap();
+        write_elf(&temp.join("usr/lib/systemd/systemd"));
+        fs::create_dir_all(temp.join("etc")).unwrap();
+        fs::write(temp.join("etc/passwd"), PASSWD).unwrap();
+        fs::write(temp.join("etc/shadow"), SHADOW).unwrap();
+        temp
+    }
+
+    #[test]
+    fn test_sanity_passes_on_complete_system() {
+        let temp = make_system("recstrap_test_sanity_ok");
+        assert_eq!(sanity_problems(&temp), Vec::<String>::new());
+        assert!(verify_system_sanity(&temp).is_ok());
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_sanity_reports_broken_files() {
+        let temp = make_system("recstrap_test_sanity_broken");
+        // A shell script where init should be, and a dangling sh
+        fs::write(temp.join("usr/lib/systemd/systemd"), "#!/bin/sh\n").unwrap();
+        fs::remove_file(temp.join("usr/bin/bash")).unwrap();
+        fs::remove_file(temp.join("usr/lib64/ld-linux-x86-64.so.2")).unwrap();
+
+        let problems = sanity_problems(&temp);
+        assert_eq!(problems.len(), 3, "problems: {:?}", problems);
+        assert!(problems[0].contains("dynamic loader"));
+        assert!(problems[1].starts_with("usr/bin/sh:"));
+        assert!(problems[2].contains("not an ELF binary"));
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_package_db_problems() {
+        let temp = make_system("recstrap_test_package_db");
+        // No package manager, nothing to check
+        assert!(verify_package_database(&temp).is_ok());
+
+        write_elf(&temp.join("usr/bin/rpm"));
+        fs::create_dir_all(temp.join("usr/lib/sysimage/rpm")).unwrap();
+        let problems = package_db_problems(&temp);
+        assert_eq!(
+            problems,
+            [
+                "rpm is installed but its database (/usr/lib/sysimage/rpm or /var/lib/rpm) \
+              is missing or empty"
+            ]
+        );
+        fs::write(temp.join("usr/lib/sysimage/rpm/rpmdb.sqlite"), "SQLite").unwrap();
+        assert!(package_db_problems(&temp).is_empty());
+
+        // Found through the usrmerge symlink, populated or not
+        fs::create_dir_all(temp.join("usr/sbin")).unwrap();
+        symlink("usr/sbin", temp.join("sbin")).unwrap();
+        write_elf(&temp.join("usr/sbin/apk"));
+        fs::create_dir_all(temp.join("usr/lib/apk/db")).unwrap();
+        fs::write(temp.join("usr/lib/apk/db/installed"), "").unwrap();
+        let problems = package_db_problems(&temp);
+        assert_eq!(problems.len(), 1, "problems: {:?}", problems);
+        assert!(problems[0].starts_with("apk is installed"));
+        fs::write(temp.join("usr/lib/apk/db/installed"), "P:musl\n").unwrap();
+        assert!(verify_package_database(&temp).is_ok());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_usrmerge_problems() {
+        let temp = std::env::temp_dir().join("recstrap_test_usrmerge");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("usr/bin")).unwrap();
+        symlink("usr/bin", temp.join("bin")).unwrap();
+        symlink("usr/lib", temp.join("lib64")).unwrap();
+        fs::create_dir_all(temp.join("sbin")).unwrap();
+
+        let links: Vec<(PathBuf, PathBuf)> = [
+            ("bin", "usr/bin"),
+            ("sbin", "usr/bin"),
+            ("lib64", "usr/lib64"),
+            ("lib", "usr/lib"),
+            // Not a usrmerge link
+            ("media", "run/media"),
+        ]
+        .iter()
+        .map(|(n, l)| (PathBuf::from(n), PathBuf::from(l)))
+        .collect();
+
+        let problems = usrmerge_problems(&temp, &links);
+        assert_eq!(problems.len(), 3, "problems: {:?}", problems);
+        assert!(problems[0].starts_with("/sbin is a directory"));
+        assert!(problems[1].starts_with("/lib64 points to usr/lib"));
+        assert!(problems[2].starts_with("/lib "));
+        assert!(verify_usrmerge(&temp, &links[..1]).is_ok());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_check_passwd() {
+        assert!(check_passwd(PASSWD).is_ok());
+        assert!(check_passwd("bin:x:1:1:bin:/bin:/sbin/nologin\n")
+            .unwrap_err()
+            .contains("root"));
+        assert!(check_passwd("root:x:0:0:root:/root\n")
+            .unwrap_err()
+            .contains("7 fields"));
+        assert!(check_passwd("root:x:zero:0:root:/root:/bin/sh\n").is_err());
+    }
+
+    #[test]
+    fn test_check_shadow() {
+        assert!(check_shadow(SHADOW).is_ok());
+        assert!(check_shadow("root:!:19000\n").is_err());
+        assert!(check_shadow("# comment\n\n").is_err());
+    }
+}
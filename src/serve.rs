@@ -0,0 +1,578 @@
+//! `recstrap --serve SOCKET` - installs on behalf of an unprivileged
+//! frontend.
+//!
+//! The graphical installer runs as a normal user and asks a supervised,
+//! root recstrap daemon to do the privileged work over a Unix socket.
+//! The protocol is JSON-RPC 2.0, one request or response per line:
+//!
+//! - `preflight` (params: install options) - the `--check` checks; result
+//!   `{passed, checks, warnings, diagnostics, error}`
+//! - `start` (params: install options) - start an install in the
+//!   background; result `{started: true}`
+//! - `progress` - `{state, target, phase, entries, bytes, statistics,
+//!   warnings, diagnostics, error}`, `state` being `idle`, `running`,
+//!   `succeeded`, `failed` or `cancelled`; `diagnostics` documents a
+//!   failed check (what it protects, its severity, how it could be
+//!   weakened, the consequence)
+//! - `cancel` - stop the running install (or pre-flight checks) at the
+//!   next file; result `{cancelled}`, false when nothing was running
+//!
+//! Install options are `target` (required), `rootfs`, `flavor`, `force`,
+//! `reinstall`, `verify`, `workdir` and `strict`, as on the command line;
+//! unknown ones are rejected. One install or pre-flight run at a time;
+//! `start` or `preflight` during one is error -32001. A failed check or install is
+//! not a JSON-RPC error: its `error` member has the recstrap code
+//! (`E012`, ...), exit code and message.
+//!
+//! The socket is created with mode 0660; the supervisor decides who may
+//! connect by its directory or group.
+//!
+//! `--serve -` speaks the same protocol on stdin/stdout to a single
+//! client, for frontends that start recstrap through polkit's `pkexec`
+//! rather than relying on a daemon: the policy in
+//! `data/org.levitateos.recstrap.policy` has polkit ask for an
+//! administrator's password (every time) before the helper runs as
+//! root. If the frontend goes away, a running install is cancelled.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use clap::ValueEnum;
+use distro_spec::shared::error::ToolErrorCode;
+
+use crate::cli::VerifyLevel;
+use crate::context::CancellationToken;
+use crate::error::{RecError, Result};
+use crate::helpers::is_root;
+use crate::installer::{ExtractOptions, Installer};
+use crate::json::{self, Value};
+use crate::validation::{diagnostics_json, take_diagnostics};
+use crate::warnings::take_warnings;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The daemon itself is broken
+const INTERNAL_ERROR: i64 = -32000;
+/// `start` or `preflight` while an install or pre-flight run is going on
+const BUSY: i64 = -32001;
+
+const OPTION_KEYS: &[&str] = &[
+    "target",
+    "rootfs",
+    "flavor",
+    "force",
+    "reinstall",
+    "verify",
+    "workdir",
+    "strict",
+];
+
+/// What the daemon is doing, shared by all connections.
+#[derive(Default)]
+struct Job {
+    target: Option<String>,
+    cancel: Option<CancellationToken>,
+    running: bool,
+    phase: String,
+    entries: u64,
+    bytes: u64,
+    /// Result of the last install, once it finished
+    outcome: Option<Value>,
+    state: &'static str,
+    /// The installing thread, for `--serve -` to wait for
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+type Shared = Arc<Mutex<Job>>;
+
+/// Serve requests on `socket` until killed, or on stdin/stdout for `-`
+/// until stdin closes.
+pub fn run(socket: &str, quiet: bool) -> Result<()> {
+    if !is_root() {
+        return Err(RecError::not_root());
+    }
+    let job: Shared = Arc::new(Mutex::new(Job {
+        state: "idle",
+        ..Default::default()
+    }));
+    if socket == "-" {
+        return serve_stdio(&job, quiet);
+    }
+    let listener = bind(Path::new(socket)).map_err(|e| {
+        RecError::configuration_failed("control socket", &format!("{}: {}", socket, e))
+    })?;
+    if !quiet {
+        eprintln!("recstrap: serving on {}", socket);
+    }
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        let job = Arc::clone(&job);
+        thread::spawn(move || {
+            let _ = stream
+                .try_clone()
+                .and_then(|writer| serve_lines(BufReader::new(stream), writer, &job, quiet));
+        });
+    }
+    Ok(())
+}
+
+/// One client on stdin/stdout. Whatever else would go to stdout (tools the
+/// install runs) goes to stderr, so the responses stay parseable.
+fn serve_stdio(job: &Shared, quiet: bool) -> Result<()> {
+    let responses = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if responses < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(RecError::configuration_failed(
+            "control channel",
+            &io::Error::last_os_error().to_string(),
+        ));
+    }
+    let responses = unsafe { File::from_raw_fd(responses) };
+    let _ = serve_lines(io::stdin().lock(), responses, job, quiet);
+
+    // The frontend is gone: nobody is left to report to
+    cancel(job);
+    let worker = job.lock().ok().and_then(|mut job| job.worker.take());
+    if let Some(worker) = worker {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+/// Bind `path`, replacing a stale socket left by an earlier daemon (but no
+/// other kind of file).
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "exists and is not a socket",
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another daemon is serving on it",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+fn serve_lines(
+    reader: impl BufRead,
+    mut writer: impl Write,
+    job: &Shared,
+    quiet: bool,
+) -> io::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = respond(&line, job, quiet) {
+            writeln!(writer, "{}", response)?;
+        }
+    }
+    Ok(())
+}
+
+/// The response line for one request line; `None` for notifications.
+fn respond(line: &str, job: &Shared, quiet: bool) -> Option<Value> {
+    let request = match json::parse(line) {
+        Ok(request) => request,
+        Err(e) => return Some(error_response(Value::Null, PARSE_ERROR, &e)),
+    };
+    let id = request.get("id").cloned();
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return Some(error_response(
+            id.unwrap_or(Value::Null),
+            INVALID_REQUEST,
+            "not a JSON-RPC request",
+        ));
+    };
+    let params = request
+        .get("params")
+        .cloned()
+        .unwrap_or(Value::Object(Vec::new()));
+    let result = match method {
+        "preflight" => preflight(&params, job),
+        "start" => start(&params, job, quiet),
+        "progress" => Ok(progress(job)),
+        "cancel" => Ok(cancel(job)),
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method '{}'", method))),
+    };
+    let id = id?;
+    Some(match result {
+        Ok(result) => Value::object([
+            ("jsonrpc", Value::from("2.0")),
+            ("id", id),
+            ("result", result),
+        ]),
+        Err((code, message)) => error_response(id, code, &message),
+    })
+}
+
+type MethodResult = std::result::Result<Value, (i64, String)>;
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    let error = Value::object([
+        ("code", Value::from(code)),
+        ("message", Value::from(message)),
+    ]);
+    Value::object([
+        ("jsonrpc", Value::from("2.0")),
+        ("id", id),
+        ("error", error),
+    ])
+}
+
+fn error_json(e: &RecError) -> Value {
+    Value::object([
+        ("code", Value::from(e.code.code())),
+        ("exit_code", Value::from(u32::from(e.code.exit_code()))),
+        ("message", Value::from(e.message.as_str())),
+    ])
+}
+
+/// The target and options named in `params`.
+fn options(params: &Value) -> std::result::Result<(String, ExtractOptions), String> {
+    let Value::Object(pairs) = params else {
+        return Err("params must be an object".to_string());
+    };
+    if let Some((key, _)) = pairs
+        .iter()
+        .find(|(k, _)| !OPTION_KEYS.contains(&k.as_str()))
+    {
+        return Err(format!("unknown option '{}'", key));
+    }
+    let string = |key: &str| -> std::result::Result<Option<String>, String> {
+        match params.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(format!("'{}' must be a string", key)),
+        }
+    };
+    let flag = |key: &str| -> std::result::Result<bool, String> {
+        match params.get(key) {
+            None | Some(Value::Null) => Ok(false),
+            Some(Value::Bool(b)) => Ok(*b),
+            Some(_) => Err(format!("'{}' must be true or false", key)),
+        }
+    };
+    let target = string("target")?.ok_or("'target' is required")?;
+    let verify = match string("verify")? {
+        Some(level) => VerifyLevel::from_str(&level, false).map_err(|_| {
+            let levels = VerifyLevel::value_variants()
+                .iter()
+                .filter_map(|v| v.to_possible_value())
+                .map(|v| v.get_name().to_string())
+                .collect::<Vec<_>>();
+            format!(
+                "'verify' must be one of {}, not '{}'",
+                levels.join(", "),
+                level
+            )
+        })?,
+        None => VerifyLevel::Basic,
+    };
+    let rootfs = string("rootfs")?;
+    if rootfs.as_deref() == Some("-") {
+        return Err(
+            "'rootfs' can't be '-': stdin isn't available to daemon clients; \
+             save the image to a file"
+                .to_string(),
+        );
+    }
+    Ok((
+        target,
+        ExtractOptions {
+            rootfs: rootfs.map(PathBuf::from),
+            flavor: string("flavor")?,
+            force: flag("force")?,
+            reinstall: flag("reinstall")?,
+            verify,
+            workdir: string("workdir")?.map(PathBuf::from),
+            strict: flag("strict")?,
+            ..Default::default()
+        },
+    ))
+}
+
+/// The daemon held busy by a pre-flight run, until dropped. `progress`
+/// then reports the state from before it again.
+struct PreflightClaim<'a> {
+    job: &'a Shared,
+    previous: &'static str,
+}
+
+impl<'a> PreflightClaim<'a> {
+    fn take(
+        job: &'a Shared,
+        cancel: &CancellationToken,
+    ) -> std::result::Result<Self, (i64, String)> {
+        let Ok(mut current) = job.lock() else {
+            return Err((INTERNAL_ERROR, "daemon state is poisoned".to_string()));
+        };
+        if current.running {
+            return Err((BUSY, "an install is running".to_string()));
+        }
+        current.running = true;
+        current.cancel = Some(cancel.clone());
+        let previous = std::mem::replace(&mut current.state, "preflight");
+        Ok(Self { job, previous })
+    }
+}
+
+impl Drop for PreflightClaim<'_> {
+    fn drop(&mut self) {
+        let mut job = self.job.lock().unwrap_or_else(|e| e.into_inner());
+        job.running = false;
+        job.cancel = None;
+        job.state = self.previous;
+    }
+}
+
+fn preflight(params: &Value, job: &Shared) -> MethodResult {
+    let (target, options) = options(params).map_err(|e| (INVALID_PARAMS, e))?;
+    // The checks mount the image like an install does; held until the
+    // report is built
+    let cancel = CancellationToken::new();
+    let _claim = PreflightClaim::take(job, &cancel)?;
+    let options = ExtractOptions {
+        cancel: Some(cancel),
+        ..options
+    };
+    let report = Installer::new(target).with_options(options).preflight();
+    let checks = report
+        .checks
+        .iter()
+        .map(|c| {
+            Value::object([
+                ("id", Value::from(c.id.as_str())),
+                ("protects", Value::from(c.protects.as_str())),
+                ("severity", Value::from(c.severity.as_str())),
+                ("passed", Value::from(c.passed)),
+            ])
+        })
+        .collect::<Vec<_>>();
+    Ok(Value::object([
+        ("passed", Value::from(report.passed())),
+        ("checks", Value::Array(checks)),
+        ("warnings", Value::from(report.warnings)),
+        ("diagnostics", diagnostics_json(&report.diagnostics)),
+        (
+            "error",
+            report.error.as_ref().map_or(Value::Null, error_json),
+        ),
+    ]))
+}
+
+fn start(params: &Value, job: &Shared, quiet: bool) -> MethodResult {
+    let (target, options) = options(params).map_err(|e| (INVALID_PARAMS, e))?;
+    let cancel = CancellationToken::new();
+    {
+        let Ok(mut current) = job.lock() else {
+            return Err((INTERNAL_ERROR, "daemon state is poisoned".to_string()));
+        };
+        if current.running {
+            return Err((BUSY, "an install is running".to_string()));
+        }
+        *current = Job {
+            target: Some(target.clone()),
+            cancel: Some(cancel.clone()),
+            running: true,
+            phase: "starting".to_string(),
+            state: "running",
+            ..Default::default()
+        };
+    }
+
+    let updates = Arc::clone(job);
+    let finished = Arc::clone(job);
+    let options = ExtractOptions {
+        cancel: Some(cancel.clone()),
+        ..options
+    }
+    .on_progress(move |p| {
+        if let Ok(mut job) = updates.lock() {
+            job.phase = p.phase.to_string();
+            job.entries = p.entries;
+            job.bytes = p.bytes;
+        }
+    });
+    if !quiet {
+        eprintln!("recstrap: installing into {}", target);
+    }
+    let worker = thread::spawn(move || {
+        let result = Installer::new(&target).with_options(options).install();
+        let warnings = Value::from(take_warnings());
+        let diagnostics = diagnostics_json(&take_diagnostics());
+        if !quiet {
+            match &result {
+                Ok(_) => eprintln!("recstrap: installed into {}", target),
+                Err(e) => eprintln!("recstrap: install into {} failed: {}", target, e),
+            }
+        }
+        if let Ok(mut job) = finished.lock() {
+            job.running = false;
+            job.state = match &result {
+                Ok(_) => "succeeded",
+                Err(_) if cancel.is_cancelled() => "cancelled",
+                Err(_) => "failed",
+            };
+            job.outcome = Some(Value::object([
+                (
+                    "statistics",
+                    result.as_ref().map_or(Value::Null, |s| s.to_json()),
+                ),
+                ("warnings", warnings),
+                ("diagnostics", diagnostics),
+                (
+                    "error",
+                    result.as_ref().err().map_or(Value::Null, error_json),
+                ),
+            ]));
+        }
+    });
+    if let Ok(mut job) = job.lock() {
+        job.worker = Some(worker);
+    }
+    Ok(Value::object([("started", Value::from(true))]))
+}
+
+fn progress(job: &Shared) -> Value {
+    let Ok(job) = job.lock() else {
+        return Value::object([("state", Value::from("failed"))]);
+    };
+    let mut value = Value::object([
+        ("state", Value::from(job.state)),
+        ("target", Value::from(job.target.clone())),
+        ("phase", Value::from(job.phase.as_str())),
+        ("entries", Value::from(job.entries)),
+        ("bytes", Value::from(job.bytes)),
+    ]);
+    for key in ["statistics", "warnings", "diagnostics", "error"] {
+        let member = job
+            .outcome
+            .as_ref()
+            .and_then(|o| o.get(key))
+            .cloned()
+            .unwrap_or(Value::Null);
+        value.push(key, member);
+    }
+    value
+}
+
+fn cancel(job: &Shared) -> Value {
+    let cancelled = match job.lock() {
+        Ok(job) if job.running => {
+            if let Some(cancel) = &job.cancel {
+                cancel.cancel();
+            }
+            true
+        }
+        _ => false,
+    };
+    Value::object([("cancelled", Value::from(cancelled))])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idle() -> Shared {
+        Arc::new(Mutex::new(Job {
+            state: "idle",
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn test_respond() {
+        let job = idle();
+        let reply = |line: &str| respond(line, &job, true).map(|v| v.to_string());
+
+        assert_eq!(
+            reply(r#"{"jsonrpc":"2.0","id":1,"method":"progress"}"#).unwrap(),
+            r#"{"jsonrpc":"2.0","id":1,"result":{"state":"idle","target":null,"phase":"","entries":0,"bytes":0,"statistics":null,"warnings":null,"diagnostics":null,"error":null}}"#
+        );
+        assert_eq!(
+            reply(r#"{"jsonrpc":"2.0","id":2,"method":"cancel"}"#).unwrap(),
+            r#"{"jsonrpc":"2.0","id":2,"result":{"cancelled":false}}"#
+        );
+        assert!(reply(r#"{"jsonrpc":"2.0","id":3,"method":"format"}"#)
+            .unwrap()
+            .contains(r#""code":-32601"#));
+        assert!(reply("{").unwrap().contains(r#""code":-32700"#));
+        assert!(reply(
+            r#"{"jsonrpc":"2.0","id":4,"method":"start","params":{"target":"/mnt","force":"yes"}}"#
+        )
+        .unwrap()
+        .contains("'force' must be true or false"));
+        // The daemon's stdin may be this very protocol stream
+        for method in ["preflight", "start"] {
+            let line = format!(
+                r#"{{"jsonrpc":"2.0","id":5,"method":"{}","params":{{"target":"/mnt","rootfs":"-"}}}}"#,
+                method
+            );
+            let reply = reply(&line).unwrap();
+            assert!(reply.contains(r#""code":-32602"#), "{}", reply);
+            assert!(reply.contains("stdin isn't available"), "{}", reply);
+        }
+        assert_eq!(job.lock().unwrap().state, "idle");
+        // Notifications get no response
+        assert_eq!(reply(r#"{"jsonrpc":"2.0","method":"progress"}"#), None);
+    }
+
+    #[test]
+    fn test_options() {
+        let params = json::parse(r#"{"target":"/mnt","verify":"full","force":true}"#).unwrap();
+        let (target, parsed) = options(&params).unwrap();
+        assert_eq!(target, "/mnt");
+        assert_eq!(parsed.verify, VerifyLevel::Full);
+        assert!(parsed.force && !parsed.reinstall);
+
+        let unknown = json::parse(r#"{"target":"/mnt","umount_after":true}"#).unwrap();
+        assert_eq!(
+            options(&unknown).unwrap_err(),
+            "unknown option 'umount_after'"
+        );
+        assert!(options(&Value::Object(Vec::new())).is_err());
+
+        let level = json::parse(r#"{"target":"/mnt","verify":"paranoid"}"#).unwrap();
+        assert_eq!(
+            options(&level).unwrap_err(),
+            "'verify' must be one of basic, sample, inline, full, not 'paranoid'"
+        );
+    }
+
+    #[test]
+    fn test_preflight_holds_the_daemon() {
+        let job = idle();
+        let token = CancellationToken::new();
+        {
+            let _claim = PreflightClaim::take(&job, &token).unwrap();
+            assert_eq!(progress(&job).get("state"), Some(&Value::from("preflight")));
+            assert_eq!(
+                PreflightClaim::take(&job, &token).err().map(|e| e.0),
+                Some(BUSY)
+            );
+            assert!(start(&json::parse(r#"{"target":"/mnt"}"#).unwrap(), &job, true).is_err());
+            assert_eq!(cancel(&job).get("cancelled"), Some(&Value::from(true)));
+        }
+        assert!(token.is_cancelled());
+        assert_eq!(progress(&job).get("state"), Some(&Value::from("idle")));
+    }
+}
@@ -0,0 +1,97 @@
+//! Read-only btrfs snapshot of a target before `--force` overwrites it.
+//!
+//! Extracting over existing content is the most dangerous thing recstrap
+//! does. On btrfs a snapshot is nearly free, so we take one first and tell
+//! the user how to get their data back.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::error::{ErrorCode, RecError, Result};
+use crate::helpers::{format_utc, path_to_cstring};
+use crate::runner;
+
+/// `BTRFS_SUPER_MAGIC` from linux/magic.h
+const BTRFS_SUPER_MAGIC: i64 = 0x9123_683e;
+
+/// Inode number of every btrfs subvolume root
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+
+/// Snapshot names start with this; deliberately not `.recstrap_` so
+/// `recstrap clean` never removes them.
+pub const SNAPSHOT_PREFIX: &str = ".recstrap-pre-force-";
+
+/// Whether `path` lives on btrfs.
+#[allow(clippy::unnecessary_cast)] // f_type width varies by platform
+pub fn is_btrfs(path: &Path) -> bool {
+    let Ok(c_path) = path_to_cstring(path) else {
+        return false;
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::statfs(c_path.as_ptr(), &mut stat) == 0 && stat.f_type as i64 == BTRFS_SUPER_MAGIC
+    }
+}
+
+/// Whether `path` is the root of a btrfs subvolume (only those can be
+/// snapshotted).
+pub fn is_subvolume(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    is_btrfs(path) && std::fs::metadata(path).is_ok_and(|m| m.ino() == BTRFS_FIRST_FREE_OBJECTID)
+}
+
+/// Snapshot name for a given Unix time: `.recstrap-pre-force-20260131T120000Z`.
+pub fn snapshot_name(secs: u64) -> String {
+    let stamp: String = format_utc(secs)
+        .chars()
+        .filter(|c| *c != '-' && *c != ':')
+        .collect();
+    format!("{}{}", SNAPSHOT_PREFIX, stamp)
+}
+
+/// Create a read-only snapshot of subvolume `target` inside itself.
+pub fn snapshot_target(target: &Path) -> Result<PathBuf> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let snapshot = target.join(snapshot_name(now));
+
+    let output = runner::output(
+        Command::new("btrfs")
+            .args(["subvolume", "snapshot", "-r"])
+            .arg(target)
+            .arg(&snapshot),
+    )
+    .map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => RecError::tool_not_installed("btrfs", "btrfs-progs"),
+        _ => RecError::new(
+            ErrorCode::ExtractionFailed,
+            format!("failed to run btrfs: {}", e),
+        ),
+    })?;
+    if !output.status.success() {
+        return Err(RecError::new(
+            ErrorCode::ExtractionFailed,
+            format!(
+                "btrfs snapshot of {} failed: {} (use --no-snapshot to proceed without one)",
+                target.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_name() {
+        assert_eq!(
+            snapshot_name(1_767_225_599),
+            ".recstrap-pre-force-20251231T235959Z"
+        );
+        assert!(!snapshot_name(0).starts_with(".recstrap_"));
+    }
+}
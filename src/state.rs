@@ -0,0 +1,132 @@
+//! Extraction state file kept in the target while an extraction runs.
+//!
+//! Written before copying starts and removed once verification passes, so
+//! its presence means an extraction was interrupted or failed. It records
+//! which top-level entries already existed, which lets `recstrap clean`
+//! remove exactly what the failed run added.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::helpers::format_utc;
+
+/// File name of the state file, directly inside the target.
+pub const STATE_FILE: &str = ".recstrap_state";
+
+/// Contents of the state file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractionState {
+    /// PID of the recstrap process doing the extraction
+    pub pid: u32,
+    /// Image being extracted
+    pub rootfs: PathBuf,
+    /// When the extraction started (RFC 3339 UTC)
+    pub started: String,
+    /// Top-level entries that existed in the target before extraction
+    pub preexisting: Vec<String>,
+}
+
+impl ExtractionState {
+    /// Describe an extraction of `rootfs` into `target` starting now.
+    pub fn begin(target: &Path, rootfs: &Path) -> io::Result<Self> {
+        let mut preexisting: Vec<String> = fs::read_dir(target)?
+            .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<io::Result<_>>()?;
+        preexisting.sort();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Ok(Self {
+            pid: std::process::id(),
+            rootfs: rootfs.to_path_buf(),
+            started: format_utc(now),
+            preexisting,
+        })
+    }
+
+    pub fn write(&self, target: &Path) -> io::Result<()> {
+        let mut out = String::from("# recstrap extraction in progress - see `recstrap clean`\n");
+        out.push_str(&format!("pid={}\n", self.pid));
+        out.push_str(&format!("rootfs={}\n", self.rootfs.display()));
+        out.push_str(&format!("started={}\n", self.started));
+        for name in &self.preexisting {
+            out.push_str(&format!("preexisting={}\n", name));
+        }
+        fs::write(target.join(STATE_FILE), out)
+    }
+
+    /// Read the state file from `target`; `Ok(None)` if there is none.
+    pub fn read(target: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(target.join(STATE_FILE)) {
+            Ok(content) => Self::parse(&content)
+                .map(Some)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed state file")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parse(content: &str) -> Option<Self> {
+        let mut pid = None;
+        let mut rootfs = None;
+        let mut started = String::new();
+        let mut preexisting = Vec::new();
+        for line in content.lines().filter(|l| !l.starts_with('#')) {
+            match line.split_once('=') {
+                Some(("pid", v)) => pid = v.parse().ok(),
+                Some(("rootfs", v)) => rootfs = Some(PathBuf::from(v)),
+                Some(("started", v)) => started = v.to_string(),
+                Some(("preexisting", v)) => preexisting.push(v.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            pid: pid?,
+            rootfs: rootfs?,
+            started,
+            preexisting,
+        })
+    }
+
+    /// Whether the process that wrote this state is still running.
+    pub fn owner_alive(&self) -> bool {
+        self.pid == std::process::id() || Path::new(&format!("/proc/{}", self.pid)).exists()
+    }
+
+    /// Remove the state file: the extraction finished and was verified.
+    pub fn finish(target: &Path) -> io::Result<()> {
+        fs::remove_file(target.join(STATE_FILE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let temp = std::env::temp_dir().join("recstrap_test_state");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("lost+found")).unwrap();
+
+        let state = ExtractionState::begin(&temp, Path::new("/run/live/filesystem.erofs")).unwrap();
+        assert_eq!(state.preexisting, vec!["lost+found"]);
+        state.write(&temp).unwrap();
+
+        let read = ExtractionState::read(&temp).unwrap().unwrap();
+        assert_eq!(read, state);
+        assert!(read.owner_alive());
+
+        ExtractionState::finish(&temp).unwrap();
+        assert_eq!(ExtractionState::read(&temp).unwrap(), None);
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_state_parse_rejects_missing_fields() {
+        assert_eq!(ExtractionState::parse("rootfs=/x\n"), None);
+        assert!(ExtractionState::parse("pid=1\nrootfs=/x\n").is_some());
+    }
+}
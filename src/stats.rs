@@ -0,0 +1,290 @@
+//! Install statistics: image size against extracted size, counts, timing.
+//!
+//! Printed at the end of every install and available as JSON (`--json`),
+//! so image builders can track how releases grow. `--stats-file` writes
+//! them, with the outcome and warnings, for provisioning pipelines.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::checks::Check;
+use crate::json::Value;
+use crate::probe::format_duration;
+use crate::rootfs::RootfsInfo;
+use crate::validation::diagnostics_json;
+
+const MB: u64 = 1024 * 1024;
+
+/// What one install moved and how long it took.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstallStats {
+    /// Size of the compressed image file
+    pub image_bytes: u64,
+    /// Regular file data written to the target
+    pub extracted_bytes: u64,
+    /// Space the extraction took on the target filesystem; `None` when
+    /// statvfs failed
+    pub disk_bytes: Option<u64>,
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub extraction_secs: f64,
+    pub total_secs: f64,
+    /// The image that was installed
+    pub image: Option<RootfsInfo>,
+    /// Everything warned about up to the end of the install
+    pub warnings: Vec<String>,
+}
+
+impl InstallStats {
+    /// Extracted data per image byte; `None` for an empty image.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.image_bytes > 0).then(|| self.extracted_bytes as f64 / self.image_bytes as f64)
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("image_bytes", Value::from(self.image_bytes)),
+            ("extracted_bytes", Value::from(self.extracted_bytes)),
+            (
+                "disk_bytes",
+                self.disk_bytes.map_or(Value::Null, Value::from),
+            ),
+            (
+                "compression_ratio",
+                self.ratio()
+                    .map_or(Value::Null, |r| Value::from((r * 100.0).round() / 100.0)),
+            ),
+            ("files", Value::from(self.files)),
+            ("dirs", Value::from(self.dirs)),
+            ("symlinks", Value::from(self.symlinks)),
+            (
+                "extraction_seconds",
+                Value::from(round_secs(self.extraction_secs)),
+            ),
+            ("total_seconds", Value::from(round_secs(self.total_secs))),
+            (
+                "image",
+                self.image.as_ref().map_or(Value::Null, RootfsInfo::to_json),
+            ),
+            ("warnings", warnings_json(&self.warnings)),
+        ])
+    }
+
+    /// Human-readable report, one line per item.
+    pub fn render(&self) -> String {
+        let mut out = String::from("Statistics:\n");
+        out.push_str(&format!("  Image:     {} MB\n", self.image_bytes / MB));
+        out.push_str(&format!(
+            "  Extracted: {} MB in {} files, {} dirs, {} symlinks",
+            self.extracted_bytes / MB,
+            self.files,
+            self.dirs,
+            self.symlinks
+        ));
+        if let Some(ratio) = self.ratio() {
+            out.push_str(&format!(" ({:.2}x the image)", ratio));
+        }
+        out.push('\n');
+        if let Some(disk) = self.disk_bytes {
+            out.push_str(&format!("  On disk:   {} MB\n", disk / MB));
+        }
+        out.push_str(&format!(
+            "  Time:      {} extracting, {} total\n",
+            format_duration(self.extraction_secs as u64),
+            format_duration(self.total_secs as u64)
+        ));
+        if !self.warnings.is_empty() {
+            out.push_str(&format!("  Warnings:  {}\n", self.warnings.len()));
+            for warning in &self.warnings {
+                out.push_str(&format!("    {}\n", warning));
+            }
+        }
+        out
+    }
+}
+
+thread_local! {
+    static RECORDED: RefCell<Option<InstallStats>> = const { RefCell::new(None) };
+}
+
+/// Keep the statistics of a finished extraction for [`take_recorded`].
+pub fn record(stats: &InstallStats) {
+    RECORDED.with(|r| *r.borrow_mut() = Some(stats.clone()));
+}
+
+/// The statistics recorded on this thread, if an extraction finished.
+pub fn take_recorded() -> Option<InstallStats> {
+    RECORDED.with(|r| r.borrow_mut().take())
+}
+
+/// The `--stats-file` document. `stats` is `None` when the run failed
+/// before extraction finished; `verified` is `None` when verification
+/// never ran to a verdict; `diagnostics` document the failed check.
+pub fn report(
+    stats: Option<&InstallStats>,
+    verify_level: &str,
+    verified: Option<bool>,
+    error: Option<&str>,
+    diagnostics: &[&Check],
+    warnings: &[String],
+) -> Value {
+    Value::object([
+        ("success", Value::from(error.is_none())),
+        ("error", error.map_or(Value::Null, Value::from)),
+        ("diagnostics", diagnostics_json(diagnostics)),
+        (
+            "statistics",
+            stats.map_or(Value::Null, InstallStats::to_json),
+        ),
+        (
+            "verification",
+            Value::object([
+                ("level", Value::from(verify_level)),
+                ("passed", verified.map_or(Value::Null, Value::from)),
+            ]),
+        ),
+        ("warnings", warnings_json(warnings)),
+    ])
+}
+
+fn warnings_json(warnings: &[String]) -> Value {
+    Value::from(
+        warnings
+            .iter()
+            .map(|w| Value::from(w.as_str()))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Write a report as pretty JSON.
+pub fn write_report(path: &Path, report: &Value) -> io::Result<()> {
+    fs::write(path, report.to_pretty_string())
+}
+
+fn round_secs(secs: f64) -> f64 {
+    (secs * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> InstallStats {
+        InstallStats {
+            image_bytes: 800 * MB,
+            extracted_bytes: 2000 * MB,
+            disk_bytes: Some(2100 * MB),
+            files: 45000,
+            dirs: 5000,
+            symlinks: 8000,
+            extraction_secs: 80.44,
+            total_secs: 105.0,
+            image: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let text = sample().render();
+        assert!(
+            text.contains(
+                "Extracted: 2000 MB in 45000 files, 5000 dirs, 8000 symlinks (2.50x the image)"
+            ),
+            "{}",
+            text
+        );
+        assert!(text.contains("On disk:   2100 MB"), "{}", text);
+        assert!(text.contains("1m 20s extracting, 1m 45s total"), "{}", text);
+        assert!(!text.contains("Warnings"), "{}", text);
+
+        let warned = InstallStats {
+            warnings: vec!["cannot check free inodes".to_string()],
+            ..sample()
+        };
+        let text = warned.render();
+        assert!(
+            text.ends_with("  Warnings:  1\n    cannot check free inodes\n"),
+            "{}",
+            text
+        );
+        let json = warned.to_json().to_string();
+        assert!(
+            json.ends_with("\"warnings\":[\"cannot check free inodes\"]}"),
+            "{}",
+            json
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json = sample().to_json().to_string();
+        assert!(json.contains("\"compression_ratio\":2.5"), "{}", json);
+        assert!(json.contains("\"extraction_seconds\":80.4"), "{}", json);
+
+        let empty = InstallStats {
+            image_bytes: 0,
+            disk_bytes: None,
+            ..sample()
+        };
+        let json = empty.to_json().to_string();
+        assert!(json.contains("\"disk_bytes\":null"), "{}", json);
+        assert!(json.contains("\"compression_ratio\":null"), "{}", json);
+    }
+
+    #[test]
+    fn test_report() {
+        let warnings = vec!["cannot check disk space".to_string()];
+        let json = report(Some(&sample()), "full", Some(true), None, &[], &warnings).to_string();
+        assert!(
+            json.starts_with("{\"success\":true,\"error\":null,"),
+            "{}",
+            json
+        );
+        assert!(json.contains("\"files\":45000"), "{}", json);
+        assert!(
+            json.contains("\"verification\":{\"level\":\"full\",\"passed\":true}"),
+            "{}",
+            json
+        );
+        assert!(
+            json.ends_with("\"warnings\":[\"cannot check disk space\"]}"),
+            "{}",
+            json
+        );
+
+        let json = report(
+            None,
+            "basic",
+            None,
+            Some("E009: not empty"),
+            &[&crate::checks::ROOTFS_IS_FILE],
+            &[],
+        )
+        .to_string();
+        assert!(json.contains("\"success\":false"), "{}", json);
+        assert!(
+            json.contains(
+                "\"diagnostics\":[{\"id\":\"rootfs-is-file\",\"stage\":\"preflight\",\"prerequisite\":true,\
+                 \"protects\":\"Rootfs path points to a file, not directory\",\"severity\":\"CRITICAL\",\
+                 \"cheats\":[\"Accept directories\",\"Skip type check\"],\
+                 \"consequence\":\"Extraction fails with confusing error about invalid format\"}]"
+            ),
+            "{}",
+            json
+        );
+        assert!(json.contains("\"statistics\":null"), "{}", json);
+        assert!(json.contains("\"passed\":null"), "{}", json);
+    }
+
+    #[test]
+    fn test_record() {
+        assert_eq!(take_recorded(), None);
+        record(&sample());
+        assert_eq!(take_recorded(), Some(sample()));
+        assert_eq!(take_recorded(), None);
+    }
+}
@@ -0,0 +1,310 @@
+//! Minimal TOML reader for answers files.
+//!
+//! Like [`crate::json`], hand-rolled to keep the live ISO's recstrap free of
+//! dependencies. It covers the subset answers files use: bare keys, basic
+//! and literal strings, integers, booleans, (multi-line) arrays, `[table]`
+//! and `[[array-of-tables]]` headers, and comments. Anything else is an
+//! error rather than a guess.
+
+/// A TOML value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/// Key/value pairs of one table, in file order.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Table {
+    pub entries: Vec<(String, Value)>,
+}
+
+impl Table {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+}
+
+/// A parsed document: the top-level table, then each `[name]` or
+/// `[[name]]` table in file order.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Document {
+    pub root: Table,
+    /// (name, is array-of-tables entry, table)
+    pub tables: Vec<(String, bool, Table)>,
+}
+
+impl Document {
+    /// The `[name]` table, if present.
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables
+            .iter()
+            .find(|(n, array, _)| n == name && !array)
+            .map(|(_, _, t)| t)
+    }
+
+    /// All `[[name]]` tables, in order.
+    pub fn array(&self, name: &str) -> Vec<&Table> {
+        self.tables
+            .iter()
+            .filter(|(n, array, _)| n == name && *array)
+            .map(|(_, _, t)| t)
+            .collect()
+    }
+}
+
+/// Parse a document. Errors name the offending line.
+pub fn parse(content: &str) -> Result<Document, String> {
+    let mut doc = Document::default();
+    let mut lines = content.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let err = |msg: String| format!("line {}: {}", i + 1, msg);
+        let trimmed = strip_comment(line).trim().to_string();
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = trimmed.strip_prefix('[') {
+            let (name, array) = match header.strip_prefix('[') {
+                Some(rest) => (rest.strip_suffix("]]"), true),
+                None => (header.strip_suffix(']'), false),
+            };
+            let name = name
+                .map(str::trim)
+                .filter(|n| is_bare_key(n))
+                .ok_or_else(|| err(format!("invalid table header '{}'", trimmed)))?;
+            if !array && doc.tables.iter().any(|(n, _, _)| n == name) {
+                return Err(err(format!("table [{}] defined twice", name)));
+            }
+            doc.tables.push((name.to_string(), array, Table::default()));
+            continue;
+        }
+
+        let (key, raw) = trimmed
+            .split_once('=')
+            .ok_or_else(|| err("expected 'key = value'".to_string()))?;
+        let key = key.trim();
+        if !is_bare_key(key) {
+            return Err(err(format!("invalid key '{}'", key)));
+        }
+        // Arrays may span lines: keep reading until the brackets balance
+        let mut raw = raw.trim().to_string();
+        while raw.starts_with('[') && !brackets_balanced(&raw) {
+            let (_, next) = lines
+                .next()
+                .ok_or_else(|| err("unterminated array".to_string()))?;
+            raw.push(' ');
+            raw.push_str(strip_comment(next).trim());
+        }
+
+        let mut chars = raw.chars().peekable();
+        let value = parse_value(&mut chars).map_err(err)?;
+        if chars.any(|c| !c.is_whitespace()) {
+            return Err(err(format!("unexpected text after value in '{}'", raw)));
+        }
+
+        let table = match doc.tables.last_mut() {
+            Some((_, _, table)) => table,
+            None => &mut doc.root,
+        };
+        if table.get(key).is_some() {
+            return Err(err(format!("key '{}' defined twice", key)));
+        }
+        table.entries.push((key.to_string(), value));
+    }
+    Ok(doc)
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Drop a `#` comment, unless the `#` is inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn brackets_balanced(raw: &str) -> bool {
+    let mut depth = 0i32;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in raw.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+        escaped = false;
+    }
+    depth <= 0
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn parse_value(chars: &mut Chars) -> Result<Value, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('"') => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => return Ok(Value::String(s)),
+                    Some('\\') => s.push(match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        other => {
+                            return Err(format!("unsupported escape '\\{}'", other.unwrap_or(' ')))
+                        }
+                    }),
+                    Some(c) => s.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+        }
+        Some('\'') => {
+            chars.next();
+            let s: String = chars.by_ref().take_while(|&c| c != '\'').collect();
+            Ok(Value::String(s))
+        }
+        Some('[') => {
+            chars.next();
+            let mut items = Vec::new();
+            loop {
+                skip_whitespace(chars);
+                if chars.next_if_eq(&']').is_some() {
+                    return Ok(Value::Array(items));
+                }
+                items.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Value::Array(items)),
+                    _ => return Err("expected ',' or ']' in array".to_string()),
+                }
+            }
+        }
+        Some(_) => {
+            let word: String = std::iter::from_fn(|| {
+                chars.next_if(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '_'))
+            })
+            .collect();
+            match word.as_str() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                _ => word
+                    .replace('_', "")
+                    .parse()
+                    .map(Value::Int)
+                    .map_err(|_| format!("invalid value '{}'", word)),
+            }
+        }
+        None => Err("missing value".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_document() {
+        let doc = parse(
+            r#"
+# Install answers
+target = "/mnt"   # trailing comment
+quiet = false
+retries = 5
+path = 'C:\raw # not a comment'
+
+[system]
+enable = [
+    "sshd",   # ssh
+    "chronyd",
+]
+
+[[users]]
+name = "alice"
+groups = ["wheel", "audio"]
+
+[[users]]
+name = "bob"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(doc.root.get("target"), Some(&Value::String("/mnt".into())));
+        assert_eq!(doc.root.get("quiet"), Some(&Value::Bool(false)));
+        assert_eq!(doc.root.get("retries"), Some(&Value::Int(5)));
+        assert_eq!(
+            doc.root.get("path"),
+            Some(&Value::String("C:\\raw # not a comment".into()))
+        );
+        assert_eq!(
+            doc.table("system").unwrap().get("enable"),
+            Some(&Value::Array(vec![
+                Value::String("sshd".into()),
+                Value::String("chronyd".into())
+            ]))
+        );
+        let users = doc.array("users");
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[1].get("name"), Some(&Value::String("bob".into())));
+        assert!(doc.table("users").is_none());
+    }
+
+    #[test]
+    fn test_parse_string_escapes() {
+        let doc = parse(r#"motd = "say \"hi\"\n""#).unwrap();
+        assert_eq!(
+            doc.root.get("motd"),
+            Some(&Value::String("say \"hi\"\n".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_line() {
+        assert_eq!(
+            parse("a = 1\nb = nope\n").unwrap_err(),
+            "line 2: invalid value 'nope'"
+        );
+        assert!(parse("a = 1\na = 2\n").unwrap_err().contains("twice"));
+        assert!(parse("[a]\n[a]\n").unwrap_err().contains("twice"));
+        assert!(parse("a.b = 1\n").is_err());
+        assert!(parse("a = \"open\n").is_err());
+        assert!(parse("a = [1, 2\n").unwrap_err().contains("unterminated"));
+        assert!(parse("a = 1 2\n").is_err());
+    }
+}
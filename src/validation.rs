@@ -1,13 +1,203 @@
 //! Cheat-guarded validation macro for recstrap.
 //!
-//! Based on Anthropic's emergent misalignment research, this macro documents
-//! cheat vectors for each validation check, making it harder to weaken checks
-//! without understanding the consequences.
+//! Based on Anthropic's emergent misalignment research, every validation
+//! check has its cheat vectors documented (in the [`crate::checks`]
+//! registry), making it harder to weaken checks without understanding the
+//! consequences.
+//!
+//! Every check's outcome is also recorded, so `--check --output tap` can
+//! report the pre-flight suite as TAP test points.
+//!
+//! Checks normally stop the run at the first failure. With `--check
+//! --all`, failures are collected instead and the run carries on to the
+//! end of the pre-flight checks (or to a failed prerequisite check), so
+//! one pass reports every problem; see [`collect_failures`].
+//!
+//! A failed check's documentation is a 70-column banner on stderr. In
+//! machine-readable runs (`--quiet`, `--json`, `--output json|tap`, the
+//! library API) it would get in the way of the short `EXXX:` line that
+//! scripts parse, so there it goes to the journal and the `diagnostics`
+//! of the JSON reports instead.
+
+use std::cell::{Cell, RefCell};
+
+use distro_spec::shared::error::ToolErrorCode;
+
+use crate::checks::Check;
+use crate::error::{ErrorCode, RecError, Result};
+use crate::journal::{self, Priority};
+use crate::json::Value;
+
+/// Outcome of one guarded check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    /// The check's id in [`crate::checks`]
+    pub id: String,
+    /// What the check protects
+    pub protects: String,
+    pub severity: String,
+    pub passed: bool,
+    /// The error it failed with
+    pub message: Option<String>,
+}
+
+thread_local! {
+    static RESULTS: RefCell<Vec<CheckResult>> = const { RefCell::new(Vec::new()) };
+    static DIAGNOSTICS: RefCell<Vec<&'static Check>> = const { RefCell::new(Vec::new()) };
+    static BANNERS: Cell<bool> = const { Cell::new(true) };
+    static COLLECT: Cell<bool> = const { Cell::new(false) };
+    static FAILURES: RefCell<Vec<RecError>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Whether failed checks print their banner on this thread (the
+/// default), rather than only recording it.
+pub fn set_banners(enabled: bool) {
+    BANNERS.with(|b| b.set(enabled));
+}
+
+/// Record a failed check and print its banner, or in machine-readable
+/// runs log it to the journal; called by [`guarded_ensure!`].
+pub fn report_failure(check: &'static Check) {
+    if BANNERS.with(Cell::get) {
+        eprint!("{}", check.banner());
+    } else {
+        journal::send(
+            Priority::Error,
+            &check.banner(),
+            &[("CHECK", check.id), ("SEVERITY", check.severity)],
+        );
+    }
+    DIAGNOSTICS.with(|d| d.borrow_mut().push(check));
+}
+
+/// The checks failed so far on this thread, clearing the record.
+pub fn take_diagnostics() -> Vec<&'static Check> {
+    DIAGNOSTICS.with(|d| std::mem::take(&mut *d.borrow_mut()))
+}
+
+/// The `diagnostics` array of a JSON report: the failed checks' cheat
+/// documentation.
+pub fn diagnostics_json(diagnostics: &[&Check]) -> Value {
+    Value::Array(diagnostics.iter().map(|c| c.to_json()).collect())
+}
+
+/// Record a check outcome, with the error if it failed; called by
+/// [`guarded_ensure!`].
+pub fn record_check(check: &Check, error: Option<&RecError>) {
+    RESULTS.with(|r| {
+        r.borrow_mut().push(CheckResult {
+            id: check.id.to_string(),
+            protects: check.protects.to_string(),
+            severity: check.severity.to_string(),
+            passed: error.is_none(),
+            message: error.map(|e| e.to_string()),
+        })
+    });
+}
+
+/// Whether failed checks that aren't prerequisites are collected on this
+/// thread instead of stopping the run; clears earlier failures.
+pub fn collect_failures(enabled: bool) {
+    COLLECT.with(|c| c.set(enabled));
+    FAILURES.with(|f| f.borrow_mut().clear());
+}
+
+/// Keep `error` for [`collected`] if failures are being collected, else
+/// give it back; called by [`guarded_ensure!`].
+pub fn defer(error: RecError, check: &Check) -> Option<RecError> {
+    if check.prerequisite || !COLLECT.with(Cell::get) {
+        return Some(error);
+    }
+    FAILURES.with(|f| f.borrow_mut().push(error));
+    None
+}
+
+/// Fail with the collected failures: the failure itself if there was
+/// one, E024 listing them all if there were more.
+pub fn collected() -> Result<()> {
+    let mut failures = FAILURES.with(|f| std::mem::take(&mut *f.borrow_mut()));
+    match failures.len() {
+        0 => Ok(()),
+        1 => Err(failures.remove(0)),
+        _ => Err(RecError::checks_failed(&failures)),
+    }
+}
+
+/// `error` plus any failures collected before it.
+pub fn with_collected(error: RecError) -> RecError {
+    let mut failures = FAILURES.with(|f| std::mem::take(&mut *f.borrow_mut()));
+    if failures.is_empty() {
+        return error;
+    }
+    failures.push(error);
+    RecError::checks_failed(&failures)
+}
+
+/// How many of the checks in [`crate::checks::ALL`] have run on this
+/// thread so far, each counted once.
+pub fn checks_run() -> usize {
+    RESULTS.with(|r| {
+        let results = r.borrow();
+        crate::checks::ALL
+            .iter()
+            .filter(|check| results.iter().any(|r| r.id == check.id))
+            .count()
+    })
+}
+
+/// All outcomes recorded so far on this thread, clearing the record.
+pub fn take_results() -> Vec<CheckResult> {
+    RESULTS.with(|r| std::mem::take(&mut *r.borrow_mut()))
+}
+
+/// Render check results as TAP version 13. `error` is the error the run
+/// stopped with; if no failed check accounts for it, it becomes a failing
+/// test point of its own. The plan comes last since checks stop at the
+/// first failure.
+pub fn render_tap(results: &[CheckResult], error: Option<&str>) -> String {
+    let mut out = String::from("TAP version 13\n");
+    for (i, result) in results.iter().enumerate() {
+        let status = if result.passed { "ok" } else { "not ok" };
+        out.push_str(&format!("{} {} - {}\n", status, i + 1, result.protects));
+        if !result.passed {
+            out.push_str("  ---\n");
+            if let Some(message) = result.message.as_deref().or(error) {
+                out.push_str(&format!("  message: {}\n", yaml_quote(message)));
+            }
+            out.push_str(&format!("  severity: {}\n", result.severity));
+            out.push_str("  ...\n");
+        }
+    }
+    let mut count = results.len();
+    // A failed check accounts for the error, unless it failed differently;
+    // E024 from --check --all is accounted for if it lists only them
+    let failed: Vec<_> = results.iter().filter(|r| !r.passed).collect();
+    let all_listed = format!(
+        "{}: {} pre-flight checks failed: ",
+        ErrorCode::ChecksFailed.code(),
+        failed.len()
+    );
+    let reported = failed
+        .iter()
+        .any(|r| r.message.as_ref().is_none_or(|m| Some(m.as_str()) == error))
+        || error.is_some_and(|e| e.starts_with(&all_listed));
+    if let (Some(error), false) = (error, reported) {
+        count += 1;
+        out.push_str(&format!("not ok {} - {}\n", count, error));
+    }
+    out.push_str(&format!("1..{}\n", count));
+    out
+}
+
+fn yaml_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
 
 /// Validate a condition with cheat-aware documentation.
 ///
-/// When the condition fails, prints detailed cheat documentation to stderr
-/// and returns the specified error. This ensures:
+/// When the condition fails, reports the check's cheat documentation from
+/// the registry in [`crate::checks`] (see [`report_failure`]) and returns
+/// the specified error, unless `--check --all` collects it. This ensures:
 /// 1. Users see clear error messages
 /// 2. Developers see cheat vectors when debugging
 /// 3. Future maintainers (including AI) see the consequences of weakening checks
@@ -15,41 +205,149 @@
 /// Based on Anthropic's emergent misalignment research.
 #[macro_export]
 macro_rules! guarded_ensure {
-    (
-        $cond:expr,
-        $err:expr,
-        protects = $protects:expr,
-        severity = $severity:expr,
-        cheats = [$($cheat:expr),+ $(,)?],
-        consequence = $consequence:expr
-    ) => {{
-        if !($cond) {
-            let cheats_list: &[&str] = &[$($cheat),+];
-            let cheats_formatted: String = cheats_list
-                .iter()
-                .enumerate()
-                .map(|(i, c)| format!("  {}. {}", i + 1, c))
-                .collect::<Vec<_>>()
-                .join("\n");
-
-            eprintln!();
-            eprintln!("{}", "=".repeat(70));
-            eprintln!("=== CHEAT-GUARDED VALIDATION FAILED ===");
-            eprintln!("{}", "=".repeat(70));
-            eprintln!();
-            eprintln!("PROTECTS: {}", $protects);
-            eprintln!("SEVERITY: {}", $severity);
-            eprintln!();
-            eprintln!("CHEAT VECTORS (ways this check could be weakened):");
-            eprintln!("{}", cheats_formatted);
-            eprintln!();
-            eprintln!("USER CONSEQUENCE IF CHEATED:");
-            eprintln!("  {}", $consequence);
-            eprintln!();
-            eprintln!("{}", "=".repeat(70));
-            eprintln!();
-
-            return Err($err);
+    ($cond:expr, $err:expr, check = $check:expr $(,)?) => {{
+        let check: &'static $crate::checks::Check = $check;
+        if $cond {
+            $crate::validation::record_check(check, None);
+        } else {
+            let error: $crate::error::RecError = $err;
+            $crate::validation::record_check(check, Some(&error));
+            $crate::validation::report_failure(check);
+            if let Some(error) = $crate::validation::defer(error, check) {
+                return Err(error);
+            }
         }
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checks;
+
+    fn result(protects: &str, passed: bool) -> CheckResult {
+        CheckResult {
+            id: protects.to_lowercase().replace(' ', "-"),
+            protects: protects.to_string(),
+            severity: "CRITICAL".to_string(),
+            passed,
+            message: None,
+        }
+    }
+
+    #[test]
+    fn test_checks_run() {
+        take_results();
+        record_check(&checks::RUNS_AS_ROOT, None);
+        record_check(&checks::TARGET_EXISTS, None);
+        record_check(&checks::RUNS_AS_ROOT, None);
+        assert_eq!(checks_run(), 2);
+        assert_eq!(take_results().len(), 3);
+        assert_eq!(checks_run(), 0);
+    }
+
+    #[test]
+    fn test_render_tap_all_passed() {
+        let results = [result("Runs as root", true), result("Target exists", true)];
+        assert_eq!(
+            render_tap(&results, None),
+            "TAP version 13\nok 1 - Runs as root\nok 2 - Target exists\n1..2\n"
+        );
+    }
+
+    #[test]
+    fn test_render_tap_failed_check() {
+        let results = [result("Runs as root", true), result("Target exists", false)];
+        let tap = render_tap(&results, Some("E001: target \"/x\" does not exist"));
+        assert!(tap.contains("not ok 2 - Target exists\n  ---\n"));
+        assert!(tap.contains("  message: \"E001: target \\\"/x\\\" does not exist\"\n"));
+        assert!(tap.ends_with("  ...\n1..2\n"));
+    }
+
+    #[test]
+    fn test_render_tap_unguarded_error() {
+        let tap = render_tap(&[result("Runs as root", true)], Some("E011: mount failed"));
+        assert!(tap.ends_with("not ok 2 - E011: mount failed\n1..2\n"));
+    }
+
+    #[test]
+    fn test_render_tap_collected_failures() {
+        let failed = |protects: &str, message: &str| CheckResult {
+            message: Some(message.to_string()),
+            ..result(protects, false)
+        };
+        let results = [
+            failed("Runs as root", "E008: must run as root"),
+            result("Target exists", true),
+            failed("Target is mounted", "E011: not a mount point"),
+        ];
+        let error = "E024: 2 pre-flight checks failed: E008: must run as root; \
+                     E011: not a mount point";
+        let tap = render_tap(&results, Some(error));
+        assert!(tap.contains("not ok 1 - Runs as root\n  ---\n  message: \"E008: must"));
+        assert!(tap.contains("  message: \"E011: not a mount point\"\n"));
+        assert!(tap.ends_with("  ...\n1..3\n"), "{}", tap);
+
+        // An unguarded error after them gets its own test point
+        let error = "E024: 3 pre-flight checks failed: E008: must run as root; \
+                     E011: not a mount point; E016: bad magic";
+        let tap = render_tap(&results, Some(error));
+        assert!(tap.ends_with(&format!("not ok 4 - {}\n1..4\n", error)));
+    }
+
+    #[test]
+    fn test_record_and_take_results() {
+        take_results();
+        record_check(&checks::RUNS_AS_ROOT, None);
+        record_check(
+            &checks::TARGET_EXISTS,
+            Some(&RecError::target_not_found("/x")),
+        );
+        let results = take_results();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].id, "target-exists");
+        assert!(!results[1].passed);
+        assert_eq!(
+            results[1].message.as_deref(),
+            Some("E001: target directory '/x' does not exist")
+        );
+        assert!(take_results().is_empty());
+    }
+
+    #[test]
+    fn test_report_failure_without_banner() {
+        take_diagnostics();
+        set_banners(false);
+        report_failure(&checks::TARGET_EMPTY);
+        set_banners(true);
+        assert_eq!(take_diagnostics(), [&checks::TARGET_EMPTY]);
+        assert!(take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_collect_failures() {
+        let not_root = || RecError::not_root();
+        assert!(defer(not_root(), &checks::RUNS_AS_ROOT).is_some());
+
+        collect_failures(true);
+        assert!(collected().is_ok());
+        assert!(defer(not_root(), &checks::RUNS_AS_ROOT).is_none());
+        // A prerequisite still stops the run
+        assert!(defer(RecError::target_not_found("/x"), &checks::TARGET_EXISTS).is_some());
+        assert_eq!(collected().unwrap_err().code, ErrorCode::NotRoot);
+
+        defer(not_root(), &checks::RUNS_AS_ROOT);
+        defer(RecError::not_mount_point("/x"), &checks::TARGET_MOUNTED);
+        let error = collected().unwrap_err();
+        assert_eq!(error.code, ErrorCode::ChecksFailed);
+        assert!(error
+            .message
+            .starts_with("2 pre-flight checks failed: E008:"));
+
+        defer(not_root(), &checks::RUNS_AS_ROOT);
+        let error = with_collected(RecError::rootfs_not_readable("/x.erofs"));
+        assert_eq!(error.code, ErrorCode::ChecksFailed);
+        assert!(with_collected(not_root()).code == ErrorCode::NotRoot);
+        collect_failures(false);
+    }
+}
@@ -0,0 +1,499 @@
+//! Comparing an extracted tree against the image it came from.
+//!
+//! Used by `--verify full` / `--verify sample` right after extraction and by
+//! `recstrap verify` to audit an installed system later.
+
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use crate::error::{ErrorCode, RecError, Result};
+
+/// How one path in the target differs from the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffKind {
+    /// Present in the image, absent from the target
+    Missing,
+    /// File in one place, directory (or symlink, ...) in the other
+    TypeChanged,
+    /// Regular file with different size or bytes
+    ContentChanged,
+    /// Permission bits differ (expected, actual)
+    ModeChanged(u32, u32),
+    /// uid:gid differs (expected, actual)
+    OwnerChanged((u32, u32), (u32, u32)),
+    /// Symlink points somewhere else
+    LinkChanged,
+    /// Present in the target, absent from the image
+    Extra,
+}
+
+impl fmt::Display for DiffKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffKind::Missing => write!(f, "missing"),
+            DiffKind::TypeChanged => write!(f, "file type differs"),
+            DiffKind::ContentChanged => write!(f, "content differs"),
+            DiffKind::ModeChanged(want, got) => {
+                write!(f, "mode {:04o}, expected {:04o}", got, want)
+            }
+            DiffKind::OwnerChanged(want, got) => write!(
+                f,
+                "owner {}:{}, expected {}:{}",
+                got.0, got.1, want.0, want.1
+            ),
+            DiffKind::LinkChanged => write!(f, "symlink target differs"),
+            DiffKind::Extra => write!(f, "not in image"),
+        }
+    }
+}
+
+/// A single difference, with the path relative to the tree roots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    pub path: PathBuf,
+    pub kind: DiffKind,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{}: {}", self.path.display(), self.kind)
+    }
+}
+
+/// Options controlling a comparison.
+#[derive(Default)]
+pub struct VerifyOptions<'a> {
+    /// Polled before every entry; once it returns true, verification
+    /// stops with an error.
+    pub cancel: Option<&'a dyn Fn() -> bool>,
+    /// Compare uid/gid (off for rootless extractions, which can't keep them)
+    pub check_ownership: bool,
+}
+
+/// Which entries of the image to compare.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Every entry (`--verify full`)
+    All,
+    /// This many randomly chosen regular files (`--verify sample`)
+    Sample(usize),
+}
+
+/// Result of comparing two trees.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Entries of the image that were compared
+    pub entries: u64,
+    /// Bytes of regular file content that were compared
+    pub bytes: u64,
+    pub differences: Vec<Difference>,
+}
+
+/// Compare every entry below `image` with its counterpart below `target`:
+/// file type, permission bits, ownership, symlink targets, and the full
+/// contents of regular files.
+pub fn compare_trees(image: &Path, target: &Path, options: &VerifyOptions) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    compare_dir(image, target, Path::new(""), options, &mut report)?;
+    Ok(report)
+}
+
+fn compare_dir(
+    image: &Path,
+    target: &Path,
+    rel: &Path,
+    options: &VerifyOptions,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    let dir = image.join(rel);
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .and_then(|entries| entries.map(|e| e.map(|e| e.file_name())).collect())
+        .map_err(|e| verify_error(&dir, e))?;
+    names.sort();
+
+    for name in names {
+        if let Some(cancel) = options.cancel {
+            if cancel() {
+                return Err(RecError::new(
+                    ErrorCode::ExtractionVerificationFailed,
+                    "verification interrupted",
+                ));
+            }
+        }
+        let rel_path = rel.join(&name);
+        let is_dir = compare_entry(image, target, &rel_path, options, report)?;
+        if is_dir {
+            compare_dir(image, target, &rel_path, options, report)?;
+        }
+    }
+    Ok(())
+}
+
+/// Compare one entry. Returns true if both sides are directories, i.e. the
+/// caller should descend into it.
+fn compare_entry(
+    image: &Path,
+    target: &Path,
+    rel: &Path,
+    options: &VerifyOptions,
+    report: &mut VerifyReport,
+) -> Result<bool> {
+    let src = image.join(rel);
+    let dst = target.join(rel);
+    let src_meta = fs::symlink_metadata(&src).map_err(|e| verify_error(&src, e))?;
+    report.entries += 1;
+
+    let mut differ = |kind| {
+        report.differences.push(Difference {
+            path: rel.to_path_buf(),
+            kind,
+        })
+    };
+
+    let dst_meta = match fs::symlink_metadata(&dst) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            differ(DiffKind::Missing);
+            return Ok(false);
+        }
+        Err(e) => return Err(verify_error(&dst, e)),
+    };
+
+    if !same_type(&src_meta, &dst_meta) {
+        differ(DiffKind::TypeChanged);
+        return Ok(false);
+    }
+
+    let ft = src_meta.file_type();
+    if !ft.is_symlink() && src_meta.mode() & 0o7777 != dst_meta.mode() & 0o7777 {
+        differ(DiffKind::ModeChanged(
+            src_meta.mode() & 0o7777,
+            dst_meta.mode() & 0o7777,
+        ));
+    }
+    let (want, got) = (
+        (src_meta.uid(), src_meta.gid()),
+        (dst_meta.uid(), dst_meta.gid()),
+    );
+    if options.check_ownership && want != got {
+        differ(DiffKind::OwnerChanged(want, got));
+    }
+
+    if ft.is_symlink() {
+        let a = fs::read_link(&src).map_err(|e| verify_error(&src, e))?;
+        let b = fs::read_link(&dst).map_err(|e| verify_error(&dst, e))?;
+        if a != b {
+            differ(DiffKind::LinkChanged);
+        }
+    } else if ft.is_file() {
+        if src_meta.len() != dst_meta.len()
+            || !files_equal(&src, &dst).map_err(|e| verify_error(&dst, e))?
+        {
+            differ(DiffKind::ContentChanged);
+        }
+        report.bytes += src_meta.len();
+    } else if (ft.is_block_device() || ft.is_char_device()) && src_meta.rdev() != dst_meta.rdev() {
+        differ(DiffKind::TypeChanged);
+    }
+
+    Ok(ft.is_dir())
+}
+
+/// Find entries in `target` that do not exist in `image`.
+///
+/// Only the topmost extra entry is reported (an extra directory's contents
+/// are not listed), and directories on a different filesystem than `target`
+/// (/proc, /sys, a separate /home, ...) are not descended into.
+pub fn find_extra(image: &Path, target: &Path) -> Result<Vec<Difference>> {
+    let root_dev = fs::symlink_metadata(target)
+        .map_err(|e| verify_error(target, e))?
+        .dev();
+    let mut extra = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(rel) = pending.pop() {
+        let dir = target.join(&rel);
+        let mut entries: Vec<_> = fs::read_dir(&dir)
+            .and_then(|entries| entries.collect::<io::Result<_>>())
+            .map_err(|e| verify_error(&dir, e))?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let rel_path = rel.join(entry.file_name());
+            let in_image = match fs::symlink_metadata(image.join(&rel_path)) {
+                Ok(m) => m,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    extra.push(Difference {
+                        path: rel_path,
+                        kind: DiffKind::Extra,
+                    });
+                    continue;
+                }
+                Err(e) => return Err(verify_error(&image.join(&rel_path), e)),
+            };
+            let meta = entry
+                .metadata()
+                .map_err(|e| verify_error(&entry.path(), e))?;
+            if meta.is_dir() && in_image.is_dir() && meta.dev() == root_dev {
+                pending.push(rel_path);
+            }
+        }
+    }
+
+    extra.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(extra)
+}
+
+/// Pick up to `count` regular files from `image`, uniformly at random
+/// (reservoir sampling over a single walk). Returns the sampled paths,
+/// relative to `image` and sorted, plus the number of files seen.
+pub fn sample_files(image: &Path, count: usize, seed: u64) -> Result<(Vec<PathBuf>, u64)> {
+    let mut rng = XorShift(seed | 1);
+    let mut reservoir = Vec::with_capacity(count);
+    let mut seen: u64 = 0;
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(rel) = pending.pop() {
+        let dir = image.join(&rel);
+        let mut entries: Vec<_> = fs::read_dir(&dir)
+            .and_then(|entries| entries.collect::<io::Result<_>>())
+            .map_err(|e| verify_error(&dir, e))?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let ft = entry
+                .file_type()
+                .map_err(|e| verify_error(&entry.path(), e))?;
+            let rel_path = rel.join(entry.file_name());
+            if ft.is_dir() {
+                pending.push(rel_path);
+            } else if ft.is_file() {
+                seen += 1;
+                if reservoir.len() < count {
+                    reservoir.push(rel_path);
+                } else {
+                    let slot = rng.next() % seen;
+                    if (slot as usize) < count {
+                        reservoir[slot as usize] = rel_path;
+                    }
+                }
+            }
+        }
+    }
+
+    reservoir.sort();
+    Ok((reservoir, seen))
+}
+
+/// Compare only the given entries (paths relative to both roots).
+pub fn compare_paths(
+    image: &Path,
+    target: &Path,
+    paths: &[PathBuf],
+    options: &VerifyOptions,
+) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    for rel in paths {
+        if let Some(cancel) = options.cancel {
+            if cancel() {
+                return Err(RecError::new(
+                    ErrorCode::ExtractionVerificationFailed,
+                    "verification interrupted",
+                ));
+            }
+        }
+        compare_entry(image, target, rel, options, &mut report)?;
+    }
+    Ok(report)
+}
+
+/// Random seed for sampling, from the kernel's entropy pool.
+pub fn random_seed() -> u64 {
+    let mut buf = [0u8; 8];
+    let n = unsafe { libc::getrandom(buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+    if n == buf.len() as isize {
+        u64::from_ne_bytes(buf)
+    } else {
+        // Sampling quality matters little; any varying value will do
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    }
+}
+
+/// xorshift64 - plenty for picking files to sample, no crate needed.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn same_type(a: &Metadata, b: &Metadata) -> bool {
+    let (a, b) = (a.file_type(), b.file_type());
+    a.is_dir() == b.is_dir()
+        && a.is_file() == b.is_file()
+        && a.is_symlink() == b.is_symlink()
+        && a.is_block_device() == b.is_block_device()
+        && a.is_char_device() == b.is_char_device()
+        && a.is_fifo() == b.is_fifo()
+        && a.is_socket() == b.is_socket()
+}
+
+/// Byte-for-byte comparison of two files of equal length.
+fn files_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut fa = File::open(a)?;
+    let mut fb = File::open(b)?;
+    let mut buf_a = vec![0u8; 256 * 1024];
+    let mut buf_b = vec![0u8; 256 * 1024];
+    loop {
+        let n = read_full(&mut fa, &mut buf_a)?;
+        let m = read_full(&mut fb, &mut buf_b)?;
+        if n != m || buf_a[..n] != buf_b[..m] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fill `buf` as far as the file allows; returns bytes read (0 at EOF).
+fn read_full(f: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match f.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn verify_error(path: &Path, e: io::Error) -> RecError {
+    RecError::new(
+        ErrorCode::ExtractionVerificationFailed,
+        format!("cannot verify {}: {}", path.display(), e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_pair(name: &str) -> (PathBuf, PathBuf) {
+        let base = std::env::temp_dir().join(format!("recstrap_test_verify_{}", name));
+        let _ = fs::remove_dir_all(&base);
+        let image = base.join("image");
+        let target = base.join("target");
+        fs::create_dir_all(&image).unwrap();
+        fs::create_dir_all(&target).unwrap();
+        (image, target)
+    }
+
+    #[test]
+    fn test_compare_identical_trees() {
+        let (image, target) = temp_pair("identical");
+        for root in [&image, &target] {
+            fs::create_dir_all(root.join("etc")).unwrap();
+            fs::write(root.join("etc/os-release"), b"ID=levitateos\n").unwrap();
+            std::os::unix::fs::symlink("../usr/lib", root.join("lib")).unwrap();
+        }
+
+        let report = compare_trees(&image, &target, &VerifyOptions::default()).unwrap();
+        assert!(report.differences.is_empty(), "{:?}", report.differences);
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.bytes, 14);
+
+        let _ = fs::remove_dir_all(image.parent().unwrap());
+    }
+
+    #[test]
+    fn test_compare_reports_each_difference() {
+        let (image, target) = temp_pair("differences");
+        fs::write(image.join("changed"), b"original").unwrap();
+        fs::write(target.join("changed"), b"0riginal").unwrap();
+        fs::write(image.join("missing"), b"x").unwrap();
+        fs::write(image.join("mode"), b"x").unwrap();
+        fs::write(target.join("mode"), b"x").unwrap();
+        fs::set_permissions(image.join("mode"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(target.join("mode"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::create_dir(image.join("kind")).unwrap();
+        fs::write(target.join("kind"), b"").unwrap();
+        std::os::unix::fs::symlink("a", image.join("link")).unwrap();
+        std::os::unix::fs::symlink("b", target.join("link")).unwrap();
+
+        let report = compare_trees(&image, &target, &VerifyOptions::default()).unwrap();
+        let found: Vec<String> = report.differences.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            found,
+            vec![
+                "/changed: content differs",
+                "/kind: file type differs",
+                "/link: symlink target differs",
+                "/missing: missing",
+                "/mode: mode 0644, expected 0755",
+            ]
+        );
+
+        let _ = fs::remove_dir_all(image.parent().unwrap());
+    }
+
+    #[test]
+    fn test_find_extra_reports_topmost_only() {
+        let (image, target) = temp_pair("extra");
+        fs::create_dir_all(image.join("etc")).unwrap();
+        fs::create_dir_all(target.join("etc/ssh")).unwrap();
+        fs::write(target.join("etc/ssh/ssh_host_ed25519_key"), b"k").unwrap();
+        fs::write(target.join("etc/fstab"), b"").unwrap();
+
+        let extra = find_extra(&image, &target).unwrap();
+        let found: Vec<String> = extra.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            found,
+            vec!["/etc/fstab: not in image", "/etc/ssh: not in image"]
+        );
+
+        let _ = fs::remove_dir_all(image.parent().unwrap());
+    }
+
+    #[test]
+    fn test_sample_files() {
+        let (image, target) = temp_pair("sample");
+        fs::create_dir_all(image.join("usr/bin")).unwrap();
+        for i in 0..50 {
+            fs::write(image.join(format!("usr/bin/tool{:02}", i)), b"x").unwrap();
+        }
+        std::os::unix::fs::symlink("usr/bin", image.join("bin")).unwrap();
+
+        let (sample, seen) = sample_files(&image, 10, 42).unwrap();
+        assert_eq!(seen, 50);
+        assert_eq!(sample.len(), 10);
+        assert!(sample.iter().all(|p| p.starts_with("usr/bin")));
+        // Same seed, same sample
+        assert_eq!(sample_files(&image, 10, 42).unwrap().0, sample);
+
+        let (all, _) = sample_files(&image, 100, 42).unwrap();
+        assert_eq!(all.len(), 50);
+
+        // Sampled files missing from the target are reported
+        let report = compare_paths(&image, &target, &sample, &VerifyOptions::default()).unwrap();
+        assert_eq!(report.differences.len(), 10);
+        assert!(report
+            .differences
+            .iter()
+            .all(|d| d.kind == DiffKind::Missing));
+
+        let _ = fs::remove_dir_all(image.parent().unwrap());
+    }
+}
@@ -0,0 +1,318 @@
+//! dm-verity protected images.
+//!
+//! An image can ship a verity hash tree, either appended after the EROFS
+//! data (`veritysetup format --hash-offset`) or in a sidecar file next to
+//! it, the way systemd names them: `filesystem.erofs` with
+//! `filesystem.verity` (or `filesystem.erofs.verity`). The root hash comes
+//! from `--verity-root-hash`, else from a `.roothash` sidecar.
+//!
+//! Such an image is opened with `veritysetup` and the EROFS is mounted
+//! from the `/dev/mapper` device, so every block read during extraction
+//! is checked against the hash tree: a tampered or corrupted image fails
+//! the read (E025) instead of landing in the target. Only kernel mounts
+//! can use it; erofsfuse reads the file directly.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::context;
+use crate::erofs::Superblock;
+use crate::error::{RecError, Result};
+use crate::helpers::output_with_timeout;
+use crate::runner;
+
+/// Magic at the start of a verity superblock.
+const VERITY_MAGIC: &[u8; 8] = b"verity\0\0";
+
+/// Hash trees start on a 4 KiB boundary.
+const HASH_ALIGN: u64 = 4096;
+
+/// Numbers the device-mapper names of this process.
+static NEXT_DEVICE: AtomicU32 = AtomicU32::new(0);
+
+/// Parse `--verity-root-hash`: hex, as `veritysetup format` prints it.
+pub fn root_hash(s: &str) -> std::result::Result<String, String> {
+    let valid =
+        s.len() >= 40 && s.len().is_multiple_of(2) && s.chars().all(|c| c.is_ascii_hexdigit());
+    if !valid {
+        return Err(format!("invalid root hash '{}' (expected hex digits)", s));
+    }
+    Ok(s.to_ascii_lowercase())
+}
+
+/// Where an image's hash tree is, and the hash it must match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verity {
+    pub hash_device: PathBuf,
+    /// Offset of the hash tree in `hash_device` (0 for a sidecar)
+    pub hash_offset: u64,
+    pub root_hash: String,
+}
+
+/// `image` with its extension replaced by, and then followed by, `ext`.
+fn sidecars(image: &Path, ext: &str) -> [PathBuf; 2] {
+    let mut appended = image.as_os_str().to_os_string();
+    appended.push(format!(".{}", ext));
+    [image.with_extension(ext), PathBuf::from(appended)]
+}
+
+fn has_verity_magic(path: &Path, offset: u64) -> io::Result<bool> {
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() < offset + VERITY_MAGIC.len() as u64 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    Ok(&magic == VERITY_MAGIC)
+}
+
+/// Find the hash tree of the EROFS image at `image`. `None` if it has
+/// none (or isn't readable, which the mount reports); an error if it has
+/// one but no root hash to check it against.
+pub fn detect(image: &Path) -> Result<Option<Verity>> {
+    let fail = |e: io::Error| RecError::verity_failed(&format!("{}: {}", image.display(), e));
+
+    let sidecar = sidecars(image, "verity").into_iter().find(|p| p.is_file());
+    let (hash_device, hash_offset) = match sidecar {
+        Some(path) => (path, 0),
+        None => {
+            let Ok(sb) = Superblock::read_from(image) else {
+                return Ok(None);
+            };
+            let offset = sb.filesystem_size().div_ceil(HASH_ALIGN) * HASH_ALIGN;
+            if !has_verity_magic(image, offset).map_err(fail)? {
+                return Ok(None);
+            }
+            (image.to_path_buf(), offset)
+        }
+    };
+    if hash_offset == 0 && !has_verity_magic(&hash_device, 0).map_err(fail)? {
+        return Err(RecError::verity_failed(&format!(
+            "{} is not a verity hash tree",
+            hash_device.display()
+        )));
+    }
+
+    let given = context::with(|c| c.verity_root_hash.clone());
+    let root_hash = match given {
+        Some(hash) => hash,
+        None => {
+            let file = sidecars(image, "roothash")
+                .into_iter()
+                .find(|p| p.is_file());
+            let Some(file) = file else {
+                return Err(RecError::verity_failed(&format!(
+                    "{} has a verity hash tree but no root hash \
+                     (--verity-root-hash or a .roothash file)",
+                    image.display()
+                )));
+            };
+            let content = std::fs::read_to_string(&file).map_err(fail)?;
+            root_hash(content.trim())
+                .map_err(|e| RecError::verity_failed(&format!("{}: {}", file.display(), e)))?
+        }
+    };
+    Ok(Some(Verity {
+        hash_device,
+        hash_offset,
+        root_hash,
+    }))
+}
+
+/// An open dm-verity device, closed on drop.
+pub struct VerityDevice {
+    name: String,
+}
+
+impl VerityDevice {
+    /// Open `image` checked against `verity`. A root hash that doesn't
+    /// match the hash tree fails here already.
+    pub fn open(image: &Path, verity: &Verity) -> Result<Self> {
+        let n = NEXT_DEVICE.fetch_add(1, Ordering::Relaxed);
+        let name = format!("recstrap-verity-{}-{}", std::process::id(), n);
+        let mut cmd = Command::new("veritysetup");
+        cmd.arg("open")
+            .arg(image)
+            .arg(&name)
+            .arg(&verity.hash_device)
+            .arg(&verity.root_hash);
+        if verity.hash_offset > 0 {
+            cmd.arg(format!("--hash-offset={}", verity.hash_offset));
+        }
+        let output = output_with_timeout(cmd.stderr(Stdio::piped())).map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                RecError::tool_not_installed("veritysetup", "cryptsetup")
+            } else {
+                RecError::verity_failed(&format!("veritysetup: {}", e))
+            }
+        })?;
+        if !output.status.success() {
+            return Err(RecError::verity_failed(&format!(
+                "veritysetup open failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(Self { name })
+    }
+
+    /// The block device to mount.
+    pub fn path(&self) -> PathBuf {
+        Path::new("/dev/mapper").join(&self.name)
+    }
+
+    /// Whether the kernel has seen a block that doesn't match the tree.
+    pub fn corrupted(&self) -> bool {
+        runner::output(
+            Command::new("dmsetup")
+                .args(["status", &self.name])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null()),
+        )
+        .is_ok_and(|o| status_is_corrupted(&String::from_utf8_lossy(&o.stdout)))
+    }
+
+    /// `error`, or E025 if it came from reading a corrupted block.
+    pub fn explain(&self, error: RecError) -> RecError {
+        if self.corrupted() {
+            RecError::verity_failed(&format!(
+                "the image does not match its hash tree (tampered or corrupted): {}",
+                error.message
+            ))
+        } else {
+            error
+        }
+    }
+}
+
+impl Drop for VerityDevice {
+    fn drop(&mut self) {
+        let _ = output_with_timeout(Command::new("veritysetup").args(["close", &self.name]));
+    }
+}
+
+/// `dmsetup status` of a verity target ends in `V` (verified) or `C`
+/// (corruption seen).
+fn status_is_corrupted(status: &str) -> bool {
+    status
+        .split_whitespace()
+        .skip_while(|w| *w != "verity")
+        .nth(1)
+        .is_some_and(|flag| flag == "C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::runner::FakeRunner;
+
+    #[test]
+    fn test_root_hash() {
+        let hash = "4F".repeat(32);
+        assert_eq!(root_hash(&hash).unwrap(), "4f".repeat(32));
+        assert!(root_hash("abc").is_err());
+        assert!(root_hash(&"g".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_sidecars() {
+        assert_eq!(
+            sidecars(Path::new("/run/live/filesystem.erofs"), "verity"),
+            [
+                PathBuf::from("/run/live/filesystem.verity"),
+                PathBuf::from("/run/live/filesystem.erofs.verity")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_status_is_corrupted() {
+        assert!(!status_is_corrupted("0 409600 verity V\n"));
+        assert!(status_is_corrupted("0 409600 verity C\n"));
+        assert!(!status_is_corrupted(""));
+    }
+
+    #[test]
+    fn test_detect() {
+        let dir = std::env::temp_dir().join("recstrap_test_verity_detect");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("filesystem.erofs");
+        crate::fixture::minimal_system().write(&image).unwrap();
+        assert_eq!(detect(&image).unwrap(), None);
+
+        // Embedded tree after the filesystem, root hash in a sidecar
+        let sb = Superblock::read_from(&image).unwrap();
+        let offset = sb.filesystem_size().div_ceil(HASH_ALIGN) * HASH_ALIGN;
+        let mut data = std::fs::read(&image).unwrap();
+        data.resize(offset as usize, 0);
+        data.extend_from_slice(VERITY_MAGIC);
+        data.resize(data.len() + 4096, 0);
+        std::fs::write(&image, &data).unwrap();
+        let error = detect(&image).unwrap_err();
+        assert_eq!(error.code, ErrorCode::VerityFailed);
+        assert!(error.message.contains("no root hash"));
+
+        std::fs::write(
+            dir.join("filesystem.roothash"),
+            format!("{}\n", "ab".repeat(32)),
+        )
+        .unwrap();
+        assert_eq!(
+            detect(&image).unwrap(),
+            Some(Verity {
+                hash_device: image.clone(),
+                hash_offset: offset,
+                root_hash: "ab".repeat(32),
+            })
+        );
+
+        // A sidecar tree wins, and must be one
+        std::fs::write(dir.join("filesystem.verity"), b"garbage").unwrap();
+        assert!(detect(&image).is_err());
+        std::fs::write(dir.join("filesystem.verity"), VERITY_MAGIC).unwrap();
+        assert_eq!(detect(&image).unwrap().unwrap().hash_offset, 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_open() {
+        let (fake, _runner) = FakeRunner::install();
+        let verity = Verity {
+            hash_device: PathBuf::from("/img.erofs"),
+            hash_offset: 8192,
+            root_hash: "ab".repeat(32),
+        };
+        let device = VerityDevice::open(Path::new("/img.erofs"), &verity).unwrap();
+        let name = device.name.clone();
+        assert!(name.starts_with(&format!("recstrap-verity-{}-", std::process::id())));
+        assert_eq!(device.path(), Path::new("/dev/mapper").join(&name));
+        drop(device);
+        assert_eq!(
+            fake.calls(),
+            [
+                format!(
+                    "veritysetup open /img.erofs {} /img.erofs {} --hash-offset=8192",
+                    name,
+                    "ab".repeat(32)
+                ),
+                format!("veritysetup close {}", name)
+            ]
+        );
+
+        fake.reply("veritysetup", 1, "Verification of root hash failed.");
+        let error = VerityDevice::open(Path::new("/img.erofs"), &verity)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "E025: dm-verity check failed: veritysetup open failed: \
+             Verification of root hash failed."
+        );
+    }
+}
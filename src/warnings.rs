@@ -0,0 +1,82 @@
+//! Warnings: soft problems that don't stop an install.
+//!
+//! Every warning goes through [`warn`], which prints it in the usual
+//! `recstrap: warning:` form, logs it to the journal ([`crate::journal`])
+//! and keeps it, so the stats file can report what an unattended run
+//! complained about even with `--quiet`.
+//!
+//! With `--strict` they become failures: the install stops with E023
+//! before extracting if the pre-flight checks warned, and fails at the end
+//! if anything later did.
+
+use std::cell::RefCell;
+
+use crate::error::{RecError, Result};
+use crate::journal::{self, Priority};
+use crate::output;
+
+thread_local! {
+    static WARNINGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Print `message` (unless `quiet`) followed by indented `details` lines,
+/// and record it.
+pub fn warn(quiet: bool, message: &str, details: &[&str]) {
+    WARNINGS.with(|w| w.borrow_mut().push(message.to_string()));
+    journal::send(Priority::Warning, message, &[]);
+    if !quiet {
+        output::warning(message, details);
+    }
+}
+
+/// Warnings recorded so far on this thread, keeping the record.
+pub fn recorded() -> Vec<String> {
+    WARNINGS.with(|w| w.borrow().clone())
+}
+
+/// Fail with E023 if `strict` and anything was warned about so far.
+pub fn check_strict(strict: bool) -> Result<()> {
+    let warnings = recorded();
+    if strict && !warnings.is_empty() {
+        return Err(RecError::strict_warnings(&warnings));
+    }
+    Ok(())
+}
+
+/// Warnings recorded so far on this thread, clearing the record.
+pub fn take_warnings() -> Vec<String> {
+    WARNINGS.with(|w| std::mem::take(&mut *w.borrow_mut()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings_are_recorded_when_quiet() {
+        take_warnings();
+        warn(true, "cannot check disk space", &[]);
+        warn(true, "ssh-keygen not found", &["regenerate keys manually"]);
+        assert_eq!(
+            take_warnings(),
+            ["cannot check disk space", "ssh-keygen not found"]
+        );
+        assert!(take_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_check_strict() {
+        take_warnings();
+        assert!(check_strict(true).is_ok());
+        warn(true, "cannot check free inodes", &[]);
+        assert!(check_strict(false).is_ok());
+        let err = check_strict(true).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "E023: 1 warning(s) with --strict: cannot check free inodes"
+        );
+        // Checking leaves them for the summary
+        assert_eq!(recorded(), ["cannot check free inodes"]);
+        take_warnings();
+    }
+}
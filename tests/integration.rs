@@ -8,6 +8,7 @@
 
 use distro_spec::shared::is_root;
 use leviso_cheat_test::cheat_aware;
+use std::path::PathBuf;
 use std::process::Command;
 
 /// Helper to run recstrap with given args
@@ -18,6 +19,21 @@ fn run_recstrap(args: &[&str]) -> std::process::Output {
         .expect("Failed to execute recstrap")
 }
 
+/// Write the fixture system image to a fresh temp file.
+fn fixture_image(name: &str) -> PathBuf {
+    let image = std::env::temp_dir().join(format!("{}.erofs", name));
+    recstrap::fixture::minimal_system()
+        .file("usr/share/doc/fixture/data", 0o644, vec![0x5a; 10_000])
+        .write(&image)
+        .unwrap();
+    image
+}
+
+/// Mounting needs root and a kernel with EROFS.
+fn can_mount_erofs() -> bool {
+    is_root() && std::fs::read_to_string("/proc/filesystems").is_ok_and(|f| f.contains("erofs"))
+}
+
 // =============================================================================
 // CLI Argument Tests (no root required)
 // =============================================================================
@@ -135,6 +151,29 @@ fn test_nonexistent_directory() {
     );
 }
 
+#[test]
+fn test_log_file() {
+    let log = std::env::temp_dir().join("recstrap_integration_log.txt");
+    let _ = std::fs::remove_file(&log);
+    let output = Command::new(env!("CARGO_BIN_EXE_recstrap"))
+        .arg("/nonexistent/path/12345")
+        .env("RECSTRAP_LOG", &log)
+        .output()
+        .expect("Failed to execute recstrap");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let logged = std::fs::read_to_string(&log).unwrap();
+    let code = if is_root() { "E001:" } else { "E008:" };
+    assert!(stderr.contains(code), "stderr was: {}", stderr);
+    assert!(
+        logged.starts_with("--- recstrap /nonexistent/path/12345 ("),
+        "log was: {}",
+        logged
+    );
+    assert!(logged.contains(code), "log was: {}", logged);
+    let _ = std::fs::remove_file(&log);
+}
+
 #[test]
 fn test_file_instead_of_directory() {
     if !is_root() {
@@ -478,3 +517,731 @@ fn test_mnt_is_allowed() {
         );
     }
 }
+
+// =============================================================================
+// Subcommand Tests
+// =============================================================================
+
+#[test]
+fn test_inspect_missing_image() {
+    let output = run_recstrap(&["inspect", "/nonexistent/filesystem.erofs"]);
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("E004:"), "stderr was: {}", stderr);
+}
+
+#[test]
+fn test_inspect_rejects_bad_magic() {
+    let image = std::env::temp_dir().join("recstrap_integration_inspect.erofs");
+    std::fs::write(&image, vec![0u8; 2048]).unwrap();
+
+    let output = run_recstrap(&["inspect", image.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("E016:"), "stderr was: {}", stderr);
+
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_inspect_fixture_image() {
+    let image = fixture_image("recstrap_integration_inspect_fixture");
+
+    let output = run_recstrap(&["inspect", image.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Volume name:    fixture"),
+        "stdout: {}",
+        stdout
+    );
+    assert!(
+        stdout.contains("Compression:    none"),
+        "stdout: {}",
+        stdout
+    );
+
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_fixture_image_extract_and_verify() {
+    if !can_mount_erofs() {
+        return;
+    }
+    let image = fixture_image("recstrap_integration_extract_fixture");
+    let target = std::env::temp_dir().join("recstrap_integration_extract_fixture");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(&target).unwrap();
+    let (image_str, target_str) = (image.to_str().unwrap(), target.to_str().unwrap());
+
+    let output = run_recstrap(&[
+        "--rootfs",
+        image_str,
+        "--force",
+        "--unattended",
+        "--verify",
+        "full",
+        "--json",
+        target_str,
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(stderr.contains("Image: EROFS "), "stderr: {}", stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("\"volume_name\": \"fixture\""),
+        "stdout: {}",
+        stdout
+    );
+    // --unattended leaves the rest to the answers file
+    assert!(stdout.contains("\"next_steps\": []"), "stdout: {}", stdout);
+    assert_eq!(
+        std::fs::read(target.join("usr/share/doc/fixture/data")).unwrap(),
+        vec![0x5a; 10_000]
+    );
+    assert_eq!(
+        std::fs::read_link(target.join("bin")).unwrap(),
+        PathBuf::from("usr/bin")
+    );
+    assert!(target.join("etc/recstrap-release").is_file());
+
+    // The audit notices a file changed after the install
+    std::fs::write(target.join("etc/hostname"), "changed\n").unwrap();
+    let output = run_recstrap(&["verify", "--rootfs", image_str, target_str]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(
+        stdout.contains("/etc/hostname: content differs"),
+        "stdout: {}",
+        stdout
+    );
+
+    let _ = std::fs::remove_dir_all(&target);
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_fixture_image_verify_inline() {
+    if !can_mount_erofs() {
+        return;
+    }
+    let image = fixture_image("recstrap_integration_inline_fixture");
+    let target = std::env::temp_dir().join("recstrap_integration_inline_fixture");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(&target).unwrap();
+
+    let output = run_recstrap(&[
+        "--rootfs",
+        image.to_str().unwrap(),
+        "--force",
+        "--unattended",
+        "--verify",
+        "inline",
+        target.to_str().unwrap(),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(
+        stderr.contains("checksums taken while copying"),
+        "stderr: {}",
+        stderr
+    );
+    assert_eq!(
+        std::fs::read(target.join("usr/share/doc/fixture/data")).unwrap(),
+        vec![0x5a; 10_000]
+    );
+
+    let _ = std::fs::remove_dir_all(&target);
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_options_from_environment() {
+    if !can_mount_erofs() {
+        return;
+    }
+    let image = fixture_image("recstrap_integration_env");
+    let target = std::env::temp_dir().join("recstrap_integration_env");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(&target).unwrap();
+
+    // As with --strict, the missing /etc/ssh fails the run
+    let output = Command::new(env!("CARGO_BIN_EXE_recstrap"))
+        .args(["--force", "--unattended", target.to_str().unwrap()])
+        .env("RECSTRAP_ROOTFS", &image)
+        .env("RECSTRAP_STRICT", "1")
+        .output()
+        .expect("Failed to execute recstrap");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(23), "stderr: {}", stderr);
+
+    // Arguments win over the environment; a stats file from the
+    // environment is ignored by --check instead of conflicting with it
+    let stats = std::env::temp_dir().join("recstrap_integration_env.json");
+    let _ = std::fs::remove_file(&stats);
+    let output = Command::new(env!("CARGO_BIN_EXE_recstrap"))
+        .args(["--check", "--force", "--space-margin", "0"])
+        .arg(target.to_str().unwrap())
+        .env("RECSTRAP_ROOTFS", &image)
+        .env("RECSTRAP_SPACE_MARGIN", "1001")
+        .env("RECSTRAP_STATS_FILE", &stats)
+        .output()
+        .expect("Failed to execute recstrap");
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!stats.exists());
+
+    let _ = std::fs::remove_dir_all(&target);
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_min_free_overrides_default() {
+    if !can_mount_erofs() {
+        return;
+    }
+    let image = fixture_image("recstrap_integration_min_free");
+    let target = std::env::temp_dir().join("recstrap_integration_min_free");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(&target).unwrap();
+
+    let output = run_recstrap(&[
+        "--rootfs",
+        image.to_str().unwrap(),
+        "--check",
+        "--force",
+        "--min-free",
+        "1000T",
+        target.to_str().unwrap(),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(12), "stderr: {}", stderr);
+    assert!(stderr.contains("need ~1048576000MB"), "stderr: {}", stderr);
+
+    let output = run_recstrap(&[
+        "--rootfs",
+        image.to_str().unwrap(),
+        "--check",
+        "--force",
+        "--min-free",
+        "1M",
+        "--space-margin",
+        "0",
+        target.to_str().unwrap(),
+    ]);
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let _ = std::fs::remove_dir_all(&target);
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_check_all_reports_every_failure() {
+    let output = run_recstrap(&["--all", "/mnt"]);
+    assert_eq!(output.status.code(), Some(2), "--all needs --check");
+
+    if !can_mount_erofs() {
+        return;
+    }
+    let image = fixture_image("recstrap_integration_check_all");
+    let target = std::env::temp_dir().join("recstrap_integration_check_all");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(target.join("data")).unwrap();
+
+    // Not a mount point, not empty, and too small
+    let output = run_recstrap(&[
+        "--rootfs",
+        image.to_str().unwrap(),
+        "--check",
+        "--all",
+        "--quiet",
+        "--min-free",
+        "1000T",
+        target.to_str().unwrap(),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(24), "stderr: {}", stderr);
+    assert!(
+        stderr.starts_with("recstrap: E024: 3 pre-flight checks failed: E011:"),
+        "stderr: {}",
+        stderr
+    );
+    assert!(
+        stderr.contains("; E009:") && stderr.contains("; E012:"),
+        "stderr: {}",
+        stderr
+    );
+
+    let _ = std::fs::remove_dir_all(&target);
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_strict_fails_on_warnings() {
+    if !can_mount_erofs() {
+        return;
+    }
+    // The fixture has no /etc/ssh, so key regeneration is skipped with a warning
+    let image = fixture_image("recstrap_integration_strict");
+    let target = std::env::temp_dir().join("recstrap_integration_strict");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(&target).unwrap();
+
+    let output = run_recstrap(&[
+        "--rootfs",
+        image.to_str().unwrap(),
+        "--force",
+        "--unattended",
+        "--strict",
+        target.to_str().unwrap(),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(23), "stderr: {}", stderr);
+    assert!(stderr.contains("Warnings:  1"), "stderr: {}", stderr);
+    assert!(
+        stderr.contains("E023: 1 warning(s) with --strict: /etc/ssh not found"),
+        "stderr: {}",
+        stderr
+    );
+
+    let _ = std::fs::remove_dir_all(&target);
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_check_reports_collisions() {
+    if !can_mount_erofs() {
+        return;
+    }
+    let image = fixture_image("recstrap_integration_collisions");
+    let target = std::env::temp_dir().join("recstrap_integration_collisions");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(target.join("etc")).unwrap();
+    std::fs::create_dir_all(target.join("home/user")).unwrap();
+    std::fs::write(target.join("etc/hostname"), "old\n").unwrap();
+    std::fs::write(target.join("etc/fstab"), "").unwrap();
+
+    let output = run_recstrap(&[
+        "--rootfs",
+        image.to_str().unwrap(),
+        "--force",
+        "--check",
+        target.to_str().unwrap(),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(
+        stderr.contains("Existing paths the image would overwrite:\n  etc/ (1 path)\n"),
+        "stderr: {}",
+        stderr
+    );
+    assert!(stderr.contains("Left alone: home/\n"), "stderr: {}", stderr);
+    // Nothing was touched
+    assert_eq!(
+        std::fs::read_to_string(target.join("etc/hostname")).unwrap(),
+        "old\n"
+    );
+
+    let _ = std::fs::remove_dir_all(&target);
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_force_preserves_paths() {
+    if !can_mount_erofs() {
+        return;
+    }
+    let image = fixture_image("recstrap_integration_preserve");
+    let target = std::env::temp_dir().join("recstrap_integration_preserve");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(target.join("etc")).unwrap();
+    std::fs::create_dir_all(target.join("srv/www")).unwrap();
+    std::fs::write(target.join("etc/hostname"), "kept\n").unwrap();
+    std::fs::write(target.join("srv/www/index.html"), "hi\n").unwrap();
+
+    let output = run_recstrap(&[
+        "--rootfs",
+        image.to_str().unwrap(),
+        "--force",
+        "--unattended",
+        "--preserve",
+        "/etc/hostname",
+        "--preserve",
+        "srv",
+        target.to_str().unwrap(),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(
+        stderr.contains("Preserving: /etc/hostname, /srv"),
+        "stderr: {}",
+        stderr
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.join("etc/hostname")).unwrap(),
+        "kept\n"
+    );
+    assert!(target.join("srv/www/index.html").is_file());
+    assert!(target.join("usr/bin/sh").is_file());
+    assert!(!target.join(".recstrap-preserved").exists());
+
+    let _ = std::fs::remove_dir_all(&target);
+    let _ = std::fs::remove_file(&image);
+}
+
+#[test]
+fn test_self_test() {
+    let output = run_recstrap(&["self-test"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !is_root() {
+        assert!(stderr.contains("E008:"), "stderr was: {}", stderr);
+    } else if can_mount_erofs() {
+        assert!(output.status.success(), "stderr was: {}", stderr);
+        assert!(
+            stderr.contains("Self-test passed"),
+            "stderr was: {}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_inspect_listed_in_help() {
+    let output = run_recstrap(&["--help"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("inspect"), "Help should list inspect");
+    assert!(stdout.contains("find"), "Help should list find");
+}
+
+#[test]
+fn test_rootfs_from_stdin_validates_magic() {
+    if !is_root() {
+        return;
+    }
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let target = std::env::temp_dir().join("recstrap_integration_stdin");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(&target).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_recstrap"))
+        .args(["--force", "--check", "--rootfs", "-"])
+        .arg(&target)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute recstrap");
+    child.stdin.take().unwrap().write_all(&[0u8; 4096]).unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("E016:"), "stderr was: {}", stderr);
+
+    let _ = std::fs::remove_dir_all(&target);
+}
+
+#[test]
+fn test_check_tap_output() {
+    let output = run_recstrap(&["--check", "--output", "tap", "/nonexistent/path/12345"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.starts_with("TAP version 13\n"),
+        "stdout was: {}",
+        stdout
+    );
+    assert!(stdout.contains("\nnot ok "), "stdout was: {}", stdout);
+    if is_root() {
+        assert!(
+            stdout.contains("ok 1 - Installation runs with sufficient privileges"),
+            "stdout was: {}",
+            stdout
+        );
+        assert!(stdout.ends_with("1..2\n"), "stdout was: {}", stdout);
+    }
+}
+
+#[test]
+fn test_output_requires_check() {
+    let output = run_recstrap(&["--output", "tap", "/mnt"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_password_hash_fd_validated_up_front() {
+    // stdin/stdout/stderr are taken
+    let output = run_recstrap(&["--password-hash-fd", "0", "/mnt"]);
+    assert_eq!(output.status.code(), Some(2));
+
+    if !is_root() {
+        return;
+    }
+    let output = run_recstrap(&["--password-hash-fd", "9", "/nonexistent"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(20), "stderr was: {}", stderr);
+    assert!(
+        stderr.contains("9 is not an open file descriptor"),
+        "stderr was: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_autologin_rejects_invalid_user() {
+    let output = run_recstrap(&["--autologin", "Kiosk:x", "/mnt"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr.contains("invalid user name"),
+        "stderr was: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_answers_file_rejects_unknown_keys() {
+    let file = std::env::temp_dir().join("recstrap_test_answers.toml");
+    std::fs::write(&file, "target = \"/mnt\"\nhostnme = \"typo\"\n").unwrap();
+    let output = run_recstrap(&["--answers", file.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(20), "stderr was: {}", stderr);
+    assert!(
+        stderr.contains("unknown key 'hostnme'"),
+        "stderr was: {}",
+        stderr
+    );
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+fn test_answers_conflicts_with_target() {
+    let output = run_recstrap(&["--answers", "install.toml", "/mnt"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_stats_file_written_on_failure() {
+    let file = std::env::temp_dir().join("recstrap_test_stats.json");
+    let _ = std::fs::remove_file(&file);
+    let output = run_recstrap(&[
+        "--stats-file",
+        file.to_str().unwrap(),
+        "/nonexistent/path/12345",
+    ]);
+    assert!(!output.status.success());
+    let report = std::fs::read_to_string(&file).unwrap();
+    assert!(
+        report.contains("\"success\": false"),
+        "report was: {}",
+        report
+    );
+    assert!(
+        report.contains("\"statistics\": null"),
+        "report was: {}",
+        report
+    );
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+fn test_quiet_failure_moves_banner_to_stats_file() {
+    let file = std::env::temp_dir().join("recstrap_test_diagnostics.json");
+    let _ = std::fs::remove_file(&file);
+    let output = run_recstrap(&[
+        "--quiet",
+        "--stats-file",
+        file.to_str().unwrap(),
+        "/nonexistent/path/12345",
+    ]);
+    assert!(!output.status.success());
+    // Only the error line (E001 as root, E008 otherwise)
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.lines().count(), 1, "stderr was: {}", stderr);
+    assert!(
+        stderr.starts_with("recstrap: E00"),
+        "stderr was: {}",
+        stderr
+    );
+    let report = std::fs::read_to_string(&file).unwrap();
+    assert!(
+        report.contains("\"diagnostics\": [") && report.contains("\"cheats\": ["),
+        "report was: {}",
+        report
+    );
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+fn test_checks_list() {
+    let output = run_recstrap(&["checks", "--list", "--json"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.trim_start().starts_with('['), "stdout: {}", stdout);
+    assert!(
+        stdout.contains("\"id\": \"runs-as-root\""),
+        "stdout: {}",
+        stdout
+    );
+    assert!(
+        stdout.contains("\"stage\": \"verification\""),
+        "stdout: {}",
+        stdout
+    );
+
+    let output = run_recstrap(&["checks"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_targets_conflicts_with_target() {
+    let output = run_recstrap(&["--targets", "/mnt/a,/mnt/b", "/mnt"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_targets_stops_at_first_failure() {
+    // E001 as root, E008 otherwise: either way only the first is tried
+    let output = run_recstrap(&["--targets", "/nonexistent/a,/nonexistent/b"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Target 1 of 2"), "stderr: {}", stderr);
+    assert!(!stderr.contains("Target 2 of 2"), "stderr: {}", stderr);
+}
+
+#[test]
+fn test_rootless_rejects_kernel_backend() {
+    let output = run_recstrap(&["--rootless", "--backend", "erofs-mount", "/tmp"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("E018:"), "stderr was: {}", stderr);
+    assert!(stderr.contains("erofs-mount"), "stderr was: {}", stderr);
+}
+
+#[test]
+fn test_preview_missing_image() {
+    let output = run_recstrap(&["preview", "/nonexistent/filesystem.erofs"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let expected = if is_root() { "E004:" } else { "E008:" };
+    assert!(stderr.contains(expected), "stderr was: {}", stderr);
+}
+
+#[test]
+fn test_library_preflight_reports_failure() {
+    let report = recstrap::Installer::new("/nonexistent/path/12345").preflight();
+    assert!(!report.passed());
+    let error = report.error.unwrap().to_string();
+    let expected = if is_root() { "E001:" } else { "E008:" };
+    assert!(error.starts_with(expected), "error was: {}", error);
+    assert!(report.checks.iter().any(|c| !c.passed));
+}
+
+#[test]
+fn test_serve_installs_over_socket() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    if !can_mount_erofs() {
+        return;
+    }
+    let image = fixture_image("recstrap_integration_serve");
+    let target = std::env::temp_dir().join("recstrap_integration_serve");
+    let socket = std::env::temp_dir().join("recstrap_integration_serve.sock");
+    let _ = std::fs::remove_dir_all(&target);
+    std::fs::create_dir_all(&target).unwrap();
+
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_recstrap"))
+        .args(["--quiet", "--serve", socket.to_str().unwrap()])
+        .spawn()
+        .unwrap();
+    let stream = (0..50)
+        .find_map(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            UnixStream::connect(&socket).ok()
+        })
+        .expect("daemon did not start");
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut call = |id: u32, method: &str, params: &str| {
+        writeln!(
+            writer,
+            r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{}}}"#,
+            id, method, params
+        )
+        .unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line
+    };
+
+    let params = format!(
+        r#"{{"target":"{}","rootfs":"{}","force":true}}"#,
+        target.display(),
+        image.display()
+    );
+    let preflight = call(1, "preflight", &params);
+    assert!(preflight.contains(r#""passed":true"#), "{}", preflight);
+    let started = call(2, "start", &params);
+    assert!(started.contains(r#""started":true"#), "{}", started);
+
+    let mut progress = String::new();
+    for id in 3..300 {
+        progress = call(id, "progress", "{}");
+        if !progress.contains(r#""state":"running""#) {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let _ = daemon.kill();
+    let _ = daemon.wait();
+    assert!(progress.contains(r#""state":"succeeded""#), "{}", progress);
+    assert!(progress.contains(r#""statistics":{"#), "{}", progress);
+    assert!(target.join("usr/share/doc/fixture/data").is_file());
+
+    let _ = std::fs::remove_dir_all(&target);
+    let _ = std::fs::remove_file(&image);
+    let _ = std::fs::remove_file(&socket);
+}
+
+#[test]
+fn test_serve_stdio() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    if !is_root() {
+        return;
+    }
+    let mut helper = Command::new(env!("CARGO_BIN_EXE_recstrap"))
+        .args(["--serve", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Closing stdin ends the session
+    helper
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(
+            b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"progress\"}\n\
+              {\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"cancel\"}\n",
+        )
+        .unwrap();
+    let output = helper.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "stdout: {}", stdout);
+    assert!(lines[0].starts_with(r#"{"jsonrpc":"2.0","id":1,"result":{"state":"idle""#));
+    assert_eq!(
+        lines[1],
+        r#"{"jsonrpc":"2.0","id":2,"result":{"cancelled":false}}"#
+    );
+}